@@ -38,14 +38,14 @@ mod expand {
         let parse_maybe_fn_content = parse_maybe_fn_content(&data_model)?;
 
         let parse_fn = quote! {
-            fn parse_maybe(args: &mut &[&#lifetime str]) -> Result<Option<Self>, ::command_args::Error> {
+            fn parse_maybe(args: &mut &[&#lifetime [u8]]) -> Result<Option<Self>, ::command_args::Error> {
                 #parse_maybe_fn_content
             }
         };
 
         let encode_fn_content = encode_fn_content(&data_model)?;
         let encode_fn = quote! {
-            fn encode(&self, target: &mut Vec<String>) -> Result<(), ::command_args::Error> {
+            fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), ::command_args::Error> {
                 #encode_fn_content
             }
         };
@@ -374,7 +374,7 @@ mod expand {
         };
 
         Ok(quote_spanned! {span=>
-            let result = match args.get(0) {
+            let result = match args.first() {
                 #(#variant_matches)*
                 #catch_all_arm
             };
@@ -393,7 +393,7 @@ mod expand {
     /// ```
     /// into
     /// ```rust,ignore
-    /// Some(a) if a.eq_ignore_ascii_case("NX") => Some(Self::Nx)
+    /// Some(a) if a.eq_ignore_ascii_case("NX".as_bytes()) => Some(Self::Nx)
     /// ```
     fn variant_match_arm(variant: &Variant) -> Result<TokenStream> {
         let span = variant.span;
@@ -406,7 +406,7 @@ mod expand {
                 let span = named.span();
 
                 quote_spanned! {span=>
-                    Some(a) if a.eq_ignore_ascii_case(#variant_token) => {
+                    Some(a) if a.eq_ignore_ascii_case(#variant_token.as_bytes()) => {
                         *args = &args[1..];
                         #field_vars
 
@@ -419,7 +419,7 @@ mod expand {
                 let span = tuple.span();
 
                 quote_spanned! {span=>
-                    Some(a) if a.eq_ignore_ascii_case(#variant_token) => {
+                    Some(a) if a.eq_ignore_ascii_case(#variant_token.as_bytes()) => {
                         *args = &args[1..];
                         #field_vars
 
@@ -429,7 +429,7 @@ mod expand {
             }
             Fields::Unit => {
                 quote_spanned! {span=>
-                    Some(a) if a.eq_ignore_ascii_case(#variant_token) => {
+                    Some(a) if a.eq_ignore_ascii_case(#variant_token.as_bytes()) => {
                         *args = &args[1..];
                         Some(Self::#ident)
                     }
@@ -442,8 +442,8 @@ mod expand {
         let parse_token = if let Some(token) = &s.token {
             let token_span = token.span();
             quote_spanned! {token_span=>
-                match args.get(0) {
-                    Some(s) if s.eq_ignore_ascii_case(#token) => {
+                match args.first() {
+                    Some(s) if s.eq_ignore_ascii_case(#token.as_bytes()) => {
                         *args = &args[1..];
                     },
                     _ => { return Ok(None); }
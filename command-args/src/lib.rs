@@ -1,28 +1,58 @@
 use std::fmt::Display;
 
+/// Arguments of a command, parsed from and encoded to binary-safe strings
 pub trait CommandArgs<'a>: Sized {
-    fn encode(&self, target: &mut Vec<String>) -> Result<(), Error>;
-    fn parse_maybe(args: &mut &[&'a str]) -> Result<Option<Self>, Error>;
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error>;
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error>;
 }
 
+/// Takes the first argument, if any
+fn next_arg<'a>(args: &mut &[&'a [u8]]) -> Option<&'a [u8]> {
+    let (first, rest) = args.split_first()?;
+    *args = rest;
+
+    Some(first)
+}
+
+/// Parses the first argument as text with `FromStr`
+fn parse_next<T: std::str::FromStr>(args: &mut &[&[u8]]) -> Result<Option<T>, Error> {
+    match next_arg(args) {
+        Some(s) => {
+            let s = std::str::from_utf8(s).map_err(|_| Error::Parse)?;
+            Ok(Some(s.parse().map_err(|_| Error::Parse)?))
+        }
+        None => Ok(None),
+    }
+}
+
+impl<'a> CommandArgs<'a> for &'a [u8] {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        target.push(self.to_vec());
+        Ok(())
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
+        Ok(next_arg(args))
+    }
+}
+
+/// Text argument, fails to parse if it is not valid UTF-8
 impl<'a> CommandArgs<'a> for &'a str {
-    fn encode(&self, target: &mut Vec<String>) -> Result<(), Error> {
-        target.push(self.to_string());
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        target.push(self.as_bytes().to_vec());
         Ok(())
     }
 
-    fn parse_maybe(args: &mut &[&'a str]) -> Result<Option<Self>, Error> {
-        if let Some(s) = args.get(0) {
-            *args = &args[1..];
-            Ok(Some(s))
-        } else {
-            Ok(None)
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
+        match next_arg(args) {
+            Some(s) => Ok(Some(std::str::from_utf8(s).map_err(|_| Error::Parse)?)),
+            None => Ok(None),
         }
     }
 }
 
 impl<'a, T: CommandArgs<'a>> CommandArgs<'a> for Vec<T> {
-    fn encode(&self, target: &mut Vec<String>) -> Result<(), Error> {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
         for ele in self.iter() {
             ele.encode(target)?;
         }
@@ -30,7 +60,7 @@ impl<'a, T: CommandArgs<'a>> CommandArgs<'a> for Vec<T> {
         Ok(())
     }
 
-    fn parse_maybe(args: &mut &[&'a str]) -> Result<Option<Self>, Error> {
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
         if args.is_empty() {
             return Ok(None);
         }
@@ -45,21 +75,69 @@ impl<'a, T: CommandArgs<'a>> CommandArgs<'a> for Vec<T> {
 }
 
 impl<'a> CommandArgs<'a> for usize {
-    fn encode(&self, target: &mut Vec<String>) -> Result<(), Error> {
-        target.push(format!("{}", self));
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        target.push(self.to_string().into_bytes());
         Ok(())
     }
 
-    fn parse_maybe(args: &mut &[&'a str]) -> Result<Option<Self>, Error> {
-        if let Some(s) = args.get(0) {
-            *args = &args[1..];
-            Ok(Some(s.parse().map_err(|_| Error::Parse)?))
-        } else {
-            Ok(None)
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
+        parse_next(args)
+    }
+}
+
+impl<'a> CommandArgs<'a> for i64 {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        target.push(self.to_string().into_bytes());
+        Ok(())
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
+        parse_next(args)
+    }
+}
+
+impl<'a> CommandArgs<'a> for f64 {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        target.push(self.to_string().into_bytes());
+        Ok(())
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
+        match parse_next::<f64>(args)? {
+            Some(f) if f.is_nan() => Err(Error::Parse),
+            f => Ok(f),
         }
     }
 }
 
+/// Elements prefixed by their count, like the `numkeys key [key ...]` arguments of ZUNION
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumKeys<T>(pub Vec<T>);
+
+impl<'a, T: CommandArgs<'a>> CommandArgs<'a> for NumKeys<T> {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+        self.0.len().encode(target)?;
+        self.0.encode(target)
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, Error> {
+        let count = match usize::parse_maybe(args)? {
+            Some(count) => count,
+            None => return Ok(None),
+        };
+        if count > args.len() {
+            return Err(Error::InvalidLength);
+        }
+
+        let mut result = Vec::with_capacity(count);
+        for _ in 0..count {
+            result.push(T::parse_maybe(args)?.ok_or(Error::InvalidLength)?);
+        }
+
+        Ok(Some(NumKeys(result)))
+    }
+}
+
 pub trait CommandBuilder<'a> {
     const NAME: &'static str;
 }
@@ -85,8 +163,61 @@ mod tests {
 
     #[test]
     fn test_parse_usize() {
-        let args = ["1"];
+        let args: [&[u8]; 1] = [b"1"];
         let s = <usize as CommandArgs>::parse_maybe(&mut &args[..]).unwrap();
         assert_eq!(s, Some(1));
     }
+
+    #[test]
+    fn test_parse_i64() {
+        let args: [&[u8]; 1] = [b"-10"];
+        let s = <i64 as CommandArgs>::parse_maybe(&mut &args[..]).unwrap();
+        assert_eq!(s, Some(-10));
+
+        let args: [&[u8]; 1] = [b"a"];
+        assert!(<i64 as CommandArgs>::parse_maybe(&mut &args[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_bytes_str() {
+        let args: [&[u8]; 2] = [b"\xff\x00", b"text"];
+        let mut rest = &args[..];
+        let bytes = <&[u8] as CommandArgs>::parse_maybe(&mut rest).unwrap();
+        assert_eq!(bytes, Some(&b"\xff\x00"[..]));
+        let text = <&str as CommandArgs>::parse_maybe(&mut rest).unwrap();
+        assert_eq!(text, Some("text"));
+
+        let args: [&[u8]; 1] = [b"\xff"];
+        assert!(<&str as CommandArgs>::parse_maybe(&mut &args[..]).is_err());
+    }
+
+    #[test]
+    fn test_num_keys() {
+        let args: [&[u8]; 4] = [b"2", b"a", b"b", b"WEIGHTS"];
+        let mut rest = &args[..];
+        let keys = <NumKeys<&str> as CommandArgs>::parse_maybe(&mut rest).unwrap();
+        assert_eq!(keys, Some(NumKeys(vec!["a", "b"])));
+        assert_eq!(rest, [b"WEIGHTS"]);
+
+        let mut encoded = Vec::new();
+        keys.unwrap().encode(&mut encoded).unwrap();
+        assert_eq!(encoded, vec![b"2", b"a", b"b"]);
+
+        let args: [&[u8]; 3] = [b"3", b"a", b"b"];
+        assert!(<NumKeys<&str> as CommandArgs>::parse_maybe(&mut &args[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_f64() {
+        let args: [&[u8]; 1] = [b"1.5"];
+        let f = <f64 as CommandArgs>::parse_maybe(&mut &args[..]).unwrap();
+        assert_eq!(f, Some(1.5));
+
+        let args: [&[u8]; 1] = [b"-inf"];
+        let f = <f64 as CommandArgs>::parse_maybe(&mut &args[..]).unwrap();
+        assert_eq!(f, Some(f64::NEG_INFINITY));
+
+        let args: [&[u8]; 1] = [b"nan"];
+        assert!(<f64 as CommandArgs>::parse_maybe(&mut &args[..]).is_err());
+    }
 }
@@ -0,0 +1,309 @@
+use std::collections::{HashMap, VecDeque};
+
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{byte_string::ByteString, database::ListEnd, memds::MemDS, Error};
+
+/// Pop done for a client blocked by BLPOP, BLMOVE, BZPOPMIN..., from the first of its keys
+/// holding a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockedPop {
+    /// Pop an element from an end of a list, BLPOP and BRPOP
+    List(ListEnd),
+    /// Move an element between lists, BLMOVE and BRPOPLPUSH
+    Move {
+        destination: ByteString,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    /// Pop the member with the lowest score of a sorted set, the highest if `max` is true
+    SortedSet { max: bool },
+}
+
+impl BlockedPop {
+    /// Whether the pop can be done from `value`, a client stays blocked on a key of
+    /// another type
+    pub(crate) fn accepts(&self, value: &MemDS) -> bool {
+        match self {
+            BlockedPop::List(_) | BlockedPop::Move { .. } => matches!(value, MemDS::List(_)),
+            BlockedPop::SortedSet { .. } => matches!(value, MemDS::SortedSet(_)),
+        }
+    }
+
+    /// Command equivalent to the pop from `key`, logged to the AOF
+    pub(crate) fn aof_record(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let command: &[u8] = match self {
+            BlockedPop::List(ListEnd::Head) => b"LPOP",
+            BlockedPop::List(ListEnd::Tail) => b"RPOP",
+            BlockedPop::Move { .. } => b"LMOVE",
+            BlockedPop::SortedSet { max: false } => b"ZPOPMIN",
+            BlockedPop::SortedSet { max: true } => b"ZPOPMAX",
+        };
+        let mut record = vec![command.to_vec(), key.to_vec()];
+        if let BlockedPop::Move {
+            destination,
+            from,
+            to,
+        } = self
+        {
+            record.push(destination.to_vec());
+            record.push(direction(*from).to_vec());
+            record.push(direction(*to).to_vec());
+        }
+
+        record
+    }
+}
+
+fn direction(end: ListEnd) -> &'static [u8] {
+    match end {
+        ListEnd::Head => b"LEFT",
+        ListEnd::Tail => b"RIGHT",
+    }
+}
+
+/// What was popped for a blocking command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Popped {
+    List {
+        key: ByteString,
+        element: ByteString,
+    },
+    /// Element moved from the source list `key` to the destination list
+    Moved {
+        key: ByteString,
+        element: ByteString,
+    },
+    SortedSet {
+        key: ByteString,
+        member: ByteString,
+        score: f64,
+    },
+}
+
+impl Popped {
+    /// Key popped from
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Popped::List { key, .. }
+            | Popped::Moved { key, .. }
+            | Popped::SortedSet { key, .. } => key,
+        }
+    }
+}
+
+/// Result of a pop sent to a blocked client, an error if the pop failed
+pub type PopResult = Result<Popped, Error>;
+
+/// Outcome of `Database::pop_or_block`
+#[derive(Debug)]
+pub enum PopOrBlock {
+    Popped(Popped),
+    /// None of the keys holds a value and the client may not block
+    Empty,
+    Blocked(Blocked),
+}
+
+/// Client blocked on keys of a database until a pop is done for it or its timeout
+#[derive(Debug)]
+pub struct Blocked {
+    /// Number of the database of the keys
+    pub(crate) db: usize,
+    /// Id of the client in the wait queues of the database
+    pub(crate) id: u64,
+    pub(crate) receiver: oneshot::Receiver<PopResult>,
+    /// When the client stops waiting, None to wait forever
+    pub(crate) deadline: Option<Instant>,
+    /// Whether the reply is written with RESP3
+    pub(crate) resp3: bool,
+}
+
+/// How a blocked client is woken up
+#[derive(Debug)]
+pub enum Wakeup {
+    Popped(PopResult),
+    TimedOut,
+}
+
+impl Blocked {
+    /// Waits until a pop is done for the client or its deadline passes, cancel safe
+    pub async fn wait(&mut self) -> Wakeup {
+        let received = match self.deadline {
+            Some(deadline) => tokio::select! {
+                received = &mut self.receiver => received,
+                _ = tokio::time::sleep_until(deadline) => return Wakeup::TimedOut,
+            },
+            None => (&mut self.receiver).await,
+        };
+
+        match received {
+            Ok(result) => Wakeup::Popped(result),
+            // the wait queues are gone with the database
+            Err(_) => Wakeup::TimedOut,
+        }
+    }
+}
+
+struct Waiter {
+    keys: Vec<ByteString>,
+    pop: BlockedPop,
+    sender: oneshot::Sender<PopResult>,
+}
+
+/// Clients blocked on the keys of a database, served in the order they blocked
+#[derive(Default)]
+pub(crate) struct WaitQueues {
+    waiters: HashMap<u64, Waiter>,
+    /// Ids of the clients blocked on each key, oldest first
+    queues: HashMap<ByteString, VecDeque<u64>>,
+    /// Keys with blocked clients modified since they were last served
+    ready: Vec<ByteString>,
+}
+
+impl WaitQueues {
+    /// Number of blocked clients
+    pub(crate) fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// Blocks client `id` on `keys` for `pop`, its result is sent to `sender`
+    pub(crate) fn add(
+        &mut self,
+        id: u64,
+        keys: &[&[u8]],
+        pop: BlockedPop,
+        sender: oneshot::Sender<PopResult>,
+    ) {
+        let keys: Vec<ByteString> = keys.iter().map(|&key| key.into()).collect();
+        for key in &keys {
+            let queue = self.queues.entry(key.clone()).or_default();
+            // a key given twice is waited once
+            if !queue.contains(&id) {
+                queue.push_back(id);
+            }
+        }
+        self.waiters.insert(id, Waiter { keys, pop, sender });
+    }
+
+    /// Unblocks client `id` without a result, returns false if it was not blocked anymore
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        self.take(id).is_some()
+    }
+
+    fn take(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|&waiting| waiting != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+
+        Some(waiter)
+    }
+
+    /// Marks `key` modified, to serve the clients blocked on it
+    pub(crate) fn mark_ready(&mut self, key: &[u8]) {
+        if self.queues.contains_key(key) && !self.ready.iter().any(|ready| ready == key) {
+            self.ready.push(key.into());
+        }
+    }
+
+    /// Marks all the keys with blocked clients modified
+    pub(crate) fn mark_all_ready(&mut self) {
+        self.ready = self.queues.keys().cloned().collect();
+    }
+
+    /// Takes the keys marked modified, in the order they were modified
+    pub(crate) fn take_ready(&mut self) -> Vec<ByteString> {
+        std::mem::take(&mut self.ready)
+    }
+
+    /// Oldest client blocked on `key` with its pop
+    pub(crate) fn first(&self, key: &[u8]) -> Option<(u64, &BlockedPop)> {
+        let id = *self.queues.get(key)?.front()?;
+
+        Some((id, &self.waiters[&id].pop))
+    }
+
+    /// Unblocks client `id` with the `result` of its pop
+    pub(crate) fn wake(&mut self, id: u64, result: PopResult) {
+        if let Some(waiter) = self.take(id) {
+            if waiter.sender.send(result).is_err() {
+                tracing::warn!("Blocked client {} is gone, its pop is lost", id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_queues() {
+        let mut queues = WaitQueues::default();
+        let (sender_a, mut receiver_a) = oneshot::channel();
+        let (sender_b, _receiver_b) = oneshot::channel();
+        queues.add(1, &[b"x", b"y"], BlockedPop::List(ListEnd::Head), sender_a);
+        queues.add(
+            2,
+            &[b"y", b"y"],
+            BlockedPop::SortedSet { max: false },
+            sender_b,
+        );
+        assert_eq!(queues.len(), 2);
+
+        queues.mark_ready(b"y");
+        queues.mark_ready(b"z");
+        queues.mark_ready(b"y");
+        assert_eq!(queues.take_ready(), vec![ByteString::from("y")]);
+        assert_eq!(
+            queues.first(b"y"),
+            Some((1, &BlockedPop::List(ListEnd::Head)))
+        );
+
+        let popped = Popped::Moved {
+            key: "x".into(),
+            element: "a".into(),
+        };
+        queues.wake(1, Ok(popped.clone()));
+        assert_eq!(receiver_a.try_recv().unwrap().unwrap(), popped);
+        assert_eq!(queues.first(b"x"), None);
+        assert_eq!(
+            queues.first(b"y"),
+            Some((2, &BlockedPop::SortedSet { max: false }))
+        );
+
+        assert!(queues.remove(2));
+        assert!(!queues.remove(2));
+        assert_eq!(queues.len(), 0);
+        queues.mark_all_ready();
+        assert!(queues.take_ready().is_empty());
+    }
+
+    #[test]
+    fn test_aof_record() {
+        let pop = BlockedPop::Move {
+            destination: "d".into(),
+            from: ListEnd::Tail,
+            to: ListEnd::Head,
+        };
+        assert_eq!(
+            pop.aof_record(b"s"),
+            vec![
+                b"LMOVE".to_vec(),
+                b"s".to_vec(),
+                b"d".to_vec(),
+                b"RIGHT".to_vec(),
+                b"LEFT".to_vec()
+            ]
+        );
+        assert_eq!(
+            BlockedPop::SortedSet { max: true }.aof_record(b"z"),
+            vec![b"ZPOPMAX".to_vec(), b"z".to_vec()]
+        );
+    }
+}
@@ -1,25 +1,34 @@
 use anyhow::Context;
 use command_args::CommandArgs;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::de::DeserializeOwned;
 use tokio::net::{tcp::OwnedWriteHalf, TcpStream, ToSocketAddrs};
 
 use crate::{
-    command::CommandHandler,
+    command::{connection::HelloCommand, CommandHandler},
     connection::{flush, FrameReader},
+    resp,
 };
 
 pub struct Client {
     frame_reader: FrameReader,
     writer: OwnedWriteHalf,
     write_buf: Vec<u8>,
-    command_buf: Vec<String>,
+    command_buf: Vec<Vec<u8>>,
 }
 
 impl Client {
+    /// Connects to the server at `addr` and switches to RESP3, the protocol replies are
+    /// parsed with
     pub async fn from_addr<A: ToSocketAddrs>(addr: A) -> anyhow::Result<Self> {
         let conn = TcpStream::connect(addr).await?;
+        let mut client = Self::new(conn);
+        let hello = HelloCommand {
+            protover: 3,
+            auth: None,
+        };
+        client.execute(&hello).await?;
 
-        Ok(Self::new(conn))
+        Ok(client)
     }
 
     pub fn new(socket: TcpStream) -> Self {
@@ -49,10 +58,7 @@ impl Client {
             .encode(&mut self.command_buf)
             .context("Failed to encode command")?;
 
-        let mut serializer = deseresp::from_write(&mut self.write_buf);
-        self.command_buf
-            .serialize(&mut serializer)
-            .context("Failed to serialize command to RESP")?;
+        resp::write_command(&self.command_buf, &mut self.write_buf);
 
         flush(&mut self.writer, &mut self.write_buf).await;
 
@@ -1,5 +1,7 @@
+use std::fmt::Write;
+
 use command_args_derive::CommandArgsBlock;
-use deseresp::types::OkResponse;
+use deseresp::types::{owned::BlobString, OkResponse};
 
 use crate::database::Database;
 
@@ -18,3 +20,94 @@ impl CommandHandler for SaveCommand {
         Ok(OkResponse)
     }
 }
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BGREWRITEAOF")]
+pub struct BgrewriteaofCommand;
+
+impl CommandHandler for BgrewriteaofCommand {
+    type Output = String;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.bgrewriteaof()?;
+
+        Ok("Background append only file rewriting started".to_string())
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BGSAVE")]
+pub struct BgsaveCommand;
+
+impl CommandHandler for BgsaveCommand {
+    type Output = String;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.bgsave()?;
+
+        Ok("Background saving started".to_string())
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LASTSAVE")]
+pub struct LastsaveCommand;
+
+impl CommandHandler for LastsaveCommand {
+    type Output = u64;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.lastsave())
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("INFO")]
+pub struct InfoCommand<'a> {
+    pub section: Option<&'a str>,
+}
+
+impl<'a> CommandHandler for InfoCommand<'a> {
+    type Output = BlobString;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let mut info = String::new();
+        let section = self.section.map(str::to_ascii_lowercase);
+
+        if matches!(
+            section.as_deref(),
+            None | Some("persistence" | "default" | "all" | "everything")
+        ) {
+            let persistence = db.persistence_info();
+            let flag = |b: bool| if b { 1 } else { 0 };
+
+            info.push_str("# Persistence\r\n");
+            write!(
+                info,
+                "rdb_changes_since_last_save:{}\r\n\
+                 rdb_bgsave_in_progress:{}\r\n\
+                 rdb_last_save_time:{}\r\n\
+                 rdb_last_bgsave_status:{}\r\n\
+                 current_save_keys_processed:{}\r\n\
+                 current_save_keys_total:{}\r\n\
+                 aof_enabled:{}\r\n\
+                 aof_rewrite_in_progress:{}\r\n",
+                persistence.changes_since_last_save,
+                flag(persistence.bgsave_in_progress),
+                persistence.last_save,
+                if persistence.last_bgsave_ok {
+                    "ok"
+                } else {
+                    "err"
+                },
+                persistence.save_keys_processed,
+                persistence.save_keys_total,
+                flag(persistence.aof_enabled),
+                flag(persistence.aof_rewrite_in_progress),
+            )
+            .unwrap();
+        }
+
+        Ok(BlobString(info))
+    }
+}
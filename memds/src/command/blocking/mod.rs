@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use serde::{ser::SerializeSeq, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    blocking::{BlockedPop, PopOrBlock, Popped, Wakeup},
+    byte_string,
+    database::Database,
+    resp,
+};
+
+use super::{ClientState, Error};
+
+/// Reply of a blocking command
+#[derive(Debug, PartialEq)]
+pub enum BlockingReply {
+    Popped {
+        popped: Popped,
+        /// Command equivalent to the pop, logged to the AOF instead of the blocking command
+        record: Vec<Vec<u8>>,
+    },
+    /// Nothing was popped before the timeout, a null reply
+    TimedOut,
+    /// The client is blocked, its reply is written once it is woken up
+    Blocked,
+}
+
+impl BlockingReply {
+    fn popped(popped: Popped, pop: &BlockedPop) -> Self {
+        BlockingReply::Popped {
+            record: pop.aof_record(popped.key()),
+            popped,
+        }
+    }
+
+    /// Replaces the blocking command logged to the AOF by the command equivalent to its pop,
+    /// nothing is logged if nothing was popped yet
+    pub(crate) fn amend_aof(&self, args: &mut Vec<Vec<u8>>) {
+        match self {
+            BlockingReply::Popped { record, .. } => args.clone_from(record),
+            BlockingReply::TimedOut | BlockingReply::Blocked => args.clear(),
+        }
+    }
+
+    /// Writes the reply, nothing while the client is blocked
+    pub(crate) fn write(self, resp3: bool, write_buf: &mut Vec<u8>) -> Result<(), Error> {
+        if self == BlockingReply::Blocked {
+            return Ok(());
+        }
+        let mut serializer = resp::from_write(write_buf, resp3);
+
+        self.serialize(&mut serializer)
+            .map_err(|e| Error::Serialize(e.to_string()))
+    }
+}
+
+impl Serialize for BlockingReply {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            BlockingReply::Popped {
+                popped: Popped::List { key, element },
+                ..
+            } => (key, element).serialize(serializer),
+            BlockingReply::Popped {
+                popped: Popped::Moved { element, .. },
+                ..
+            } => element.serialize(serializer),
+            BlockingReply::Popped {
+                popped: Popped::SortedSet { key, member, score },
+                ..
+            } => {
+                let mut seq = serializer.serialize_seq(Some(3))?;
+                seq.serialize_element(key)?;
+                seq.serialize_element(member)?;
+                seq.serialize_element(score)?;
+                seq.end()
+            }
+            BlockingReply::TimedOut => resp::NullArray.serialize(serializer),
+            BlockingReply::Blocked => Err(serde::ser::Error::custom(
+                "blocked clients get their reply once woken up",
+            )),
+        }
+    }
+}
+
+/// Parses a timeout in seconds, 0 to block forever
+fn parse_timeout(timeout: &[u8]) -> Result<Option<Duration>, Error> {
+    let secs: f64 = byte_string::parse(timeout)
+        .filter(|secs: &f64| secs.is_finite())
+        .ok_or_else(|| Error::Handle("ERR timeout is not a float or out of range".to_string()))?;
+    if secs < 0.0 {
+        return Err(Error::Handle("ERR timeout is negative".to_string()));
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .map_err(|_| Error::Handle("ERR timeout is out of range".to_string()))
+}
+
+/// Splits the keys and the timeout given last
+pub(crate) fn split_timeout<'a, 'b>(
+    keys_and_timeout: &'b [&'a [u8]],
+) -> Result<(&'b [&'a [u8]], &'a [u8]), Error> {
+    match keys_and_timeout.split_last() {
+        Some((timeout, keys)) if !keys.is_empty() => Ok((keys, timeout)),
+        _ => Err(Error::Parse(command_args::Error::InvalidLength)),
+    }
+}
+
+/// Pops for `pop` from the first of `keys` holding a value, otherwise blocks the client
+/// until one of them gets a value or `timeout` passes.
+/// Inside a transaction the client is never blocked, nothing is popped instead.
+pub(crate) fn pop_or_block(
+    db: &Database,
+    client: &mut ClientState,
+    keys: &[&[u8]],
+    timeout: &[u8],
+    pop: BlockedPop,
+) -> Result<BlockingReply, Error> {
+    let timeout = parse_timeout(timeout)?;
+    let resp3 = client.resp3();
+
+    match db.pop_or_block(keys, pop.clone(), !client.deny_blocking)? {
+        PopOrBlock::Popped(popped) => Ok(BlockingReply::popped(popped, &pop)),
+        PopOrBlock::Empty => Ok(BlockingReply::TimedOut),
+        PopOrBlock::Blocked(mut blocked) => {
+            blocked.deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+            blocked.resp3 = resp3;
+            client.blocked = Some(blocked);
+
+            Ok(BlockingReply::Blocked)
+        }
+    }
+}
+
+/// Writes the reply of the blocked client once woken up, returns true if it is an error
+pub fn unblock(
+    db: &Database,
+    client: &mut ClientState,
+    wakeup: Wakeup,
+    write_buf: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    let mut blocked = client
+        .blocked
+        .take()
+        .expect("only blocked clients are woken up");
+    let result = match wakeup {
+        Wakeup::Popped(result) => result,
+        Wakeup::TimedOut => {
+            if db.select(blocked.db)?.unblock(blocked.id) {
+                BlockingReply::TimedOut.write(blocked.resp3, write_buf)?;
+                return Ok(false);
+            }
+            // the pop was done right before the timeout
+            match blocked.receiver.try_recv() {
+                Ok(result) => result,
+                Err(_) => {
+                    BlockingReply::TimedOut.write(blocked.resp3, write_buf)?;
+                    return Ok(false);
+                }
+            }
+        }
+    };
+
+    match result {
+        Ok(popped) => {
+            // the pop was logged to the AOF when it was done
+            let reply = BlockingReply::Popped {
+                popped,
+                record: Vec::new(),
+            };
+            reply.write(blocked.resp3, write_buf)?;
+
+            Ok(false)
+        }
+        Err(Error::Handle(e)) => {
+            tracing::error!("Failed to pop for blocked client, e: {}", e);
+            let mut serializer = resp::from_write(write_buf, blocked.resp3);
+            deseresp::types::owned::SimpleError(e)
+                .serialize(&mut serializer)
+                .map_err(|e| Error::Serialize(e.to_string()))?;
+
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Unblocks a client, when its connection ends
+pub fn reset(db: &Database, client: &mut ClientState) {
+    if let Some(blocked) = client.blocked.take() {
+        if let Ok(db) = db.select(blocked.db) {
+            db.unblock(blocked.id);
+        }
+    }
+}
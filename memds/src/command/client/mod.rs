@@ -0,0 +1,463 @@
+use std::io::Write;
+
+use command_args_derive::CommandArgsBlock;
+use deseresp::types::OkResponse;
+use serde::Serialize;
+
+use crate::{
+    byte_string::ByteString,
+    database::Database,
+    tracking::{Push, TrackingOptions, INVALIDATE_CHANNEL},
+};
+
+use super::{
+    pubsub::write_push_header, requires_connection, write_bulk_bytes, ClientState, CommandHandler,
+    Error, MapReply,
+};
+
+/// Whether the command is CLIENT CACHING, which applies to the command following it
+pub(crate) fn is_caching_command(args: &[&[u8]]) -> bool {
+    matches!(args, [command, subcommand, ..]
+        if command.eq_ignore_ascii_case(b"CLIENT") && subcommand.eq_ignore_ascii_case(b"CACHING"))
+}
+
+/// Registers the connection of a client, giving it its id and the invalidations pushed to it
+pub fn connect(db: &Database, client: &mut ClientState) {
+    client.pushes = Some(db.tracking().connect());
+}
+
+/// Stops client-side caching for a client, when its connection ends
+pub fn reset(db: &Database, client: &mut ClientState) {
+    if let Some(pushes) = client.pushes.take() {
+        db.tracking().disconnect(pushes.id());
+    }
+    client.tracking = None;
+    client.caching = None;
+}
+
+/// Writes a message pushed to the client. Invalidations are pushed with RESP3, with RESP2
+/// they are written as messages of `INVALIDATE_CHANNEL` if the client subscribed to it,
+/// the client they are redirected to usually.
+pub fn write_push(client: &ClientState, push: &Push, write_buf: &mut Vec<u8>) -> Result<(), Error> {
+    match push {
+        Push::Invalidate(keys) => {
+            if client.resp3() {
+                write_push_header(2, true, write_buf)?;
+                write_bulk_bytes(Some(b"invalidate"), true, write_buf)?;
+            } else if client.subscription.as_ref().is_some_and(|subscription| {
+                subscription
+                    .channels()
+                    .any(|channel| channel == INVALIDATE_CHANNEL)
+            }) {
+                write_push_header(3, false, write_buf)?;
+                write_bulk_bytes(Some(b"message"), false, write_buf)?;
+                write_bulk_bytes(Some(INVALIDATE_CHANNEL), false, write_buf)?;
+            } else {
+                return Ok(());
+            }
+            match keys {
+                Some(keys) => {
+                    write_push_header(keys.len(), false, write_buf)?;
+                    for key in keys {
+                        write_bulk_bytes(Some(key), client.resp3(), write_buf)?;
+                    }
+                }
+                // all keys were flushed
+                None => write_bulk_bytes(None, client.resp3(), write_buf)?,
+            }
+        }
+        Push::RedirectBroken(id) => {
+            if client.resp3() {
+                write_push_header(2, true, write_buf)?;
+                write_bulk_bytes(Some(b"tracking-redir-broken"), true, write_buf)?;
+                write!(write_buf, ":{}\r\n", id).map_err(|e| Error::Serialize(e.to_string()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub enum Switch {
+    #[argtoken("ON")]
+    On,
+    #[argtoken("OFF")]
+    Off,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub enum YesNo {
+    #[argtoken("YES")]
+    Yes,
+    #[argtoken("NO")]
+    No,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub enum TrackingOption<'a> {
+    #[argtoken("REDIRECT")]
+    Redirect { client_id: usize },
+    #[argtoken("PREFIX")]
+    Prefix { prefix: &'a [u8] },
+    #[argtoken("BCAST")]
+    Bcast,
+    #[argtoken("OPTIN")]
+    Optin,
+    #[argtoken("OPTOUT")]
+    Optout,
+    #[argtoken("NOLOOP")]
+    Noloop,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub enum ClientSubcommand<'a> {
+    #[argtoken("ID")]
+    Id,
+    #[argtoken("TRACKING")]
+    Tracking {
+        status: Switch,
+        options: Option<Vec<TrackingOption<'a>>>,
+    },
+    #[argtoken("CACHING")]
+    Caching { mode: YesNo },
+    #[argtoken("GETREDIR")]
+    Getredir,
+    #[argtoken("TRACKINGINFO")]
+    Trackinginfo,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("CLIENT")]
+pub struct ClientCommand<'a> {
+    pub subcommand: ClientSubcommand<'a>,
+}
+
+/// Value of a field of CLIENT TRACKINGINFO
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum TrackingInfo {
+    Flags(Vec<&'static str>),
+    Redirect(i64),
+    Prefixes(Vec<ByteString>),
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ClientReply {
+    Id(u64),
+    Ok(OkResponse),
+    Redirect(i64),
+    Trackinginfo(MapReply<&'static str, TrackingInfo>),
+}
+
+fn tracking_options(options: Vec<TrackingOption>) -> Result<TrackingOptions, Error> {
+    let mut tracking = TrackingOptions::default();
+    for option in options {
+        match option {
+            TrackingOption::Redirect { client_id } => tracking.redirect = Some(client_id as u64),
+            TrackingOption::Prefix { prefix } => tracking.prefixes.push(prefix.into()),
+            TrackingOption::Bcast => tracking.bcast = true,
+            TrackingOption::Optin => tracking.optin = true,
+            TrackingOption::Optout => tracking.optout = true,
+            TrackingOption::Noloop => tracking.noloop = true,
+        }
+    }
+
+    if !tracking.bcast && !tracking.prefixes.is_empty() {
+        return Err(Error::Handle(
+            "ERR PREFIX option requires BCAST mode to be enabled".to_string(),
+        ));
+    }
+    if tracking.optin && tracking.optout {
+        return Err(Error::Handle(
+            "ERR You can't use both OPTIN and OPTOUT".to_string(),
+        ));
+    }
+    if tracking.bcast && (tracking.optin || tracking.optout) {
+        return Err(Error::Handle(
+            "ERR OPTIN and OPTOUT are not compatible with BCAST".to_string(),
+        ));
+    }
+
+    Ok(tracking)
+}
+
+/// Redirection of the invalidations, -1 if tracking is off and 0 without redirection
+fn redirect(client: &ClientState) -> i64 {
+    match &client.tracking {
+        Some(tracking) => tracking.redirect.map_or(0, |id| id as i64),
+        None => -1,
+    }
+}
+
+fn tracking_info(client: &ClientState) -> MapReply<&'static str, TrackingInfo> {
+    let mut flags = Vec::new();
+    let mut prefixes = Vec::new();
+    match &client.tracking {
+        Some(tracking) => {
+            flags.push("on");
+            for (flag, set) in [
+                ("bcast", tracking.bcast),
+                ("optin", tracking.optin),
+                ("optout", tracking.optout),
+                ("caching-yes", client.caching == Some(true)),
+                ("caching-no", client.caching == Some(false)),
+                ("noloop", tracking.noloop),
+            ] {
+                if set {
+                    flags.push(flag);
+                }
+            }
+            prefixes.clone_from(&tracking.prefixes);
+        }
+        None => flags.push("off"),
+    }
+
+    MapReply {
+        entries: vec![
+            ("flags", TrackingInfo::Flags(flags)),
+            ("redirect", TrackingInfo::Redirect(redirect(client))),
+            ("prefixes", TrackingInfo::Prefixes(prefixes)),
+        ],
+        resp3: client.resp3(),
+    }
+}
+
+impl<'a> CommandHandler for ClientCommand<'a> {
+    type Output = ClientReply;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(requires_connection("CLIENT"))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        Ok(match self.subcommand {
+            ClientSubcommand::Id => ClientReply::Id(client.id()),
+            ClientSubcommand::Tracking { status, options } => {
+                if client.pushes.is_none() {
+                    return Err(requires_connection("CLIENT TRACKING"));
+                }
+                let id = client.id();
+                match status {
+                    Switch::On => {
+                        let options = tracking_options(options.unwrap_or_default())?;
+                        db.tracking().enable(id, options)?;
+                        client.tracking = db.tracking().options(id);
+                    }
+                    Switch::Off => {
+                        db.tracking().disable(id);
+                        client.tracking = None;
+                        client.caching = None;
+                    }
+                }
+                ClientReply::Ok(OkResponse)
+            }
+            ClientSubcommand::Caching { mode } => {
+                let (optin, optout) = client
+                    .tracking
+                    .as_ref()
+                    .map_or((false, false), |tracking| (tracking.optin, tracking.optout));
+                if !optin && !optout {
+                    return Err(Error::Handle(
+                        "ERR CLIENT CACHING can be called only when the client is in tracking \
+                         mode with OPTIN or OPTOUT mode enabled"
+                            .to_string(),
+                    ));
+                }
+                client.caching = match mode {
+                    YesNo::Yes if optin => Some(true),
+                    YesNo::No if optout => Some(false),
+                    YesNo::Yes => {
+                        return Err(Error::Handle(
+                            "ERR CLIENT CACHING YES is only valid when tracking is enabled in \
+                             OPTIN mode."
+                                .to_string(),
+                        ))
+                    }
+                    YesNo::No => {
+                        return Err(Error::Handle(
+                            "ERR CLIENT CACHING NO is only valid when tracking is enabled in \
+                             OPTOUT mode."
+                                .to_string(),
+                        ))
+                    }
+                };
+                ClientReply::Ok(OkResponse)
+            }
+            ClientSubcommand::Getredir => ClientReply::Redirect(redirect(client)),
+            ClientSubcommand::Trackinginfo => ClientReply::Trackinginfo(tracking_info(client)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::run_commands;
+
+    fn pushes(client: &mut ClientState) -> String {
+        let mut write_buf = Vec::new();
+        while let Some(push) = client.pushes.as_mut().unwrap().try_recv() {
+            write_push(client, &push, &mut write_buf).unwrap();
+        }
+
+        String::from_utf8(write_buf).unwrap()
+    }
+
+    fn connected(db: &Database, protocol: usize) -> ClientState {
+        let mut client = ClientState {
+            protocol,
+            ..Default::default()
+        };
+        connect(db, &mut client);
+
+        client
+    }
+
+    #[test]
+    fn test_tracking() {
+        let db = Database::new(String::new()).unwrap();
+        let mut reader = connected(&db, 3);
+        let mut writer = connected(&db, 3);
+
+        let reply = run_commands(
+            &db,
+            &mut reader,
+            &[
+                &["CLIENT", "TRACKING", "ON"],
+                &["GET", "a"],
+                &["MGET", "b", "c"],
+            ],
+        );
+        assert_eq!(reply, "+OK\r\n_\r\n*2\r\n_\r\n_\r\n");
+        run_commands(&db, &mut writer, &[&["SET", "a", "1"], &["SET", "a", "2"]]);
+        run_commands(&db, &mut writer, &[&["DEL", "c"], &["SET", "d", "1"]]);
+        assert_eq!(
+            pushes(&mut reader),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\na\r\n\
+             >2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nc\r\n"
+        );
+
+        run_commands(&db, &mut writer, &[&["FLUSHALL"]]);
+        assert_eq!(pushes(&mut reader), ">2\r\n$10\r\ninvalidate\r\n_\r\n");
+
+        run_commands(
+            &db,
+            &mut reader,
+            &[&["CLIENT", "TRACKING", "OFF"], &["GET", "a"]],
+        );
+        run_commands(&db, &mut writer, &[&["SET", "a", "3"]]);
+        assert_eq!(pushes(&mut reader), "");
+        assert!(pushes(&mut writer).is_empty());
+    }
+
+    #[test]
+    fn test_tracking_modes() {
+        let db = Database::new(String::new()).unwrap();
+        let mut reader = connected(&db, 3);
+        let mut writer = connected(&db, 3);
+
+        run_commands(
+            &db,
+            &mut reader,
+            &[
+                &["CLIENT", "TRACKING", "ON", "OPTIN"],
+                &["GET", "a"],
+                &["CLIENT", "CACHING", "YES"],
+                &["GET", "b"],
+                &["GET", "c"],
+            ],
+        );
+        run_commands(&db, &mut writer, &[&["MSET", "a", "1", "b", "1", "c", "1"]]);
+        assert_eq!(
+            pushes(&mut reader),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nb\r\n"
+        );
+
+        let reply = run_commands(
+            &db,
+            &mut reader,
+            &[
+                &["CLIENT", "CACHING", "NO"],
+                &["CLIENT", "TRACKING", "OFF"],
+                &[
+                    "CLIENT", "TRACKING", "ON", "BCAST", "PREFIX", "user:", "NOLOOP",
+                ],
+                &["SET", "user:1", "a"],
+                &["CLIENT", "GETREDIR"],
+            ],
+        );
+        assert_eq!(
+            reply,
+            "-ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.\r\n\
+             +OK\r\n+OK\r\n+OK\r\n:0\r\n"
+        );
+        run_commands(
+            &db,
+            &mut writer,
+            &[&["SET", "user:2", "a"], &["SET", "x", "a"]],
+        );
+        assert_eq!(
+            pushes(&mut reader),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$6\r\nuser:2\r\n"
+        );
+
+        let reply = run_commands(&db, &mut reader, &[&["CLIENT", "TRACKINGINFO"]]);
+        assert_eq!(
+            reply,
+            "%3\r\n+flags\r\n*3\r\n+on\r\n+bcast\r\n+noloop\r\n\
+             +redirect\r\n:0\r\n+prefixes\r\n*1\r\n+user:\r\n"
+        );
+    }
+
+    #[test]
+    fn test_tracking_redirect() {
+        let db = Database::new(String::new()).unwrap();
+        let mut reader = connected(&db, 2);
+        let mut target = connected(&db, 2);
+        let redirect = target.id().to_string();
+
+        run_commands(&db, &mut target, &[&["SUBSCRIBE", "__redis__:invalidate"]]);
+        let reply = run_commands(
+            &db,
+            &mut reader,
+            &[
+                &["CLIENT", "TRACKING", "ON", "REDIRECT", &redirect],
+                &["GET", "a"],
+                &["SET", "a", "1"],
+            ],
+        );
+        assert_eq!(reply, "+OK\r\n$-1\r\n+OK\r\n");
+        assert_eq!(pushes(&mut reader), "");
+        assert_eq!(
+            pushes(&mut target),
+            "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\na\r\n"
+        );
+
+        let reply = run_commands(
+            &db,
+            &mut reader,
+            &[
+                &["CLIENT", "TRACKING", "ON", "PREFIX", "a"],
+                &["CLIENT", "TRACKING", "ON", "OPTIN", "OPTOUT"],
+                &["CLIENT", "TRACKING", "ON", "BCAST", "OPTIN"],
+                &["CLIENT", "TRACKING", "ON", "REDIRECT", "1000"],
+            ],
+        );
+        assert_eq!(
+            reply,
+            "-ERR PREFIX option requires BCAST mode to be enabled\r\n\
+             -ERR You can't use both OPTIN and OPTOUT\r\n\
+             -ERR OPTIN and OPTOUT are not compatible with BCAST\r\n\
+             -ERR The client ID you want redirect to does not exist\r\n"
+        );
+
+        reader.protocol = 3;
+        reset(&db, &mut target);
+        assert_eq!(
+            pushes(&mut reader),
+            format!(">2\r\n$21\r\ntracking-redir-broken\r\n:{}\r\n", redirect)
+        );
+    }
+}
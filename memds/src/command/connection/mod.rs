@@ -1,10 +1,10 @@
 use command_args_derive::CommandArgsBlock;
-use deseresp::types::owned::SimpleString;
-use serde::Serialize;
+use deseresp::types::{owned::SimpleString, OkResponse};
+use serde::{Deserialize, Serialize};
 
-use crate::database::Database;
+use crate::{byte_string::ByteString, database::Database, resp};
 
-use super::{CommandHandler, Error};
+use super::{pubsub, write_bulk_bytes, ClientState, CommandHandler, Error};
 
 #[derive(Debug, CommandArgsBlock)]
 #[argtoken("AUTH")]
@@ -24,7 +24,8 @@ pub struct HelloCommand<'a> {
 #[argtoken("COMMAND")]
 pub struct CommandCommand;
 
-#[derive(Debug, Serialize)]
+/// Reply of HELLO, a map with RESP3 and a flat array of keys and values with RESP2
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ServerProperties {
     pub server: String,
     pub version: String,
@@ -33,18 +34,70 @@ pub struct ServerProperties {
 
 #[derive(Debug, CommandArgsBlock)]
 #[argtoken("PING")]
-pub struct PingCommand;
+pub struct PingCommand<'a> {
+    pub message: Option<&'a [u8]>,
+}
+
+/// Reply of PING
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged, from = "PingRepr")]
+pub enum PingReply {
+    Pong(SimpleString),
+    /// The message given to PING, replied as a blob string
+    Message(ByteString),
+    /// Reply to a RESP2 client in subscribed mode, "pong" and the message
+    Subscribed(ByteString, ByteString),
+}
+
+/// Reply of PING as read by a client, which can't tell PONG from a message
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PingRepr {
+    Message(ByteString),
+    Subscribed(ByteString, ByteString),
+}
+
+impl From<PingRepr> for PingReply {
+    fn from(repr: PingRepr) -> Self {
+        match repr {
+            PingRepr::Message(message) if message.as_bytes() == b"PONG" => {
+                PingReply::Pong(SimpleString("PONG".into()))
+            }
+            PingRepr::Message(message) => PingReply::Message(message),
+            PingRepr::Subscribed(pong, message) => PingReply::Subscribed(pong, message),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SELECT")]
+pub struct SelectCommand {
+    pub index: usize,
+}
 
 impl<'a> CommandHandler for HelloCommand<'a> {
     type Output = ServerProperties;
 
     fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        if !(2..=3).contains(&self.protover) {
+            return Err(Error::Handle(
+                "NOPROTO unsupported protocol version".to_string(),
+            ));
+        }
+
         Ok(ServerProperties {
             server: String::from("memds"),
             version: String::from("0.0.1"),
-            proto: 3,
+            proto: self.protover,
         })
     }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let properties = self.handle(db)?;
+        client.protocol = properties.proto;
+
+        Ok(properties)
+    }
 }
 
 impl CommandHandler for CommandCommand {
@@ -55,11 +108,63 @@ impl CommandHandler for CommandCommand {
     }
 }
 
-impl CommandHandler for PingCommand {
-    type Output = SimpleString;
+impl<'a> CommandHandler for PingCommand<'a> {
+    type Output = PingReply;
 
     fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
-        Ok(SimpleString("PONG".into()))
+        Ok(match self.message {
+            Some(message) => PingReply::Message(message.into()),
+            None => PingReply::Pong(SimpleString("PONG".into())),
+        })
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        if pubsub::in_subscriber_mode(client) {
+            let message = self.message.unwrap_or_default();
+            return Ok(PingReply::Subscribed(b"pong"[..].into(), message.into()));
+        }
+
+        self.handle(db)
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match output {
+            PingReply::Pong(pong) => {
+                let mut serializer = resp::from_write(write_buf, resp3);
+                pong.serialize(&mut serializer)
+                    .map_err(|e| Error::Serialize(e.to_string()))
+            }
+            PingReply::Message(message) => {
+                write_bulk_bytes(Some(message.as_bytes()), resp3, write_buf)
+            }
+            PingReply::Subscribed(pong, message) => {
+                write_buf.extend_from_slice(b"*2\r\n");
+                write_bulk_bytes(Some(pong.as_bytes()), resp3, write_buf)?;
+                write_bulk_bytes(Some(message.as_bytes()), resp3, write_buf)
+            }
+        }
+    }
+}
+
+impl CommandHandler for SelectCommand {
+    type Output = OkResponse;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.select(self.index)?;
+
+        Ok(OkResponse)
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let index = self.index;
+        let reply = self.handle(db)?;
+        client.db = index;
+
+        Ok(reply)
     }
 }
 
@@ -70,10 +175,31 @@ mod tests {
 
     #[test]
     fn test_parse_hello_command() {
-        let args = ["HELLO", "3", "AUTH", "user", "pass"];
+        let args = ["HELLO", "3", "AUTH", "user", "pass"].map(str::as_bytes);
         let command = HelloCommand::parse_maybe(&mut &args[..]).unwrap().unwrap();
         assert_eq!(command.protover, 3);
         assert_eq!(command.auth.as_ref().unwrap().username, "user");
         assert_eq!(command.auth.as_ref().unwrap().username, "user");
     }
+
+    #[test]
+    fn test_hello_protocols() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut write_buf = Vec::new();
+        for args in [["HELLO", "2"], ["GET", "k"], ["HELLO", "3"], ["GET", "k"]] {
+            let args = args.map(str::as_bytes);
+            crate::command::parse_and_handle(&args, &db, &mut client, &mut write_buf).unwrap();
+        }
+
+        assert_eq!(
+            String::from_utf8(write_buf).unwrap(),
+            concat!(
+                "*6\r\n+server\r\n+memds\r\n+version\r\n+0.0.1\r\n+proto\r\n:2\r\n",
+                "$-1\r\n",
+                "%3\r\n+server\r\n+memds\r\n+version\r\n+0.0.1\r\n+proto\r\n:3\r\n",
+                "_\r\n",
+            )
+        );
+    }
 }
@@ -0,0 +1,477 @@
+use command_args_derive::CommandArgsBlock;
+use serde::Serialize;
+
+use crate::{byte_string::ByteString, database::Database};
+
+use super::{
+    keyspace::{scan_count, ScanCount, ScanMatch},
+    ClientState, CommandHandler, Error, MapReply,
+};
+
+#[derive(Debug, CommandArgsBlock)]
+pub struct FieldValue<'a> {
+    pub field: &'a [u8],
+    pub value: &'a [u8],
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HSET")]
+pub struct HsetCommand<'a> {
+    pub key: &'a [u8],
+    pub field_values: Vec<FieldValue<'a>>,
+}
+
+impl<'a> CommandHandler for HsetCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let field_values: Vec<_> = self
+            .field_values
+            .iter()
+            .map(|fv| (fv.field, fv.value))
+            .collect();
+
+        db.hset(self.key, &field_values)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HSETNX")]
+pub struct HsetnxCommand<'a> {
+    pub key: &'a [u8],
+    pub field: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl<'a> CommandHandler for HsetnxCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hsetnx(self.key, self.field, self.value).map(usize::from)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HGET")]
+pub struct HgetCommand<'a> {
+    pub key: &'a [u8],
+    pub field: &'a [u8],
+}
+
+impl<'a> CommandHandler for HgetCommand<'a> {
+    type Output = Option<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.hmget(self.key, &[self.field])?.pop().flatten())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HMGET")]
+pub struct HmgetCommand<'a> {
+    pub key: &'a [u8],
+    pub fields: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for HmgetCommand<'a> {
+    type Output = Vec<Option<ByteString>>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hmget(self.key, &self.fields)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HDEL")]
+pub struct HdelCommand<'a> {
+    pub key: &'a [u8],
+    pub fields: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for HdelCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hdel(self.key, &self.fields)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HEXISTS")]
+pub struct HexistsCommand<'a> {
+    pub key: &'a [u8],
+    pub field: &'a [u8],
+}
+
+impl<'a> CommandHandler for HexistsCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hexists(self.key, self.field).map(usize::from)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HLEN")]
+pub struct HlenCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for HlenCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hlen(self.key)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HKEYS")]
+pub struct HkeysCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for HkeysCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let entries = db.hgetall(self.key)?;
+
+        Ok(entries.into_iter().map(|(field, _)| field).collect())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HVALS")]
+pub struct HvalsCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for HvalsCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let entries = db.hgetall(self.key)?;
+
+        Ok(entries.into_iter().map(|(_, value)| value).collect())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HGETALL")]
+pub struct HgetallCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for HgetallCommand<'a> {
+    type Output = MapReply<ByteString, ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hgetall(self.key).map(MapReply::new)
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        reply.resp3 = client.resp3();
+
+        Ok(reply)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HINCRBY")]
+pub struct HincrbyCommand<'a> {
+    pub key: &'a [u8],
+    pub field: &'a [u8],
+    pub increment: i64,
+}
+
+impl<'a> CommandHandler for HincrbyCommand<'a> {
+    type Output = i64;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hincrby(self.key, self.field, self.increment)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HINCRBYFLOAT")]
+pub struct HincrbyfloatCommand<'a> {
+    pub key: &'a [u8],
+    pub field: &'a [u8],
+    pub increment: f64,
+}
+
+impl<'a> CommandHandler for HincrbyfloatCommand<'a> {
+    type Output = String;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hincrbyfloat(self.key, self.field, self.increment)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HSTRLEN")]
+pub struct HstrlenCommand<'a> {
+    pub key: &'a [u8],
+    pub field: &'a [u8],
+}
+
+impl<'a> CommandHandler for HstrlenCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.hstrlen(self.key, self.field)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("WITHVALUES")]
+pub struct WithValues;
+
+#[derive(Debug, CommandArgsBlock)]
+pub struct HrandfieldCount {
+    pub count: i64,
+    pub withvalues: Option<WithValues>,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HRANDFIELD")]
+pub struct HrandfieldCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<HrandfieldCount>,
+}
+
+/// Reply of HRANDFIELD: a single field, or an array of fields (and values with `WITHVALUES`)
+/// when `count` is given
+#[derive(Debug, PartialEq)]
+pub enum HrandfieldOutput {
+    One(Option<ByteString>),
+    Many(Vec<ByteString>),
+}
+
+impl Serialize for HrandfieldOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            HrandfieldOutput::One(field) => field.serialize(serializer),
+            HrandfieldOutput::Many(fields) => fields.serialize(serializer),
+        }
+    }
+}
+
+impl<'a> CommandHandler for HrandfieldCommand<'a> {
+    type Output = HrandfieldOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        match self.count {
+            None => {
+                let entries = db.hrandfield(self.key, 1)?;
+                Ok(HrandfieldOutput::One(
+                    entries.into_iter().next().map(|(field, _)| field),
+                ))
+            }
+            Some(HrandfieldCount { count, withvalues }) => {
+                let entries = db.hrandfield(self.key, count)?;
+                let fields = if withvalues.is_some() {
+                    entries.into_iter().flat_map(|(f, v)| [f, v]).collect()
+                } else {
+                    entries.into_iter().map(|(f, _)| f).collect()
+                };
+                Ok(HrandfieldOutput::Many(fields))
+            }
+        }
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("NOVALUES")]
+pub struct NoValues;
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("HSCAN")]
+pub struct HscanCommand<'a> {
+    pub key: &'a [u8],
+    pub cursor: usize,
+    pub pattern: Option<ScanMatch<'a>>,
+    pub count: Option<ScanCount>,
+    pub novalues: Option<NoValues>,
+}
+
+impl<'a> CommandHandler for HscanCommand<'a> {
+    /// Next cursor and the scanned fields and values
+    type Output = (String, Vec<ByteString>);
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let (cursor, entries) = db.hscan(
+            self.key,
+            self.cursor,
+            scan_count(self.count),
+            self.pattern.map(|m| m.pattern),
+        )?;
+        let fields = if self.novalues.is_some() {
+            entries.into_iter().map(|(f, _)| f).collect()
+        } else {
+            entries.into_iter().flat_map(|(f, v)| [f, v]).collect()
+        };
+
+        Ok((cursor.to_string(), fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::parse_and_handle;
+
+    #[test]
+    fn test_hash_commands() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut write_buf = Vec::new();
+        let commands: &[&[&str]] = &[
+            &["HSET", "h", "a", "1", "b", "2"],
+            &["HSET", "h", "a", "3"],
+            &["HSETNX", "h", "a", "4"],
+            &["HMGET", "h", "a", "c"],
+            &["HINCRBY", "h", "b", "5"],
+            &["HINCRBYFLOAT", "h", "b", "0.5"],
+            &["HSTRLEN", "h", "b"],
+            &["HDEL", "h", "b", "c"],
+            &["HGETALL", "h"],
+            &["HELLO", "3"],
+            &["HGETALL", "h"],
+            &["HSCAN", "h", "0", "MATCH", "z*"],
+            &["HDEL", "h", "a"],
+            &["HEXISTS", "h", "a"],
+            &["HLEN", "h"],
+        ];
+        for args in commands {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            parse_and_handle(&args, &db, &mut client, &mut write_buf).unwrap();
+        }
+
+        let result_s = std::str::from_utf8(&write_buf).unwrap();
+        assert_eq!(
+            result_s,
+            concat!(
+                ":2\r\n:0\r\n:0\r\n*2\r\n+3\r\n$-1\r\n:7\r\n+7.5\r\n:3\r\n:1\r\n",
+                "*2\r\n+a\r\n+3\r\n",
+                "%3\r\n+server\r\n+memds\r\n+version\r\n+0.0.1\r\n+proto\r\n:3\r\n",
+                "%1\r\n+a\r\n+3\r\n",
+                "*2\r\n+0\r\n*0\r\n",
+                ":1\r\n:0\r\n:0\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_hrandfield() {
+        let db = Database::new(String::new()).unwrap();
+        db.hset(b"h", &[(b"a", b"1"), (b"b", b"2")]).unwrap();
+
+        let mut fields: Vec<_> = db.hrandfield(b"h", 5).unwrap();
+        fields.sort();
+        assert_eq!(
+            fields,
+            vec![("a".into(), "1".into()), ("b".into(), "2".into())]
+        );
+        assert_eq!(db.hrandfield(b"h", -5).unwrap().len(), 5);
+        assert!(db.hrandfield(b"missing", -5).unwrap().is_empty());
+        // extreme counts neither overflow nor pick forever
+        assert_eq!(db.hrandfield(b"h", i64::MAX).unwrap().len(), 2);
+        assert!(matches!(
+            db.hrandfield(b"h", i64::MIN),
+            Err(Error::Handle(e)) if e == "ERR value is out of range"
+        ));
+        assert!(db.hrandfield(b"h", -(i64::MAX / 2) - 1).is_err());
+
+        let command = HrandfieldCommand {
+            key: b"h",
+            count: Some(HrandfieldCount {
+                count: 1,
+                withvalues: Some(WithValues),
+            }),
+        };
+        match command.handle(&db).unwrap() {
+            HrandfieldOutput::Many(entry) => {
+                assert!(entry == vec!["a", "1"] || entry == vec!["b", "2"]);
+            }
+            output => panic!("unexpected output {:?}", output),
+        }
+    }
+    #[test]
+    fn test_hscan_cursor() {
+        let db = Database::new(String::new()).unwrap();
+        let fields: Vec<_> = (0..25).map(|i| format!("f{}", i)).collect();
+        let entries: Vec<(&[u8], &[u8])> =
+            fields.iter().map(|f| (f.as_bytes(), &b"v"[..])).collect();
+        db.hset(b"h", &entries).unwrap();
+
+        let mut scanned = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let command = HscanCommand {
+                key: b"h",
+                cursor,
+                pattern: None,
+                count: Some(ScanCount { count: 10 }),
+                novalues: Some(NoValues),
+            };
+            let (next, fields) = command.handle(&db).unwrap();
+            assert!(fields.len() < 25);
+            scanned.extend(fields);
+            cursor = next.parse().unwrap();
+            if cursor == 0 {
+                break;
+            }
+        }
+        scanned.sort();
+        let mut expected: Vec<ByteString> = fields.iter().map(|f| f.as_str().into()).collect();
+        expected.sort();
+        assert_eq!(scanned, expected);
+    }
+}
@@ -0,0 +1,891 @@
+use command_args::CommandArgs;
+use command_args_derive::CommandArgsBlock;
+
+use deseresp::types::{owned::SimpleString, OkResponse};
+
+use crate::{
+    byte_string::ByteString,
+    database::{now_ms, Database, ExpiryCondition},
+};
+
+use super::{write_bulk_bytes, BulkBytes, CommandHandler, Error};
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum ExpireCondition {
+    #[argtoken("NX")]
+    NotVolatile,
+    #[argtoken("XX")]
+    Volatile,
+    #[argtoken("GT")]
+    Greater,
+    #[argtoken("LT")]
+    Less,
+    #[argnotoken]
+    Always,
+}
+
+impl From<ExpireCondition> for ExpiryCondition {
+    fn from(cond: ExpireCondition) -> Self {
+        match cond {
+            ExpireCondition::NotVolatile => ExpiryCondition::NotVolatile,
+            ExpireCondition::Volatile => ExpiryCondition::Volatile,
+            ExpireCondition::Greater => ExpiryCondition::Greater,
+            ExpireCondition::Less => ExpiryCondition::Less,
+            ExpireCondition::Always => ExpiryCondition::Always,
+        }
+    }
+}
+
+/// Converts `time` in `unit_ms` milliseconds offset by `base_ms` to unix time in milliseconds
+fn expire_at_ms(base_ms: i64, time: i64, unit_ms: i64, command: &str) -> Result<i64, Error> {
+    time.checked_mul(unit_ms)
+        .and_then(|ms| ms.checked_add(base_ms))
+        .ok_or_else(|| Error::Handle(format!("ERR invalid expire time in '{}' command", command)))
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EXPIRE")]
+pub struct ExpireCommand<'a> {
+    pub key: &'a [u8],
+    pub seconds: i64,
+    pub condition: ExpireCondition,
+}
+
+impl<'a> CommandHandler for ExpireCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let when = expire_at_ms(now_ms() as i64, self.seconds, 1000, "expire")?;
+
+        db.expire(self.key, when, self.condition.into())
+            .map(usize::from)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        match expire_at_ms(now_ms() as i64, self.seconds, 1000, "expire") {
+            Ok(when) => PexpireatCommand {
+                key: self.key,
+                unix_time_milliseconds: when,
+                condition: self.condition,
+            }
+            .encode(target),
+            Err(_) => self.encode(target),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PEXPIRE")]
+pub struct PexpireCommand<'a> {
+    pub key: &'a [u8],
+    pub milliseconds: i64,
+    pub condition: ExpireCondition,
+}
+
+impl<'a> CommandHandler for PexpireCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let when = expire_at_ms(now_ms() as i64, self.milliseconds, 1, "pexpire")?;
+
+        db.expire(self.key, when, self.condition.into())
+            .map(usize::from)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        match expire_at_ms(now_ms() as i64, self.milliseconds, 1, "pexpire") {
+            Ok(when) => PexpireatCommand {
+                key: self.key,
+                unix_time_milliseconds: when,
+                condition: self.condition,
+            }
+            .encode(target),
+            Err(_) => self.encode(target),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EXPIREAT")]
+pub struct ExpireatCommand<'a> {
+    pub key: &'a [u8],
+    pub unix_time_seconds: i64,
+    pub condition: ExpireCondition,
+}
+
+impl<'a> CommandHandler for ExpireatCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let when = expire_at_ms(0, self.unix_time_seconds, 1000, "expireat")?;
+
+        db.expire(self.key, when, self.condition.into())
+            .map(usize::from)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        match expire_at_ms(0, self.unix_time_seconds, 1000, "expireat") {
+            Ok(when) => PexpireatCommand {
+                key: self.key,
+                unix_time_milliseconds: when,
+                condition: self.condition,
+            }
+            .encode(target),
+            Err(_) => self.encode(target),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PEXPIREAT")]
+pub struct PexpireatCommand<'a> {
+    pub key: &'a [u8],
+    pub unix_time_milliseconds: i64,
+    pub condition: ExpireCondition,
+}
+
+impl<'a> CommandHandler for PexpireatCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.expire(self.key, self.unix_time_milliseconds, self.condition.into())
+            .map(usize::from)
+    }
+}
+
+/// Reply of TTL family commands: -2 if the key does not exist,
+/// -1 if the key has no expiry, otherwise computed from the expiry time with `f`
+fn ttl_reply<F: FnOnce(u64) -> i64>(expire_time: Option<Option<u64>>, f: F) -> i64 {
+    match expire_time {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(when)) => f(when),
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("TTL")]
+pub struct TtlCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for TtlCommand<'a> {
+    type Output = i64;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let now = now_ms();
+        Ok(ttl_reply(db.expire_time(self.key), |when| {
+            (when.saturating_sub(now) as i64 + 500) / 1000
+        }))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PTTL")]
+pub struct PttlCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for PttlCommand<'a> {
+    type Output = i64;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let now = now_ms();
+        Ok(ttl_reply(db.expire_time(self.key), |when| {
+            when.saturating_sub(now) as i64
+        }))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EXPIRETIME")]
+pub struct ExpiretimeCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for ExpiretimeCommand<'a> {
+    type Output = i64;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(ttl_reply(db.expire_time(self.key), |when| {
+            (when / 1000) as i64
+        }))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PEXPIRETIME")]
+pub struct PexpiretimeCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for PexpiretimeCommand<'a> {
+    type Output = i64;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(ttl_reply(db.expire_time(self.key), |when| when as i64))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PERSIST")]
+pub struct PersistCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for PersistCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(usize::from(db.persist(self.key)))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("DUMP")]
+pub struct DumpCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for DumpCommand<'a> {
+    type Output = Option<BulkBytes>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.dump(self.key).map(BulkBytes))
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        write_bulk_bytes(output.as_ref().map(|b| &b.0[..]), resp3, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("REPLACE")]
+pub struct RestoreReplace;
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("ABSTTL")]
+pub struct RestoreAbsTtl;
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RESTORE")]
+pub struct RestoreCommand<'a> {
+    pub key: &'a [u8],
+    /// Time to live in milliseconds, or unix time in milliseconds with ABSTTL, 0 for no expiry
+    pub ttl: i64,
+    pub serialized_value: &'a [u8],
+    pub replace: Option<RestoreReplace>,
+    pub absttl: Option<RestoreAbsTtl>,
+}
+
+impl<'a> RestoreCommand<'a> {
+    /// Expiry time in unix time milliseconds
+    fn expire_at(&self, now: u64) -> Result<Option<u64>, Error> {
+        let invalid = || Error::Handle("ERR Invalid TTL value, must be >= 0".to_string());
+        match self.ttl {
+            0 => Ok(None),
+            ttl if ttl < 0 => Err(invalid()),
+            ttl if self.absttl.is_some() => Ok(Some(ttl as u64)),
+            ttl => now.checked_add(ttl as u64).map(Some).ok_or_else(invalid),
+        }
+    }
+}
+
+impl<'a> CommandHandler for RestoreCommand<'a> {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let expire_at = self.expire_at(now_ms())?;
+        db.restore(
+            self.key,
+            self.serialized_value,
+            expire_at,
+            self.replace.is_some(),
+        )?;
+
+        Ok(OkResponse)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        match self.expire_at(now_ms()) {
+            Ok(Some(when)) => RestoreCommand {
+                key: self.key,
+                ttl: when as i64,
+                serialized_value: self.serialized_value,
+                replace: self.replace,
+                absttl: Some(RestoreAbsTtl),
+            }
+            .encode(target),
+            _ => self.encode(target),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum ObjectSubcommand<'a> {
+    #[argtoken("ENCODING")]
+    Encoding { key: &'a [u8] },
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("OBJECT")]
+pub struct ObjectCommand<'a> {
+    pub subcommand: ObjectSubcommand<'a>,
+}
+
+impl<'a> CommandHandler for ObjectCommand<'a> {
+    type Output = Option<&'static str>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        match self.subcommand {
+            ObjectSubcommand::Encoding { key } => Ok(db.object_encoding(key)),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq)]
+#[argtoken("MATCH")]
+pub struct ScanMatch<'a> {
+    pub pattern: &'a [u8],
+}
+
+/// `COUNT count` of a SCAN-like command, fails to parse if the count is 0
+#[derive(Debug, PartialEq)]
+pub struct ScanCount {
+    pub count: usize,
+}
+
+impl<'a> CommandArgs<'a> for ScanCount {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error> {
+        "COUNT".encode(target)?;
+        self.count.encode(target)
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, command_args::Error> {
+        match args.first() {
+            Some(a) if a.eq_ignore_ascii_case(b"COUNT") => *args = &args[1..],
+            _ => return Ok(None),
+        }
+
+        match usize::parse_maybe(args)? {
+            Some(count) if count > 0 => Ok(Some(ScanCount { count })),
+            _ => Err(command_args::Error::Parse),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq)]
+#[argtoken("TYPE")]
+pub struct ScanType<'a> {
+    pub type_name: &'a str,
+}
+
+/// Number of keys scanned by SCAN without COUNT, as in Redis
+const SCAN_DEFAULT_COUNT: usize = 10;
+
+/// Count of a SCAN-like command, 10 if not given
+pub(crate) fn scan_count(count: Option<ScanCount>) -> usize {
+    count.map_or(SCAN_DEFAULT_COUNT, |c| c.count)
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SCAN")]
+pub struct ScanCommand<'a> {
+    pub cursor: usize,
+    pub pattern: Option<ScanMatch<'a>>,
+    pub count: Option<ScanCount>,
+    pub type_name: Option<ScanType<'a>>,
+}
+
+impl<'a> CommandHandler for ScanCommand<'a> {
+    /// Next cursor and the scanned keys
+    type Output = (String, Vec<ByteString>);
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let (cursor, keys) = db.scan(
+            self.cursor,
+            scan_count(self.count),
+            self.pattern.map(|m| m.pattern),
+            self.type_name.map(|t| t.type_name),
+        );
+
+        Ok((cursor.to_string(), keys))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("KEYS")]
+pub struct KeysCommand<'a> {
+    pub pattern: &'a [u8],
+}
+
+impl<'a> CommandHandler for KeysCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.keys(self.pattern))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("DEL")]
+pub struct DelCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for DelCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.del(&self.keys))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("UNLINK")]
+pub struct UnlinkCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for UnlinkCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.unlink(&self.keys))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EXISTS")]
+pub struct ExistsCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for ExistsCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.exists(&self.keys))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("TYPE")]
+pub struct TypeCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for TypeCommand<'a> {
+    type Output = SimpleString;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(SimpleString(db.key_type(self.key).unwrap_or("none").into()))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RENAME")]
+pub struct RenameCommand<'a> {
+    pub key: &'a [u8],
+    pub new_key: &'a [u8],
+}
+
+impl<'a> CommandHandler for RenameCommand<'a> {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.rename(self.key, self.new_key, false)?;
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RENAMENX")]
+pub struct RenamenxCommand<'a> {
+    pub key: &'a [u8],
+    pub new_key: &'a [u8],
+}
+
+impl<'a> CommandHandler for RenamenxCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.rename(self.key, self.new_key, true).map(usize::from)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("DB")]
+pub struct CopyDb {
+    pub destination_db: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("REPLACE")]
+pub struct CopyReplace;
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("COPY")]
+pub struct CopyCommand<'a> {
+    pub source: &'a [u8],
+    pub destination: &'a [u8],
+    pub db: Option<CopyDb>,
+    pub replace: Option<CopyReplace>,
+}
+
+impl<'a> CommandHandler for CopyCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.copy(
+            self.source,
+            self.destination,
+            self.db.map(|db| db.destination_db),
+            self.replace.is_some(),
+        )
+        .map(usize::from)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("MOVE")]
+pub struct MoveCommand<'a> {
+    pub key: &'a [u8],
+    pub db: usize,
+}
+
+impl<'a> CommandHandler for MoveCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.move_key(self.key, self.db).map(usize::from)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SWAPDB")]
+pub struct SwapdbCommand {
+    pub index1: usize,
+    pub index2: usize,
+}
+
+impl CommandHandler for SwapdbCommand {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.swapdb(self.index1, self.index2)?;
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RANDOMKEY")]
+pub struct RandomkeyCommand;
+
+impl CommandHandler for RandomkeyCommand {
+    type Output = Option<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.randomkey())
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("DBSIZE")]
+pub struct DbsizeCommand;
+
+impl CommandHandler for DbsizeCommand {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.dbsize())
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum FlushMode {
+    #[argtoken("ASYNC")]
+    Async,
+    #[argtoken("SYNC")]
+    Sync,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("FLUSHDB")]
+pub struct FlushdbCommand {
+    pub mode: Option<FlushMode>,
+}
+
+impl CommandHandler for FlushdbCommand {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.flush(self.mode == Some(FlushMode::Async));
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("FLUSHALL")]
+pub struct FlushallCommand {
+    pub mode: Option<FlushMode>,
+}
+
+impl CommandHandler for FlushallCommand {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.flushall(self.mode == Some(FlushMode::Async));
+
+        Ok(OkResponse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{run_commands, ClientState};
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_parse_expire() {
+        let args = ["EXPIRE", "a", "-10", "GT"].map(str::as_bytes);
+        let command = ExpireCommand::parse_maybe(&mut &args[..]).unwrap().unwrap();
+        assert_eq!(command.key, b"a");
+        assert_eq!(command.seconds, -10);
+        assert_matches!(command.condition, ExpireCondition::Greater);
+
+        let args = ["PEXPIREAT", "a", "100"].map(str::as_bytes);
+        let command = PexpireatCommand::parse_maybe(&mut &args[..])
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.unix_time_milliseconds, 100);
+        assert_matches!(command.condition, ExpireCondition::Always);
+
+        let args = ["SCAN", "0", "COUNT", "5"].map(str::as_bytes);
+        let command = ScanCommand::parse_maybe(&mut &args[..]).unwrap().unwrap();
+        assert_eq!(command.count, Some(ScanCount { count: 5 }));
+        let args = ["SCAN", "0", "COUNT", "0"].map(str::as_bytes);
+        assert!(ScanCommand::parse_maybe(&mut &args[..]).is_err());
+    }
+
+    #[test]
+    fn test_expire_at_ms_overflow() {
+        assert_eq!(expire_at_ms(5, 2, 1000, "expire").unwrap(), 2005);
+        assert!(expire_at_ms(0, i64::MAX, 1000, "expire").is_err());
+        assert!(expire_at_ms(i64::MAX, 1, 1, "pexpire").is_err());
+    }
+
+    #[test]
+    fn test_dump_restore_commands() {
+        let db = Database::new(String::new()).unwrap();
+        db.sadd(b"s", &[b"a"]).unwrap();
+        let payload = db.dump(b"s").unwrap();
+
+        let mut write_buf = Vec::new();
+        DumpCommand::write_output(
+            DumpCommand { key: b"s" }.handle(&db).unwrap(),
+            false,
+            &mut write_buf,
+        )
+        .unwrap();
+        DumpCommand::write_output(None, false, &mut write_buf).unwrap();
+        DumpCommand::write_output(None, true, &mut write_buf).unwrap();
+        let mut expected = format!("${}\r\n", payload.len()).into_bytes();
+        expected.extend_from_slice(&payload);
+        expected.extend_from_slice(b"\r\n$-1\r\n_\r\n");
+        assert_eq!(write_buf, expected);
+
+        let args = ["RESTORE", "k", "1000", "v", "REPLACE"].map(str::as_bytes);
+        let command = RestoreCommand::parse_maybe(&mut &args[..])
+            .unwrap()
+            .unwrap();
+        assert!(command.replace.is_some());
+        let mut aof = Vec::new();
+        command.encode_aof(&mut aof).unwrap();
+        assert_eq!(aof[..4], [&b"RESTORE"[..], b"k", &aof[2], b"v"]);
+        assert!(crate::byte_string::parse::<u64>(&aof[2]).unwrap() >= now_ms());
+        assert_eq!(aof[4..], ["REPLACE", "ABSTTL"].map(str::as_bytes));
+
+        let args = ["RESTORE", "k", "-1", "v"].map(str::as_bytes);
+        let command = RestoreCommand::parse_maybe(&mut &args[..])
+            .unwrap()
+            .unwrap();
+        assert!(command.handle(&db).is_err());
+    }
+
+    #[test]
+    fn test_keyspace_commands() {
+        let db = Database::new(String::new()).unwrap();
+        let result = run_commands(
+            &db,
+            &mut ClientState::default(),
+            &[
+                &["MSET", "a", "1", "b", "2"],
+                &["SADD", "s", "x"],
+                &["EXISTS", "a", "a", "missing"],
+                &["TYPE", "s"],
+                &["TYPE", "missing"],
+                &["PEXPIRE", "a", "100000"],
+                &["RENAME", "a", "c"],
+                &["PERSIST", "c"],
+                &["RENAME", "missing", "d"],
+                &["RENAMENX", "c", "b"],
+                &["COPY", "s", "t"],
+                &["COPY", "c", "b"],
+                &["COPY", "c", "b", "REPLACE"],
+                &["GET", "b"],
+                &["DEL", "b", "missing"],
+                &["UNLINK", "t"],
+                &["DBSIZE"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "+OK\r\n:1\r\n:2\r\n+set\r\n+none\r\n:1\r\n+OK\r\n:1\r\n",
+                "-ERR no such key\r\n:0\r\n:1\r\n:0\r\n:1\r\n+1\r\n:1\r\n:1\r\n",
+                ":2\r\n",
+            )
+        );
+
+        let mut keys = db.keys(b"*");
+        keys.sort();
+        assert_eq!(keys, ["c", "s"]);
+        assert_eq!(db.keys(b"[a-c]"), ["c"]);
+
+        let result = run_commands(
+            &db,
+            &mut ClientState::default(),
+            &[&["FLUSHALL", "ASYNC"], &["DBSIZE"], &["RANDOMKEY"]],
+        );
+        assert_eq!(result, "+OK\r\n:0\r\n$-1\r\n");
+    }
+
+    #[test]
+    fn test_database_commands() {
+        let db = Database::new(String::new()).unwrap();
+        let result = run_commands(
+            &db,
+            &mut ClientState::default(),
+            &[
+                &["SET", "a", "1"],
+                &["SELECT", "2"],
+                &["GET", "a"],
+                &["SET", "b", "2"],
+                &["MOVE", "b", "0"],
+                &["MOVE", "b", "2"],
+                &["COPY", "missing", "b", "DB", "0"],
+                &["SELECT", "0"],
+                &["COPY", "b", "c", "DB", "2", "REPLACE"],
+                &["MOVE", "a", "16"],
+                &["SELECT", "16"],
+                &["DBSIZE"],
+                &["SWAPDB", "0", "2"],
+                &["DBSIZE"],
+                &["GET", "c"],
+                &["FLUSHDB"],
+                &["SELECT", "2"],
+                &["DBSIZE"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "+OK\r\n+OK\r\n$-1\r\n+OK\r\n:1\r\n",
+                "-ERR source and destination objects are the same\r\n:0\r\n+OK\r\n:1\r\n",
+                "-ERR DB index is out of range\r\n-ERR DB index is out of range\r\n:2\r\n",
+                "+OK\r\n:1\r\n+2\r\n+OK\r\n+OK\r\n:2\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_object_encoding() {
+        let db = Database::new(String::new()).unwrap();
+        db.sadd(b"ints", &[b"1", b"2"]).unwrap();
+        db.sadd(b"strs", &[b"a", b"1"]).unwrap();
+        let long = "x".repeat(100);
+        db.sadd(b"big", &[long.as_bytes()]).unwrap();
+        db.hset(b"h", &[(b"f", b"v")]).unwrap();
+        db.mset(
+            &[(b"n", b"10"), (b"s", b"abc"), (b"raw", long.as_bytes())],
+            false,
+        );
+        db.incr(b"n").unwrap();
+
+        let encoding = |key| {
+            ObjectCommand {
+                subcommand: ObjectSubcommand::Encoding { key },
+            }
+            .handle(&db)
+            .unwrap()
+        };
+        assert_eq!(encoding(b"ints"), Some("intset"));
+        assert_eq!(encoding(b"strs"), Some("listpack"));
+        assert_eq!(encoding(b"big"), Some("hashtable"));
+        assert_eq!(encoding(b"h"), Some("hashtable"));
+        assert_eq!(encoding(b"n"), Some("int"));
+        assert_eq!(encoding(b"s"), Some("embstr"));
+        assert_eq!(encoding(b"raw"), Some("raw"));
+        assert_eq!(encoding(b"missing"), None);
+    }
+}
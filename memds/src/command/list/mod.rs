@@ -0,0 +1,661 @@
+use command_args_derive::CommandArgsBlock;
+use deseresp::types::OkResponse;
+use serde::Serialize;
+
+use crate::{
+    blocking::BlockedPop,
+    byte_string::ByteString,
+    database::{Database, ListEnd},
+    resp::ArrayOrNull,
+};
+
+use super::{
+    blocking::{self, BlockingReply},
+    ClientState, CommandHandler, Error,
+};
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LPUSH")]
+pub struct LpushCommand<'a> {
+    pub key: &'a [u8],
+    pub elements: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for LpushCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.push(self.key, &self.elements, ListEnd::Head, false)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RPUSH")]
+pub struct RpushCommand<'a> {
+    pub key: &'a [u8],
+    pub elements: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for RpushCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.push(self.key, &self.elements, ListEnd::Tail, false)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LPUSHX")]
+pub struct LpushxCommand<'a> {
+    pub key: &'a [u8],
+    pub elements: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for LpushxCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.push(self.key, &self.elements, ListEnd::Head, true)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RPUSHX")]
+pub struct RpushxCommand<'a> {
+    pub key: &'a [u8],
+    pub elements: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for RpushxCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.push(self.key, &self.elements, ListEnd::Tail, true)
+    }
+}
+
+/// Reply of LPOP/RPOP: a single element, or an array of elements when `count` is given
+#[derive(Debug, PartialEq)]
+pub enum PopOutput {
+    One(Option<ByteString>),
+    Many(Option<Vec<ByteString>>),
+}
+
+impl Serialize for PopOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            PopOutput::One(element) => element.serialize(serializer),
+            PopOutput::Many(elements) => ArrayOrNull(elements.as_ref()).serialize(serializer),
+        }
+    }
+}
+
+fn pop(db: &Database, key: &[u8], end: ListEnd, count: Option<usize>) -> Result<PopOutput, Error> {
+    match count {
+        Some(count) => db.pop(key, end, count).map(PopOutput::Many),
+        None => {
+            let popped = db.pop(key, end, 1)?;
+            Ok(PopOutput::One(popped.and_then(|mut e| e.pop())))
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LPOP")]
+pub struct LpopCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<usize>,
+}
+
+impl<'a> CommandHandler for LpopCommand<'a> {
+    type Output = PopOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        pop(db, self.key, ListEnd::Head, self.count)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("RPOP")]
+pub struct RpopCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<usize>,
+}
+
+impl<'a> CommandHandler for RpopCommand<'a> {
+    type Output = PopOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        pop(db, self.key, ListEnd::Tail, self.count)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LLEN")]
+pub struct LlenCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for LlenCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.llen(self.key)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LRANGE")]
+pub struct LrangeCommand<'a> {
+    pub key: &'a [u8],
+    pub start: i64,
+    pub stop: i64,
+}
+
+impl<'a> CommandHandler for LrangeCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.lrange(self.key, self.start, self.stop)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LINDEX")]
+pub struct LindexCommand<'a> {
+    pub key: &'a [u8],
+    pub index: i64,
+}
+
+impl<'a> CommandHandler for LindexCommand<'a> {
+    type Output = Option<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.lindex(self.key, self.index)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LSET")]
+pub struct LsetCommand<'a> {
+    pub key: &'a [u8],
+    pub index: i64,
+    pub element: &'a [u8],
+}
+
+impl<'a> CommandHandler for LsetCommand<'a> {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.lset(self.key, self.index, self.element)?;
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum InsertPosition {
+    #[argtoken("BEFORE")]
+    Before,
+    #[argtoken("AFTER")]
+    After,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LINSERT")]
+pub struct LinsertCommand<'a> {
+    pub key: &'a [u8],
+    pub position: InsertPosition,
+    pub pivot: &'a [u8],
+    pub element: &'a [u8],
+}
+
+impl<'a> CommandHandler for LinsertCommand<'a> {
+    type Output = i64;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let after = self.position == InsertPosition::After;
+        db.linsert(self.key, self.pivot, after, self.element)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LREM")]
+pub struct LremCommand<'a> {
+    pub key: &'a [u8],
+    pub count: i64,
+    pub element: &'a [u8],
+}
+
+impl<'a> CommandHandler for LremCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.lrem(self.key, self.count, self.element)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LTRIM")]
+pub struct LtrimCommand<'a> {
+    pub key: &'a [u8],
+    pub start: i64,
+    pub stop: i64,
+}
+
+impl<'a> CommandHandler for LtrimCommand<'a> {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.ltrim(self.key, self.start, self.stop)?;
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq)]
+#[argtoken("RANK")]
+pub struct LposRank {
+    pub rank: i64,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq)]
+#[argtoken("COUNT")]
+pub struct LposCount {
+    pub count: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq)]
+#[argtoken("MAXLEN")]
+pub struct LposMaxlen {
+    pub maxlen: usize,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LPOS")]
+pub struct LposCommand<'a> {
+    pub key: &'a [u8],
+    pub element: &'a [u8],
+    pub rank: Option<LposRank>,
+    pub count: Option<LposCount>,
+    pub maxlen: Option<LposMaxlen>,
+}
+
+/// Reply of LPOS: the first index, or an array of indexes when `COUNT` is given
+#[derive(Debug, PartialEq)]
+pub enum LposOutput {
+    One(Option<usize>),
+    Many(Vec<usize>),
+}
+
+impl Serialize for LposOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            LposOutput::One(index) => index.serialize(serializer),
+            LposOutput::Many(indexes) => indexes.serialize(serializer),
+        }
+    }
+}
+
+impl<'a> CommandHandler for LposCommand<'a> {
+    type Output = LposOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let rank = self.rank.map(|r| r.rank).unwrap_or(1);
+        if rank == 0 {
+            return Err(Error::Handle(
+                "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".to_string(),
+            ));
+        }
+        let maxlen = self.maxlen.map(|m| m.maxlen).unwrap_or(0);
+
+        match self.count {
+            Some(LposCount { count }) => db
+                .lpos(self.key, self.element, rank, count, maxlen)
+                .map(LposOutput::Many),
+            None => {
+                let positions = db.lpos(self.key, self.element, rank, 1, maxlen)?;
+                Ok(LposOutput::One(positions.first().copied()))
+            }
+        }
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum Direction {
+    #[argtoken("LEFT")]
+    Left,
+    #[argtoken("RIGHT")]
+    Right,
+}
+
+impl From<Direction> for ListEnd {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::Left => ListEnd::Head,
+            Direction::Right => ListEnd::Tail,
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LMOVE")]
+pub struct LmoveCommand<'a> {
+    pub source: &'a [u8],
+    pub destination: &'a [u8],
+    pub wherefrom: Direction,
+    pub whereto: Direction,
+}
+
+impl<'a> CommandHandler for LmoveCommand<'a> {
+    type Output = Option<ByteString>;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.lmove(
+            self.source,
+            self.destination,
+            self.wherefrom.into(),
+            self.whereto.into(),
+        )
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BLPOP")]
+pub struct BlpopCommand<'a> {
+    pub keys_and_timeout: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for BlpopCommand<'a> {
+    type Output = BlockingReply;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let (keys, timeout) = blocking::split_timeout(&self.keys_and_timeout)?;
+        blocking::pop_or_block(db, client, keys, timeout, BlockedPop::List(ListEnd::Head))
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        output.amend_aof(args);
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(resp3, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BRPOP")]
+pub struct BrpopCommand<'a> {
+    pub keys_and_timeout: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for BrpopCommand<'a> {
+    type Output = BlockingReply;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let (keys, timeout) = blocking::split_timeout(&self.keys_and_timeout)?;
+        blocking::pop_or_block(db, client, keys, timeout, BlockedPop::List(ListEnd::Tail))
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        output.amend_aof(args);
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(resp3, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BLMOVE")]
+pub struct BlmoveCommand<'a> {
+    pub source: &'a [u8],
+    pub destination: &'a [u8],
+    pub wherefrom: Direction,
+    pub whereto: Direction,
+    pub timeout: &'a [u8],
+}
+
+impl<'a> CommandHandler for BlmoveCommand<'a> {
+    type Output = BlockingReply;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let pop = BlockedPop::Move {
+            destination: self.destination.into(),
+            from: self.wherefrom.into(),
+            to: self.whereto.into(),
+        };
+        blocking::pop_or_block(db, client, &[self.source], self.timeout, pop)
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        output.amend_aof(args);
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(resp3, write_buf)
+    }
+}
+
+/// BLMOVE from the tail to the head
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BRPOPLPUSH")]
+pub struct BrpoplpushCommand<'a> {
+    pub source: &'a [u8],
+    pub destination: &'a [u8],
+    pub timeout: &'a [u8],
+}
+
+impl<'a> CommandHandler for BrpoplpushCommand<'a> {
+    type Output = BlockingReply;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let pop = BlockedPop::Move {
+            destination: self.destination.into(),
+            from: ListEnd::Tail,
+            to: ListEnd::Head,
+        };
+        blocking::pop_or_block(db, client, &[self.source], self.timeout, pop)
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        output.amend_aof(args);
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(resp3, write_buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blocking::Wakeup, command::parse_and_handle};
+
+    #[test]
+    fn test_list_commands() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut write_buf = Vec::new();
+        let commands: &[&[&str]] = &[
+            &["RPUSH", "l", "a", "b", "c"],
+            &["LPUSH", "l", "z"],
+            &["LPUSHX", "missing", "a"],
+            &["LRANGE", "l", "0", "-1"],
+            &["LPOP", "l"],
+            &["RPOP", "l", "2"],
+            &["RPOP", "missing", "2"],
+            &["LINSERT", "l", "AFTER", "a", "b"],
+            &["LSET", "l", "5", "x"],
+            &["LPOS", "l", "b", "RANK", "-1"],
+            &["LPOS", "l", "b", "COUNT", "0"],
+            &["LMOVE", "l", "other", "LEFT", "RIGHT"],
+            &["LLEN", "l"],
+            &["LPOP", "l"],
+            &["LLEN", "l"],
+            &["LINDEX", "other", "0"],
+        ];
+        for args in commands {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            parse_and_handle(&args, &db, &mut client, &mut write_buf).unwrap();
+        }
+
+        let result_s = std::str::from_utf8(&write_buf).unwrap();
+        assert_eq!(
+            result_s,
+            concat!(
+                ":3\r\n:4\r\n:0\r\n",
+                "*4\r\n+z\r\n+a\r\n+b\r\n+c\r\n",
+                "+z\r\n*2\r\n+c\r\n+b\r\n*-1\r\n",
+                ":2\r\n-ERR index out of range\r\n",
+                ":1\r\n*1\r\n:1\r\n",
+                "+a\r\n:1\r\n+b\r\n:0\r\n+a\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_blocking_commands_without_blocking() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut write_buf = Vec::new();
+        let commands: &[&[&str]] = &[
+            &["RPUSH", "l", "a", "b", "c"],
+            &["BLPOP", "missing", "l", "0"],
+            &["BRPOP", "l", "1.5"],
+            &["BLMOVE", "l", "other", "LEFT", "LEFT", "0"],
+            &["BRPOPLPUSH", "other", "l", "0"],
+            &["MULTI"],
+            &["BLPOP", "missing", "0"],
+            &["BLPOP", "l", "0"],
+            &["EXEC"],
+            &["BLPOP", "l", "-1"],
+            &["BLPOP", "l", "x"],
+            &["BLPOP", "l"],
+            &["SET", "s", "x"],
+            &["BLPOP", "s", "0"],
+        ];
+        for args in commands {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            parse_and_handle(&args, &db, &mut client, &mut write_buf).unwrap();
+        }
+
+        let result_s = std::str::from_utf8(&write_buf).unwrap();
+        assert_eq!(
+            result_s,
+            concat!(
+                ":3\r\n*2\r\n+l\r\n+a\r\n*2\r\n+l\r\n+c\r\n+b\r\n+b\r\n",
+                "+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n*-1\r\n*2\r\n+l\r\n+b\r\n",
+                "-ERR timeout is negative\r\n",
+                "-ERR timeout is not a float or out of range\r\n",
+                "-ERR failed to parse: BLPOP\r\n",
+                "+OK\r\n-ERR key s is not list\r\n",
+            )
+        );
+        assert!(client.blocked.is_none());
+    }
+
+    #[test]
+    fn test_blocking_pop_blocks() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut other = ClientState::default();
+        let mut write_buf = Vec::new();
+
+        parse_and_handle(&[b"BRPOP", b"l", b"0"], &db, &mut client, &mut write_buf).unwrap();
+        assert!(client.blocked.is_some());
+        assert!(write_buf.is_empty());
+
+        parse_and_handle(&[b"LPUSH", b"l", b"a"], &db, &mut other, &mut write_buf).unwrap();
+        let wakeup = client
+            .blocked
+            .as_mut()
+            .unwrap()
+            .receiver
+            .try_recv()
+            .unwrap();
+        blocking::unblock(&db, &mut client, Wakeup::Popped(wakeup), &mut write_buf).unwrap();
+        assert_eq!(write_buf, b":1\r\n*2\r\n+l\r\n+a\r\n");
+        assert_eq!(db.llen(b"l").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_list_wrong_type() {
+        let db = Database::new(String::new()).unwrap();
+        db.sadd(b"s", &[b"a"]).unwrap();
+        db.push(b"l", &[b"a"], ListEnd::Tail, false).unwrap();
+
+        assert!(db.push(b"s", &[b"a"], ListEnd::Head, false).is_err());
+        assert!(db.lmove(b"l", b"s", ListEnd::Head, ListEnd::Head).is_err());
+        assert_eq!(db.llen(b"l").unwrap(), 1);
+    }
+}
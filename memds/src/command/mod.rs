@@ -1,23 +1,233 @@
+use std::io::Write;
+
 use command_args::CommandArgs;
-use serde::Serialize;
+use futures::future::{self, Either};
+use serde::{
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Serialize,
+};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
 
-use crate::{database::Database, Error};
+use crate::{byte_string::ByteString, database::Database, resp, Error};
 
 pub mod admin;
+pub mod blocking;
+pub mod client;
 pub mod connection;
+pub mod hash;
+pub mod keyspace;
+pub mod list;
+pub mod pubsub;
+pub mod scripting;
 pub mod set;
+pub mod sorted_set;
+pub mod stream;
 pub mod string;
+pub mod transaction;
 
 pub trait CommandHandler {
     type Output: Serialize;
+    /// Whether the command modifies the keyspace, write commands are logged to the AOF
+    const WRITE: bool = false;
+
     fn handle(self, db: &Database) -> Result<Self::Output, Error>;
+
+    /// Handles the command for the client connection of `client`.
+    /// Commands depending on or changing the state of the connection override this.
+    fn handle_client(self, db: &Database, _client: &mut ClientState) -> Result<Self::Output, Error>
+    where
+        Self: Sized,
+    {
+        self.handle(db)
+    }
+
+    /// Encodes the command to be logged to the AOF, before it is handled.
+    /// Commands depending on the current time override this to log an equivalent command
+    /// with absolute time, so that replaying the log gives the same result.
+    fn encode_aof<'a>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'a>,
+    {
+        self.encode(target)
+    }
+
+    /// Amends the command logged to the AOF once it is handled, for commands whose effect
+    /// depends on the time they are handled and is only known from their reply.
+    fn amend_aof(_output: &Self::Output, _args: &mut Vec<Vec<u8>>) {}
+
+    /// Keys read by the command, tracked for the clients caching them.
+    /// Read-only commands on keys override this.
+    fn read_keys(&self) -> Vec<&[u8]> {
+        Vec::new()
+    }
+
+    /// Writes the reply of the command to `write_buf`, with RESP3 if `resp3` is true.
+    /// Commands replying binary data always written as a blob string override this.
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut serializer = resp::from_write(write_buf, resp3);
+
+        output
+            .serialize(&mut serializer)
+            .map_err(|e| Error::Serialize(e.to_string()))
+    }
+}
+
+/// Map reply, written as a flat array of keys and values with RESP2
+#[derive(Debug, PartialEq)]
+pub struct MapReply<K, V> {
+    pub entries: Vec<(K, V)>,
+    pub resp3: bool,
+}
+
+impl<K, V> MapReply<K, V> {
+    pub fn new(entries: Vec<(K, V)>) -> Self {
+        MapReply {
+            entries,
+            resp3: false,
+        }
+    }
+}
+
+impl<K: Serialize, V: Serialize> Serialize for MapReply<K, V> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.resp3 {
+            let mut map = serializer.serialize_map(Some(self.entries.len()))?;
+            for (k, v) in &self.entries {
+                map.serialize_entry(k, v)?;
+            }
+            map.end()
+        } else {
+            let mut seq = serializer.serialize_seq(Some(self.entries.len() * 2))?;
+            for (k, v) in &self.entries {
+                seq.serialize_element(k)?;
+                seq.serialize_element(v)?;
+            }
+            seq.end()
+        }
+    }
+}
+
+/// Binary bulk string reply
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulkBytes(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+/// Writes a binary bulk string reply, or a null reply for None
+fn write_bulk_bytes(
+    bytes: Option<&[u8]>,
+    resp3: bool,
+    write_buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    match bytes {
+        Some(bytes) => {
+            write!(write_buf, "${}\r\n", bytes.len())
+                .map_err(|e| Error::Serialize(e.to_string()))?;
+            write_buf.extend_from_slice(bytes);
+            write_buf.extend_from_slice(b"\r\n");
+        }
+        None if resp3 => write_buf.extend_from_slice(b"_\r\n"),
+        None => write_buf.extend_from_slice(b"$-1\r\n"),
+    }
+
+    Ok(())
+}
+
+/// State of a client connection, kept between its commands
+#[derive(Debug)]
+pub struct ClientState {
+    /// RESP protocol version, changed by HELLO
+    pub protocol: usize,
+    /// Number of the selected database, changed by SELECT
+    pub db: usize,
+    /// Commands queued since MULTI, None outside of a transaction
+    pub transaction: Option<transaction::Transaction>,
+    /// Keys watched by WATCH until the next EXEC, DISCARD or UNWATCH
+    pub watched: Vec<transaction::WatchedKey>,
+    /// Channels and patterns subscribed, with the messages published to them,
+    /// None until the first subscription
+    pub subscription: Option<crate::pubsub::Subscription>,
+    /// Pop the client is blocked on by BLPOP, BLMOVE, BZPOPMIN..., its next commands are
+    /// handled once it is unblocked
+    pub blocked: Option<crate::blocking::Blocked>,
+    /// Whether blocking commands reply at once instead of blocking, while EXEC runs
+    pub deny_blocking: bool,
+    /// Invalidations pushed to the client, with its id. None until its connection is
+    /// registered by `client::connect`.
+    pub pushes: Option<crate::tracking::Inbox>,
+    /// Client-side caching options, None unless CLIENT TRACKING is on
+    pub tracking: Option<crate::tracking::TrackingOptions>,
+    /// Whether the keys read by the next command are tracked, given by CLIENT CACHING
+    pub caching: Option<bool>,
 }
 
+impl Default for ClientState {
+    fn default() -> Self {
+        ClientState {
+            protocol: 2,
+            db: 0,
+            transaction: None,
+            watched: Vec::new(),
+            subscription: None,
+            blocked: None,
+            deny_blocking: false,
+            pushes: None,
+            tracking: None,
+            caching: None,
+        }
+    }
+}
+
+impl ClientState {
+    pub fn resp3(&self) -> bool {
+        self.protocol >= 3
+    }
+
+    /// Id of the client, 0 if its connection is not registered
+    pub fn id(&self) -> u64 {
+        self.pushes.as_ref().map_or(0, crate::tracking::Inbox::id)
+    }
+}
+
+/// Error of a command handled without the client connection it depends on
+fn requires_connection(command: &str) -> Error {
+    Error::Handle(format!("ERR {} requires a client connection", command))
+}
+
+/// Formats the arguments of a command for logs, as strings with non-printable bytes escaped
+pub(crate) struct DebugArgs<'a>(pub &'a [&'a [u8]]);
+
+impl std::fmt::Debug for DebugArgs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|&arg| ByteString::from(arg)))
+            .finish()
+    }
+}
+
+/// What is done with a parsed command
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dispatch {
+    /// Handle the command and write its reply
+    Handle,
+    /// Only check that the command is supported and parses, for queuing in a transaction
+    Validate,
+}
+
+/// Parses the command as a `T`, then handles it unless only validating.
+/// Returns None if the command is not a `T`, otherwise whether it is a write command.
 fn parse_handle<'a, T>(
-    args: &[&'a str],
+    args: &[&'a [u8]],
     db: &Database,
+    client: &mut ClientState,
     write_buf: &mut Vec<u8>,
-) -> Result<bool, Error>
+    dispatch: Dispatch,
+) -> Result<Option<bool>, Error>
 where
     T: CommandHandler,
     T: CommandArgs<'a>,
@@ -25,24 +235,68 @@ where
 {
     let command = T::parse_maybe(&mut &args[..]).map_err(Error::Parse)?;
 
-    let mut serializer = deseresp::from_write(write_buf);
     if let Some(command) = command {
-        let result = command.handle(db)?;
+        if dispatch == Dispatch::Validate {
+            return Ok(Some(T::WRITE));
+        }
 
-        result
-            .serialize(&mut serializer)
-            .map_err(|e| Error::Serialize(e.to_string()))?;
+        if !T::WRITE {
+            track_reads(&command, db, client);
+        }
+
+        let aof = if T::WRITE { db.aof() } else { None };
+        let result = match aof {
+            Some(mut aof) => {
+                let mut args = Vec::new();
+                command.encode_aof(&mut args).map_err(Error::Parse)?;
+                let result = command.handle_client(db, client)?;
+                T::amend_aof(&result, &mut args);
+                // amended to nothing when the command turned out to change nothing
+                if !args.is_empty() {
+                    aof.append(db.index(), &args)?;
+                }
+
+                result
+            }
+            None => command.handle_client(db, client)?,
+        };
+        if T::WRITE {
+            db.record_write();
+        }
 
-        Ok(true)
+        T::write_output(result, client.resp3(), write_buf)?;
+
+        Ok(Some(T::WRITE))
     } else {
-        Ok(false)
+        Ok(None)
     }
 }
 
-fn handle_unsupported_command(args: &[&str], write_buf: &mut Vec<u8>) -> Result<(), Error> {
-    let mut serializer = deseresp::from_write(write_buf);
-    let response =
-        deseresp::types::owned::SimpleError(format!("ERR command {} not supported", args[0]));
+/// Tracks the keys read by `command` if the client caches them, before reading them so no
+/// modification in between is missed
+fn track_reads<T: CommandHandler>(command: &T, db: &Database, client: &ClientState) {
+    let tracked = client
+        .tracking
+        .as_ref()
+        .is_some_and(|tracking| tracking.tracks_reads(client.caching));
+    if tracked {
+        let keys = command.read_keys();
+        if !keys.is_empty() {
+            db.tracking().track(client.id(), &keys);
+        }
+    }
+}
+
+fn handle_unsupported_command(
+    args: &[&[u8]],
+    resp3: bool,
+    write_buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut serializer = resp::from_write(write_buf, resp3);
+    let response = deseresp::types::owned::SimpleError(format!(
+        "ERR command {} not supported",
+        String::from_utf8_lossy(args[0])
+    ));
 
     response
         .serialize(&mut serializer)
@@ -52,36 +306,193 @@ fn handle_unsupported_command(args: &[&str], write_buf: &mut Vec<u8>) -> Result<
 }
 
 macro_rules! try_commands {
-    (($args:ident, $db:ident, $write_buf:ident) {$command_type:path}) => {
-        if parse_handle::<$command_type>($args, $db, $write_buf)? {
-            return Ok(());
-        }
+    (($args:ident, $db:ident, $client:ident, $write_buf:ident, $dispatch:ident) {$($command_type:path),+}) => {
+        $(
+            if let Some(write) =
+                parse_handle::<$command_type>($args, $db, $client, $write_buf, $dispatch)?
+            {
+                return Ok(Some(write));
+            }
+        )+
     };
-    (($args:ident, $db:ident, $write_buf:ident) {$cmd_type1:path, $($cmd_type2:path),+}) => {
-        try_commands!(($args, $db, $write_buf) {$cmd_type1});
-        try_commands!(($args, $db, $write_buf) {$($cmd_type2),+})
-    }
 }
 
+/// Routes the command to its type, returns None if the command is not supported,
+/// otherwise whether it is a write command
 fn parse_and_handle_main(
-    args: &[&str],
+    args: &[&[u8]],
     db: &Database,
+    client: &mut ClientState,
     write_buf: &mut Vec<u8>,
-) -> Result<(), Error> {
-    try_commands!((args, db, write_buf) {
+    dispatch: Dispatch,
+) -> Result<Option<bool>, Error> {
+    try_commands!((args, db, client, write_buf, dispatch) {
         self::connection::HelloCommand,
         self::connection::CommandCommand,
         self::connection::PingCommand,
+        self::connection::SelectCommand,
+        self::client::ClientCommand,
+        self::transaction::MultiCommand,
+        self::transaction::ExecCommand,
+        self::transaction::DiscardCommand,
+        self::transaction::WatchCommand,
+        self::transaction::UnwatchCommand,
+        self::pubsub::SubscribeCommand,
+        self::pubsub::UnsubscribeCommand,
+        self::pubsub::PsubscribeCommand,
+        self::pubsub::PunsubscribeCommand,
+        self::pubsub::PublishCommand,
+        self::pubsub::PubsubCommand,
+        self::scripting::EvalCommand,
+        self::scripting::EvalshaCommand,
+        self::scripting::ScriptCommand,
         self::string::GetCommand,
         self::string::SetCommand,
         self::string::IncrCommand,
+        self::string::IncrbyCommand,
+        self::string::DecrCommand,
+        self::string::DecrbyCommand,
+        self::string::IncrbyfloatCommand,
+        self::string::AppendCommand,
+        self::string::StrlenCommand,
+        self::string::GetrangeCommand,
+        self::string::SetrangeCommand,
+        self::string::MgetCommand,
+        self::string::MsetCommand,
+        self::string::MsetnxCommand,
+        self::string::GetsetCommand,
+        self::string::GetdelCommand,
+        self::string::GetexCommand,
+        self::string::SetnxCommand,
+        self::string::SetexCommand,
+        self::string::PsetexCommand,
+        self::string::LcsCommand,
+        self::keyspace::ExpireCommand,
+        self::keyspace::PexpireCommand,
+        self::keyspace::ExpireatCommand,
+        self::keyspace::PexpireatCommand,
+        self::keyspace::TtlCommand,
+        self::keyspace::PttlCommand,
+        self::keyspace::ExpiretimeCommand,
+        self::keyspace::PexpiretimeCommand,
+        self::keyspace::PersistCommand,
+        self::keyspace::DumpCommand,
+        self::keyspace::RestoreCommand,
+        self::keyspace::ObjectCommand,
+        self::keyspace::DelCommand,
+        self::keyspace::UnlinkCommand,
+        self::keyspace::ExistsCommand,
+        self::keyspace::TypeCommand,
+        self::keyspace::RenameCommand,
+        self::keyspace::RenamenxCommand,
+        self::keyspace::CopyCommand,
+        self::keyspace::MoveCommand,
+        self::keyspace::SwapdbCommand,
+        self::keyspace::RandomkeyCommand,
+        self::keyspace::DbsizeCommand,
+        self::keyspace::FlushdbCommand,
+        self::keyspace::FlushallCommand,
+        self::keyspace::KeysCommand,
+        self::keyspace::ScanCommand,
+        self::list::LpushCommand,
+        self::list::RpushCommand,
+        self::list::LpushxCommand,
+        self::list::RpushxCommand,
+        self::list::LpopCommand,
+        self::list::RpopCommand,
+        self::list::LlenCommand,
+        self::list::LrangeCommand,
+        self::list::LindexCommand,
+        self::list::LsetCommand,
+        self::list::LinsertCommand,
+        self::list::LremCommand,
+        self::list::LtrimCommand,
+        self::list::LposCommand,
+        self::list::LmoveCommand,
+        self::list::BlpopCommand,
+        self::list::BrpopCommand,
+        self::list::BlmoveCommand,
+        self::list::BrpoplpushCommand,
+        self::hash::HsetCommand,
+        self::hash::HsetnxCommand,
+        self::hash::HgetCommand,
+        self::hash::HmgetCommand,
+        self::hash::HdelCommand,
+        self::hash::HexistsCommand,
+        self::hash::HlenCommand,
+        self::hash::HkeysCommand,
+        self::hash::HvalsCommand,
+        self::hash::HgetallCommand,
+        self::hash::HincrbyCommand,
+        self::hash::HincrbyfloatCommand,
+        self::hash::HstrlenCommand,
+        self::hash::HrandfieldCommand,
+        self::hash::HscanCommand,
         self::set::SaddCommand,
         self::set::SmembersCommand,
-        self::admin::SaveCommand
+        self::set::SremCommand,
+        self::set::ScardCommand,
+        self::set::SismemberCommand,
+        self::set::SmismemberCommand,
+        self::set::SpopCommand,
+        self::set::SrandmemberCommand,
+        self::set::SmoveCommand,
+        self::set::SinterCommand,
+        self::set::SinterstoreCommand,
+        self::set::SunionCommand,
+        self::set::SunionstoreCommand,
+        self::set::SdiffCommand,
+        self::set::SdiffstoreCommand,
+        self::set::SintercardCommand,
+        self::set::SscanCommand,
+        self::sorted_set::ZaddCommand,
+        self::sorted_set::ZincrbyCommand,
+        self::sorted_set::ZremCommand,
+        self::sorted_set::ZscoreCommand,
+        self::sorted_set::ZmscoreCommand,
+        self::sorted_set::ZcardCommand,
+        self::sorted_set::ZcountCommand,
+        self::sorted_set::ZrankCommand,
+        self::sorted_set::ZrevrankCommand,
+        self::sorted_set::ZrangeCommand,
+        self::sorted_set::ZrangestoreCommand,
+        self::sorted_set::ZpopminCommand,
+        self::sorted_set::ZpopmaxCommand,
+        self::sorted_set::BzpopminCommand,
+        self::sorted_set::BzpopmaxCommand,
+        self::sorted_set::ZremrangebyrankCommand,
+        self::sorted_set::ZremrangebyscoreCommand,
+        self::sorted_set::ZremrangebylexCommand,
+        self::sorted_set::ZunionCommand,
+        self::sorted_set::ZunionstoreCommand,
+        self::sorted_set::ZinterCommand,
+        self::sorted_set::ZinterstoreCommand,
+        self::sorted_set::ZintercardCommand,
+        self::sorted_set::ZdiffCommand,
+        self::sorted_set::ZdiffstoreCommand,
+        self::stream::XaddCommand,
+        self::stream::XlenCommand,
+        self::stream::XrangeCommand,
+        self::stream::XrevrangeCommand,
+        self::stream::XdelCommand,
+        self::stream::XtrimCommand,
+        self::stream::XsetidCommand,
+        self::stream::XreadCommand,
+        self::stream::XreadgroupCommand,
+        self::stream::XgroupCommand,
+        self::stream::XackCommand,
+        self::stream::XpendingCommand,
+        self::stream::XclaimCommand,
+        self::stream::XautoclaimCommand,
+        self::stream::XinfoCommand,
+        self::admin::SaveCommand,
+        self::admin::BgrewriteaofCommand,
+        self::admin::BgsaveCommand,
+        self::admin::LastsaveCommand,
+        self::admin::InfoCommand
     });
 
-    // not supported command
-    handle_unsupported_command(args, write_buf)
+    Ok(None)
 }
 
 // Entry point of command routing
@@ -89,17 +500,178 @@ fn parse_and_handle_main(
 // On error, return true for connection event loop to Flush response
 // Return Ok(false) if everything ok
 pub fn parse_and_handle(
-    args: &[&str],
+    args: &[&[u8]],
     db: &Database,
+    client: &mut ClientState,
     write_buf: &mut Vec<u8>,
 ) -> Result<bool, Error> {
-    match parse_and_handle_main(args, db, write_buf) {
+    if let Some(result) = handle_before_access(args, db, client, write_buf) {
+        return result;
+    }
+    let access = futures::executor::block_on(wait_access(args, db));
+
+    handle_with_access(args, db, client, write_buf, access)
+}
+
+/// Handles `commands` in turn for `client`, returns their replies
+#[cfg(test)]
+pub(crate) fn run_commands(
+    db: &Database,
+    client: &mut ClientState,
+    commands: &[&[&str]],
+) -> String {
+    let mut write_buf = Vec::new();
+    for args in commands {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        parse_and_handle(&args, db, client, &mut write_buf).unwrap();
+    }
+
+    String::from_utf8(write_buf).unwrap()
+}
+
+/// Handles the commands not waiting for access to the databases: the ones replied BUSY or
+/// refused in subscribed mode, the ones queued in a transaction and SCRIPT KILL.
+/// Returns None, having done nothing, for the others.
+pub fn handle_before_access(
+    args: &[&[u8]],
+    db: &Database,
+    client: &mut ClientState,
+    write_buf: &mut Vec<u8>,
+) -> Option<Result<bool, Error>> {
+    // checked before waiting for the script to give back access to the databases
+    if let Some(busy) = scripting::busy_error(args, db) {
+        return Some(write_error(args, Err(busy), client.resp3(), write_buf));
+    }
+    if pubsub::in_subscriber_mode(client) && !pubsub::is_subscriber_command(args) {
+        let error = format!(
+            "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in \
+             this context",
+            String::from_utf8_lossy(args[0])
+        );
+        return Some(write_error(
+            args,
+            Err(Error::Handle(error)),
+            client.resp3(),
+            write_buf,
+        ));
+    }
+    if client.transaction.is_some() && !transaction::is_control_command(args) {
+        return Some(transaction::queue(args, db, client, write_buf));
+    }
+
+    // SCRIPT KILL runs alongside the script it kills
+    if scripting::is_script_kill(args) {
+        return Some(handle_command(args, db, client, write_buf));
+    }
+
+    None
+}
+
+/// Access to the databases held while a command is handled, see `wait_access`
+pub enum Access {
+    Shared(OwnedRwLockReadGuard<()>),
+    Exclusive(OwnedRwLockWriteGuard<()>),
+}
+
+/// Waits in turn for access to the databases to handle the command, exclusive for EXEC and
+/// the scripts. Fails with BUSY if a script runs past the time limit meanwhile, so the
+/// command is not waited for.
+pub async fn wait_access(args: &[&[u8]], db: &Database) -> Result<Access, Error> {
+    let access = async {
+        if is_exclusive(args) {
+            Access::Exclusive(db.lock_exclusive().await)
+        } else {
+            Access::Shared(db.lock_shared().await)
+        }
+    };
+    futures::pin_mut!(access);
+
+    loop {
+        let busy = db.scripts().busy_notified();
+        futures::pin_mut!(busy);
+        if let Some(busy) = scripting::busy_error(args, db) {
+            return Err(busy);
+        }
+        if let Either::Left((access, _)) = future::select(access.as_mut(), busy).await {
+            return Ok(access);
+        }
+    }
+}
+
+/// Handles the command with the `access` to the databases given by `wait_access`
+pub fn handle_with_access(
+    args: &[&[u8]],
+    db: &Database,
+    client: &mut ClientState,
+    write_buf: &mut Vec<u8>,
+    access: Result<Access, Error>,
+) -> Result<bool, Error> {
+    let _access = match access {
+        Ok(access) => access,
+        Err(busy) => return write_error(args, Err(busy), client.resp3(), write_buf),
+    };
+
+    let result = db
+        .tracking()
+        .as_client(client.id(), || handle_command(args, db, client, write_buf));
+    db.serve_blocked();
+    // CLIENT CACHING applies to the next command, or to the transaction it starts
+    if client.transaction.is_none() && !client::is_caching_command(args) {
+        client.caching = None;
+    }
+
+    result
+}
+
+/// Whether the command runs with exclusive access to the databases, EXEC and the scripts
+pub(crate) fn is_exclusive(args: &[&[u8]]) -> bool {
+    transaction::is_exec(args) || scripting::is_script(args)
+}
+
+/// Handles the command in the database selected by the client,
+/// the caller holds the access to the database
+fn handle_command(
+    args: &[&[u8]],
+    db: &Database,
+    client: &mut ClientState,
+    write_buf: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    let selected;
+    let db = if client.db == db.index() {
+        db
+    } else {
+        selected = db.select(client.db)?;
+        &selected
+    };
+
+    let result = match parse_and_handle_main(args, db, client, write_buf, Dispatch::Handle) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => {
+            return handle_unsupported_command(args, client.resp3(), write_buf).map(|_| false)
+        }
+        Err(e) => Err(e),
+    };
+
+    write_error(args, result, client.resp3(), write_buf)
+}
+
+/// Writes the error reply of a command that failed to parse or to be handled,
+/// returns true if there is an error reply to flush
+fn write_error(
+    args: &[&[u8]],
+    result: Result<(), Error>,
+    resp3: bool,
+    write_buf: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    match result {
         Ok(_) => Ok(false),
         Err(Error::Parse(e)) => {
-            tracing::error!("Failed to parse command: {:?}, e: {}", &args, e);
-            let mut serializer = deseresp::from_write(write_buf);
-            let response =
-                deseresp::types::owned::SimpleError(format!("ERR failed to parse: {}", args[0]));
+            tracing::error!("Failed to parse command: {:?}, e: {}", DebugArgs(args), e);
+            let mut serializer = resp::from_write(write_buf, resp3);
+            let response = deseresp::types::owned::SimpleError(format!(
+                "ERR failed to parse: {}",
+                String::from_utf8_lossy(args[0])
+            ));
 
             response
                 .serialize(&mut serializer)
@@ -108,8 +680,8 @@ pub fn parse_and_handle(
             Ok(true)
         }
         Err(Error::Handle(e)) => {
-            tracing::error!("Failed to handle command: {:?}, e: {}", &args, e);
-            let mut serializer = deseresp::from_write(write_buf);
+            tracing::error!("Failed to handle command: {:?}, e: {}", DebugArgs(args), e);
+            let mut serializer = resp::from_write(write_buf, resp3);
             let response = deseresp::types::owned::SimpleError(e);
 
             response
@@ -132,14 +704,88 @@ mod tests {
 
     #[test]
     fn test_handle_and_parse_hello_command() {
-        let db = Database::new(String::new());
-        let args = ["HELLO", "3", "AUTH", "user", "pass"];
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let args = ["HELLO", "3", "AUTH", "user", "pass"].map(str::as_bytes);
         let mut write_buf = Vec::new();
-        parse_and_handle(&args, &db, &mut write_buf).unwrap();
+        parse_and_handle(&args, &db, &mut client, &mut write_buf).unwrap();
         let result_s = std::str::from_utf8(&write_buf).unwrap();
         assert_eq!(
             result_s,
             "%3\r\n+server\r\n+memds\r\n+version\r\n+0.0.1\r\n+proto\r\n:3\r\n"
         );
     }
+
+    #[test]
+    fn test_handle_set_command_options() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut write_buf = Vec::new();
+
+        parse_and_handle(
+            &[b"SET", b"lock", b"a", b"NX", b"PX", b"10000"],
+            &db,
+            &mut client,
+            &mut write_buf,
+        )
+        .unwrap();
+        parse_and_handle(
+            &[b"SET", b"lock", b"b", b"NX", b"PX", b"10000"],
+            &db,
+            &mut client,
+            &mut write_buf,
+        )
+        .unwrap();
+        parse_and_handle(
+            &[b"SET", b"lock", b"c", b"XX", b"GET"],
+            &db,
+            &mut client,
+            &mut write_buf,
+        )
+        .unwrap();
+        parse_and_handle(
+            &[b"SET", b"other", b"c", b"GET"],
+            &db,
+            &mut client,
+            &mut write_buf,
+        )
+        .unwrap();
+        parse_and_handle(
+            &[b"SET", b"lock", b"c", b"EX", b"0"],
+            &db,
+            &mut client,
+            &mut write_buf,
+        )
+        .unwrap();
+        let result_s = std::str::from_utf8(&write_buf).unwrap();
+        assert_eq!(
+            result_s,
+            "+OK\r\n$-1\r\n+a\r\n$-1\r\n-ERR invalid expire time in 'set' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_handle_binary_key_and_value() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut write_buf = Vec::new();
+
+        let commands: [&[&[u8]]; 4] = [
+            &[b"SET", b"\xff\x00", b"\x00\xfe\r\n"],
+            &[b"GET", b"\xff\x00"],
+            &[b"SADD", b"s", b"\xc3\x28", b"text"],
+            &[b"SISMEMBER", b"s", b"\xc3\x28"],
+        ];
+        for args in commands {
+            parse_and_handle(args, &db, &mut client, &mut write_buf).unwrap();
+        }
+        assert_eq!(write_buf, b"+OK\r\n$4\r\n\x00\xfe\r\n\r\n:2\r\n:1\r\n");
+        assert_eq!(db.get(b"\xff\x00").unwrap().unwrap(), &b"\x00\xfe\r\n"[..]);
+
+        // an error quoting a key with CR or LF still takes one line
+        write_buf.clear();
+        parse_and_handle(&[b"SADD", b"a\rb", b"x"], &db, &mut client, &mut write_buf).unwrap();
+        parse_and_handle(&[b"GET", b"a\rb"], &db, &mut client, &mut write_buf).unwrap();
+        assert_eq!(write_buf, b":1\r\n-ERR key a b is not string\r\n");
+    }
 }
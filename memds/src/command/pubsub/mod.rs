@@ -0,0 +1,511 @@
+use std::io::Write;
+
+use command_args_derive::CommandArgsBlock;
+use serde::Serialize;
+
+use crate::{
+    byte_string::ByteString,
+    database::Database,
+    pubsub::{Message, PubSub, Subscription},
+};
+
+use super::{requires_connection, write_bulk_bytes, ClientState, CommandHandler, Error, MapReply};
+
+/// Whether the command is allowed for a RESP2 client with subscriptions
+pub(crate) fn is_subscriber_command(args: &[&[u8]]) -> bool {
+    args.first().is_some_and(|command| {
+        [
+            &b"SUBSCRIBE"[..],
+            b"UNSUBSCRIBE",
+            b"PSUBSCRIBE",
+            b"PUNSUBSCRIBE",
+            b"PING",
+        ]
+        .iter()
+        .any(|name| command.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Whether the client is in subscriber mode, only RESP2 clients with subscriptions are
+pub(crate) fn in_subscriber_mode(client: &ClientState) -> bool {
+    !client.resp3()
+        && client
+            .subscription
+            .as_ref()
+            .is_some_and(|subscription| subscription.count() > 0)
+}
+
+/// Removes the subscriptions of a client, when its connection ends
+pub fn reset(db: &Database, client: &mut ClientState) {
+    if let Some(subscription) = client.subscription.take() {
+        db.pubsub().unregister(&subscription);
+    }
+}
+
+/// Writes the header of an array of `len` elements, a push with RESP3
+pub(crate) fn write_push_header(
+    len: usize,
+    resp3: bool,
+    write_buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let marker = if resp3 { '>' } else { '*' };
+    write!(write_buf, "{}{}\r\n", marker, len).map_err(|e| Error::Serialize(e.to_string()))
+}
+
+/// Writes a message published to a subscription of the client,
+/// as an array with RESP2 or a push with RESP3
+pub(crate) fn write_message(
+    message: &Message,
+    resp3: bool,
+    write_buf: &mut Vec<u8>,
+) -> Result<(), Error> {
+    match &message.pattern {
+        Some(pattern) => {
+            write_push_header(4, resp3, write_buf)?;
+            write_bulk_bytes(Some(b"pmessage"), resp3, write_buf)?;
+            write_bulk_bytes(Some(pattern.as_bytes()), resp3, write_buf)?;
+        }
+        None => {
+            write_push_header(3, resp3, write_buf)?;
+            write_bulk_bytes(Some(b"message"), resp3, write_buf)?;
+        }
+    }
+    write_bulk_bytes(Some(message.channel.as_bytes()), resp3, write_buf)?;
+    write_bulk_bytes(Some(message.payload.as_bytes()), resp3, write_buf)
+}
+
+/// Confirmations of (un)subscriptions, one for each channel or pattern with the number of
+/// subscriptions of the client after it, written by `SubscriptionReplies::write`
+#[derive(Debug, PartialEq)]
+pub struct SubscriptionReplies {
+    kind: &'static str,
+    replies: Vec<(Option<ByteString>, usize)>,
+    resp3: bool,
+}
+
+impl Serialize for SubscriptionReplies {
+    fn serialize<S>(&self, _serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Err(serde::ser::Error::custom(
+            "replies of subscriptions are written by SubscriptionReplies::write",
+        ))
+    }
+}
+
+impl SubscriptionReplies {
+    fn write(self, write_buf: &mut Vec<u8>) -> Result<(), Error> {
+        for (name, count) in self.replies {
+            write_push_header(3, self.resp3, write_buf)?;
+            write_bulk_bytes(Some(self.kind.as_bytes()), self.resp3, write_buf)?;
+            write_bulk_bytes(
+                name.as_ref().map(ByteString::as_bytes),
+                self.resp3,
+                write_buf,
+            )?;
+            write!(write_buf, ":{}\r\n", count).map_err(|e| Error::Serialize(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Subscribes the client to each name with `subscribe`
+fn subscribe_all(
+    db: &Database,
+    client: &mut ClientState,
+    kind: &'static str,
+    names: Vec<&[u8]>,
+    subscribe: fn(&PubSub, &mut Subscription, &[u8]) -> usize,
+) -> SubscriptionReplies {
+    let pubsub = db.pubsub();
+    let subscription = client.subscription.get_or_insert_with(|| pubsub.register());
+    let replies = names
+        .into_iter()
+        .map(|name| {
+            let count = subscribe(pubsub, subscription, name);
+            (Some(name.into()), count)
+        })
+        .collect();
+
+    SubscriptionReplies {
+        kind,
+        replies,
+        resp3: client.resp3(),
+    }
+}
+
+/// Unsubscribes the client from each name with `unsubscribe`,
+/// or from all its subscriptions listed by `subscribed` if no name is given
+fn unsubscribe_all(
+    db: &Database,
+    client: &mut ClientState,
+    kind: &'static str,
+    names: Option<Vec<&[u8]>>,
+    subscribed: fn(&Subscription) -> Vec<ByteString>,
+    unsubscribe: fn(&PubSub, &mut Subscription, &[u8]) -> usize,
+) -> SubscriptionReplies {
+    let pubsub = db.pubsub();
+    let resp3 = client.resp3();
+    let Some(subscription) = client.subscription.as_mut() else {
+        let replies = match names {
+            Some(names) => names
+                .into_iter()
+                .map(|name| (Some(name.into()), 0))
+                .collect(),
+            None => vec![(None, 0)],
+        };
+        return SubscriptionReplies {
+            kind,
+            replies,
+            resp3,
+        };
+    };
+
+    let names = match names {
+        Some(names) => names.into_iter().map(ByteString::from).collect(),
+        None => subscribed(subscription),
+    };
+    let mut replies: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let count = unsubscribe(pubsub, subscription, &name);
+            (Some(name), count)
+        })
+        .collect();
+    if replies.is_empty() {
+        replies.push((None, subscription.count()));
+    }
+
+    SubscriptionReplies {
+        kind,
+        replies,
+        resp3,
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SUBSCRIBE")]
+pub struct SubscribeCommand<'a> {
+    pub channels: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SubscribeCommand<'a> {
+    type Output = SubscriptionReplies;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(requires_connection("SUBSCRIBE"))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        Ok(subscribe_all(
+            db,
+            client,
+            "subscribe",
+            self.channels,
+            PubSub::subscribe,
+        ))
+    }
+
+    fn write_output(
+        output: Self::Output,
+        _resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("UNSUBSCRIBE")]
+pub struct UnsubscribeCommand<'a> {
+    /// Unsubscribes from all channels if None
+    pub channels: Option<Vec<&'a [u8]>>,
+}
+
+impl<'a> CommandHandler for UnsubscribeCommand<'a> {
+    type Output = SubscriptionReplies;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(requires_connection("UNSUBSCRIBE"))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        Ok(unsubscribe_all(
+            db,
+            client,
+            "unsubscribe",
+            self.channels,
+            |subscription| subscription.channels().cloned().collect(),
+            PubSub::unsubscribe,
+        ))
+    }
+
+    fn write_output(
+        output: Self::Output,
+        _resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PSUBSCRIBE")]
+pub struct PsubscribeCommand<'a> {
+    pub patterns: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for PsubscribeCommand<'a> {
+    type Output = SubscriptionReplies;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(requires_connection("PSUBSCRIBE"))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        Ok(subscribe_all(
+            db,
+            client,
+            "psubscribe",
+            self.patterns,
+            PubSub::psubscribe,
+        ))
+    }
+
+    fn write_output(
+        output: Self::Output,
+        _resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PUNSUBSCRIBE")]
+pub struct PunsubscribeCommand<'a> {
+    /// Unsubscribes from all patterns if None
+    pub patterns: Option<Vec<&'a [u8]>>,
+}
+
+impl<'a> CommandHandler for PunsubscribeCommand<'a> {
+    type Output = SubscriptionReplies;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(requires_connection("PUNSUBSCRIBE"))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        Ok(unsubscribe_all(
+            db,
+            client,
+            "punsubscribe",
+            self.patterns,
+            |subscription| subscription.patterns().cloned().collect(),
+            PubSub::punsubscribe,
+        ))
+    }
+
+    fn write_output(
+        output: Self::Output,
+        _resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PUBLISH")]
+pub struct PublishCommand<'a> {
+    pub channel: &'a [u8],
+    pub message: &'a [u8],
+}
+
+impl<'a> CommandHandler for PublishCommand<'a> {
+    /// Number of subscriptions that received the message
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.pubsub().publish(self.channel, self.message))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub enum PubsubSubcommand<'a> {
+    #[argtoken("CHANNELS")]
+    Channels { pattern: Option<&'a [u8]> },
+    #[argtoken("NUMSUB")]
+    Numsub { channels: Option<Vec<&'a [u8]>> },
+    #[argtoken("NUMPAT")]
+    Numpat,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum PubsubReply {
+    Channels(Vec<ByteString>),
+    Numsub(MapReply<ByteString, usize>),
+    Numpat(usize),
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PUBSUB")]
+pub struct PubsubCommand<'a> {
+    pub subcommand: PubsubSubcommand<'a>,
+}
+
+impl<'a> CommandHandler for PubsubCommand<'a> {
+    type Output = PubsubReply;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let pubsub = db.pubsub();
+
+        Ok(match self.subcommand {
+            PubsubSubcommand::Channels { pattern } => {
+                let mut channels = pubsub.channels(pattern);
+                channels.sort();
+                PubsubReply::Channels(channels)
+            }
+            PubsubSubcommand::Numsub { channels } => PubsubReply::Numsub(MapReply::new(
+                channels
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|channel| (channel.into(), pubsub.numsub(channel)))
+                    .collect(),
+            )),
+            PubsubSubcommand::Numpat => PubsubReply::Numpat(pubsub.numpat()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::run_commands;
+
+    fn deliver(client: &mut ClientState) -> String {
+        let resp3 = client.resp3();
+        let mut write_buf = Vec::new();
+        let subscription = client.subscription.as_mut().unwrap();
+        while let Some(message) = subscription.try_recv() {
+            write_message(&message, resp3, &mut write_buf).unwrap();
+        }
+
+        String::from_utf8(write_buf).unwrap()
+    }
+
+    #[test]
+    fn test_subscribe_publish() {
+        let db = Database::new(String::new()).unwrap();
+        let mut subscriber = ClientState::default();
+        let mut publisher = ClientState::default();
+
+        let result = run_commands(
+            &db,
+            &mut subscriber,
+            &[&["SUBSCRIBE", "a", "b"], &["PSUBSCRIBE", "n*"]],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n",
+                "*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n",
+                "*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:3\r\n",
+            )
+        );
+
+        let result = run_commands(
+            &db,
+            &mut publisher,
+            &[
+                &["PUBLISH", "a", "hello"],
+                &["PUBLISH", "news", "hi"],
+                &["PUBLISH", "c", "nobody"],
+                &["PUBSUB", "CHANNELS"],
+                &["PUBSUB", "NUMSUB", "a", "c"],
+                &["PUBSUB", "NUMPAT"],
+            ],
+        );
+        assert_eq!(
+            result,
+            ":1\r\n:1\r\n:0\r\n*2\r\n+a\r\n+b\r\n*4\r\n+a\r\n:1\r\n+c\r\n:0\r\n:1\r\n"
+        );
+        assert_eq!(
+            deliver(&mut subscriber),
+            concat!(
+                "*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$5\r\nhello\r\n",
+                "*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$2\r\nhi\r\n",
+            )
+        );
+
+        // a RESP2 subscriber can only manage its subscriptions
+        let result = run_commands(
+            &db,
+            &mut subscriber,
+            &[&["GET", "a"], &["PING"], &["PING", "hey"]],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "-ERR Can't execute 'GET': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are ",
+                "allowed in this context\r\n",
+                "*2\r\n$4\r\npong\r\n$0\r\n\r\n*2\r\n$4\r\npong\r\n$3\r\nhey\r\n",
+            )
+        );
+
+        let result = run_commands(
+            &db,
+            &mut subscriber,
+            &[
+                &["UNSUBSCRIBE", "a"],
+                &["PUNSUBSCRIBE"],
+                &["UNSUBSCRIBE"],
+                &["UNSUBSCRIBE"],
+                &["GET", "a"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:2\r\n",
+                "*3\r\n$12\r\npunsubscribe\r\n$2\r\nn*\r\n:1\r\n",
+                "*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:0\r\n",
+                "*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n",
+                "$-1\r\n",
+            )
+        );
+        assert_eq!(
+            run_commands(&db, &mut publisher, &[&["PUBLISH", "a", "x"]]),
+            ":0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_subscribe_resp3() {
+        let db = Database::new(String::new()).unwrap();
+        let mut subscriber = ClientState::default();
+
+        let result = run_commands(
+            &db,
+            &mut subscriber,
+            &[
+                &["HELLO", "3"],
+                &["SUBSCRIBE", "a"],
+                &["SET", "k", "v"],
+                &["PUBLISH", "a", "hello"],
+            ],
+        );
+        assert!(result.ends_with("+OK\r\n:1\r\n"));
+        assert!(result.contains(">3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n"));
+        assert_eq!(
+            deliver(&mut subscriber),
+            ">3\r\n$7\r\nmessage\r\n$1\r\na\r\n$5\r\nhello\r\n"
+        );
+
+        reset(&db, &mut subscriber);
+        assert_eq!(db.pubsub().numsub(b"a"), 0);
+    }
+}
@@ -0,0 +1,772 @@
+use std::{cell::RefCell, io::Write};
+
+use command_args::{CommandArgs, NumKeys};
+use command_args_derive::CommandArgsBlock;
+use deseresp::types::OkResponse;
+use mlua::{HookTriggers, IntoLua, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use serde::Serialize;
+
+use crate::{
+    byte_string,
+    database::Database,
+    resp,
+    scripting::{sha1hex, Run},
+};
+
+use super::{
+    keyspace::FlushMode, transaction, write_bulk_bytes, ClientState, CommandHandler, Dispatch,
+    Error,
+};
+
+/// Number of Lua instructions between checks that the script was not killed nor passed the
+/// time limit
+const KILL_CHECK_INSTRUCTIONS: u32 = 10_000;
+/// Limit of the nesting of the tables replied by a script
+const MAX_REPLY_DEPTH: usize = 128;
+
+const BUSY: &str =
+    "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.";
+const KILLED: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+fn is_command(args: &[&[u8]], names: &[&[u8]]) -> bool {
+    args.first()
+        .is_some_and(|command| names.iter().any(|name| command.eq_ignore_ascii_case(name)))
+}
+
+/// Whether the command runs a script, with exclusive access to the databases
+pub(crate) fn is_script(args: &[&[u8]]) -> bool {
+    is_command(args, &[b"EVAL", b"EVALSHA"])
+}
+
+/// Whether the command is SCRIPT KILL, handled while a script runs
+pub(crate) fn is_script_kill(args: &[&[u8]]) -> bool {
+    is_command(args, &[b"SCRIPT"])
+        && args
+            .get(1)
+            .is_some_and(|sub| sub.eq_ignore_ascii_case(b"KILL"))
+}
+
+/// Error replied to the commands other than SCRIPT KILL while a script runs past the time
+/// limit, None otherwise
+pub(crate) fn busy_error(args: &[&[u8]], db: &Database) -> Option<Error> {
+    (db.scripts().busy() && !is_script_kill(args)).then(|| Error::Handle(BUSY.to_string()))
+}
+
+/// Whether a script may not call the command, as it depends on the connection
+fn is_denied(name: &[u8]) -> bool {
+    [
+        &b"MULTI"[..],
+        b"EXEC",
+        b"DISCARD",
+        b"WATCH",
+        b"UNWATCH",
+        b"SUBSCRIBE",
+        b"UNSUBSCRIBE",
+        b"PSUBSCRIBE",
+        b"PUNSUBSCRIBE",
+        b"EVAL",
+        b"EVALSHA",
+        b"SCRIPT",
+        b"CLIENT",
+        b"HELLO",
+    ]
+    .iter()
+    .any(|denied| name.eq_ignore_ascii_case(denied))
+}
+
+/// Error reply of a command called by a script, raised by redis.call
+#[derive(Debug)]
+struct ReplyError(String);
+
+impl std::fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+/// Commands called by a running script, as a client of its own
+struct Caller<'a> {
+    client: ClientState,
+    run: &'a Run,
+    /// Whether the writes are logged to the AOF between MULTI and EXEC, unless the script
+    /// runs in a transaction already logged so
+    wrap_aof: bool,
+    /// Whether MULTI was logged to the AOF before the first write
+    logged: bool,
+}
+
+impl Caller<'_> {
+    /// Calls the command given by the script, returns its reply converted to Lua or its
+    /// error message
+    fn call<'lua>(
+        &mut self,
+        lua: &'lua Lua,
+        db: &Database,
+        args: Variadic<Value<'lua>>,
+    ) -> mlua::Result<Result<Value<'lua>, String>> {
+        let args = match command_args(&args) {
+            Ok(args) => args,
+            Err(e) => return Ok(Err(e)),
+        };
+        let args: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+        if is_denied(args[0]) {
+            return Ok(Err(
+                "ERR This Redis command is not allowed from script".to_string()
+            ));
+        }
+
+        let mut buf = Vec::new();
+        let validated =
+            super::parse_and_handle_main(&args, db, &mut self.client, &mut buf, Dispatch::Validate);
+        match validated {
+            Ok(None) => {
+                return Ok(Err(
+                    "ERR Unknown Redis command called from script".to_string()
+                ))
+            }
+            Ok(Some(true)) => {
+                if !self.run.mark_written() {
+                    return Err(mlua::Error::external(ReplyError(KILLED.to_string())));
+                }
+                if self.wrap_aof && !self.logged {
+                    if let Some(mut aof) = db.aof() {
+                        let mut record = Vec::new();
+                        transaction::MultiCommand
+                            .encode(&mut record)
+                            .map_err(|e| mlua::Error::external(Error::Parse(e)))?;
+                        aof.append(self.client.db, &record)
+                            .map_err(mlua::Error::external)?;
+                        self.logged = true;
+                    }
+                }
+            }
+            // the error is replied by handling the command
+            Ok(Some(false)) | Err(_) => {}
+        }
+
+        resp::with_blob_strings(|| super::handle_command(&args, db, &mut self.client, &mut buf))
+            .map_err(mlua::Error::external)?;
+        if let Some(b'-' | b'!') = buf.first() {
+            let error = reply_to_lua(lua, &mut &buf[..])?;
+            return Ok(Err(error_message(&error).unwrap_or_default()));
+        }
+
+        Ok(Ok(reply_to_lua(lua, &mut &buf[..])?))
+    }
+
+    /// Logs EXEC to the AOF once the script is done, if it logged MULTI
+    fn finish(self, db: &Database) -> Result<(), Error> {
+        if self.logged {
+            if let Some(mut aof) = db.aof() {
+                let mut record = Vec::new();
+                transaction::ExecCommand
+                    .encode(&mut record)
+                    .map_err(Error::Parse)?;
+                aof.append(self.client.db, &record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Arguments of a command called by a script, given as strings or numbers
+fn command_args(args: &[Value]) -> Result<Vec<Vec<u8>>, String> {
+    if args.is_empty() {
+        return Err("ERR Please specify at least one argument for this redis lib call".to_string());
+    }
+
+    args.iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(s.as_bytes().to_vec()),
+            Value::Integer(i) => Ok(i.to_string().into_bytes()),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                Ok((*n as i64).to_string().into_bytes())
+            }
+            Value::Number(n) => Ok(n.to_string().into_bytes()),
+            _ => Err("ERR Lua redis lib command arguments must be strings or integers".to_string()),
+        })
+        .collect()
+}
+
+fn invalid_reply() -> mlua::Error {
+    mlua::Error::external(ReplyError("ERR invalid reply of command".to_string()))
+}
+
+/// Takes the next line of a reply, without its CRLF
+fn take_line<'a>(reply: &mut &'a [u8]) -> mlua::Result<&'a [u8]> {
+    let end = reply
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or_else(invalid_reply)?;
+    let line = &reply[..end];
+    *reply = &reply[end + 2..];
+
+    Ok(line)
+}
+
+/// Length of a blob or an aggregate, None for a RESP2 null
+fn parse_len(len: &[u8]) -> mlua::Result<Option<usize>> {
+    match len {
+        b"-1" => Ok(None),
+        len => byte_string::parse(len).map(Some).ok_or_else(invalid_reply),
+    }
+}
+
+fn error_table<'lua>(lua: &'lua Lua, message: &[u8]) -> mlua::Result<Value<'lua>> {
+    let table = lua.create_table()?;
+    table.raw_set("err", lua.create_string(message)?)?;
+
+    Ok(Value::Table(table))
+}
+
+fn status_table<'lua>(lua: &'lua Lua, status: mlua::String<'lua>) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.raw_set("ok", status)?;
+
+    Ok(table)
+}
+
+/// Message of an error reply converted to Lua, a table with an `err` field
+fn error_message(value: &Value) -> Option<String> {
+    match value {
+        Value::Table(table) => match table.raw_get("err") {
+            Ok(Value::String(message)) => Some(message.to_string_lossy().into_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Converts the reply of a command called by a script to a Lua value: strings and doubles to
+/// strings, integers to numbers, aggregates to tables, nulls to false, status replies to
+/// tables with an `ok` field and errors to tables with an `err` field
+fn reply_to_lua<'lua>(lua: &'lua Lua, reply: &mut &[u8]) -> mlua::Result<Value<'lua>> {
+    let line = take_line(reply)?;
+    let (&marker, rest) = line.split_first().ok_or_else(invalid_reply)?;
+
+    Ok(match marker {
+        // the strings of the commands called by scripts are written as blob strings
+        b'+' => Value::Table(status_table(lua, lua.create_string(rest)?)?),
+        b',' => Value::String(lua.create_string(rest)?),
+        b'-' => error_table(lua, rest)?,
+        b':' => byte_string::parse::<i64>(rest)
+            .ok_or_else(invalid_reply)?
+            .into_lua(lua)?,
+        b'#' => Value::Boolean(rest == b"t"),
+        b'_' => Value::Boolean(false),
+        b'$' | b'!' => match parse_len(rest)? {
+            Some(len) if reply.len() >= len + 2 => {
+                let (blob, rest) = reply.split_at(len);
+                *reply = &rest[2..];
+                match marker {
+                    b'!' => error_table(lua, blob)?,
+                    _ => Value::String(lua.create_string(blob)?),
+                }
+            }
+            Some(_) => return Err(invalid_reply()),
+            None => Value::Boolean(false),
+        },
+        b'*' | b'~' | b'%' => match parse_len(rest)? {
+            Some(len) => {
+                // maps are flattened to their keys and values, like with RESP2
+                let len = if marker == b'%' { len * 2 } else { len };
+                let table = lua.create_table()?;
+                for i in 1..=len {
+                    table.raw_set(i, reply_to_lua(lua, reply)?)?;
+                }
+                Value::Table(table)
+            }
+            None => Value::Boolean(false),
+        },
+        _ => return Err(invalid_reply()),
+    })
+}
+
+/// Writes the value returned by a script as a reply: strings as blob strings, numbers as
+/// integers, true as 1, tables with an `ok` or `err` field as status or error, other tables
+/// as arrays of their elements up to the first nil, and anything else as null
+fn write_value(
+    value: &Value,
+    resp3: bool,
+    write_buf: &mut Vec<u8>,
+    depth: usize,
+) -> Result<(), Error> {
+    if depth > MAX_REPLY_DEPTH {
+        return Err(Error::Handle("ERR reached lua stack limit".to_string()));
+    }
+    let line = |write_buf: &mut Vec<u8>, marker: &str, value: &dyn std::fmt::Display| {
+        write!(write_buf, "{}{}\r\n", marker, value).map_err(|e| Error::Serialize(e.to_string()))
+    };
+
+    match value {
+        Value::String(s) => write_bulk_bytes(Some(s.as_bytes()), resp3, write_buf),
+        Value::Integer(i) => line(write_buf, ":", i),
+        Value::Number(n) => line(write_buf, ":", &(*n as i64)),
+        Value::Boolean(true) => line(write_buf, ":", &1),
+        Value::Table(table) => {
+            if let Some(message) = error_message(value) {
+                return line(write_buf, "-", &message.replace(['\r', '\n'], " "));
+            }
+            if let Ok(Value::String(status)) = table.raw_get("ok") {
+                let status = status.to_string_lossy().replace(['\r', '\n'], " ");
+                return line(write_buf, "+", &status);
+            }
+
+            let elements: Vec<Value> = table
+                .clone()
+                .sequence_values()
+                .map_while(Result::ok)
+                .collect();
+            line(write_buf, "*", &elements.len())?;
+            elements
+                .iter()
+                .try_for_each(|element| write_value(element, resp3, write_buf, depth + 1))
+        }
+        _ => write_bulk_bytes(None, resp3, write_buf),
+    }
+}
+
+/// Converts an error of a script to its error reply
+fn script_error(e: &mlua::Error, sha: &str) -> Error {
+    let running = |message: &dyn std::fmt::Display| {
+        let message = message.to_string();
+        Error::Handle(format!(
+            "ERR Error running script (call to f_{}): {}",
+            sha,
+            first_line(&message)
+        ))
+    };
+
+    match e {
+        mlua::Error::SyntaxError { message, .. } => Error::Handle(format!(
+            "ERR Error compiling script (new function): {}",
+            first_line(message)
+        )),
+        mlua::Error::CallbackError { cause, .. } => script_error(cause, sha),
+        mlua::Error::ExternalError(external) => match external.downcast_ref::<ReplyError>() {
+            Some(ReplyError(message)) => Error::Handle(message.clone()),
+            None => running(external),
+        },
+        mlua::Error::RuntimeError(message) => running(message),
+        e => running(e),
+    }
+}
+
+/// First line of a Lua error, without the stack traceback that follows
+fn first_line(message: &str) -> &str {
+    message.lines().next().unwrap_or_default()
+}
+
+/// Registers the API of the scripts: KEYS, ARGV and the redis library.
+/// redis.call raises the error replies of the commands, redis.pcall returns them.
+fn register_api<'lua, 'scope>(
+    lua: &'lua Lua,
+    scope: &mlua::Scope<'lua, 'scope>,
+    db: &'scope Database,
+    caller: &'scope RefCell<Caller<'_>>,
+    keys: &[&[u8]],
+    args: &[&[u8]],
+) -> mlua::Result<()>
+where
+    'lua: 'scope,
+{
+    let globals = lua.globals();
+    let strings = |values: &[&[u8]]| {
+        values
+            .iter()
+            .map(|value| lua.create_string(value))
+            .collect::<mlua::Result<Vec<_>>>()
+    };
+    globals.raw_set("KEYS", lua.create_sequence_from(strings(keys)?)?)?;
+    globals.raw_set("ARGV", lua.create_sequence_from(strings(args)?)?)?;
+    // the scripts may not read files
+    globals.raw_set("dofile", Value::Nil)?;
+    globals.raw_set("loadfile", Value::Nil)?;
+
+    let redis = lua.create_table()?;
+    let call = scope.create_function(move |lua, args: Variadic<Value>| {
+        match caller.borrow_mut().call(lua, db, args)? {
+            Ok(value) => Ok(value),
+            Err(message) => Err(mlua::Error::external(ReplyError(message))),
+        }
+    })?;
+    redis.raw_set("call", call)?;
+    let pcall = scope.create_function(move |lua, args: Variadic<Value>| {
+        match caller.borrow_mut().call(lua, db, args)? {
+            Ok(value) => Ok(value),
+            Err(message) => error_table(lua, message.as_bytes()),
+        }
+    })?;
+    redis.raw_set("pcall", pcall)?;
+    let error_reply =
+        lua.create_function(|lua, message: mlua::String| error_table(lua, message.as_bytes()))?;
+    redis.raw_set("error_reply", error_reply)?;
+    redis.raw_set("status_reply", lua.create_function(status_table)?)?;
+    let sha1hex = lua.create_function(|_, s: mlua::String| Ok(sha1hex(s.as_bytes())))?;
+    redis.raw_set("sha1hex", sha1hex)?;
+    globals.raw_set("redis", redis)?;
+
+    Ok(())
+}
+
+/// Makes the globals read-only to the scripts: creating a global variable or reading a
+/// missing one raises an error, so that scripts don't leak state to the following ones
+fn protect_globals(lua: &Lua) -> mlua::Result<()> {
+    lua.load(
+        r#"
+        setmetatable(_G, {
+            __newindex = function(_, name)
+                error("Script attempted to create global variable '" .. tostring(name) .. "'", 2)
+            end,
+            __index = function(_, name)
+                error("Script attempted to access nonexistent global variable '"
+                    .. tostring(name) .. "'", 2)
+            end,
+        })
+        "#,
+    )
+    .set_name("@protect_globals")
+    .exec()
+}
+
+/// Runs `script` with digest `sha`, the caller holds exclusive access to the databases.
+/// The writes of the script are logged to the AOF between MULTI and EXEC.
+fn run_script(
+    db: &Database,
+    client: &ClientState,
+    sha: &str,
+    script: &[u8],
+    keys: &[&[u8]],
+    args: &[&[u8]],
+) -> Result<ScriptReply, Error> {
+    let guard = db.scripts().start();
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )
+    .map_err(|e| script_error(&e, sha))?;
+    let run = guard.run().clone();
+    let hook_db = db.clone();
+    let triggers = HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS);
+    lua.set_hook(triggers, move |_, _| {
+        hook_db.scripts().check_time_limit(&run);
+        match run.killed() {
+            true => Err(mlua::Error::external(ReplyError(KILLED.to_string()))),
+            false => Ok(()),
+        }
+    });
+
+    let caller = RefCell::new(Caller {
+        client: ClientState {
+            db: client.db,
+            // blocking would never end, nothing else runs until the script is done
+            deny_blocking: true,
+            ..Default::default()
+        },
+        run: guard.run(),
+        // EXEC is the only command running with blocking denied
+        wrap_aof: !client.deny_blocking,
+        logged: false,
+    });
+    let result = lua.scope(|scope| {
+        register_api(&lua, scope, db, &caller, keys, args)?;
+        protect_globals(&lua)?;
+        let value: Value = lua.load(script).set_name("@user_script").call(())?;
+
+        let mut buf = Vec::new();
+        let reply = match error_message(&value) {
+            Some(message) => Err(Error::Handle(message)),
+            None => write_value(&value, client.resp3(), &mut buf, 0).map(|_| ScriptReply(buf)),
+        };
+        Ok(reply)
+    });
+    caller.into_inner().finish(db)?;
+
+    result.map_err(|e| script_error(&e, sha))?
+}
+
+/// Reply of a script, already written
+#[derive(Debug)]
+pub struct ScriptReply(Vec<u8>);
+
+impl Serialize for ScriptReply {
+    fn serialize<S>(&self, _serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Err(serde::ser::Error::custom(
+            "replies of scripts are written by write_output",
+        ))
+    }
+}
+
+fn write_script_reply(output: ScriptReply, write_buf: &mut Vec<u8>) -> Result<(), Error> {
+    write_buf.extend_from_slice(&output.0);
+
+    Ok(())
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EVAL")]
+pub struct EvalCommand<'a> {
+    pub script: &'a [u8],
+    pub keys: NumKeys<&'a [u8]>,
+    pub args: Option<Vec<&'a [u8]>>,
+}
+
+impl<'a> CommandHandler for EvalCommand<'a> {
+    type Output = ScriptReply;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let sha = db.scripts().load(self.script);
+        let args = self.args.unwrap_or_default();
+
+        run_script(db, client, &sha, self.script, &self.keys.0, &args)
+    }
+
+    fn write_output(
+        output: Self::Output,
+        _resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        write_script_reply(output, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EVALSHA")]
+pub struct EvalshaCommand<'a> {
+    pub sha1: &'a str,
+    pub keys: NumKeys<&'a [u8]>,
+    pub args: Option<Vec<&'a [u8]>>,
+}
+
+impl<'a> CommandHandler for EvalshaCommand<'a> {
+    type Output = ScriptReply;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let script = db.scripts().get(self.sha1).ok_or_else(|| {
+            Error::Handle("NOSCRIPT No matching script. Please use EVAL.".to_string())
+        })?;
+        let sha = self.sha1.to_ascii_lowercase();
+        let args = self.args.unwrap_or_default();
+
+        run_script(db, client, &sha, &script, &self.keys.0, &args)
+    }
+
+    fn write_output(
+        output: Self::Output,
+        _resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        write_script_reply(output, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub enum ScriptSubcommand<'a> {
+    #[argtoken("LOAD")]
+    Load { script: &'a [u8] },
+    #[argtoken("EXISTS")]
+    Exists { sha1s: Vec<&'a str> },
+    #[argtoken("FLUSH")]
+    Flush { mode: Option<FlushMode> },
+    #[argtoken("KILL")]
+    Kill,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SCRIPT")]
+pub struct ScriptCommand<'a> {
+    pub subcommand: ScriptSubcommand<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ScriptCommandReply {
+    Sha1(String),
+    Exists(Vec<usize>),
+    Ok(OkResponse),
+}
+
+impl<'a> CommandHandler for ScriptCommand<'a> {
+    type Output = ScriptCommandReply;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let scripts = db.scripts();
+
+        Ok(match self.subcommand {
+            ScriptSubcommand::Load { script } => ScriptCommandReply::Sha1(scripts.load(script)),
+            ScriptSubcommand::Exists { sha1s } => ScriptCommandReply::Exists(
+                sha1s
+                    .into_iter()
+                    .map(|sha| scripts.exists(sha) as usize)
+                    .collect(),
+            ),
+            // the cache only holds the source of the scripts, it is freed at once
+            ScriptSubcommand::Flush { .. } => {
+                scripts.flush();
+                ScriptCommandReply::Ok(OkResponse)
+            }
+            ScriptSubcommand::Kill => {
+                scripts.kill()?;
+                ScriptCommandReply::Ok(OkResponse)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::run_commands;
+
+    #[test]
+    fn test_eval() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+
+        let reply = run_commands(
+            &db,
+            &mut client,
+            &[
+                &[
+                    "EVAL",
+                    "return {1, 'a', true, false, 2.5, {ok='fine'}}",
+                    "0",
+                ],
+                &[
+                    "EVAL",
+                    "redis.call('SET', KEYS[1], ARGV[1]); return redis.call('INCRBY', KEYS[1], 2)",
+                    "1",
+                    "counter",
+                    "40",
+                ],
+                &["GET", "counter"],
+                &["EVAL", "return redis.call('GET', KEYS[1])", "1", "missing"],
+                &["EVAL", "return redis.call('HGETALL', KEYS[1])", "1", "h"],
+                &[
+                    "EVAL",
+                    "return {redis.call('TYPE', KEYS[1]).ok, redis.call('GET', KEYS[1])}",
+                    "1",
+                    "counter",
+                ],
+                &["EVAL", "return redis.call('SET', KEYS[1], 'OK')", "1", "s"],
+            ],
+        );
+        assert_eq!(
+            reply,
+            "*6\r\n:1\r\n$1\r\na\r\n:1\r\n$-1\r\n:2\r\n+fine\r\n\
+             :42\r\n+42\r\n$-1\r\n*0\r\n*2\r\n$6\r\nstring\r\n$2\r\n42\r\n+OK\r\n"
+        );
+
+        let reply = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["EVAL", "return redis.call('INCR')", "0"],
+                &["EVAL", "return redis.pcall('LPUSH', 'counter', 'x')", "0"],
+                &["EVAL", "return redis.error_reply('MY failure')", "0"],
+                &["EVAL", "return redis.call('MULTI')", "0"],
+                &["EVAL", "return redis.call('NOPE')", "0"],
+                &["EVAL", "return nil +", "0"],
+                &["EVAL", "x = 1", "0"],
+                &["EVAL", "return", "2", "a"],
+            ],
+        );
+        let errors: Vec<&str> = reply.lines().collect();
+        assert_eq!(errors[0], "-ERR failed to parse: INCR");
+        assert_eq!(errors[1], "-ERR key counter is not list");
+        assert_eq!(errors[2], "-MY failure");
+        assert_eq!(
+            errors[3],
+            "-ERR This Redis command is not allowed from script"
+        );
+        assert_eq!(errors[4], "-ERR Unknown Redis command called from script");
+        assert!(errors[5].starts_with("-ERR Error compiling script"));
+        assert!(errors[6].ends_with("Script attempted to create global variable 'x'"));
+        assert_eq!(errors.len(), 8);
+    }
+
+    #[test]
+    fn test_evalsha_and_script() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let sha = sha1hex(b"return ARGV[1]");
+
+        let reply = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["EVALSHA", &sha, "0", "a"],
+                &["SCRIPT", "LOAD", "return ARGV[1]"],
+                &["EVALSHA", &sha.to_ascii_uppercase(), "0", "a"],
+                &["SCRIPT", "EXISTS", &sha, "0000"],
+                &["SCRIPT", "FLUSH"],
+                &["SCRIPT", "EXISTS", &sha],
+                &["SCRIPT", "KILL"],
+            ],
+        );
+        assert_eq!(
+            reply,
+            format!(
+                "-NOSCRIPT No matching script. Please use EVAL.\r\n+{}\r\n$1\r\na\r\n\
+                 *2\r\n:1\r\n:0\r\n+OK\r\n*1\r\n:0\r\n\
+                 -NOTBUSY No scripts in execution right now.\r\n",
+                sha
+            )
+        );
+    }
+
+    #[test]
+    fn test_script_in_transaction() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+
+        let reply = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["MULTI"],
+                &["EVAL", "return redis.call('RPUSH', KEYS[1], 'a')", "1", "l"],
+                &["EVAL", "return redis.call('BLPOP', 'empty', 0)", "0"],
+                &["EXEC"],
+            ],
+        );
+        assert_eq!(reply, "+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n:1\r\n$-1\r\n");
+    }
+
+    #[test]
+    fn test_kill_script() {
+        let db = Database::new(String::new()).unwrap();
+        db.scripts().set_time_limit(0);
+
+        let killer = {
+            let db = db.clone();
+            std::thread::spawn(move || loop {
+                let mut client = ClientState::default();
+                let reply = run_commands(&db, &mut client, &[&["SCRIPT", "KILL"], &["PING"]]);
+                if reply.starts_with("+OK") {
+                    break reply;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            })
+        };
+        let mut client = ClientState::default();
+        let reply = run_commands(&db, &mut client, &[&["EVAL", "while true do end", "0"]]);
+        assert_eq!(reply, format!("-{}\r\n", KILLED));
+        // other commands are replied BUSY while the script runs past the time limit
+        assert_eq!(killer.join().unwrap(), format!("+OK\r\n-{}\r\n", BUSY));
+    }
+}
@@ -1,18 +1,27 @@
+use command_args::NumKeys;
 use command_args_derive::CommandArgsBlock;
+use serde::Serialize;
 
-use crate::database::Database;
+use crate::{
+    byte_string::ByteString,
+    database::{Database, SetOperation},
+};
 
-use super::{CommandHandler, Error};
+use super::{
+    keyspace::{scan_count, ScanCount, ScanMatch},
+    CommandHandler, Error,
+};
 
 #[derive(Debug, CommandArgsBlock)]
 #[argtoken("SADD")]
 pub struct SaddCommand<'a> {
-    pub key: &'a str,
-    pub elements: Vec<&'a str>,
+    pub key: &'a [u8],
+    pub elements: Vec<&'a [u8]>,
 }
 
 impl<'a> CommandHandler for SaddCommand<'a> {
     type Output = usize;
+    const WRITE: bool = true;
 
     fn handle(self, db: &Database) -> Result<Self::Output, Error> {
         db.sadd(self.key, &self.elements)
@@ -22,13 +31,522 @@ impl<'a> CommandHandler for SaddCommand<'a> {
 #[derive(Debug, CommandArgsBlock)]
 #[argtoken("SMEMBERS")]
 pub struct SmembersCommand<'a> {
-    pub key: &'a str,
+    pub key: &'a [u8],
 }
 
 impl<'a> CommandHandler for SmembersCommand<'a> {
-    type Output = Option<Vec<String>>;
+    type Output = Option<Vec<ByteString>>;
 
     fn handle(self, db: &Database) -> Result<Self::Output, Error> {
         db.smembers(self.key)
     }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SREM")]
+pub struct SremCommand<'a> {
+    pub key: &'a [u8],
+    pub members: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SremCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.srem(self.key, &self.members)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SCARD")]
+pub struct ScardCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for ScardCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scard(self.key)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SISMEMBER")]
+pub struct SismemberCommand<'a> {
+    pub key: &'a [u8],
+    pub member: &'a [u8],
+}
+
+impl<'a> CommandHandler for SismemberCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let found = db.smismember(self.key, &[self.member])?;
+
+        Ok(found[0] as usize)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SMISMEMBER")]
+pub struct SmismemberCommand<'a> {
+    pub key: &'a [u8],
+    pub members: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SmismemberCommand<'a> {
+    type Output = Vec<usize>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let found = db.smismember(self.key, &self.members)?;
+
+        Ok(found.into_iter().map(|f| f as usize).collect())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+/// Reply of SPOP and SRANDMEMBER: a single member, or an array of members
+/// when `count` is given
+#[derive(Debug, PartialEq)]
+pub enum RandomMembers {
+    One(Option<ByteString>),
+    Many(Vec<ByteString>),
+}
+
+impl RandomMembers {
+    fn members(&self) -> &[ByteString] {
+        match self {
+            RandomMembers::One(member) => member.as_slice(),
+            RandomMembers::Many(members) => members,
+        }
+    }
+}
+
+impl Serialize for RandomMembers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            RandomMembers::One(member) => member.serialize(serializer),
+            RandomMembers::Many(members) => members.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SPOP")]
+pub struct SpopCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<usize>,
+}
+
+impl<'a> CommandHandler for SpopCommand<'a> {
+    type Output = RandomMembers;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        match self.count {
+            None => {
+                let popped = db.spop(self.key, 1)?;
+                Ok(RandomMembers::One(popped.into_iter().next()))
+            }
+            Some(count) => Ok(RandomMembers::Many(db.spop(self.key, count)?)),
+        }
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        // log the popped members, replaying SPOP would pop other members
+        let members = output.members();
+        if members.is_empty() {
+            return;
+        }
+        let key = args[1].clone();
+        args.clear();
+        args.push(b"SREM".to_vec());
+        args.push(key);
+        args.extend(members.iter().map(|m| m.to_vec()));
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SRANDMEMBER")]
+pub struct SrandmemberCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<i64>,
+}
+
+impl<'a> CommandHandler for SrandmemberCommand<'a> {
+    type Output = RandomMembers;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        match self.count {
+            None => {
+                let members = db.srandmember(self.key, 1)?;
+                Ok(RandomMembers::One(members.into_iter().next()))
+            }
+            Some(count) => Ok(RandomMembers::Many(db.srandmember(self.key, count)?)),
+        }
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SMOVE")]
+pub struct SmoveCommand<'a> {
+    pub source: &'a [u8],
+    pub destination: &'a [u8],
+    pub member: &'a [u8],
+}
+
+impl<'a> CommandHandler for SmoveCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let moved = db.smove(self.source, self.destination, self.member)?;
+
+        Ok(moved as usize)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SINTER")]
+pub struct SinterCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SinterCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scombine(&self.keys, SetOperation::Inter)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SINTERSTORE")]
+pub struct SinterstoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SinterstoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scombinestore(self.destination, &self.keys, SetOperation::Inter)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SUNION")]
+pub struct SunionCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SunionCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scombine(&self.keys, SetOperation::Union)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SUNIONSTORE")]
+pub struct SunionstoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SunionstoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scombinestore(self.destination, &self.keys, SetOperation::Union)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SDIFF")]
+pub struct SdiffCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SdiffCommand<'a> {
+    type Output = Vec<ByteString>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scombine(&self.keys, SetOperation::Diff)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SDIFFSTORE")]
+pub struct SdiffstoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for SdiffstoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.scombinestore(self.destination, &self.keys, SetOperation::Diff)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("LIMIT")]
+pub struct SintercardLimit {
+    pub limit: usize,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SINTERCARD")]
+pub struct SintercardCommand<'a> {
+    pub keys: NumKeys<&'a [u8]>,
+    pub limit: Option<SintercardLimit>,
+}
+
+impl<'a> CommandHandler for SintercardCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        if self.keys.0.is_empty() {
+            return Err(Error::Handle(
+                "ERR numkeys should be greater than 0".to_string(),
+            ));
+        }
+
+        db.sintercard(&self.keys.0, self.limit.map_or(0, |l| l.limit))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.0.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SSCAN")]
+pub struct SscanCommand<'a> {
+    pub key: &'a [u8],
+    pub cursor: usize,
+    pub pattern: Option<ScanMatch<'a>>,
+    pub count: Option<ScanCount>,
+}
+
+impl<'a> CommandHandler for SscanCommand<'a> {
+    /// Next cursor and the scanned members
+    type Output = (String, Vec<ByteString>);
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let (cursor, members) = db.sscan(
+            self.key,
+            self.cursor,
+            scan_count(self.count),
+            self.pattern.map(|m| m.pattern),
+        )?;
+
+        Ok((cursor.to_string(), members))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{run_commands, ClientState};
+
+    #[test]
+    fn test_set_commands() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["SADD", "s", "a", "b", "c"],
+                &["SREM", "s", "a", "x"],
+                &["SCARD", "s"],
+                &["SISMEMBER", "s", "b"],
+                &["SMISMEMBER", "s", "a", "b", "c"],
+                &["SMOVE", "s", "d", "b"],
+                &["SMOVE", "s", "d", "b"],
+                &["SMOVE", "s", "d", "c"],
+                &["SCARD", "s"],
+                &["SCARD", "d"],
+                &["SREM", "d", "b", "c"],
+                &["SMEMBERS", "d"],
+                &["SADD", "z", "a"],
+                &["SPOP", "z"],
+                &["SPOP", "z"],
+                &["SPOP", "z", "2"],
+                &["SRANDMEMBER", "z"],
+                &["SADD", "r", "a"],
+                &["SRANDMEMBER", "r", "-3"],
+                &["SSCAN", "r", "0", "MATCH", "b*"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                ":3\r\n:1\r\n:2\r\n:1\r\n*3\r\n:0\r\n:1\r\n:1\r\n",
+                ":1\r\n:0\r\n:1\r\n:0\r\n:2\r\n:2\r\n$-1\r\n",
+                ":1\r\n+a\r\n$-1\r\n*0\r\n$-1\r\n",
+                ":1\r\n*3\r\n+a\r\n+a\r\n+a\r\n*2\r\n+0\r\n*0\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        run_commands(
+            &db,
+            &mut client,
+            &[
+                &["SADD", "a", "1", "2", "3"],
+                &["SADD", "b", "2", "3", "4"],
+                &["SET", "str", "x"],
+            ],
+        );
+        let members = |operation, keys: &[&str]| {
+            let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+            let mut members = db.scombine(&keys, operation).unwrap();
+            members.sort();
+            members
+        };
+        assert_eq!(members(SetOperation::Inter, &["a", "b"]), ["2", "3"]);
+        assert!(members(SetOperation::Inter, &["a", "missing"]).is_empty());
+        assert_eq!(
+            members(SetOperation::Union, &["a", "b", "missing"]),
+            ["1", "2", "3", "4"]
+        );
+        assert_eq!(members(SetOperation::Diff, &["a", "b"]), ["1"]);
+        assert!(members(SetOperation::Diff, &["missing", "a"]).is_empty());
+
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["SUNIONSTORE", "c", "a", "b"],
+                &["SINTERCARD", "2", "a", "c"],
+                &["SINTERCARD", "2", "a", "c", "LIMIT", "1"],
+                &["SDIFFSTORE", "c", "a", "b"],
+                &["SMEMBERS", "c"],
+                &["SINTERSTORE", "c", "a", "missing"],
+                &["SCARD", "c"],
+                &["SINTER", "a", "str"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                ":4\r\n:3\r\n:1\r\n:1\r\n*1\r\n+1\r\n:0\r\n:0\r\n",
+                "-ERR key str is not set\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_random_members_extreme_counts() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["SADD", "s", "a", "b"],
+                &["SRANDMEMBER", "s", "9223372036854775807"],
+                &["SRANDMEMBER", "s", "-9223372036854775808"],
+                &["SPOP", "s", "9223372036854775807"],
+                &["SCARD", "s"],
+            ],
+        );
+        let replies: Vec<&str> = result.split("\r\n").collect();
+        assert_eq!(replies[1], "*2");
+        assert_eq!(replies[4], "-ERR value is out of range");
+        assert_eq!(replies[5], "*2");
+        assert_eq!(replies[8], ":0");
+    }
+
+    #[test]
+    fn test_sscan_cursor() {
+        let db = Database::new(String::new()).unwrap();
+        let ints: Vec<_> = (0..25).map(|i| i.to_string()).collect();
+        let strings: Vec<_> = (0..200).map(|i| format!("m{}", i)).collect();
+        for (key, members) in [(&b"ints"[..], ints), (&b"strings"[..], strings)] {
+            let args: Vec<&[u8]> = members.iter().map(|m| m.as_bytes()).collect();
+            db.sadd(key, &args).unwrap();
+
+            let mut scanned = Vec::new();
+            let mut cursor = 0;
+            loop {
+                let command = SscanCommand {
+                    key,
+                    cursor,
+                    pattern: None,
+                    count: Some(ScanCount { count: 10 }),
+                };
+                let (next, members) = command.handle(&db).unwrap();
+                assert!(members.len() < 20);
+                scanned.extend(members);
+                cursor = next.parse().unwrap();
+                if cursor == 0 {
+                    break;
+                }
+            }
+            scanned.sort();
+            let mut expected: Vec<ByteString> = members.iter().map(|m| m.as_str().into()).collect();
+            expected.sort();
+            assert_eq!(scanned, expected);
+        }
+    }
+
+    #[test]
+    fn test_spop_aof() {
+        let mut args = vec![b"SPOP".to_vec(), b"s".to_vec(), b"2".to_vec()];
+        SpopCommand::amend_aof(
+            &RandomMembers::Many(vec!["a".into(), "b".into()]),
+            &mut args,
+        );
+        assert_eq!(args, ["SREM", "s", "a", "b"].map(str::as_bytes));
+    }
 }
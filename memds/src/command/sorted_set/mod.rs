@@ -0,0 +1,1171 @@
+use command_args::{CommandArgs, NumKeys};
+use command_args_derive::CommandArgsBlock;
+use serde::{ser::SerializeSeq, Serialize};
+
+use crate::{
+    blocking::BlockedPop,
+    byte_string::{self, ByteString},
+    database::{Aggregate, Database, ScoreCondition, SetCondition, SetOperation, ZaddOptions},
+    memds::{LexBound, ScoreBound, ZRange},
+};
+
+use super::{
+    blocking::{self, BlockingReply},
+    ClientState, CommandHandler, Error,
+};
+
+/// Members of a sorted set, with their scores if `withscores` is true:
+/// as a flat array of members and scores with RESP2, or an array of pairs with RESP3
+#[derive(Debug, PartialEq)]
+pub struct ScoredMembers {
+    pub elements: Vec<(ByteString, f64)>,
+    pub withscores: bool,
+    pub resp3: bool,
+}
+
+impl ScoredMembers {
+    pub fn new(elements: Vec<(ByteString, f64)>, withscores: bool) -> Self {
+        ScoredMembers {
+            elements,
+            withscores,
+            resp3: false,
+        }
+    }
+}
+
+impl Serialize for ScoredMembers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let len = match (self.withscores, self.resp3) {
+            (true, false) => self.elements.len() * 2,
+            _ => self.elements.len(),
+        };
+        let mut seq = serializer.serialize_seq(Some(len))?;
+        for (member, score) in &self.elements {
+            match (self.withscores, self.resp3) {
+                (false, _) => seq.serialize_element(member)?,
+                (true, false) => {
+                    seq.serialize_element(member)?;
+                    seq.serialize_element(score)?;
+                }
+                (true, true) => seq.serialize_element(&(member, score))?,
+            }
+        }
+        seq.end()
+    }
+}
+
+/// Flag of ZADD, given in any order before the scores and members
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum ZaddFlag {
+    #[argtoken("NX")]
+    NotExistedOnly,
+    #[argtoken("XX")]
+    ExistedOnly,
+    #[argtoken("GT")]
+    Greater,
+    #[argtoken("LT")]
+    Less,
+    #[argtoken("CH")]
+    Changed,
+    #[argtoken("INCR")]
+    Incr,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub struct ScoreMember<'a> {
+    pub score: f64,
+    pub member: &'a [u8],
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZADD")]
+pub struct ZaddCommand<'a> {
+    pub key: &'a [u8],
+    pub flags: Vec<ZaddFlag>,
+    pub score_members: Vec<ScoreMember<'a>>,
+}
+
+/// Reply of ZADD: the number of added (or changed with `CH`) members,
+/// or the new score with `INCR`
+#[derive(Debug, PartialEq)]
+pub enum ZaddOutput {
+    Count(usize),
+    Score(Option<f64>),
+}
+
+impl Serialize for ZaddOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            ZaddOutput::Count(count) => count.serialize(serializer),
+            ZaddOutput::Score(score) => score.serialize(serializer),
+        }
+    }
+}
+
+impl<'a> CommandHandler for ZaddCommand<'a> {
+    type Output = ZaddOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let flag = |flag| self.flags.contains(&flag);
+        let (nx, xx) = (flag(ZaddFlag::NotExistedOnly), flag(ZaddFlag::ExistedOnly));
+        let (gt, lt) = (flag(ZaddFlag::Greater), flag(ZaddFlag::Less));
+        let (changed, incr) = (flag(ZaddFlag::Changed), flag(ZaddFlag::Incr));
+        if nx && xx {
+            return Err(Error::Handle(
+                "ERR XX and NX options at the same time are not compatible".to_string(),
+            ));
+        }
+        if nx && (gt || lt) || gt && lt {
+            return Err(Error::Handle(
+                "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+            ));
+        }
+        if incr && self.score_members.len() > 1 {
+            return Err(Error::Handle(
+                "ERR INCR option supports a single increment-element pair".to_string(),
+            ));
+        }
+
+        let options = ZaddOptions {
+            exists: match (nx, xx) {
+                (true, _) => SetCondition::NotExists,
+                (_, true) => SetCondition::Exists,
+                _ => SetCondition::Always,
+            },
+            score: match (gt, lt) {
+                (true, _) => ScoreCondition::Greater,
+                (_, true) => ScoreCondition::Less,
+                _ => ScoreCondition::Always,
+            },
+            incr,
+        };
+        let elements: Vec<_> = self
+            .score_members
+            .iter()
+            .map(|sm| (sm.score, sm.member))
+            .collect();
+        let outcome = db.zadd(self.key, &elements, options)?;
+
+        Ok(match (incr, changed) {
+            (true, _) => ZaddOutput::Score(outcome.score),
+            (false, true) => ZaddOutput::Count(outcome.added + outcome.updated),
+            (false, false) => ZaddOutput::Count(outcome.added),
+        })
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZINCRBY")]
+pub struct ZincrbyCommand<'a> {
+    pub key: &'a [u8],
+    pub increment: f64,
+    pub member: &'a [u8],
+}
+
+impl<'a> CommandHandler for ZincrbyCommand<'a> {
+    type Output = f64;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let options = ZaddOptions {
+            exists: SetCondition::Always,
+            score: ScoreCondition::Always,
+            incr: true,
+        };
+        let outcome = db.zadd(self.key, &[(self.increment, self.member)], options)?;
+
+        Ok(outcome.score.unwrap_or(self.increment))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZREM")]
+pub struct ZremCommand<'a> {
+    pub key: &'a [u8],
+    pub members: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for ZremCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.zrem(self.key, &self.members)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZSCORE")]
+pub struct ZscoreCommand<'a> {
+    pub key: &'a [u8],
+    pub member: &'a [u8],
+}
+
+impl<'a> CommandHandler for ZscoreCommand<'a> {
+    type Output = Option<f64>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.zscores(self.key, &[self.member])?.pop().flatten())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZMSCORE")]
+pub struct ZmscoreCommand<'a> {
+    pub key: &'a [u8],
+    pub members: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for ZmscoreCommand<'a> {
+    type Output = Vec<Option<f64>>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.zscores(self.key, &self.members)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZCARD")]
+pub struct ZcardCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for ZcardCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.zcard(self.key)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZCOUNT")]
+pub struct ZcountCommand<'a> {
+    pub key: &'a [u8],
+    pub min: &'a [u8],
+    pub max: &'a [u8],
+}
+
+impl<'a> CommandHandler for ZcountCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let range = ZRange::Score(ScoreBound::parse(self.min)?, ScoreBound::parse(self.max)?);
+
+        db.zcount(self.key, &range)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("WITHSCORE")]
+pub struct WithScore;
+
+/// Reply of ZRANK/ZREVRANK: the rank, or the rank and the score with `WITHSCORE`
+#[derive(Debug, PartialEq)]
+pub enum RankOutput {
+    Rank(Option<usize>),
+    WithScore(Option<(usize, f64)>),
+}
+
+impl Serialize for RankOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            RankOutput::Rank(rank) => rank.serialize(serializer),
+            RankOutput::WithScore(rank) => rank.serialize(serializer),
+        }
+    }
+}
+
+fn rank(
+    db: &Database,
+    key: &[u8],
+    member: &[u8],
+    rev: bool,
+    withscore: Option<WithScore>,
+) -> Result<RankOutput, Error> {
+    let rank = db.zrank(key, member, rev)?;
+
+    Ok(match withscore {
+        Some(WithScore) => RankOutput::WithScore(rank),
+        None => RankOutput::Rank(rank.map(|(rank, _)| rank)),
+    })
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZRANK")]
+pub struct ZrankCommand<'a> {
+    pub key: &'a [u8],
+    pub member: &'a [u8],
+    pub withscore: Option<WithScore>,
+}
+
+impl<'a> CommandHandler for ZrankCommand<'a> {
+    type Output = RankOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        rank(db, self.key, self.member, false, self.withscore)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZREVRANK")]
+pub struct ZrevrankCommand<'a> {
+    pub key: &'a [u8],
+    pub member: &'a [u8],
+    pub withscore: Option<WithScore>,
+}
+
+impl<'a> CommandHandler for ZrevrankCommand<'a> {
+    type Output = RankOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        rank(db, self.key, self.member, true, self.withscore)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum ZrangeBy {
+    #[argtoken("BYSCORE")]
+    Score,
+    #[argtoken("BYLEX")]
+    Lex,
+    #[argnotoken]
+    Rank,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("REV")]
+pub struct ZrangeRev;
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("LIMIT")]
+pub struct ZrangeLimit {
+    pub offset: i64,
+    pub count: i64,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("WITHSCORES")]
+pub struct WithScores;
+
+/// Range query of ZRANGE and ZRANGESTORE
+struct RangeQuery {
+    range: ZRange,
+    rev: bool,
+    offset: usize,
+    count: Option<usize>,
+}
+
+impl RangeQuery {
+    fn parse(
+        start: &[u8],
+        stop: &[u8],
+        by: ZrangeBy,
+        rev: Option<ZrangeRev>,
+        limit: Option<ZrangeLimit>,
+    ) -> Result<Self, Error> {
+        let rev = rev.is_some();
+        // with REV, the range of scores or members is given from max to min
+        let (min, max) = match (by, rev) {
+            (ZrangeBy::Rank, _) | (_, false) => (start, stop),
+            (_, true) => (stop, start),
+        };
+        let range = match by {
+            ZrangeBy::Rank => {
+                let parse = |s: &[u8]| {
+                    byte_string::parse::<i64>(s).ok_or_else(|| {
+                        Error::Handle("ERR value is not an integer or out of range".to_string())
+                    })
+                };
+                ZRange::Rank(parse(min)?, parse(max)?)
+            }
+            ZrangeBy::Score => ZRange::Score(ScoreBound::parse(min)?, ScoreBound::parse(max)?),
+            ZrangeBy::Lex => ZRange::Lex(LexBound::parse(min)?, LexBound::parse(max)?),
+        };
+
+        let (offset, count) = match limit {
+            Some(_) if by == ZrangeBy::Rank => {
+                return Err(Error::Handle(
+                    "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".to_string(),
+                ))
+            }
+            // a negative offset gives an empty range
+            Some(ZrangeLimit { offset, .. }) if offset < 0 => (0, Some(0)),
+            Some(ZrangeLimit { offset, count }) => {
+                (offset as usize, (count >= 0).then_some(count as usize))
+            }
+            None => (0, None),
+        };
+
+        Ok(RangeQuery {
+            range,
+            rev,
+            offset,
+            count,
+        })
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZRANGE")]
+pub struct ZrangeCommand<'a> {
+    pub key: &'a [u8],
+    pub start: &'a [u8],
+    pub stop: &'a [u8],
+    pub by: ZrangeBy,
+    pub rev: Option<ZrangeRev>,
+    pub limit: Option<ZrangeLimit>,
+    pub withscores: Option<WithScores>,
+}
+
+impl<'a> CommandHandler for ZrangeCommand<'a> {
+    type Output = ScoredMembers;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        if self.by == ZrangeBy::Lex && self.withscores.is_some() {
+            return Err(Error::Handle(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+            ));
+        }
+        let query = RangeQuery::parse(self.start, self.stop, self.by, self.rev, self.limit)?;
+        let elements = db.zrange(self.key, &query.range, query.rev, query.offset, query.count)?;
+
+        Ok(ScoredMembers::new(elements, self.withscores.is_some()))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        reply.resp3 = client.resp3();
+
+        Ok(reply)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZRANGESTORE")]
+pub struct ZrangestoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub source: &'a [u8],
+    pub start: &'a [u8],
+    pub stop: &'a [u8],
+    pub by: ZrangeBy,
+    pub rev: Option<ZrangeRev>,
+    pub limit: Option<ZrangeLimit>,
+}
+
+impl<'a> CommandHandler for ZrangestoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let query = RangeQuery::parse(self.start, self.stop, self.by, self.rev, self.limit)?;
+
+        db.zrangestore(
+            self.destination,
+            self.source,
+            &query.range,
+            query.rev,
+            query.offset,
+            query.count,
+        )
+    }
+}
+
+fn pop(
+    db: &Database,
+    key: &[u8],
+    count: Option<usize>,
+    max: bool,
+    client: &ClientState,
+) -> Result<ScoredMembers, Error> {
+    let elements = db.zpop(key, count.unwrap_or(1), max)?;
+    let mut reply = ScoredMembers::new(elements, true);
+    // a single popped member is always replied as a flat array
+    reply.resp3 = count.is_some() && client.resp3();
+
+    Ok(reply)
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZPOPMIN")]
+pub struct ZpopminCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<usize>,
+}
+
+impl<'a> CommandHandler for ZpopminCommand<'a> {
+    type Output = ScoredMembers;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        pop(db, self.key, self.count, false, client)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZPOPMAX")]
+pub struct ZpopmaxCommand<'a> {
+    pub key: &'a [u8],
+    pub count: Option<usize>,
+}
+
+impl<'a> CommandHandler for ZpopmaxCommand<'a> {
+    type Output = ScoredMembers;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        pop(db, self.key, self.count, true, client)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BZPOPMIN")]
+pub struct BzpopminCommand<'a> {
+    pub keys_and_timeout: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for BzpopminCommand<'a> {
+    type Output = BlockingReply;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let (keys, timeout) = blocking::split_timeout(&self.keys_and_timeout)?;
+        blocking::pop_or_block(
+            db,
+            client,
+            keys,
+            timeout,
+            BlockedPop::SortedSet { max: false },
+        )
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        output.amend_aof(args);
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(resp3, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("BZPOPMAX")]
+pub struct BzpopmaxCommand<'a> {
+    pub keys_and_timeout: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for BzpopmaxCommand<'a> {
+    type Output = BlockingReply;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.handle_client(db, &mut ClientState::default())
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let (keys, timeout) = blocking::split_timeout(&self.keys_and_timeout)?;
+        blocking::pop_or_block(
+            db,
+            client,
+            keys,
+            timeout,
+            BlockedPop::SortedSet { max: true },
+        )
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        output.amend_aof(args);
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.write(resp3, write_buf)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZREMRANGEBYRANK")]
+pub struct ZremrangebyrankCommand<'a> {
+    pub key: &'a [u8],
+    pub start: i64,
+    pub stop: i64,
+}
+
+impl<'a> CommandHandler for ZremrangebyrankCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.zremrange(self.key, &ZRange::Rank(self.start, self.stop))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZREMRANGEBYSCORE")]
+pub struct ZremrangebyscoreCommand<'a> {
+    pub key: &'a [u8],
+    pub min: &'a [u8],
+    pub max: &'a [u8],
+}
+
+impl<'a> CommandHandler for ZremrangebyscoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let range = ZRange::Score(ScoreBound::parse(self.min)?, ScoreBound::parse(self.max)?);
+
+        db.zremrange(self.key, &range)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZREMRANGEBYLEX")]
+pub struct ZremrangebylexCommand<'a> {
+    pub key: &'a [u8],
+    pub min: &'a [u8],
+    pub max: &'a [u8],
+}
+
+impl<'a> CommandHandler for ZremrangebylexCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let range = ZRange::Lex(LexBound::parse(self.min)?, LexBound::parse(self.max)?);
+
+        db.zremrange(self.key, &range)
+    }
+}
+
+/// `WEIGHTS weight [weight ...]`, with as many weights as input keys
+#[derive(Debug, PartialEq)]
+pub struct Weights(pub Vec<f64>);
+
+impl<'a> CommandArgs<'a> for Weights {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error> {
+        "WEIGHTS".encode(target)?;
+        self.0.encode(target)
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, command_args::Error> {
+        match args.first() {
+            Some(a) if a.eq_ignore_ascii_case(b"WEIGHTS") => *args = &args[1..],
+            _ => return Ok(None),
+        }
+
+        // the number of weights is only known from the keys, take all the numbers
+        let mut weights = Vec::new();
+        while let Some(weight) = args
+            .first()
+            .and_then(|a| byte_string::parse::<f64>(a))
+            .filter(|w| !w.is_nan())
+        {
+            weights.push(weight);
+            *args = &args[1..];
+        }
+
+        Ok(Some(Weights(weights)))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum AggregateFunction {
+    #[argtoken("SUM")]
+    Sum,
+    #[argtoken("MIN")]
+    Min,
+    #[argtoken("MAX")]
+    Max,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("AGGREGATE")]
+pub struct AggregateOption {
+    pub function: AggregateFunction,
+}
+
+/// Checks the arguments shared by the ZUNION, ZINTER and ZDIFF families
+fn check_combine_args(
+    name: &str,
+    keys: &NumKeys<&[u8]>,
+    weights: &Option<Weights>,
+) -> Result<(), Error> {
+    if keys.0.is_empty() {
+        return Err(Error::Handle(format!(
+            "ERR at least 1 input key is needed for '{}' command",
+            name
+        )));
+    }
+    if let Some(weights) = weights {
+        if weights.0.len() != keys.0.len() {
+            return Err(Error::Handle("ERR syntax error".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn aggregate(option: Option<AggregateOption>) -> Aggregate {
+    match option.map(|o| o.function) {
+        None | Some(AggregateFunction::Sum) => Aggregate::Sum,
+        Some(AggregateFunction::Min) => Aggregate::Min,
+        Some(AggregateFunction::Max) => Aggregate::Max,
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZUNION")]
+pub struct ZunionCommand<'a> {
+    pub keys: NumKeys<&'a [u8]>,
+    pub weights: Option<Weights>,
+    pub aggregate: Option<AggregateOption>,
+    pub withscores: Option<WithScores>,
+}
+
+impl<'a> CommandHandler for ZunionCommand<'a> {
+    type Output = ScoredMembers;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        check_combine_args("zunion", &self.keys, &self.weights)?;
+        let elements = db.zcombine(
+            &self.keys.0,
+            self.weights.as_ref().map(|w| &w.0[..]),
+            SetOperation::Union,
+            aggregate(self.aggregate),
+        )?;
+
+        Ok(ScoredMembers::new(elements, self.withscores.is_some()))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        reply.resp3 = client.resp3();
+
+        Ok(reply)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.0.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZUNIONSTORE")]
+pub struct ZunionstoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub keys: NumKeys<&'a [u8]>,
+    pub weights: Option<Weights>,
+    pub aggregate: Option<AggregateOption>,
+}
+
+impl<'a> CommandHandler for ZunionstoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        check_combine_args("zunionstore", &self.keys, &self.weights)?;
+
+        db.zcombinestore(
+            self.destination,
+            &self.keys.0,
+            self.weights.as_ref().map(|w| &w.0[..]),
+            SetOperation::Union,
+            aggregate(self.aggregate),
+        )
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZINTER")]
+pub struct ZinterCommand<'a> {
+    pub keys: NumKeys<&'a [u8]>,
+    pub weights: Option<Weights>,
+    pub aggregate: Option<AggregateOption>,
+    pub withscores: Option<WithScores>,
+}
+
+impl<'a> CommandHandler for ZinterCommand<'a> {
+    type Output = ScoredMembers;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        check_combine_args("zinter", &self.keys, &self.weights)?;
+        let elements = db.zcombine(
+            &self.keys.0,
+            self.weights.as_ref().map(|w| &w.0[..]),
+            SetOperation::Inter,
+            aggregate(self.aggregate),
+        )?;
+
+        Ok(ScoredMembers::new(elements, self.withscores.is_some()))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        reply.resp3 = client.resp3();
+
+        Ok(reply)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.0.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZINTERSTORE")]
+pub struct ZinterstoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub keys: NumKeys<&'a [u8]>,
+    pub weights: Option<Weights>,
+    pub aggregate: Option<AggregateOption>,
+}
+
+impl<'a> CommandHandler for ZinterstoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        check_combine_args("zinterstore", &self.keys, &self.weights)?;
+
+        db.zcombinestore(
+            self.destination,
+            &self.keys.0,
+            self.weights.as_ref().map(|w| &w.0[..]),
+            SetOperation::Inter,
+            aggregate(self.aggregate),
+        )
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZINTERCARD")]
+pub struct ZintercardCommand<'a> {
+    pub keys: NumKeys<&'a [u8]>,
+    pub limit: Option<ZintercardLimit>,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("LIMIT")]
+pub struct ZintercardLimit {
+    pub limit: usize,
+}
+
+impl<'a> CommandHandler for ZintercardCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        if self.keys.0.is_empty() {
+            return Err(Error::Handle(
+                "ERR numkeys should be greater than 0".to_string(),
+            ));
+        }
+
+        db.zintercard(&self.keys.0, self.limit.map_or(0, |l| l.limit))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.0.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZDIFF")]
+pub struct ZdiffCommand<'a> {
+    pub keys: NumKeys<&'a [u8]>,
+    pub withscores: Option<WithScores>,
+}
+
+impl<'a> CommandHandler for ZdiffCommand<'a> {
+    type Output = ScoredMembers;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        check_combine_args("zdiff", &self.keys, &None)?;
+        let elements = db.zcombine(&self.keys.0, None, SetOperation::Diff, Aggregate::Sum)?;
+
+        Ok(ScoredMembers::new(elements, self.withscores.is_some()))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        reply.resp3 = client.resp3();
+
+        Ok(reply)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.0.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("ZDIFFSTORE")]
+pub struct ZdiffstoreCommand<'a> {
+    pub destination: &'a [u8],
+    pub keys: NumKeys<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for ZdiffstoreCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        check_combine_args("zdiffstore", &self.keys, &None)?;
+
+        db.zcombinestore(
+            self.destination,
+            &self.keys.0,
+            None,
+            SetOperation::Diff,
+            Aggregate::Sum,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::run_commands;
+
+    #[test]
+    fn test_zadd_options() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["ZADD", "z", "1", "a", "2", "b"],
+                &["ZADD", "z", "NX", "5", "a", "3", "c"],
+                &["ZADD", "z", "XX", "CH", "5", "a", "4", "d"],
+                &["ZADD", "z", "GT", "CH", "1", "a", "6", "b"],
+                &["ZADD", "z", "LT", "INCR", "1", "a"],
+                &["ZADD", "z", "INCR", "-1.5", "a"],
+                &["ZADD", "z", "NX", "GT", "1", "a"],
+                &["ZADD", "z", "INCR", "1", "a", "2", "b"],
+                &["ZADD", "z", "XX", "NX", "1", "a"],
+                &["ZADD", "z", "GT", "LT", "1", "a"],
+                &["ZADD", "z", "CH", "XX", "7", "a"],
+                &["ZADD", "z", "INCR", "GT", "1", "b"],
+                &["ZINCRBY", "z", "10", "e"],
+                &["ZMSCORE", "z", "a", "b", "x"],
+                &["ZADD", "missing", "XX", "1", "a"],
+                &["ZCARD", "missing"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                ":2\r\n:1\r\n:1\r\n:1\r\n$-1\r\n$3\r\n3.5\r\n",
+                "-ERR GT, LT, and/or NX options at the same time are not compatible\r\n",
+                "-ERR INCR option supports a single increment-element pair\r\n",
+                "-ERR XX and NX options at the same time are not compatible\r\n",
+                "-ERR GT, LT, and/or NX options at the same time are not compatible\r\n",
+                ":1\r\n$1\r\n7\r\n",
+                "$2\r\n10\r\n*3\r\n$1\r\n7\r\n$1\r\n7\r\n$-1\r\n:0\r\n:0\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_zrange() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+                &["ZRANGE", "z", "0", "1", "WITHSCORES"],
+                &["ZRANGE", "z", "(1", "+inf", "BYSCORE", "LIMIT", "1", "2"],
+                &["ZRANGE", "z", "3", "-inf", "BYSCORE", "REV"],
+                &["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"],
+                &["ZRANGE", "z", "[b", "[c", "BYLEX"],
+                &["ZRANGESTORE", "dst", "z", "-2", "-1"],
+                &["ZCOUNT", "z", "2", "(4"],
+                &["ZRANK", "z", "c"],
+                &["ZREVRANK", "z", "c", "WITHSCORE"],
+                &["ZRANK", "z", "x"],
+                &["ZPOPMIN", "z"],
+                &["ZPOPMAX", "z", "2"],
+                &["ZREMRANGEBYSCORE", "dst", "-inf", "(4"],
+                &["ZRANGE", "dst", "0", "-1"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                ":4\r\n*4\r\n+a\r\n$1\r\n1\r\n+b\r\n$1\r\n2\r\n*2\r\n+c\r\n+d\r\n*3\r\n+c\r\n+b\r\n+a\r\n",
+                "-ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX\r\n",
+                "*2\r\n+b\r\n+c\r\n:2\r\n:2\r\n:2\r\n*2\r\n:1\r\n$1\r\n3\r\n$-1\r\n",
+                "*2\r\n+a\r\n$1\r\n1\r\n*4\r\n+d\r\n$1\r\n4\r\n+c\r\n$1\r\n3\r\n",
+                ":1\r\n*1\r\n+d\r\n",
+            )
+        );
+
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[&["HELLO", "3"], &["ZRANGE", "dst", "0", "-1", "WITHSCORES"]],
+        );
+        assert!(result.ends_with("*1\r\n*2\r\n+d\r\n,4\r\n"));
+    }
+
+    #[test]
+    fn test_bzpop() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["ZADD", "z", "1", "a", "2.5", "b", "3", "c"],
+                &["BZPOPMIN", "missing", "z", "0"],
+                &["BZPOPMAX", "z", "0.1"],
+                &["HELLO", "3"],
+                &["MULTI"],
+                &["BZPOPMIN", "missing", "0"],
+                &["EXEC"],
+            ],
+        );
+        assert!(
+            result.starts_with(":3\r\n*3\r\n+z\r\n+a\r\n$1\r\n1\r\n*3\r\n+z\r\n+c\r\n$1\r\n3\r\n")
+        );
+        assert!(result.ends_with("+OK\r\n+QUEUED\r\n*1\r\n_\r\n"));
+
+        let result = run_commands(&db, &mut client, &[&["BZPOPMIN", "z", "0"]]);
+        assert_eq!(result, "*3\r\n+z\r\n+b\r\n,2.5\r\n");
+        assert!(client.blocked.is_none());
+    }
+
+    #[test]
+    fn test_zremrange() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["ZADD", "z", "0", "a", "0", "b", "0", "c", "0", "d"],
+                &["ZREMRANGEBYLEX", "z", "(a", "[c"],
+                &["ZREMRANGEBYRANK", "z", "0", "0"],
+                &["ZRANGE", "z", "0", "-1"],
+                &["ZREMRANGEBYRANK", "z", "0", "-1"],
+                &["ZCARD", "z"],
+            ],
+        );
+        assert_eq!(result, ":4\r\n:2\r\n:1\r\n*1\r\n+d\r\n:1\r\n:0\r\n");
+    }
+
+    #[test]
+    fn test_zset_algebra() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["ZADD", "z1", "1", "a", "2", "b", "3", "c"],
+                &["ZADD", "z2", "10", "b", "20", "c", "30", "d"],
+                &["SADD", "s", "c", "e"],
+                &["ZUNION", "2", "z1", "z2", "WITHSCORES"],
+                &[
+                    "ZINTER",
+                    "3",
+                    "z1",
+                    "z2",
+                    "s",
+                    "WEIGHTS",
+                    "1",
+                    "2",
+                    "3",
+                    "AGGREGATE",
+                    "MAX",
+                    "WITHSCORES",
+                ],
+                &["ZDIFF", "2", "z1", "s"],
+                &["ZUNIONSTORE", "dst", "2", "z1", "s", "AGGREGATE", "MIN"],
+                &["ZRANGE", "dst", "0", "-1", "WITHSCORES"],
+                &["ZINTERSTORE", "dst", "2", "z1", "missing"],
+                &["ZCARD", "dst"],
+                &["ZDIFFSTORE", "dst", "1", "z2"],
+                &["ZINTERCARD", "2", "z1", "z2"],
+                &["ZINTERCARD", "2", "z1", "z2", "LIMIT", "1"],
+                &["ZUNION", "2", "z1", "z2", "WEIGHTS", "1"],
+                &["ZUNION", "0"],
+                &["ZINTERCARD", "0"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                ":3\r\n:3\r\n:2\r\n",
+                "*8\r\n+a\r\n$1\r\n1\r\n+b\r\n$2\r\n12\r\n+c\r\n$2\r\n23\r\n+d\r\n$2\r\n30\r\n",
+                "*2\r\n+c\r\n$2\r\n40\r\n",
+                "*2\r\n+a\r\n+b\r\n",
+                ":4\r\n*8\r\n+a\r\n$1\r\n1\r\n+c\r\n$1\r\n1\r\n+e\r\n$1\r\n1\r\n+b\r\n$1\r\n2\r\n",
+                ":0\r\n:0\r\n:3\r\n:2\r\n:1\r\n",
+                "-ERR syntax error\r\n",
+                "-ERR at least 1 input key is needed for 'zunion' command\r\n",
+                "-ERR numkeys should be greater than 0\r\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,1179 @@
+use command_args::CommandArgs;
+use command_args_derive::CommandArgsBlock;
+use deseresp::types::OkResponse;
+use serde::Serialize;
+
+use crate::{
+    byte_string::ByteString,
+    database::{now_ms, Database, GroupEntries, StreamStart},
+    memds::{invalid_id, ClaimOptions, NewStreamId, StreamDS, StreamFields, StreamId, StreamTrim},
+    resp::ArrayOrNull,
+};
+
+use super::{hash::FieldValue, ClientState, CommandHandler, Error, MapReply};
+
+/// Reply of an entry: its ID then its fields and values
+pub type EntryReply = (String, Vec<ByteString>);
+
+fn entry_reply((id, fields): (StreamId, StreamFields)) -> EntryReply {
+    let fields = fields
+        .into_iter()
+        .flat_map(|(field, value)| [field, value])
+        .collect();
+    (id.to_string(), fields)
+}
+
+/// Entries read per stream, a map with RESP3 and an array of key and entries pairs with RESP2
+#[derive(Debug, PartialEq)]
+pub struct StreamsReply<T> {
+    pub streams: Vec<(ByteString, Vec<T>)>,
+    pub resp3: bool,
+}
+
+impl<T: Serialize> Serialize for StreamsReply<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.resp3 {
+            MapReply {
+                entries: self.streams.iter().map(|(k, v)| (k, v)).collect(),
+                resp3: true,
+            }
+            .serialize(serializer)
+        } else {
+            self.streams.serialize(serializer)
+        }
+    }
+}
+
+fn parse_ids(ids: &[&str]) -> Result<Vec<StreamId>, Error> {
+    ids.iter().map(|id| StreamId::parse(id, 0)).collect()
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum TrimOperator {
+    #[argtoken("=")]
+    Exact,
+    #[argtoken("~")]
+    Approximate,
+    #[argnotoken]
+    Default,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("LIMIT")]
+pub struct TrimLimit {
+    pub count: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum TrimOption<'a> {
+    #[argtoken("MAXLEN")]
+    MaxLen {
+        operator: TrimOperator,
+        threshold: &'a str,
+        limit: Option<TrimLimit>,
+    },
+    #[argtoken("MINID")]
+    MinId {
+        operator: TrimOperator,
+        threshold: &'a str,
+        limit: Option<TrimLimit>,
+    },
+}
+
+impl<'a> TrimOption<'a> {
+    /// Parses the threshold, entries are always trimmed exactly,
+    /// `~` only allows to limit the number of removed entries
+    fn to_trim(self) -> Result<(StreamTrim, Option<usize>), Error> {
+        let (trim, operator, limit) = match self {
+            TrimOption::MaxLen {
+                operator,
+                threshold,
+                limit,
+            } => {
+                let max_len = threshold.parse().map_err(|_| {
+                    Error::Handle("ERR value is not an integer or out of range".to_string())
+                })?;
+                (StreamTrim::MaxLen(max_len), operator, limit)
+            }
+            TrimOption::MinId {
+                operator,
+                threshold,
+                limit,
+            } => (
+                StreamTrim::MinId(StreamId::parse(threshold, 0)?),
+                operator,
+                limit,
+            ),
+        };
+        if limit.is_some() && operator != TrimOperator::Approximate {
+            return Err(Error::Handle(
+                "ERR syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+            ));
+        }
+
+        Ok((trim, limit.map(|l| l.count)))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("NOMKSTREAM")]
+pub struct Nomkstream;
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XADD")]
+pub struct XaddCommand<'a> {
+    pub key: &'a [u8],
+    pub nomkstream: Option<Nomkstream>,
+    pub trim: Option<TrimOption<'a>>,
+    pub id: &'a str,
+    pub field_values: Vec<FieldValue<'a>>,
+}
+
+impl<'a> CommandHandler for XaddCommand<'a> {
+    type Output = Option<String>;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let id = NewStreamId::parse(self.id)?;
+        let trim = self.trim.map(TrimOption::to_trim).transpose()?;
+        let fields: Vec<_> = self
+            .field_values
+            .iter()
+            .map(|fv| (fv.field, fv.value))
+            .collect();
+        let id = db.xadd(self.key, id, &fields, self.nomkstream.is_some(), trim)?;
+
+        Ok(id.map(|id| id.to_string()))
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        // log the generated ID, found before the fields and values
+        let id = match output {
+            Some(id) => id,
+            None => return,
+        };
+        let index = {
+            let args_ref: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+            match XaddCommand::parse_maybe(&mut &args_ref[..]) {
+                Ok(Some(command)) => args.len() - command.field_values.len() * 2 - 1,
+                _ => return,
+            }
+        };
+        args[index] = id.clone().into_bytes();
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XLEN")]
+pub struct XlenCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for XlenCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.xlen(self.key)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("COUNT")]
+pub struct StreamCount {
+    pub count: usize,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XRANGE")]
+pub struct XrangeCommand<'a> {
+    pub key: &'a [u8],
+    pub start: &'a str,
+    pub end: &'a str,
+    pub count: Option<StreamCount>,
+}
+
+impl<'a> CommandHandler for XrangeCommand<'a> {
+    type Output = Vec<EntryReply>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let start = StreamId::parse_bound(self.start, true)?;
+        let end = StreamId::parse_bound(self.end, false)?;
+        let entries = db.xrange(self.key, start, end, false, self.count.map(|c| c.count))?;
+
+        Ok(entries.into_iter().map(entry_reply).collect())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XREVRANGE")]
+pub struct XrevrangeCommand<'a> {
+    pub key: &'a [u8],
+    pub end: &'a str,
+    pub start: &'a str,
+    pub count: Option<StreamCount>,
+}
+
+impl<'a> CommandHandler for XrevrangeCommand<'a> {
+    type Output = Vec<EntryReply>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let start = StreamId::parse_bound(self.start, true)?;
+        let end = StreamId::parse_bound(self.end, false)?;
+        let entries = db.xrange(self.key, start, end, true, self.count.map(|c| c.count))?;
+
+        Ok(entries.into_iter().map(entry_reply).collect())
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XDEL")]
+pub struct XdelCommand<'a> {
+    pub key: &'a [u8],
+    pub ids: Vec<&'a str>,
+}
+
+impl<'a> CommandHandler for XdelCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.xdel(self.key, &parse_ids(&self.ids)?)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XTRIM")]
+pub struct XtrimCommand<'a> {
+    pub key: &'a [u8],
+    pub trim: TrimOption<'a>,
+}
+
+impl<'a> CommandHandler for XtrimCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let (trim, limit) = self.trim.to_trim()?;
+
+        db.xtrim(self.key, trim, limit)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("ENTRIESADDED")]
+pub struct EntriesAdded {
+    pub entries_added: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("MAXDELETEDID")]
+pub struct MaxDeletedId<'a> {
+    pub id: &'a str,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XSETID")]
+pub struct XsetidCommand<'a> {
+    pub key: &'a [u8],
+    pub last_id: &'a str,
+    pub entries_added: Option<EntriesAdded>,
+    pub max_deleted_id: Option<MaxDeletedId<'a>>,
+}
+
+impl<'a> CommandHandler for XsetidCommand<'a> {
+    type Output = OkResponse;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let last_id = StreamId::parse(self.last_id, 0)?;
+        let max_deleted_id = self
+            .max_deleted_id
+            .map(|m| StreamId::parse(m.id, 0))
+            .transpose()?;
+        db.xsetid(
+            self.key,
+            last_id,
+            self.entries_added.map(|e| e.entries_added as u64),
+            max_deleted_id,
+        )?;
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("BLOCK")]
+pub struct StreamBlock {
+    pub milliseconds: usize,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("STREAMS")]
+pub struct Streams<'a> {
+    pub keys_and_ids: Vec<&'a [u8]>,
+}
+
+impl<'a> Streams<'a> {
+    /// Splits the keys and their start IDs, `parse` parses the IDs
+    fn parse<F>(&self, command: &str, parse: F) -> Result<Vec<(&'a [u8], StreamStart)>, Error>
+    where
+        F: Fn(&str) -> Result<StreamStart, Error>,
+    {
+        if !self.keys_and_ids.len().is_multiple_of(2) {
+            return Err(Error::Handle(format!(
+                "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+                command
+            )));
+        }
+        let (keys, ids) = self.keys_and_ids.split_at(self.keys_and_ids.len() / 2);
+        keys.iter()
+            .zip(ids)
+            .map(|(key, id)| {
+                let id = std::str::from_utf8(id).map_err(|_| invalid_id())?;
+                Ok((*key, parse(id)?))
+            })
+            .collect()
+    }
+}
+
+fn no_block(block: Option<StreamBlock>) -> Result<(), Error> {
+    match block {
+        Some(_) => Err(Error::Handle("ERR BLOCK is not supported".to_string())),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XREAD")]
+pub struct XreadCommand<'a> {
+    pub count: Option<StreamCount>,
+    pub block: Option<StreamBlock>,
+    pub streams: Streams<'a>,
+}
+
+impl<'a> CommandHandler for XreadCommand<'a> {
+    type Output = ArrayOrNull<StreamsReply<EntryReply>>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        no_block(self.block)?;
+        let streams = self.streams.parse("xread", |id| match id {
+            "$" => Ok(StreamStart::Last),
+            id => Ok(StreamStart::After(StreamId::parse(id, 0)?)),
+        })?;
+        let result = db.xread(&streams, self.count.map(|c| c.count))?;
+        if result.is_empty() {
+            return Ok(ArrayOrNull(None));
+        }
+
+        Ok(ArrayOrNull(Some(StreamsReply {
+            streams: result
+                .into_iter()
+                .map(|(key, entries)| (key, entries.into_iter().map(entry_reply).collect()))
+                .collect(),
+            resp3: false,
+        })))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        if let Some(reply) = &mut reply.0 {
+            reply.resp3 = client.resp3();
+        }
+
+        Ok(reply)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("GROUP")]
+pub struct GroupConsumer<'a> {
+    pub group: &'a str,
+    pub consumer: &'a str,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("NOACK")]
+pub struct Noack;
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XREADGROUP")]
+pub struct XreadgroupCommand<'a> {
+    pub group: GroupConsumer<'a>,
+    pub count: Option<StreamCount>,
+    pub block: Option<StreamBlock>,
+    pub noack: Option<Noack>,
+    pub streams: Streams<'a>,
+}
+
+/// Reply of an entry read by a group, without fields if it was deleted
+pub type GroupEntryReply = (String, ArrayOrNull<Vec<ByteString>>);
+
+fn group_entries_reply(entries: GroupEntries) -> Vec<GroupEntryReply> {
+    entries
+        .into_iter()
+        .map(|(id, fields)| match fields {
+            Some(fields) => {
+                let (id, fields) = entry_reply((id, fields));
+                (id, ArrayOrNull(Some(fields)))
+            }
+            None => (id.to_string(), ArrayOrNull(None)),
+        })
+        .collect()
+}
+
+impl<'a> CommandHandler for XreadgroupCommand<'a> {
+    type Output = ArrayOrNull<StreamsReply<GroupEntryReply>>;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        no_block(self.block)?;
+        let streams = self.streams.parse("xreadgroup", |id| match id {
+            ">" => Ok(StreamStart::Undelivered),
+            id => Ok(StreamStart::After(StreamId::parse(id, 0)?)),
+        })?;
+        let result = db.xreadgroup(
+            self.group.group,
+            self.group.consumer,
+            &streams,
+            self.count.map(|c| c.count),
+            self.noack.is_some(),
+        )?;
+        if result.is_empty() {
+            return Ok(ArrayOrNull(None));
+        }
+
+        Ok(ArrayOrNull(Some(StreamsReply {
+            streams: result
+                .into_iter()
+                .map(|(key, entries)| (key, group_entries_reply(entries)))
+                .collect(),
+            resp3: false,
+        })))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        if let Some(reply) = &mut reply.0 {
+            reply.resp3 = client.resp3();
+        }
+
+        Ok(reply)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("MKSTREAM")]
+pub struct Mkstream;
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("ENTRIESREAD")]
+pub struct EntriesRead {
+    pub entries_read: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum XgroupSubcommand<'a> {
+    #[argtoken("CREATE")]
+    Create {
+        key: &'a [u8],
+        group: &'a str,
+        id: &'a str,
+        mkstream: Option<Mkstream>,
+        entries_read: Option<EntriesRead>,
+    },
+    #[argtoken("SETID")]
+    SetId {
+        key: &'a [u8],
+        group: &'a str,
+        id: &'a str,
+        entries_read: Option<EntriesRead>,
+    },
+    #[argtoken("DESTROY")]
+    Destroy { key: &'a [u8], group: &'a str },
+    #[argtoken("CREATECONSUMER")]
+    CreateConsumer {
+        key: &'a [u8],
+        group: &'a str,
+        consumer: &'a str,
+    },
+    #[argtoken("DELCONSUMER")]
+    DelConsumer {
+        key: &'a [u8],
+        group: &'a str,
+        consumer: &'a str,
+    },
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XGROUP")]
+pub struct XgroupCommand<'a> {
+    pub subcommand: XgroupSubcommand<'a>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum XgroupOutput {
+    Ok,
+    Count(usize),
+}
+
+impl Serialize for XgroupOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            XgroupOutput::Ok => OkResponse.serialize(serializer),
+            XgroupOutput::Count(count) => count.serialize(serializer),
+        }
+    }
+}
+
+/// Parses the ID of a group, `$` for the last ID of the stream
+fn parse_group_id(id: &str) -> Result<Option<StreamId>, Error> {
+    match id {
+        "$" => Ok(None),
+        id => StreamId::parse(id, 0).map(Some),
+    }
+}
+
+impl<'a> CommandHandler for XgroupCommand<'a> {
+    type Output = XgroupOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        match self.subcommand {
+            XgroupSubcommand::Create {
+                key,
+                group,
+                id,
+                mkstream,
+                entries_read,
+            } => {
+                db.xgroup_create(
+                    key,
+                    group,
+                    parse_group_id(id)?,
+                    mkstream.is_some(),
+                    entries_read.map(|e| e.entries_read as u64),
+                )?;
+                Ok(XgroupOutput::Ok)
+            }
+            XgroupSubcommand::SetId {
+                key,
+                group,
+                id,
+                entries_read,
+            } => {
+                db.xgroup_setid(
+                    key,
+                    group,
+                    parse_group_id(id)?,
+                    entries_read.map(|e| e.entries_read as u64),
+                )?;
+                Ok(XgroupOutput::Ok)
+            }
+            XgroupSubcommand::Destroy { key, group } => {
+                let destroyed = db.xgroup_destroy(key, group)?;
+                Ok(XgroupOutput::Count(destroyed as usize))
+            }
+            XgroupSubcommand::CreateConsumer {
+                key,
+                group,
+                consumer,
+            } => {
+                let created = db.xgroup_createconsumer(key, group, consumer)?;
+                Ok(XgroupOutput::Count(created as usize))
+            }
+            XgroupSubcommand::DelConsumer {
+                key,
+                group,
+                consumer,
+            } => Ok(XgroupOutput::Count(
+                db.xgroup_delconsumer(key, group, consumer)?,
+            )),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XACK")]
+pub struct XackCommand<'a> {
+    pub key: &'a [u8],
+    pub group: &'a str,
+    pub ids: Vec<&'a str>,
+}
+
+impl<'a> CommandHandler for XackCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.xack(self.key, self.group, &parse_ids(&self.ids)?)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("IDLE")]
+pub struct MinIdle {
+    pub milliseconds: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub struct XpendingRange<'a> {
+    pub idle: Option<MinIdle>,
+    pub start: &'a str,
+    pub end: &'a str,
+    pub count: usize,
+    pub consumer: Option<&'a str>,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XPENDING")]
+pub struct XpendingCommand<'a> {
+    pub key: &'a [u8],
+    pub group: &'a str,
+    pub range: Option<XpendingRange<'a>>,
+}
+
+/// Reply of XPENDING: the number of pending entries, the lowest and highest pending IDs
+/// and the number of pending entries per consumer, or the pending entries in a range with
+/// their consumer, idle time and number of deliveries
+#[derive(Debug, PartialEq)]
+pub enum XpendingOutput {
+    Summary(
+        usize,
+        Option<String>,
+        Option<String>,
+        Option<Vec<(String, String)>>,
+    ),
+    Entries(Vec<(String, String, u64, u64)>),
+}
+
+impl Serialize for XpendingOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            XpendingOutput::Summary(count, min, max, consumers) => {
+                (count, min, max, ArrayOrNull(consumers.as_ref())).serialize(serializer)
+            }
+            XpendingOutput::Entries(entries) => entries.serialize(serializer),
+        }
+    }
+}
+
+impl<'a> CommandHandler for XpendingCommand<'a> {
+    type Output = XpendingOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let now = now_ms();
+        match self.range {
+            None => db.inspect_group(self.key, self.group, |_, group| {
+                let ids = |id: Option<&StreamId>| id.map(StreamId::to_string);
+                let consumers: Vec<_> = group
+                    .pending_by_consumer()
+                    .into_iter()
+                    .map(|(consumer, count)| (consumer.clone(), count.to_string()))
+                    .collect();
+                XpendingOutput::Summary(
+                    group.pending.len(),
+                    ids(group.pending.keys().next()),
+                    ids(group.pending.keys().next_back()),
+                    (!consumers.is_empty()).then_some(consumers),
+                )
+            }),
+            Some(range) => {
+                let start = StreamId::parse_bound(range.start, true)?;
+                let end = StreamId::parse_bound(range.end, false)?;
+                let min_idle = range.idle.map_or(0, |i| i.milliseconds as u64);
+                db.inspect_group(self.key, self.group, |_, group| {
+                    let entries = group
+                        .pending_range(start, end, range.count, range.consumer, min_idle, now)
+                        .into_iter()
+                        .map(|(id, entry)| {
+                            (
+                                id.to_string(),
+                                entry.consumer.clone(),
+                                now.saturating_sub(entry.delivery_time),
+                                entry.delivery_count,
+                            )
+                        })
+                        .collect();
+                    XpendingOutput::Entries(entries)
+                })
+            }
+        }
+    }
+}
+
+/// A stream ID in a list of IDs followed by options, the list ends at the first argument
+/// that is not an ID
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdArg(pub StreamId);
+
+impl<'a> CommandArgs<'a> for IdArg {
+    fn encode(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error> {
+        target.push(self.0.to_string().into_bytes());
+        Ok(())
+    }
+
+    fn parse_maybe(args: &mut &[&'a [u8]]) -> Result<Option<Self>, command_args::Error> {
+        let id = args
+            .first()
+            .and_then(|a| std::str::from_utf8(a).ok())
+            .map(|a| StreamId::parse(a, 0));
+        match id {
+            Some(Ok(id)) => {
+                *args = &args[1..];
+                Ok(Some(IdArg(id)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("TIME")]
+pub struct ClaimTime {
+    pub unix_time_milliseconds: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("RETRYCOUNT")]
+pub struct RetryCount {
+    pub count: usize,
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("FORCE")]
+pub struct Force;
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("JUSTID")]
+pub struct Justid;
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+#[argtoken("LASTID")]
+pub struct LastId<'a> {
+    pub id: &'a str,
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XCLAIM")]
+pub struct XclaimCommand<'a> {
+    pub key: &'a [u8],
+    pub group: &'a str,
+    pub consumer: &'a str,
+    pub min_idle_time: usize,
+    pub ids: Vec<IdArg>,
+    pub idle: Option<MinIdle>,
+    pub time: Option<ClaimTime>,
+    pub retry_count: Option<RetryCount>,
+    pub force: Option<Force>,
+    pub justid: Option<Justid>,
+    pub last_id: Option<LastId<'a>>,
+}
+
+/// Claimed entries, or their IDs only with `JUSTID`
+#[derive(Debug, PartialEq)]
+pub enum ClaimedOutput {
+    Entries(Vec<EntryReply>),
+    Ids(Vec<String>),
+}
+
+impl ClaimedOutput {
+    fn new(entries: Vec<(StreamId, StreamFields)>, justid: bool) -> Self {
+        if justid {
+            ClaimedOutput::Ids(entries.into_iter().map(|(id, _)| id.to_string()).collect())
+        } else {
+            ClaimedOutput::Entries(entries.into_iter().map(entry_reply).collect())
+        }
+    }
+}
+
+impl Serialize for ClaimedOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            ClaimedOutput::Entries(entries) => entries.serialize(serializer),
+            ClaimedOutput::Ids(ids) => ids.serialize(serializer),
+        }
+    }
+}
+
+impl<'a> CommandHandler for XclaimCommand<'a> {
+    type Output = ClaimedOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        if self.ids.is_empty() {
+            return Err(Error::Handle(
+                "ERR Invalid stream ID specified as stream command argument".to_string(),
+            ));
+        }
+        let options = ClaimOptions {
+            idle: self.idle.map(|i| i.milliseconds as u64),
+            time: self.time.map(|t| t.unix_time_milliseconds as u64),
+            retry_count: self.retry_count.map(|r| r.count as u64),
+            force: self.force.is_some(),
+            justid: self.justid.is_some(),
+            last_id: self.last_id.map(|l| StreamId::parse(l.id, 0)).transpose()?,
+        };
+        let ids: Vec<StreamId> = self.ids.iter().map(|id| id.0).collect();
+        let claimed = db.xclaim(
+            self.key,
+            self.group,
+            self.consumer,
+            self.min_idle_time as u64,
+            &ids,
+            &options,
+        )?;
+
+        Ok(ClaimedOutput::new(claimed, options.justid))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XAUTOCLAIM")]
+pub struct XautoclaimCommand<'a> {
+    pub key: &'a [u8],
+    pub group: &'a str,
+    pub consumer: &'a str,
+    pub min_idle_time: usize,
+    pub start: &'a str,
+    pub count: Option<StreamCount>,
+    pub justid: Option<Justid>,
+}
+
+impl<'a> CommandHandler for XautoclaimCommand<'a> {
+    type Output = (String, ClaimedOutput, Vec<String>);
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let start = StreamId::parse(self.start, 0)?;
+        let count = self.count.map_or(100, |c| c.count);
+        if count == 0 {
+            return Err(Error::Handle("ERR COUNT must be > 0".to_string()));
+        }
+        let (next, claimed, deleted) = db.xautoclaim(
+            self.key,
+            self.group,
+            self.consumer,
+            self.min_idle_time as u64,
+            start,
+            count,
+            self.justid.is_some(),
+        )?;
+
+        Ok((
+            next.to_string(),
+            ClaimedOutput::new(claimed, self.justid.is_some()),
+            deleted.iter().map(StreamId::to_string).collect(),
+        ))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock, PartialEq, Clone, Copy)]
+pub enum XinfoSubcommand<'a> {
+    #[argtoken("STREAM")]
+    Stream { key: &'a [u8] },
+    #[argtoken("GROUPS")]
+    Groups { key: &'a [u8] },
+    #[argtoken("CONSUMERS")]
+    Consumers { key: &'a [u8], group: &'a str },
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("XINFO")]
+pub struct XinfoCommand<'a> {
+    pub subcommand: XinfoSubcommand<'a>,
+}
+
+/// Value of an XINFO reply field
+#[derive(Debug, PartialEq)]
+pub enum InfoValue {
+    Int(i64),
+    /// An integer that may be unknown
+    MaybeInt(Option<u64>),
+    Str(String),
+    Entry(Option<EntryReply>),
+}
+
+impl Serialize for InfoValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            InfoValue::Int(i) => i.serialize(serializer),
+            InfoValue::MaybeInt(i) => i.serialize(serializer),
+            InfoValue::Str(s) => s.serialize(serializer),
+            InfoValue::Entry(entry) => entry.serialize(serializer),
+        }
+    }
+}
+
+pub type InfoReply = MapReply<&'static str, InfoValue>;
+
+/// Reply of XINFO: a map for STREAM, a list of maps for GROUPS and CONSUMERS
+#[derive(Debug, PartialEq)]
+pub enum XinfoOutput {
+    Stream(InfoReply),
+    List(Vec<InfoReply>),
+}
+
+impl XinfoOutput {
+    fn set_resp3(&mut self, resp3: bool) {
+        match self {
+            XinfoOutput::Stream(reply) => reply.resp3 = resp3,
+            XinfoOutput::List(replies) => replies.iter_mut().for_each(|r| r.resp3 = resp3),
+        }
+    }
+}
+
+impl Serialize for XinfoOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            XinfoOutput::Stream(reply) => reply.serialize(serializer),
+            XinfoOutput::List(replies) => replies.serialize(serializer),
+        }
+    }
+}
+
+fn stream_info(stream: &StreamDS) -> InfoReply {
+    let entry = |entry: Option<(&StreamId, &StreamFields)>| {
+        InfoValue::Entry(entry.map(|(id, fields)| entry_reply((*id, fields.clone()))))
+    };
+    let first_id = stream
+        .first_entry()
+        .map_or(StreamId::MIN, |(id, _)| *id)
+        .to_string();
+
+    MapReply::new(vec![
+        ("length", InfoValue::Int(stream.len() as i64)),
+        (
+            "last-generated-id",
+            InfoValue::Str(stream.last_id().to_string()),
+        ),
+        (
+            "max-deleted-entry-id",
+            InfoValue::Str(stream.max_deleted_id().to_string()),
+        ),
+        (
+            "entries-added",
+            InfoValue::Int(stream.entries_added() as i64),
+        ),
+        ("recorded-first-entry-id", InfoValue::Str(first_id)),
+        ("groups", InfoValue::Int(stream.groups().count() as i64)),
+        ("first-entry", entry(stream.first_entry())),
+        ("last-entry", entry(stream.last_entry())),
+    ])
+}
+
+fn groups_info(stream: &StreamDS) -> Vec<InfoReply> {
+    stream
+        .groups()
+        .map(|(name, group)| {
+            MapReply::new(vec![
+                ("name", InfoValue::Str(name.clone())),
+                ("consumers", InfoValue::Int(group.consumers.len() as i64)),
+                ("pending", InfoValue::Int(group.pending.len() as i64)),
+                (
+                    "last-delivered-id",
+                    InfoValue::Str(group.last_delivered.to_string()),
+                ),
+                ("entries-read", InfoValue::MaybeInt(group.entries_read)),
+                ("lag", InfoValue::MaybeInt(stream.lag(group))),
+            ])
+        })
+        .collect()
+}
+
+impl<'a> CommandHandler for XinfoCommand<'a> {
+    type Output = XinfoOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let no_key = || Error::Handle("ERR no such key".to_string());
+        match self.subcommand {
+            XinfoSubcommand::Stream { key } => db
+                .inspect_stream(key, stream_info)?
+                .map(XinfoOutput::Stream)
+                .ok_or_else(no_key),
+            XinfoSubcommand::Groups { key } => db
+                .inspect_stream(key, groups_info)?
+                .map(XinfoOutput::List)
+                .ok_or_else(no_key),
+            XinfoSubcommand::Consumers { key, group } => {
+                db.xlen(key)?;
+                let now = now_ms();
+                db.inspect_group(key, group, |_, group| {
+                    let pending = group.pending_by_consumer();
+                    let consumers = group
+                        .consumers
+                        .iter()
+                        .map(|(name, consumer)| {
+                            let inactive = consumer
+                                .active_time
+                                .map_or(-1, |time| now.saturating_sub(time) as i64);
+                            MapReply::new(vec![
+                                ("name", InfoValue::Str(name.clone())),
+                                (
+                                    "pending",
+                                    InfoValue::Int(*pending.get(name).unwrap_or(&0) as i64),
+                                ),
+                                (
+                                    "idle",
+                                    InfoValue::Int(now.saturating_sub(consumer.seen_time) as i64),
+                                ),
+                                ("inactive", InfoValue::Int(inactive)),
+                            ])
+                        })
+                        .collect();
+                    XinfoOutput::List(consumers)
+                })
+            }
+        }
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        reply.set_resp3(client.resp3());
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::run_commands;
+
+    #[test]
+    fn test_xadd_xrange() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["XADD", "s", "1-1", "a", "1"],
+                &["XADD", "s", "1-*", "b", "2"],
+                &["XADD", "s", "1-1", "c", "3"],
+                &["XADD", "s", "MAXLEN", "=", "2", "3", "c", "3"],
+                &["XADD", "missing", "NOMKSTREAM", "*", "a", "1"],
+                &["XADD", "s", "MAXLEN", "2", "LIMIT", "1", "4", "d", "4"],
+                &["XLEN", "s"],
+                &["XRANGE", "s", "-", "+"],
+                &["XREVRANGE", "s", "+", "(1-2", "COUNT", "1"],
+                &["XDEL", "s", "1-2", "9-9"],
+                &["XTRIM", "s", "MINID", "4"],
+                &["XRANGE", "s", "-", "+"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "+1-1\r\n+1-2\r\n",
+                "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n",
+                "+3-0\r\n$-1\r\n",
+                "-ERR syntax error, LIMIT cannot be used without the special ~ option\r\n",
+                ":2\r\n",
+                "*2\r\n*2\r\n+1-2\r\n*2\r\n+b\r\n+2\r\n*2\r\n+3-0\r\n*2\r\n+c\r\n+3\r\n",
+                "*1\r\n*2\r\n+3-0\r\n*2\r\n+c\r\n+3\r\n",
+                ":1\r\n:1\r\n*0\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_xadd_aof_id() {
+        let mut args: Vec<Vec<u8>> = ["XADD", "s", "MAXLEN", "~", "10", "*", "a", "1", "b", "2"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        XaddCommand::amend_aof(&Some("5-0".to_string()), &mut args);
+        assert_eq!(
+            args,
+            ["XADD", "s", "MAXLEN", "~", "10", "5-0", "a", "1", "b", "2"].map(str::as_bytes)
+        );
+    }
+
+    #[test]
+    fn test_consumer_group() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["XGROUP", "CREATE", "s", "g", "$"],
+                &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"],
+                &["XGROUP", "CREATE", "s", "g", "0"],
+                &["XADD", "s", "1-0", "a", "1"],
+                &["XADD", "s", "2-0", "b", "2"],
+                &["XREAD", "STREAMS", "s", "1-0"],
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    "alice",
+                    "COUNT",
+                    "1",
+                    "STREAMS",
+                    "s",
+                    ">",
+                ],
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"],
+                &["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"],
+                &["XPENDING", "s", "g"],
+                &["XACK", "s", "g", "1-0", "3-0"],
+                &["XCLAIM", "s", "g", "alice", "0", "2-0", "JUSTID"],
+                &["XPENDING", "s", "g", "-", "+", "10", "bob"],
+                &["XGROUP", "DELCONSUMER", "s", "g", "alice"],
+                &["XREADGROUP", "GROUP", "x", "c", "STREAMS", "s", ">"],
+                &["XINFO", "STREAM", "missing"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "-ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.\r\n",
+                "+OK\r\n",
+                "-BUSYGROUP Consumer Group name already exists\r\n",
+                "+1-0\r\n+2-0\r\n",
+                "*1\r\n*2\r\n+s\r\n*1\r\n*2\r\n+2-0\r\n*2\r\n+b\r\n+2\r\n",
+                "*1\r\n*2\r\n+s\r\n*1\r\n*2\r\n+1-0\r\n*2\r\n+a\r\n+1\r\n",
+                "*1\r\n*2\r\n+s\r\n*1\r\n*2\r\n+2-0\r\n*2\r\n+b\r\n+2\r\n",
+                "*-1\r\n",
+                "*4\r\n:2\r\n+1-0\r\n+2-0\r\n*2\r\n*2\r\n+alice\r\n+1\r\n*2\r\n+bob\r\n+1\r\n",
+                ":1\r\n",
+                "*1\r\n+2-0\r\n",
+                "*0\r\n",
+                ":1\r\n",
+                "-NOGROUP No such key 's' or consumer group 'x' in XREADGROUP with GROUP option\r\n",
+                "-ERR no such key\r\n",
+            )
+        );
+    }
+}
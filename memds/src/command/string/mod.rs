@@ -1,18 +1,25 @@
 use command_args_derive::CommandArgsBlock;
 use deseresp::types::OkResponse;
+use serde::Serialize;
 
-use crate::database::Database;
+use crate::{
+    byte_string::ByteString,
+    database::{now_ms, Database, SetCondition, SetExpiry},
+    memds::LcsMatch,
+};
+use command_args::CommandArgs;
 
-use super::{CommandHandler, Error};
+use super::{ClientState, CommandHandler, Error, MapReply};
 
 #[derive(Debug, CommandArgsBlock)]
 #[argtoken("INCR")]
 pub struct IncrCommand<'a> {
-    pub key: &'a str,
+    pub key: &'a [u8],
 }
 
 impl<'a> CommandHandler for IncrCommand<'a> {
     type Output = i64;
+    const WRITE: bool = true;
 
     fn handle(self, db: &Database) -> Result<Self::Output, Error> {
         db.incr(self.key)
@@ -22,28 +29,32 @@ impl<'a> CommandHandler for IncrCommand<'a> {
 #[derive(Debug, CommandArgsBlock)]
 #[argtoken("GET")]
 pub struct GetCommand<'a> {
-    pub key: &'a str,
+    pub key: &'a [u8],
 }
 
 impl<'a> CommandHandler for GetCommand<'a> {
-    type Output = Option<String>;
+    type Output = Option<ByteString>;
 
     fn handle(self, db: &Database) -> Result<Self::Output, Error> {
         db.get(self.key)
     }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
 }
 
 #[derive(CommandArgsBlock, Debug, PartialEq)]
 #[argtoken("SET")]
 pub struct SetCommand<'a> {
-    pub key: &'a str,
-    pub value: &'a str,
+    pub key: &'a [u8],
+    pub value: &'a [u8],
     pub exists: Exists,
     pub get: Option<SetGet>,
     pub expire: Option<ExpireOption>,
 }
 
-#[derive(CommandArgsBlock, Debug, PartialEq)]
+#[derive(CommandArgsBlock, Debug, PartialEq, Clone, Copy)]
 pub enum Exists {
     #[argtoken("NX")]
     NotExistedOnly,
@@ -53,7 +64,7 @@ pub enum Exists {
     Any,
 }
 
-#[derive(CommandArgsBlock, Debug, PartialEq)]
+#[derive(CommandArgsBlock, Debug, PartialEq, Clone, Copy)]
 #[argtoken("GET")]
 pub struct SetGet;
 
@@ -70,37 +81,701 @@ pub enum ExpireOption {
     KeepTTL,
 }
 
+impl ExpireOption {
+    /// Converts to the database expiry, relative times are based on `now` in ms
+    fn to_expiry(&self, now: u64) -> Result<SetExpiry, Error> {
+        self.to_expiry_for(now, "set")
+    }
+
+    /// Converts to the database expiry, `command` is named in the error of invalid times
+    fn to_expiry_for(&self, now: u64, command: &str) -> Result<SetExpiry, Error> {
+        let (base, ms) = match *self {
+            ExpireOption::ExpireAfterSecond(s) => (now, (s as u64).checked_mul(1000)),
+            ExpireOption::ExpireAfterMs(ms) => (now, Some(ms as u64)),
+            ExpireOption::ExpireAtSecond(s) => (0, (s as u64).checked_mul(1000)),
+            ExpireOption::ExpireAtMs(ms) => (0, Some(ms as u64)),
+            ExpireOption::KeepTTL => return Ok(SetExpiry::Keep),
+        };
+
+        match ms.filter(|&ms| ms > 0).and_then(|ms| ms.checked_add(base)) {
+            Some(at) => Ok(SetExpiry::At(at)),
+            None => Err(Error::Handle(format!(
+                "ERR invalid expire time in '{}' command",
+                command
+            ))),
+        }
+    }
+}
+
+/// Reply of SET: `OK` or nil depends on whether the value was written,
+/// or the old value when `GET` is given
+#[derive(Debug, PartialEq)]
+pub enum SetOutput {
+    Ok,
+    Nil,
+    Old(Option<ByteString>),
+}
+
+impl Serialize for SetOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            SetOutput::Ok => OkResponse.serialize(serializer),
+            SetOutput::Nil => serializer.serialize_none(),
+            SetOutput::Old(old) => old.serialize(serializer),
+        }
+    }
+}
+
 impl<'a> CommandHandler for SetCommand<'a> {
+    type Output = SetOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let cond = match self.exists {
+            Exists::NotExistedOnly => SetCondition::NotExists,
+            Exists::ExistedOnly => SetCondition::Exists,
+            Exists::Any => SetCondition::Always,
+        };
+        let expiry = match &self.expire {
+            Some(expire) => expire.to_expiry(now_ms())?,
+            None => SetExpiry::Persist,
+        };
+
+        let outcome = db.set(self.key, self.value, cond, expiry, self.get.is_some())?;
+
+        Ok(match (self.get, outcome.applied) {
+            (Some(SetGet), _) => SetOutput::Old(outcome.old),
+            (None, true) => SetOutput::Ok,
+            (None, false) => SetOutput::Nil,
+        })
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        let expire = match self.expire.as_ref().map(|e| e.to_expiry(now_ms())) {
+            Some(Ok(SetExpiry::At(when))) => Some(ExpireOption::ExpireAtMs(when as usize)),
+            _ => return self.encode(target),
+        };
+
+        SetCommand {
+            key: self.key,
+            value: self.value,
+            exists: self.exists,
+            get: self.get,
+            expire,
+        }
+        .encode(target)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("INCRBY")]
+pub struct IncrbyCommand<'a> {
+    pub key: &'a [u8],
+    pub increment: i64,
+}
+
+impl<'a> CommandHandler for IncrbyCommand<'a> {
+    type Output = i64;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.incr_by(self.key, self.increment)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("DECR")]
+pub struct DecrCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for DecrCommand<'a> {
+    type Output = i64;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.incr_by(self.key, -1)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("DECRBY")]
+pub struct DecrbyCommand<'a> {
+    pub key: &'a [u8],
+    pub decrement: i64,
+}
+
+impl<'a> CommandHandler for DecrbyCommand<'a> {
+    type Output = i64;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let increment = self
+            .decrement
+            .checked_neg()
+            .ok_or_else(|| Error::Handle("ERR decrement would overflow".to_string()))?;
+
+        db.incr_by(self.key, increment)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("INCRBYFLOAT")]
+pub struct IncrbyfloatCommand<'a> {
+    pub key: &'a [u8],
+    pub increment: f64,
+}
+
+impl<'a> CommandHandler for IncrbyfloatCommand<'a> {
+    type Output = String;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.incr_by_float(self.key, self.increment)
+    }
+
+    fn amend_aof(output: &Self::Output, args: &mut Vec<Vec<u8>>) {
+        // log the result, float additions may not give the same result on replay
+        let key = args[1].clone();
+        args.clear();
+        args.extend([
+            b"SET".to_vec(),
+            key,
+            output.clone().into_bytes(),
+            b"KEEPTTL".to_vec(),
+        ]);
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("APPEND")]
+pub struct AppendCommand<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl<'a> CommandHandler for AppendCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.append(self.key, self.value)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("STRLEN")]
+pub struct StrlenCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for StrlenCommand<'a> {
+    type Output = usize;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.strlen(self.key)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("GETRANGE")]
+pub struct GetrangeCommand<'a> {
+    pub key: &'a [u8],
+    pub start: i64,
+    pub end: i64,
+}
+
+impl<'a> CommandHandler for GetrangeCommand<'a> {
+    type Output = ByteString;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.getrange(self.key, self.start, self.end)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key]
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SETRANGE")]
+pub struct SetrangeCommand<'a> {
+    pub key: &'a [u8],
+    pub offset: i64,
+    pub value: &'a [u8],
+}
+
+impl<'a> CommandHandler for SetrangeCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        if self.offset < 0 {
+            return Err(Error::Handle("ERR offset is out of range".to_string()));
+        }
+
+        db.setrange(self.key, self.offset as usize, self.value)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("MGET")]
+pub struct MgetCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for MgetCommand<'a> {
+    type Output = Vec<Option<ByteString>>;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.mget(&self.keys))
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        self.keys.clone()
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+pub struct KeyValue<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+fn key_value_pairs<'a>(key_values: &[KeyValue<'a>]) -> Vec<(&'a [u8], &'a [u8])> {
+    key_values.iter().map(|kv| (kv.key, kv.value)).collect()
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("MSET")]
+pub struct MsetCommand<'a> {
+    pub key_values: Vec<KeyValue<'a>>,
+}
+
+impl<'a> CommandHandler for MsetCommand<'a> {
     type Output = OkResponse;
+    const WRITE: bool = true;
 
     fn handle(self, db: &Database) -> Result<Self::Output, Error> {
-        db.set(self.key, self.value)?;
+        db.mset(&key_value_pairs(&self.key_values), false);
+
         Ok(OkResponse)
     }
 }
 
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("MSETNX")]
+pub struct MsetnxCommand<'a> {
+    pub key_values: Vec<KeyValue<'a>>,
+}
+
+impl<'a> CommandHandler for MsetnxCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        Ok(db.mset(&key_value_pairs(&self.key_values), true) as usize)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("GETSET")]
+pub struct GetsetCommand<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl<'a> CommandHandler for GetsetCommand<'a> {
+    type Output = Option<ByteString>;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let outcome = db.set(
+            self.key,
+            self.value,
+            SetCondition::Always,
+            SetExpiry::Persist,
+            true,
+        )?;
+
+        Ok(outcome.old)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("GETDEL")]
+pub struct GetdelCommand<'a> {
+    pub key: &'a [u8],
+}
+
+impl<'a> CommandHandler for GetdelCommand<'a> {
+    type Output = Option<ByteString>;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        db.getdel(self.key)
+    }
+}
+
+#[derive(CommandArgsBlock, Debug, PartialEq)]
+pub enum GetexOption {
+    #[argtoken("EX")]
+    ExpireAfterSecond(usize),
+    #[argtoken("PX")]
+    ExpireAfterMs(usize),
+    #[argtoken("EXAT")]
+    ExpireAtSecond(usize),
+    #[argtoken("PXAT")]
+    ExpireAtMs(usize),
+    #[argtoken("PERSIST")]
+    Persist,
+}
+
+impl GetexOption {
+    fn to_expiry(&self, now: u64) -> Result<SetExpiry, Error> {
+        let expire = match *self {
+            GetexOption::ExpireAfterSecond(s) => ExpireOption::ExpireAfterSecond(s),
+            GetexOption::ExpireAfterMs(ms) => ExpireOption::ExpireAfterMs(ms),
+            GetexOption::ExpireAtSecond(s) => ExpireOption::ExpireAtSecond(s),
+            GetexOption::ExpireAtMs(ms) => ExpireOption::ExpireAtMs(ms),
+            GetexOption::Persist => return Ok(SetExpiry::Persist),
+        };
+
+        expire.to_expiry_for(now, "getex")
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("GETEX")]
+pub struct GetexCommand<'a> {
+    pub key: &'a [u8],
+    pub expire: Option<GetexOption>,
+}
+
+impl<'a> CommandHandler for GetexCommand<'a> {
+    type Output = Option<ByteString>;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let expiry = self
+            .expire
+            .as_ref()
+            .map(|expire| expire.to_expiry(now_ms()))
+            .transpose()?;
+
+        db.getex(self.key, expiry)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        let expire = match self.expire.as_ref().map(|e| e.to_expiry(now_ms())) {
+            Some(Ok(SetExpiry::At(when))) => Some(GetexOption::ExpireAtMs(when as usize)),
+            _ => return self.encode(target),
+        };
+
+        GetexCommand {
+            key: self.key,
+            expire,
+        }
+        .encode(target)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SETNX")]
+pub struct SetnxCommand<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl<'a> CommandHandler for SetnxCommand<'a> {
+    type Output = usize;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        let outcome = db.set(
+            self.key,
+            self.value,
+            SetCondition::NotExists,
+            SetExpiry::Persist,
+            false,
+        )?;
+
+        Ok(outcome.applied as usize)
+    }
+}
+
+/// Converts SETEX and PSETEX to the SET command they are equivalent to
+fn set_with_expiry<'a>(
+    key: &'a [u8],
+    value: &'a [u8],
+    time: i64,
+    expire: fn(usize) -> ExpireOption,
+    command: &str,
+) -> Result<SetCommand<'a>, Error> {
+    if time <= 0 {
+        return Err(Error::Handle(format!(
+            "ERR invalid expire time in '{}' command",
+            command
+        )));
+    }
+
+    Ok(SetCommand {
+        key,
+        value,
+        exists: Exists::Any,
+        get: None,
+        expire: Some(expire(time as usize)),
+    })
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("SETEX")]
+pub struct SetexCommand<'a> {
+    pub key: &'a [u8],
+    pub seconds: i64,
+    pub value: &'a [u8],
+}
+
+impl<'a> SetexCommand<'a> {
+    fn to_set(&self) -> Result<SetCommand<'a>, Error> {
+        set_with_expiry(
+            self.key,
+            self.value,
+            self.seconds,
+            ExpireOption::ExpireAfterSecond,
+            "setex",
+        )
+    }
+}
+
+impl<'a> CommandHandler for SetexCommand<'a> {
+    type Output = SetOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.to_set()?.handle(db)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        match self.to_set() {
+            Ok(set) => set.encode_aof(target),
+            Err(_) => self.encode(target),
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("PSETEX")]
+pub struct PsetexCommand<'a> {
+    pub key: &'a [u8],
+    pub milliseconds: i64,
+    pub value: &'a [u8],
+}
+
+impl<'a> PsetexCommand<'a> {
+    fn to_set(&self) -> Result<SetCommand<'a>, Error> {
+        set_with_expiry(
+            self.key,
+            self.value,
+            self.milliseconds,
+            ExpireOption::ExpireAfterMs,
+            "psetex",
+        )
+    }
+}
+
+impl<'a> CommandHandler for PsetexCommand<'a> {
+    type Output = SetOutput;
+    const WRITE: bool = true;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        self.to_set()?.handle(db)
+    }
+
+    fn encode_aof<'b>(&self, target: &mut Vec<Vec<u8>>) -> Result<(), command_args::Error>
+    where
+        Self: CommandArgs<'b>,
+    {
+        match self.to_set() {
+            Ok(set) => set.encode_aof(target),
+            Err(_) => self.encode(target),
+        }
+    }
+}
+
+#[derive(CommandArgsBlock, Debug, PartialEq, Clone, Copy)]
+#[argtoken("LEN")]
+pub struct LcsLen;
+
+#[derive(CommandArgsBlock, Debug, PartialEq, Clone, Copy)]
+#[argtoken("IDX")]
+pub struct LcsIdx;
+
+#[derive(CommandArgsBlock, Debug, PartialEq, Clone, Copy)]
+#[argtoken("MINMATCHLEN")]
+pub struct MinMatchLen {
+    pub len: usize,
+}
+
+#[derive(CommandArgsBlock, Debug, PartialEq, Clone, Copy)]
+#[argtoken("WITHMATCHLEN")]
+pub struct WithMatchLen;
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("LCS")]
+pub struct LcsCommand<'a> {
+    pub key1: &'a [u8],
+    pub key2: &'a [u8],
+    pub len: Option<LcsLen>,
+    pub idx: Option<LcsIdx>,
+    pub min_match_len: Option<MinMatchLen>,
+    pub with_match_len: Option<WithMatchLen>,
+}
+
+/// A match of LCS with `IDX`: the ranges in both strings, then its length with
+/// `WITHMATCHLEN`
+#[derive(Debug, PartialEq)]
+pub struct LcsMatchReply {
+    pub m: LcsMatch,
+    pub with_len: bool,
+}
+
+impl Serialize for LcsMatchReply {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let ranges = ([self.m.a.0, self.m.a.1], [self.m.b.0, self.m.b.1]);
+        if self.with_len {
+            (ranges.0, ranges.1, self.m.match_len()).serialize(serializer)
+        } else {
+            ranges.serialize(serializer)
+        }
+    }
+}
+
+/// Value of the reply of LCS with `IDX`
+#[derive(Debug, PartialEq)]
+pub enum LcsIdxValue {
+    Matches(Vec<LcsMatchReply>),
+    Len(usize),
+}
+
+impl Serialize for LcsIdxValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            LcsIdxValue::Matches(matches) => matches.serialize(serializer),
+            LcsIdxValue::Len(len) => len.serialize(serializer),
+        }
+    }
+}
+
+/// Reply of LCS: the common subsequence, its length with `LEN`, or the matches and
+/// the length with `IDX`
+#[derive(Debug, PartialEq)]
+pub enum LcsOutput {
+    Subsequence(ByteString),
+    Len(usize),
+    Idx(MapReply<&'static str, LcsIdxValue>),
+}
+
+impl Serialize for LcsOutput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            LcsOutput::Subsequence(s) => s.serialize(serializer),
+            LcsOutput::Len(len) => len.serialize(serializer),
+            LcsOutput::Idx(reply) => reply.serialize(serializer),
+        }
+    }
+}
+
+impl<'a> CommandHandler for LcsCommand<'a> {
+    type Output = LcsOutput;
+
+    fn handle(self, db: &Database) -> Result<Self::Output, Error> {
+        if self.len.is_some() && self.idx.is_some() {
+            return Err(Error::Handle(
+                "ERR If you want both the length and indexes, please just use IDX.".to_string(),
+            ));
+        }
+        let min_match_len = self.min_match_len.map_or(0, |m| m.len);
+        let (subsequence, matches) = db.lcs(self.key1, self.key2, min_match_len)?;
+
+        Ok(match (self.len, self.idx) {
+            (_, Some(LcsIdx)) => {
+                let with_len = self.with_match_len.is_some();
+                let matches = matches
+                    .into_iter()
+                    .map(|m| LcsMatchReply { m, with_len })
+                    .collect();
+                LcsOutput::Idx(MapReply::new(vec![
+                    ("matches", LcsIdxValue::Matches(matches)),
+                    ("len", LcsIdxValue::Len(subsequence.len())),
+                ]))
+            }
+            (Some(LcsLen), None) => LcsOutput::Len(subsequence.len()),
+            (None, None) => LcsOutput::Subsequence(subsequence),
+        })
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let mut reply = self.handle(db)?;
+        if let LcsOutput::Idx(reply) = &mut reply {
+            reply.resp3 = client.resp3();
+        }
+
+        Ok(reply)
+    }
+
+    fn read_keys(&self) -> Vec<&[u8]> {
+        vec![self.key1, self.key2]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::command::run_commands;
     use assert_matches::assert_matches;
-    use command_args::CommandArgs;
 
     #[test]
     fn test_parse_set() {
-        let cmd_str = vec!["SET", "a", "b", "NX", "GET", "EX", "20"];
+        let cmd_str = ["SET", "a", "b", "NX", "GET", "EX", "20"].map(str::as_bytes);
         let s = SetCommand::parse_maybe(&mut &cmd_str[..]).unwrap().unwrap();
 
-        assert_eq!(s.key, "a");
-        assert_eq!(s.value, "b");
+        assert_eq!(s.key, b"a");
+        assert_eq!(s.value, b"b");
         assert_matches!(s.exists, Exists::NotExistedOnly);
         assert_matches!(s.get, Some(SetGet));
         assert_matches!(s.expire, Some(ExpireOption::ExpireAfterSecond(20)));
 
-        let cmd_str = vec!["SET", "a", "b", "PXAT", "20"];
+        let cmd_str = ["SET", "a", "b", "PXAT", "20"].map(str::as_bytes);
         let s = SetCommand::parse_maybe(&mut &cmd_str[..]).unwrap().unwrap();
 
-        assert_eq!(s.key, "a");
-        assert_eq!(s.value, "b");
+        assert_eq!(s.key, b"a");
+        assert_eq!(s.value, b"b");
         assert_matches!(s.exists, Exists::Any);
         assert_matches!(s.get, None);
         assert_matches!(s.expire, Some(ExpireOption::ExpireAtMs(20)));
@@ -109,25 +784,201 @@ mod tests {
     #[test]
     fn test_encode_set() {
         let s = SetCommand {
-            key: "abc",
-            value: "def",
+            key: b"abc",
+            value: b"def",
             exists: Exists::Any,
             get: None,
             expire: Some(ExpireOption::ExpireAfterSecond(20)),
         };
-        let mut target: Vec<String> = Vec::new();
+        let mut target = Vec::new();
         s.encode(&mut target).unwrap();
 
-        let expected = ["SET", "abc", "def", "EX", "20"]
-            .into_iter()
-            .map(String::from)
-            .collect::<Vec<_>>();
-        assert_eq!(expected, target);
-        let target_ref = target.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(target, ["SET", "abc", "def", "EX", "20"].map(str::as_bytes));
+        let target_ref = target.iter().map(Vec::as_slice).collect::<Vec<_>>();
 
         let source = SetCommand::parse_maybe(&mut &target_ref[..])
             .unwrap()
             .unwrap();
         assert_eq!(source, s);
     }
+
+    #[test]
+    fn test_parse_set_keepttl() {
+        let cmd_str = ["SET", "a", "b", "XX", "KEEPTTL"].map(str::as_bytes);
+        let s = SetCommand::parse_maybe(&mut &cmd_str[..]).unwrap().unwrap();
+
+        assert_matches!(s.exists, Exists::ExistedOnly);
+        assert_matches!(s.expire, Some(ExpireOption::KeepTTL));
+    }
+
+    #[test]
+    fn test_encode_aof_set() {
+        let s = SetCommand {
+            key: b"abc",
+            value: b"def",
+            exists: Exists::NotExistedOnly,
+            get: None,
+            expire: Some(ExpireOption::ExpireAfterSecond(20)),
+        };
+        let before = now_ms();
+        let mut target = Vec::new();
+        s.encode_aof(&mut target).unwrap();
+
+        assert_eq!(
+            &target[..5],
+            ["SET", "abc", "def", "NX", "PXAT"].map(str::as_bytes)
+        );
+        let at: u64 = crate::byte_string::parse(&target[5]).unwrap();
+        assert!(at >= before + 20_000 && at <= now_ms() + 20_000);
+
+        let s = SetCommand {
+            key: b"abc",
+            value: b"def",
+            exists: Exists::Any,
+            get: None,
+            expire: Some(ExpireOption::KeepTTL),
+        };
+        let mut target = Vec::new();
+        s.encode_aof(&mut target).unwrap();
+        assert_eq!(target, ["SET", "abc", "def", "KeepTTL"].map(str::as_bytes));
+    }
+
+    #[test]
+    fn test_expire_option_to_expiry() {
+        let now = 1_000_000;
+        assert_eq!(
+            ExpireOption::ExpireAfterSecond(10).to_expiry(now).unwrap(),
+            SetExpiry::At(1_010_000)
+        );
+        assert_eq!(
+            ExpireOption::ExpireAfterMs(10).to_expiry(now).unwrap(),
+            SetExpiry::At(1_000_010)
+        );
+        assert_eq!(
+            ExpireOption::ExpireAtSecond(10).to_expiry(now).unwrap(),
+            SetExpiry::At(10_000)
+        );
+        assert_eq!(
+            ExpireOption::ExpireAtMs(10).to_expiry(now).unwrap(),
+            SetExpiry::At(10)
+        );
+        assert_eq!(
+            ExpireOption::KeepTTL.to_expiry(now).unwrap(),
+            SetExpiry::Keep
+        );
+        assert!(ExpireOption::ExpireAfterSecond(0).to_expiry(now).is_err());
+        assert!(ExpireOption::ExpireAfterSecond(usize::MAX)
+            .to_expiry(now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_string_commands() {
+        let db = Database::new(String::new()).unwrap();
+        let result = run_commands(
+            &db,
+            &mut ClientState::default(),
+            &[
+                &["APPEND", "s", "Hello"],
+                &["APPEND", "s", " World"],
+                &["STRLEN", "s"],
+                &["GETRANGE", "s", "-5", "-1"],
+                &["SETRANGE", "s", "6", "Redis"],
+                &["SETRANGE", "missing", "0", ""],
+                &["SETRANGE", "s", "-1", "x"],
+                &["MSET", "a", "1", "b", "2"],
+                &["MSETNX", "b", "3", "c", "3"],
+                &["MGET", "a", "b", "c", "s"],
+                &["INCRBY", "a", "10"],
+                &["DECR", "a"],
+                &["DECRBY", "a", "-9223372036854775808"],
+                &["INCRBY", "a", "9223372036854775807"],
+                &["INCRBY", "s", "1"],
+                &["INCRBYFLOAT", "b", "0.5"],
+                &["GETSET", "b", "x"],
+                &["GETDEL", "b"],
+                &["GETDEL", "b"],
+                &["SETNX", "a", "x"],
+                &["SETEX", "e", "0", "x"],
+                &["PSETEX", "e", "100000", "x"],
+                &["GETEX", "e", "PERSIST"],
+                &["GETEX", "missing", "EX", "10"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                ":5\r\n:11\r\n:11\r\n+World\r\n:11\r\n:0\r\n",
+                "-ERR offset is out of range\r\n",
+                "+OK\r\n:0\r\n*4\r\n+1\r\n+2\r\n$-1\r\n+Hello Redis\r\n",
+                ":11\r\n:10\r\n-ERR decrement would overflow\r\n",
+                "-ERR increment or decrement would overflow\r\n",
+                "-ERR value is not an integer or out of range\r\n",
+                "+2.5\r\n+2.5\r\n+x\r\n$-1\r\n:0\r\n",
+                "-ERR invalid expire time in 'setex' command\r\n",
+                "+OK\r\n+x\r\n$-1\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_lcs() {
+        let db = Database::new(String::new()).unwrap();
+        let result = run_commands(
+            &db,
+            &mut ClientState::default(),
+            &[
+                &["MSET", "key1", "ohmytext", "key2", "mynewtext"],
+                &["LCS", "key1", "key2"],
+                &["LCS", "key1", "key2", "LEN"],
+                &[
+                    "LCS",
+                    "key1",
+                    "key2",
+                    "IDX",
+                    "MINMATCHLEN",
+                    "4",
+                    "WITHMATCHLEN",
+                ],
+                &["LCS", "key1", "key2", "LEN", "IDX"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "+OK\r\n+mytext\r\n:6\r\n",
+                "*4\r\n+matches\r\n*1\r\n*3\r\n*2\r\n:4\r\n:7\r\n*2\r\n:5\r\n:8\r\n:4\r\n",
+                "+len\r\n:6\r\n",
+                "-ERR If you want both the length and indexes, please just use IDX.\r\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_encode_aof_expiry_commands() {
+        let before = now_ms();
+        let mut target = Vec::new();
+        SetexCommand {
+            key: b"k",
+            seconds: 10,
+            value: b"v",
+        }
+        .encode_aof(&mut target)
+        .unwrap();
+        assert_eq!(&target[..4], ["SET", "k", "v", "PXAT"].map(str::as_bytes));
+        assert!(crate::byte_string::parse::<u64>(&target[4]).unwrap() >= before + 10_000);
+
+        let mut target = Vec::new();
+        GetexCommand {
+            key: b"k",
+            expire: Some(GetexOption::ExpireAfterMs(10)),
+        }
+        .encode_aof(&mut target)
+        .unwrap();
+        assert_eq!(&target[..3], ["GETEX", "k", "PXAT"].map(str::as_bytes));
+
+        let mut args = [b"INCRBYFLOAT".to_vec(), b"k".to_vec(), b"0.1".to_vec()].to_vec();
+        IncrbyfloatCommand::amend_aof(&"1.1".to_string(), &mut args);
+        assert_eq!(args, ["SET", "k", "1.1", "KEEPTTL"].map(str::as_bytes));
+    }
 }
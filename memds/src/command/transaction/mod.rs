@@ -0,0 +1,538 @@
+use command_args::CommandArgs;
+use command_args_derive::CommandArgsBlock;
+use deseresp::types::{owned::SimpleString, OkResponse};
+use serde::Serialize;
+
+use crate::{byte_string::ByteString, database::Database, resp};
+
+use super::{ClientState, CommandHandler, Dispatch, Error};
+
+/// Commands queued since MULTI, executed by EXEC
+#[derive(Debug, Default)]
+pub struct Transaction {
+    commands: Vec<QueuedCommand>,
+    /// Whether a command failed to be queued, EXEC then discards the transaction
+    aborted: bool,
+}
+
+#[derive(Debug)]
+struct QueuedCommand {
+    args: Vec<Vec<u8>>,
+    write: bool,
+}
+
+/// Key watched by WATCH, in the database it was selected, with the version returned by
+/// `Database::watch`
+#[derive(Debug)]
+pub struct WatchedKey {
+    db: usize,
+    key: ByteString,
+    version: u64,
+}
+
+fn is_command(args: &[&[u8]], name: &[u8]) -> bool {
+    args.first()
+        .is_some_and(|command| command.eq_ignore_ascii_case(name))
+}
+
+/// Whether the command controls the transaction, so it is handled instead of being queued
+pub(crate) fn is_control_command(args: &[&[u8]]) -> bool {
+    [&b"MULTI"[..], b"EXEC", b"DISCARD", b"WATCH"]
+        .iter()
+        .any(|name| is_command(args, name))
+}
+
+pub(crate) fn is_exec(args: &[&[u8]]) -> bool {
+    is_command(args, b"EXEC")
+}
+
+/// Queues the command in the transaction of the client once it is validated.
+/// A command that is not supported or fails to parse gets an error reply and aborts the
+/// transaction.
+pub(crate) fn queue(
+    args: &[&[u8]],
+    db: &Database,
+    client: &mut ClientState,
+    write_buf: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    let result = super::parse_and_handle_main(args, db, client, write_buf, Dispatch::Validate);
+    let transaction = client
+        .transaction
+        .as_mut()
+        .expect("commands are only queued in a transaction");
+
+    match result {
+        Ok(Some(write)) => {
+            transaction.commands.push(QueuedCommand {
+                args: args.iter().map(|arg| arg.to_vec()).collect(),
+                // a script may write, its writes are logged within the transaction
+                write: write || super::scripting::is_script(args),
+            });
+            let mut serializer = resp::from_write(write_buf, client.resp3());
+            SimpleString("QUEUED".into())
+                .serialize(&mut serializer)
+                .map_err(|e| Error::Serialize(e.to_string()))?;
+
+            Ok(false)
+        }
+        Ok(None) => {
+            transaction.aborted = true;
+            super::handle_unsupported_command(args, client.resp3(), write_buf)?;
+
+            Ok(false)
+        }
+        Err(e) => {
+            transaction.aborted = true;
+            super::write_error(args, Err(e), client.resp3(), write_buf)
+        }
+    }
+}
+
+/// Stops watching all keys watched by the client
+fn unwatch_all(db: &Database, client: &mut ClientState) {
+    for watched in client.watched.drain(..) {
+        if let Ok(db) = db.select(watched.db) {
+            db.unwatch(&watched.key);
+        }
+    }
+}
+
+/// Discards the transaction and the watched keys of a client, when its connection ends
+pub fn reset(db: &Database, client: &mut ClientState) {
+    client.transaction = None;
+    unwatch_all(db, client);
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("MULTI")]
+pub struct MultiCommand;
+
+impl CommandHandler for MultiCommand {
+    type Output = OkResponse;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Ok(OkResponse)
+    }
+
+    fn handle_client(
+        self,
+        _db: &Database,
+        client: &mut ClientState,
+    ) -> Result<Self::Output, Error> {
+        if client.transaction.is_some() {
+            return Err(Error::Handle(
+                "ERR MULTI calls can not be nested".to_string(),
+            ));
+        }
+        client.transaction = Some(Transaction::default());
+
+        Ok(OkResponse)
+    }
+}
+
+/// Replies of the commands of a transaction, already serialized
+#[derive(Debug)]
+pub struct ExecReplies {
+    count: usize,
+    buf: Vec<u8>,
+}
+
+impl Serialize for ExecReplies {
+    fn serialize<S>(&self, _serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        Err(serde::ser::Error::custom(
+            "replies of a transaction are written by ExecCommand::write_output",
+        ))
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("EXEC")]
+pub struct ExecCommand;
+
+impl CommandHandler for ExecCommand {
+    /// None if a watched key was modified
+    type Output = Option<ExecReplies>;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(Error::Handle("ERR EXEC without MULTI".to_string()))
+    }
+
+    /// Executes the queued commands, the caller holds exclusive access to the database.
+    /// The writes are logged to the AOF between MULTI and EXEC, so a transaction cut short
+    /// at the end of the log is not replayed.
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        let transaction = client
+            .transaction
+            .take()
+            .ok_or_else(|| Error::Handle("ERR EXEC without MULTI".to_string()))?;
+        let modified = client.watched.iter().any(|watched| {
+            db.select(watched.db)
+                .map_or(true, |db| db.watch_modified(&watched.key, watched.version))
+        });
+        unwatch_all(db, client);
+
+        if transaction.aborted {
+            return Err(Error::Handle(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            ));
+        }
+        if modified {
+            return Ok(None);
+        }
+
+        let logged = transaction.commands.iter().any(|command| command.write);
+        let mut record = Vec::new();
+        if logged {
+            if let Some(mut aof) = db.aof() {
+                MultiCommand.encode(&mut record).map_err(Error::Parse)?;
+                aof.append(client.db, &record)?;
+            }
+        }
+
+        let mut buf = Vec::new();
+        // blocking would never end, nothing else runs until the transaction is done
+        client.deny_blocking = true;
+        let result = transaction.commands.iter().try_for_each(|command| {
+            let args: Vec<&[u8]> = command.args.iter().map(|arg| &arg[..]).collect();
+            super::handle_command(&args, db, client, &mut buf).map(|_| ())
+        });
+        client.deny_blocking = false;
+        result?;
+
+        if logged {
+            if let Some(mut aof) = db.aof() {
+                record.clear();
+                ExecCommand.encode(&mut record).map_err(Error::Parse)?;
+                aof.append(client.db, &record)?;
+            }
+        }
+
+        Ok(Some(ExecReplies {
+            count: transaction.commands.len(),
+            buf,
+        }))
+    }
+
+    fn write_output(
+        output: Self::Output,
+        resp3: bool,
+        write_buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match output {
+            Some(replies) => {
+                write_buf.extend_from_slice(format!("*{}\r\n", replies.count).as_bytes());
+                write_buf.extend_from_slice(&replies.buf);
+
+                Ok(())
+            }
+            None => {
+                let mut serializer = resp::from_write(write_buf, resp3);
+                resp::NullArray
+                    .serialize(&mut serializer)
+                    .map_err(|e| Error::Serialize(e.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("DISCARD")]
+pub struct DiscardCommand;
+
+impl CommandHandler for DiscardCommand {
+    type Output = OkResponse;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Err(Error::Handle("ERR DISCARD without MULTI".to_string()))
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        if client.transaction.take().is_none() {
+            return self.handle(db);
+        }
+        unwatch_all(db, client);
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("WATCH")]
+pub struct WatchCommand<'a> {
+    pub keys: Vec<&'a [u8]>,
+}
+
+impl<'a> CommandHandler for WatchCommand<'a> {
+    type Output = OkResponse;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Ok(OkResponse)
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        if client.transaction.is_some() {
+            return Err(Error::Handle(
+                "ERR WATCH inside MULTI is not allowed".to_string(),
+            ));
+        }
+
+        for key in self.keys {
+            let watching = client
+                .watched
+                .iter()
+                .any(|watched| watched.db == db.index() && watched.key == key);
+            if !watching {
+                client.watched.push(WatchedKey {
+                    db: db.index(),
+                    key: key.into(),
+                    version: db.watch(key),
+                });
+            }
+        }
+
+        Ok(OkResponse)
+    }
+}
+
+#[derive(Debug, CommandArgsBlock)]
+#[argtoken("UNWATCH")]
+pub struct UnwatchCommand;
+
+impl CommandHandler for UnwatchCommand {
+    type Output = OkResponse;
+
+    fn handle(self, _db: &Database) -> Result<Self::Output, Error> {
+        Ok(OkResponse)
+    }
+
+    fn handle_client(self, db: &Database, client: &mut ClientState) -> Result<Self::Output, Error> {
+        unwatch_all(db, client);
+
+        Ok(OkResponse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{command::run_commands, config::Config};
+
+    use super::*;
+
+    #[test]
+    fn test_multi_exec() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["MULTI"],
+                &["INCR", "a"],
+                &["SADD", "a", "x"],
+                &["SELECT", "1"],
+                &["INCR", "a"],
+                &["MULTI"],
+                &["EXEC"],
+                &["EXEC"],
+                &["DISCARD"],
+                &["GET", "a"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n",
+                "-ERR MULTI calls can not be nested\r\n",
+                "*4\r\n:1\r\n-ERR key a is not set\r\n",
+                "+OK\r\n:1\r\n",
+                "-ERR EXEC without MULTI\r\n-ERR DISCARD without MULTI\r\n+1\r\n",
+            )
+        );
+        assert_eq!(client.db, 1);
+
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[
+                &["MULTI"],
+                &["INCR", "a"],
+                &["DISCARD"],
+                &["MULTI"],
+                &["INCR", "a"],
+                &["INCR"],
+                &["NOSUCHCOMMAND"],
+                &["EXEC"],
+                &["GET", "a"],
+            ],
+        );
+        assert_eq!(
+            result,
+            concat!(
+                "+OK\r\n+QUEUED\r\n+OK\r\n+OK\r\n+QUEUED\r\n-ERR failed to parse: INCR\r\n",
+                "-ERR command NOSUCHCOMMAND not supported\r\n",
+                "-EXECABORT Transaction discarded because of previous errors.\r\n+1\r\n",
+            )
+        );
+        assert!(client.transaction.is_none());
+    }
+
+    #[test]
+    fn test_watch() {
+        let db = Database::new(String::new()).unwrap();
+        let mut client = ClientState::default();
+        let mut other = ClientState::default();
+        let transaction: &[&[&str]] = &[&["MULTI"], &["INCR", "a"], &["EXEC"]];
+
+        run_commands(&db, &mut client, &[&["WATCH", "a", "b"]]);
+        run_commands(&db, &mut other, &[&["INCR", "b"]]);
+        let result = run_commands(&db, &mut client, transaction);
+        assert_eq!(result, "+OK\r\n+QUEUED\r\n*-1\r\n");
+        assert!(client.watched.is_empty());
+        assert_eq!(db.get(b"a").unwrap(), None);
+
+        // reading or modifying other keys does not abort the transaction
+        run_commands(&db, &mut client, &[&["WATCH", "a"]]);
+        run_commands(&db, &mut other, &[&["GET", "a"], &["INCR", "b"]]);
+        let result = run_commands(&db, &mut client, transaction);
+        assert_eq!(result, "+OK\r\n+QUEUED\r\n*1\r\n:1\r\n");
+
+        // the key is only watched in the selected database
+        run_commands(&db, &mut client, &[&["WATCH", "a"], &["SELECT", "1"]]);
+        run_commands(&db, &mut other, &[&["SELECT", "1"], &["INCR", "a"]]);
+        let result = run_commands(&db, &mut client, transaction);
+        assert_eq!(result, "+OK\r\n+QUEUED\r\n*1\r\n:2\r\n");
+
+        let result = run_commands(
+            &db,
+            &mut client,
+            &[&["WATCH", "a"], &["MULTI"], &["WATCH", "a"], &["EXEC"]],
+        );
+        assert_eq!(
+            result,
+            "+OK\r\n+OK\r\n-ERR WATCH inside MULTI is not allowed\r\n*0\r\n"
+        );
+
+        run_commands(&db, &mut client, &[&["WATCH", "a"], &["UNWATCH"]]);
+        run_commands(&db, &mut other, &[&["DEL", "a"]]);
+        let result = run_commands(&db, &mut client, transaction);
+        assert_eq!(result, "+OK\r\n+QUEUED\r\n*1\r\n:1\r\n");
+
+        run_commands(&db, &mut client, &[&["WATCH", "a"]]);
+        run_commands(&db, &mut other, &[&["FLUSHALL"]]);
+        let result = run_commands(&db, &mut client, transaction);
+        assert_eq!(result, "+OK\r\n+QUEUED\r\n*-1\r\n");
+
+        // an expired watched key counts as modified
+        run_commands(
+            &db,
+            &mut client,
+            &[&["SET", "a", "1", "PX", "1"], &["WATCH", "a"]],
+        );
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let result = run_commands(&db, &mut client, transaction);
+        assert_eq!(result, "+OK\r\n+QUEUED\r\n*-1\r\n");
+
+        run_commands(&db, &mut client, &[&["WATCH", "a"]]);
+        reset(&db, &mut client);
+        assert!(client.watched.is_empty());
+    }
+
+    #[test]
+    fn test_exec_is_atomic() {
+        let db = Database::new(String::new()).unwrap();
+        let done = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut client = ClientState::default();
+                    for _ in 0..100 {
+                        let result = run_commands(
+                            &db,
+                            &mut client,
+                            &[&["MULTI"], &["INCR", "a"], &["INCR", "a"], &["EXEC"]],
+                        );
+                        let replies: Vec<i64> = result
+                            .split("\r\n")
+                            .filter_map(|line| line.strip_prefix(':'))
+                            .map(|n| n.parse().unwrap())
+                            .collect();
+                        // no other increment runs between the two of a transaction
+                        assert_eq!(replies[1], replies[0] + 1);
+                        assert_eq!(replies[0] % 2, 1);
+                    }
+                    done.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+
+        assert_eq!(done.load(Ordering::Relaxed), 4);
+        assert_eq!(db.get(b"a").unwrap(), Some("800".into()));
+    }
+
+    #[test]
+    fn test_exec_aof() {
+        let aof_path = std::env::temp_dir().join(format!("memds-multi-{}.aof", std::process::id()));
+        let aof_path = aof_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&aof_path);
+        let config = Config {
+            db_path: String::new(),
+            appendonly: true,
+            aof_path: aof_path.clone(),
+            ..Default::default()
+        };
+
+        let db = Database::from_config(&config).unwrap();
+        let mut client = ClientState::default();
+        run_commands(
+            &db,
+            &mut client,
+            &[
+                &["MULTI"],
+                &["INCR", "a"],
+                &["SELECT", "2"],
+                &["INCR", "b"],
+                &["EXEC"],
+                &["MULTI"],
+                &["GET", "b"],
+                &["EXEC"],
+            ],
+        );
+        db.aof_fsync().unwrap();
+        drop(db);
+
+        let mut commands = Vec::new();
+        crate::wal::replay(&aof_path, |args| {
+            let args: Vec<_> = args.iter().map(|a| String::from_utf8_lossy(a)).collect();
+            commands.push(args.join(" "));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            commands,
+            ["SELECT 0", "MULTI", "INCR a", "SELECT 2", "INCR b", "EXEC"]
+        );
+
+        // a transaction cut short at the end of the log is not replayed
+        let mut content = std::fs::read(&aof_path).unwrap();
+        let exec_len = b"*1\r\n$4\r\nEXEC\r\n".len();
+        content.truncate(content.len() - exec_len);
+        std::fs::write(&aof_path, &content).unwrap();
+        let db = Database::from_config(&config).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.select(2).unwrap().get(b"b").unwrap(), None);
+        drop(db);
+
+        content.extend_from_slice(b"*1\r\n$4\r\nEXEC\r\n");
+        std::fs::write(&aof_path, &content).unwrap();
+        let db = Database::from_config(&config).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some("1".into()));
+        assert_eq!(db.select(2).unwrap().get(b"b").unwrap(), Some("1".into()));
+        std::fs::remove_file(&aof_path).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::{anyhow, bail};
+
+use crate::{
+    memds::SetLimits,
+    pubsub::{KeyspaceEvents, DEFAULT_BUFFER_LIMIT},
+    scripting::DEFAULT_TIME_LIMIT,
+};
+
+/// Number of databases unless configured
+pub const DEFAULT_DATABASES: usize = 16;
+/// Limit of the number of databases, higher database numbers in a db file are invalid
+pub const MAX_DATABASES: usize = 1 << 16;
+
+/// When the append-only file is fsync-ed to disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    /// After every write command
+    Always,
+    /// Once per second in background
+    EverySec,
+    /// Leave it to the OS
+    No,
+}
+
+/// Format of the snapshot file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DbFormat {
+    /// The native format of memds
+    Memds,
+    /// The RDB format of Redis
+    Rdb,
+}
+
+/// Take a background snapshot when at least `changes` writes happened in the last `seconds`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SavePolicy {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Server configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    /// Number of databases, selected by number from 0
+    pub databases: usize,
+    /// Path of the snapshot file
+    pub db_path: String,
+    /// Format of the snapshot file written, any supported format is loaded
+    pub db_format: DbFormat,
+    /// Whether write commands are logged to the append-only file
+    pub appendonly: bool,
+    pub aof_path: String,
+    pub appendfsync: AppendFsync,
+    /// Policies of automatic background snapshots, disabled if empty
+    pub save: Vec<SavePolicy>,
+    /// Thresholds of the compact encodings of sets
+    pub set_limits: SetLimits,
+    /// Limit of the messages queued for a subscriber in bytes, 0 for no limit.
+    /// Subscribers not reading their messages fast enough are disconnected past it.
+    pub pubsub_buffer_limit: usize,
+    /// Keyspace events published to subscribers, none by default
+    pub notify_keyspace_events: KeyspaceEvents,
+    /// Time in milliseconds a script runs before other clients are replied BUSY and
+    /// SCRIPT KILL can stop it
+    pub lua_time_limit: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 6901,
+            databases: DEFAULT_DATABASES,
+            db_path: String::from("db.bin"),
+            db_format: DbFormat::Memds,
+            appendonly: false,
+            aof_path: String::from("appendonly.aof"),
+            appendfsync: AppendFsync::EverySec,
+            save: vec![
+                SavePolicy {
+                    seconds: 3600,
+                    changes: 1,
+                },
+                SavePolicy {
+                    seconds: 300,
+                    changes: 100,
+                },
+                SavePolicy {
+                    seconds: 60,
+                    changes: 10000,
+                },
+            ],
+            set_limits: SetLimits::default(),
+            pubsub_buffer_limit: DEFAULT_BUFFER_LIMIT,
+            notify_keyspace_events: KeyspaceEvents::default(),
+            lua_time_limit: DEFAULT_TIME_LIMIT,
+        }
+    }
+}
+
+fn parse_yes_no(value: &str) -> anyhow::Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => bail!("expected yes|no, got {}", value),
+    }
+}
+
+/// Parses `<seconds> <changes>` pairs, e.g. `3600 1 300 100`
+fn parse_save_policies(value: &str) -> anyhow::Result<Vec<SavePolicy>> {
+    let numbers = value
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        bail!("expected <seconds> <changes> pairs, got {}", value);
+    }
+
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SavePolicy {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+/// Parses a number of bytes with an optional redis style unit, e.g. `32mb`
+fn parse_memory(value: &str) -> anyhow::Result<usize> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => bail!("invalid memory unit in {}", value),
+    };
+
+    digits
+        .parse::<usize>()?
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow!("memory size {} is too large", value))
+}
+
+impl Config {
+    /// Parses redis style `--name value` command line arguments on top of the default config,
+    /// e.g. `--port 6901 --appendonly yes --appendfsync always`
+    pub fn from_args<I, S>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+
+        while let Some(name) = args.next() {
+            let name = name.as_ref();
+            let option = name
+                .strip_prefix("--")
+                .ok_or_else(|| anyhow!("invalid argument {}", name))?;
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("missing value for {}", name))?;
+            config.set(option, value.as_ref())?;
+        }
+
+        Ok(config)
+    }
+
+    /// Sets a config option by its name
+    pub fn set(&mut self, option: &str, value: &str) -> anyhow::Result<()> {
+        match option.to_ascii_lowercase().as_str() {
+            "port" => self.port = value.parse()?,
+            "databases" => {
+                self.databases = value.parse()?;
+                if self.databases == 0 || self.databases > MAX_DATABASES {
+                    bail!("expected 1 to {} databases, got {}", MAX_DATABASES, value);
+                }
+            }
+            "dbfilename" => self.db_path = value.to_string(),
+            "dbformat" => {
+                self.db_format = match value.to_ascii_lowercase().as_str() {
+                    "memds" => DbFormat::Memds,
+                    "rdb" => DbFormat::Rdb,
+                    _ => bail!("expected memds|rdb, got {}", value),
+                }
+            }
+            "appendonly" => self.appendonly = parse_yes_no(value)?,
+            "appendfilename" => self.aof_path = value.to_string(),
+            "appendfsync" => {
+                self.appendfsync = match value.to_ascii_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => bail!("expected always|everysec|no, got {}", value),
+                }
+            }
+            "save" => self.save = parse_save_policies(value)?,
+            "set-max-intset-entries" => self.set_limits.max_intset_entries = value.parse()?,
+            "set-max-listpack-entries" => self.set_limits.max_listpack_entries = value.parse()?,
+            "set-max-listpack-value" => self.set_limits.max_listpack_value = value.parse()?,
+            "pubsub-output-buffer-limit" => self.pubsub_buffer_limit = parse_memory(value)?,
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = KeyspaceEvents::parse(value)
+                    .ok_or_else(|| anyhow!("invalid keyspace event classes {}", value))?
+            }
+            "lua-time-limit" | "busy-reply-threshold" => self.lua_time_limit = value.parse()?,
+            _ => bail!("unknown config option {}", option),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_args() {
+        let config = Config::from_args([
+            "--port",
+            "1234",
+            "--appendonly",
+            "yes",
+            "--appendfsync",
+            "always",
+        ])
+        .unwrap();
+        assert_eq!(config.port, 1234);
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+        assert_eq!(config.db_path, "db.bin");
+        assert_eq!(config.save.len(), 3);
+
+        let config = Config::from_args(["--save", "900 1 60 1000"]).unwrap();
+        assert_eq!(
+            config.save,
+            vec![
+                SavePolicy {
+                    seconds: 900,
+                    changes: 1
+                },
+                SavePolicy {
+                    seconds: 60,
+                    changes: 1000
+                }
+            ]
+        );
+        assert!(Config::from_args(["--save", ""]).unwrap().save.is_empty());
+        assert!(Config::from_args(["--save", "900"]).is_err());
+
+        let config = Config::from_args(["--dbfilename", "dump.rdb", "--dbformat", "rdb"]).unwrap();
+        assert_eq!(config.db_format, DbFormat::Rdb);
+        assert!(Config::from_args(["--dbformat", "json"]).is_err());
+
+        let config = Config::from_args(["--set-max-intset-entries", "10"]).unwrap();
+        assert_eq!(config.set_limits.max_intset_entries, 10);
+        assert_eq!(config.set_limits.max_listpack_entries, 128);
+        assert!(Config::from_args(["--set-max-listpack-value", "-1"]).is_err());
+
+        assert_eq!(Config::default().databases, 16);
+        let config = Config::from_args(["--databases", "4"]).unwrap();
+        assert_eq!(config.databases, 4);
+        assert!(Config::from_args(["--databases", "0"]).is_err());
+        assert!(Config::from_args(["--databases", "65537"]).is_err());
+
+        assert_eq!(Config::default().pubsub_buffer_limit, 32 * 1024 * 1024);
+        let config = Config::from_args(["--pubsub-output-buffer-limit", "8mb"]).unwrap();
+        assert_eq!(config.pubsub_buffer_limit, 8 * 1024 * 1024);
+        let config = Config::from_args(["--pubsub-output-buffer-limit", "1000"]).unwrap();
+        assert_eq!(config.pubsub_buffer_limit, 1000);
+        assert!(Config::from_args(["--pubsub-output-buffer-limit", "8xb"]).is_err());
+
+        let config = Config::from_args(["--notify-keyspace-events", "KEA"]).unwrap();
+        assert_eq!(config.notify_keyspace_events.to_string(), "g$lshztxeKE");
+        assert!(Config::from_args(["--notify-keyspace-events", "Kq"]).is_err());
+
+        assert_eq!(Config::default().lua_time_limit, 5000);
+        let config = Config::from_args(["--busy-reply-threshold", "100"]).unwrap();
+        assert_eq!(config.lua_time_limit, 100);
+
+        assert!(Config::from_args(["--appendfsync", "sometimes"]).is_err());
+        assert!(Config::from_args(["--port"]).is_err());
+        assert!(Config::from_args(["port", "1"]).is_err());
+    }
+}
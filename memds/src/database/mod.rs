@@ -1,80 +1,3994 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Bound::{self, Excluded, Included},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{
+    seq::{IteratorRandom, SliceRandom},
+    Rng,
+};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
 
 use crate::{
-    memds::{MemDS, SetDS, StringDS},
-    storage, Error,
+    blocking::{Blocked, BlockedPop, PopOrBlock, Popped, WaitQueues},
+    byte_string::ByteString,
+    command::{self, ClientState},
+    config::{Config, DbFormat, SavePolicy, DEFAULT_DATABASES},
+    glob::glob_match,
+    memds::{
+        self, scan_hash, ClaimOptions, ConsumerGroup, HashDS, LcsMatch, ListDS, MemDS, NewStreamId,
+        SetDS, SetLimits, StreamDS, StreamFields, StreamId, StreamTrim, StringDS, ZRange, ZSetDS,
+    },
+    pubsub::{EventClass, PubSub},
+    rdb,
+    scripting::Scripts,
+    storage::{self, Data, ExpireTimes, FileFormat, Snapshot},
+    tracking::Tracking,
+    wal::{self, Wal},
+    Error,
 };
 
+/// One of the numbered databases of the server, the one selected by a client with SELECT.
+/// Cloning it is cheap, the keyspaces of all databases and their persistence are shared.
+#[derive(Clone)]
 pub struct Database {
+    /// Number of the database
+    index: usize,
+    shared: Arc<Shared>,
+}
+
+/// Keyspaces of all the databases with the persistence state they share
+struct Shared {
     db_path: String,
-    data: Mutex<HashMap<String, MemDS>>,
+    /// Keyspace of each database, by number.
+    /// Locks of several keyspaces are always taken in the order of their numbers.
+    keyspaces: Vec<Mutex<Keyspace>>,
+    /// Append-only log of write commands, if enabled.
+    /// Write commands are handled while holding its lock so they are logged in the order
+    /// they are applied.
+    aof: Option<Arc<Mutex<Wal>>>,
+    saving: Arc<Saving>,
+    /// Held shared while a command is handled and exclusively while a transaction is
+    /// executed, so no other command runs in the middle of a transaction.
+    /// Fair, commands get access in the order they wait for it.
+    access: Arc<RwLock<()>>,
+    /// Channels subscribed by the clients, shared by all databases
+    pubsub: Arc<PubSub>,
+    /// Id of the next client blocked on keys
+    next_blocked_id: AtomicU64,
+    /// Number of clients blocked on keys in all databases
+    blocked_clients: AtomicUsize,
+    /// Keys cached by the clients with CLIENT TRACKING, shared by all databases
+    tracking: Arc<Tracking>,
+    /// Scripts cached by EVAL and SCRIPT LOAD, shared by all databases
+    scripts: Scripts,
 }
 
-impl Database {
-    pub fn new(db_path: String) -> Self {
-        match storage::load(&db_path) {
-            Ok(d) => Database {
-                db_path,
-                data: Mutex::new(d),
+/// Delay before retrying an automatic background save after a failure, in seconds
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// Number of keys sampled by RANDOMKEY for one that is not expired
+const RANDOMKEY_SAMPLES: usize = 100;
+
+/// State of snapshots to the db file, shared with background saves
+struct Saving {
+    /// Format of the db file
+    format: DbFormat,
+    /// Number of writes since the snapshot of the last successful save
+    dirty: AtomicU64,
+    status: Mutex<SaveStatus>,
+    /// Held while writing the db file, so saves do not interleave
+    writing: Mutex<()>,
+}
+
+struct SaveStatus {
+    /// Unix time in seconds of the last successful save
+    last_save: u64,
+    /// Unix time in seconds of the last background save attempt
+    last_bgsave_try: u64,
+    last_bgsave_ok: bool,
+    /// Progress of the running background save
+    bgsave: Option<SaveProgress>,
+}
+
+#[derive(Clone)]
+struct SaveProgress {
+    keys_total: usize,
+    keys_processed: Arc<AtomicUsize>,
+}
+
+/// Status of persistence, reported by INFO
+#[derive(Debug, PartialEq)]
+pub struct PersistenceInfo {
+    pub changes_since_last_save: u64,
+    pub last_save: u64,
+    pub last_bgsave_ok: bool,
+    pub bgsave_in_progress: bool,
+    /// Number of keys written by the running background save
+    pub save_keys_processed: usize,
+    /// Number of keys to be written by the running background save
+    pub save_keys_total: usize,
+    pub aof_enabled: bool,
+    pub aof_rewrite_in_progress: bool,
+}
+
+/// Members of a set or sorted set with their scores, borrowed when possible
+type ScoredMembers<'a> = Vec<(Cow<'a, [u8]>, f64)>;
+
+/// Key-value data with the expiry time of volatile keys
+#[derive(Default)]
+struct Keyspace {
+    data: Data,
+    /// Keys of `data` ordered by `scan_hash`, see `Database::scan`
+    scan_order: BTreeSet<(usize, ByteString)>,
+    expires: Expires,
+    set_limits: SetLimits,
+    /// Versions of the keys watched by clients, see `Database::watch`
+    watched: HashMap<ByteString, WatchedVersion>,
+    /// Last version given to a modified watched key
+    version: u64,
+    /// Publishes the keyspace events of the database, None until the databases are created
+    notifier: Option<Notifier>,
+    /// Clients blocked on keys by BLPOP, BLMOVE, BZPOPMIN..., see `Database::pop_or_block`
+    blocking: WaitQueues,
+}
+
+/// Publisher of the keyspace events of a database
+struct Notifier {
+    /// Number of the database
+    db: usize,
+    pubsub: Arc<PubSub>,
+    /// Sends the invalidations of the modified keys to the clients caching them
+    tracking: Arc<Tracking>,
+}
+
+/// Version of a watched key, changed whenever the key is modified
+struct WatchedVersion {
+    version: u64,
+    /// Number of clients watching the key
+    watchers: usize,
+}
+
+/// Expiry time (unix time in milliseconds) of volatile keys,
+/// indexed by key for lookup and by time for active expiry
+#[derive(Default)]
+struct Expires {
+    by_key: ExpireTimes,
+    by_time: BTreeSet<(u64, ByteString)>,
+}
+
+/// Condition on the existence of the key for `Database::set` to be applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    Always,
+    NotExists,
+    Exists,
+}
+
+/// How `Database::set` updates the expiry of the key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetExpiry {
+    /// Remove any existing expiry
+    Persist,
+    /// Retain the expiry of the key, if any
+    Keep,
+    /// Expire at the given unix time in milliseconds
+    At(u64),
+}
+
+/// Condition on the current expiry of the key for `Database::expire` to be applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpiryCondition {
+    Always,
+    /// Key has no expiry
+    NotVolatile,
+    /// Key has an expiry
+    Volatile,
+    /// New expiry is greater than the current one, a key without expiry counts as infinite
+    Greater,
+    /// New expiry is less than the current one, a key without expiry counts as infinite
+    Less,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SetOutcome {
+    /// Whether the value was written
+    pub applied: bool,
+    /// Previous string value of the key
+    pub old: Option<ByteString>,
+}
+
+/// Condition on the current score of a member for `Database::zadd` to update it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreCondition {
+    Always,
+    /// New score is greater than the current one
+    Greater,
+    /// New score is less than the current one
+    Less,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZaddOptions {
+    /// Whether members are added or updated depending on their existence
+    pub exists: SetCondition,
+    pub score: ScoreCondition,
+    /// Increment the current score instead of setting it
+    pub incr: bool,
+}
+
+/// Operation combining the members of several sets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    Union,
+    Inter,
+    /// Members of the first set that are in none of the others
+    Diff,
+}
+
+/// How the scores of a member in several sorted sets are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, which is never a score
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+fn xgroup_no_key() -> Error {
+    Error::Handle(
+        "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string(),
+    )
+}
+
+fn no_group(key: &[u8], group: &str) -> Error {
+    Error::Handle(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        group,
+        String::from_utf8_lossy(key)
+    ))
+}
+
+/// Gets the fields of the entries, or no fields if `justid` is true
+fn with_fields(stream: &StreamDS, ids: Vec<StreamId>, justid: bool) -> StreamEntries {
+    ids.into_iter()
+        .map(|id| match justid {
+            true => (id, Vec::new()),
+            false => (id, stream.get(&id).cloned().unwrap_or_default()),
+        })
+        .collect()
+}
+
+fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+/// Where XREAD and XREADGROUP start reading a stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamStart {
+    /// `$`: after the last entry, only new entries are read
+    Last,
+    /// `>`: the entries never delivered to the consumer group
+    Undelivered,
+    /// After the ID, for XREADGROUP the pending entries of the consumer
+    After(StreamId),
+}
+
+/// Entries of a stream with their IDs
+pub type StreamEntries = Vec<(StreamId, StreamFields)>;
+
+/// Entries read from a stream by XREADGROUP, None for deleted pending entries
+pub type GroupEntries = Vec<(StreamId, Option<StreamFields>)>;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ZaddOutcome {
+    pub added: usize,
+    /// Number of existing members whose score changed
+    pub updated: usize,
+    /// Score of the last member if it was added or updated
+    pub score: Option<f64>,
+}
+
+/// End of a list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Head,
+    Tail,
+}
+
+impl ListEnd {
+    /// Keyspace event of a push to this end
+    fn push_event(self) -> &'static str {
+        match self {
+            ListEnd::Head => "lpush",
+            ListEnd::Tail => "rpush",
+        }
+    }
+
+    /// Keyspace event of a pop from this end
+    fn pop_event(self) -> &'static str {
+        match self {
+            ListEnd::Head => "lpop",
+            ListEnd::Tail => "rpop",
+        }
+    }
+}
+
+/// Checks the count of a random pick, rejecting the negative counts of picks that may repeat
+/// whose reply could never be allocated, like Redis does
+fn check_random_count(count: i64) -> Result<(), Error> {
+    if count < -(i64::MAX / 2) {
+        return Err(Error::Handle("ERR value is out of range".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Number of distinct elements picked for a positive `count` from a collection of `len`
+fn distinct_count(count: i64, len: usize) -> usize {
+    usize::try_from(count).map_or(len, |count| count.min(len))
+}
+
+/// Drops `value` in a background thread, for values that may take long to free
+fn drop_in_background<T: Send + 'static>(value: T) {
+    if let Err(e) = thread::Builder::new()
+        .name("lazyfree".to_string())
+        .spawn(move || drop(value))
+    {
+        tracing::warn!("Failed to free values in the background: {}", e);
+    }
+}
+
+/// Copies the value of `source` with its expiry from `lock` to `destination` in `target`,
+/// `lock` itself if None, see `Database::copy`
+fn copy_between(
+    lock: &mut Keyspace,
+    target: Option<&mut Keyspace>,
+    source: &[u8],
+    destination: &[u8],
+    replace: bool,
+) -> bool {
+    let now = now_ms();
+    lock.expire_if_needed(source, now);
+    let value = match lock.data.get(source) {
+        Some(value) => value.clone(),
+        None => return false,
+    };
+    let expire_at = lock.expires.get(source);
+
+    let target = match target {
+        Some(target) => target,
+        None => lock,
+    };
+    target.expire_if_needed(destination, now);
+    if !replace && target.data.contains_key(destination) {
+        return false;
+    }
+
+    target.remove(destination);
+    // the value is shared until either key is modified
+    target.put(destination, value);
+    if let Some(when) = expire_at {
+        target.expires.insert(destination, when);
+    }
+    target.notify(EventClass::Generic, "copy_to", destination);
+
+    true
+}
+
+/// Current unix time in milliseconds
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn now_secs() -> u64 {
+    now_ms() / 1000
+}
+
+impl Saving {
+    fn new(format: DbFormat) -> Self {
+        Saving {
+            format,
+            dirty: AtomicU64::new(0),
+            status: Mutex::new(SaveStatus {
+                last_save: now_secs(),
+                last_bgsave_try: 0,
+                last_bgsave_ok: true,
+                bgsave: None,
+            }),
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// Writes `snapshot` to `db_path`,
+    /// `dirty` is the number of writes included in the snapshot
+    fn save_snapshot(
+        &self,
+        db_path: &str,
+        snapshots: Vec<Snapshot>,
+        dirty: u64,
+        progress: &AtomicUsize,
+    ) -> Result<(), Error> {
+        let _writing = self.writing.lock().unwrap();
+        storage::save(db_path, self.format, &snapshots, progress)?;
+
+        self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        self.status.lock().unwrap().last_save = now_secs();
+
+        Ok(())
+    }
+}
+
+impl Expires {
+    fn from_map(by_key: ExpireTimes) -> Self {
+        let by_time = by_key
+            .iter()
+            .map(|(key, &when)| (when, key.clone()))
+            .collect();
+
+        Expires { by_key, by_time }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<u64> {
+        self.by_key.get(key).copied()
+    }
+
+    fn insert(&mut self, key: &[u8], when: u64) {
+        if let Some(old) = self.by_key.insert(key.into(), when) {
+            self.by_time.remove(&(old, key.into()));
+        }
+        self.by_time.insert((when, key.into()));
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<u64> {
+        let old = self.by_key.remove(key)?;
+        self.by_time.remove(&(old, key.into()));
+
+        Some(old)
+    }
+
+    /// Removes and returns the first key whose expiry time is at or before `now`
+    fn pop_expired(&mut self, now: u64) -> Option<ByteString> {
+        match self.by_time.first() {
+            Some((when, _)) if *when <= now => {
+                let (_, key) = self.by_time.pop_first()?;
+                self.by_key.remove(&key);
+                Some(key)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Keyspace {
+    fn get(&self, key: &[u8]) -> Option<&MemDS> {
+        self.data.get(key).map(|v| &**v)
+    }
+
+    /// Gets the value of `key` for modification,
+    /// the value is copied first if it is shared with a snapshot
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut MemDS> {
+        self.touch(key);
+        self.data.get_mut(key).map(Arc::make_mut)
+    }
+
+    /// Modifies the value of `key` with `f`, inserting `default()` first if the key does not
+    /// exist. The key is touched, and its creation notified, only once `f` succeeded: a value
+    /// inserted is removed again if `f` fails or leaves it an empty collection.
+    fn modify_or_insert_with<T, D, F>(&mut self, key: &[u8], default: D, f: F) -> Result<T, Error>
+    where
+        D: FnOnce() -> MemDS,
+        F: FnOnce(&mut MemDS) -> Result<T, Error>,
+    {
+        let created = !self.data.contains_key(key);
+        if created {
+            self.put(key, Arc::new(default()));
+        }
+        let value = Arc::make_mut(self.data.get_mut(key).expect("key was inserted"));
+        let result = f(value);
+        let empty = value.is_empty_collection();
+
+        match result {
+            Ok(_) if created && empty => {
+                self.delete(key);
+            }
+            Ok(_) => {
+                self.touch(key);
+                if created {
+                    self.notify(EventClass::New, "new", key);
+                }
+            }
+            Err(_) if created => {
+                self.delete(key);
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    fn insert(&mut self, key: &[u8], value: MemDS) {
+        self.touch(key);
+        if self.put(key, Arc::new(value)).is_none() {
+            self.notify(EventClass::New, "new", key);
+        }
+    }
+
+    /// Sets the value of `key` in the data, returns its previous value.
+    /// All the keys added to the data go through here to be ordered for SCAN.
+    fn put(&mut self, key: &[u8], value: Arc<MemDS>) -> Option<Arc<MemDS>> {
+        let old = self.data.insert(key.into(), value);
+        if old.is_none() {
+            self.scan_order.insert((scan_hash(key), key.into()));
+        }
+
+        old
+    }
+
+    /// Removes `key` from the data, returns its value.
+    /// All the keys removed from the data go through here, see `put`.
+    fn delete(&mut self, key: &[u8]) -> Option<Arc<MemDS>> {
+        let old = self.data.remove(key)?;
+        self.scan_order.remove(&(scan_hash(key), key.into()));
+
+        Some(old)
+    }
+
+    /// Removes `key` and returns its value
+    fn take(&mut self, key: &[u8]) -> Option<Arc<MemDS>> {
+        self.touch(key);
+        self.expires.remove(key);
+        self.delete(key)
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> bool {
+        self.expires.get(key).is_some_and(|when| when <= now)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        self.touch(key);
+        self.expires.remove(key);
+        self.delete(key).is_some()
+    }
+
+    /// Removes `key` once its collection became empty, which deletes the key for clients
+    fn remove_emptied(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.notify(EventClass::Generic, "del", key);
+    }
+
+    /// Publishes the `event` of `key`, if events of `class` are enabled
+    fn notify(&self, class: EventClass, event: &str, key: &[u8]) {
+        if let Some(notifier) = &self.notifier {
+            notifier
+                .pubsub
+                .notify_keyspace_event(notifier.db, class, event, key);
+        }
+    }
+
+    /// Changes the version of `key` if it is watched, for any modification of the key
+    /// including its expiry. Clients blocked on the key are served once the command is done
+    /// and clients caching it are sent its invalidation.
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            self.version += 1;
+            watched.version = self.version;
+        }
+        self.blocking.mark_ready(key);
+        if let Some(notifier) = &self.notifier {
+            notifier.tracking.invalidate(key);
+        }
+    }
+
+    /// Changes the version of all watched keys, when the whole keyspace is replaced
+    fn touch_all(&mut self) {
+        for watched in self.watched.values_mut() {
+            self.version += 1;
+            watched.version = self.version;
+        }
+        self.blocking.mark_all_ready();
+    }
+
+    /// Pops up to `count` elements from `end` of the list, see `Database::pop`
+    fn pop(
+        &mut self,
+        key: &[u8],
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Option<Vec<ByteString>>, Error> {
+        let list = match self.get_mut(key) {
+            Some(value) => value.list_mut(key)?,
+            None => return Ok(None),
+        };
+
+        let mut popped = Vec::with_capacity(count.min(list.len()));
+        while popped.len() < count {
+            let element = match end {
+                ListEnd::Head => list.pop_front(),
+                ListEnd::Tail => list.pop_back(),
+            };
+            match element {
+                Some(element) => popped.push(element),
+                None => break,
+            }
+        }
+        let empty = list.is_empty();
+        if !popped.is_empty() {
+            self.notify(EventClass::List, end.pop_event(), key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+
+        Ok(Some(popped))
+    }
+
+    /// Moves an element between lists, see `Database::lmove`
+    fn lmove(
+        &mut self,
+        source: &[u8],
+        destination: &[u8],
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<ByteString>, Error> {
+        self.expire_if_needed(destination, now_ms());
+
+        match self.get(source) {
+            Some(value) => value.list(source)?,
+            None => return Ok(None),
+        };
+        if let Some(value) = self.get(destination) {
+            value.list(destination)?;
+        }
+
+        let list = self.get_mut(source).unwrap().list_mut(source)?;
+        let element = match from {
+            ListEnd::Head => list.pop_front(),
+            ListEnd::Tail => list.pop_back(),
+        };
+        let element = element.expect("lists in the keyspace are never empty");
+        let empty = list.is_empty();
+        self.notify(EventClass::List, from.pop_event(), source);
+        if empty {
+            self.remove_emptied(source);
+        }
+
+        self.modify_or_insert_with(
+            destination,
+            || MemDS::List(ListDS::default()),
+            |value| {
+                let list = value.list_mut(destination)?;
+                match to {
+                    ListEnd::Head => list.push_front(element.clone()),
+                    ListEnd::Tail => list.push_back(element.clone()),
+                }
+                Ok(())
             },
-            Err(e) => {
-                tracing::error!("Failed to load data: {}", e);
-                Database {
-                    db_path,
-                    data: Mutex::new(Default::default()),
+        )?;
+        self.notify(EventClass::List, to.push_event(), destination);
+
+        Ok(Some(element))
+    }
+
+    /// Calls `f` with the sorted set of `key` to remove members, see `Database::with_zset_mut`
+    fn with_zset_mut<T, F>(&mut self, key: &[u8], default: T, event: &str, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut ZSetDS) -> T,
+    {
+        let zset = match self.get_mut(key) {
+            Some(value) => value.zset_mut(key)?,
+            None => return Ok(default),
+        };
+
+        let len = zset.len();
+        let result = f(zset);
+        let (removed, empty) = (zset.len() < len, zset.is_empty());
+        if removed {
+            self.notify(EventClass::SortedSet, event, key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+
+        Ok(result)
+    }
+
+    /// Pops up to `count` members with the lowest scores, see `Database::zpop`
+    fn zpop(
+        &mut self,
+        key: &[u8],
+        count: usize,
+        max: bool,
+    ) -> Result<Vec<(ByteString, f64)>, Error> {
+        let event = if max { "zpopmax" } else { "zpopmin" };
+        self.with_zset_mut(key, Vec::new(), event, |zset| {
+            let popped = zset.range(&ZRange::Rank(0, -1), max, 0, Some(count));
+            for (member, _) in &popped {
+                zset.remove(member);
+            }
+            popped
+        })
+    }
+
+    /// Does `pop` from `key` for a blocking command, returns None if the key does not exist
+    fn blocked_pop(&mut self, key: &[u8], pop: &BlockedPop) -> Result<Option<Popped>, Error> {
+        let popped = match pop {
+            BlockedPop::List(end) => self
+                .pop(key, *end, 1)?
+                .and_then(|mut elements| elements.pop())
+                .map(|element| Popped::List {
+                    key: key.into(),
+                    element,
+                }),
+            BlockedPop::Move {
+                destination,
+                from,
+                to,
+            } => self
+                .lmove(key, destination, *from, *to)?
+                .map(|element| Popped::Moved {
+                    key: key.into(),
+                    element,
+                }),
+            BlockedPop::SortedSet { max } => {
+                self.zpop(key, 1, *max)?
+                    .pop()
+                    .map(|(member, score)| Popped::SortedSet {
+                        key: key.into(),
+                        member,
+                        score,
+                    })
+            }
+        };
+
+        Ok(popped)
+    }
+
+    /// Serves the clients blocked on the keys modified since the last call, oldest first,
+    /// until the keys have nothing left to pop. Keys of another type keep their clients
+    /// blocked. Returns the commands equivalent to the pops, to log to the AOF.
+    fn serve_blocked(&mut self) -> Vec<Vec<Vec<u8>>> {
+        let mut records = Vec::new();
+        loop {
+            // pops modify keys, moving to a list with blocked clients makes it ready again
+            let ready = self.blocking.take_ready();
+            if ready.is_empty() {
+                break;
+            }
+
+            for key in ready {
+                self.expire_if_needed(&key, now_ms());
+                while let Some((id, pop)) = self.blocking.first(&key) {
+                    if !self.get(&key).is_some_and(|value| pop.accepts(value)) {
+                        break;
+                    }
+                    let pop = pop.clone();
+                    match self.blocked_pop(&key, &pop) {
+                        Ok(Some(popped)) => {
+                            records.push(pop.aof_record(&key));
+                            self.blocking.wake(id, Ok(popped));
+                        }
+                        Ok(None) => break,
+                        Err(e) => self.blocking.wake(id, Err(e)),
+                    }
+                }
+            }
+        }
+
+        records
+    }
+
+    /// Gets the members of the sorted set or set at `key` with their scores,
+    /// members of a set have a score of 1
+    fn scored_members(&self, key: &[u8]) -> Result<ScoredMembers<'_>, Error> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(MemDS::Set(set)) => Ok(set.iter().map(|member| (member, 1.0)).collect()),
+            Some(value) => Ok(value
+                .zset(key)?
+                .iter()
+                .map(|(member, score)| (Cow::Borrowed(member.as_bytes()), score))
+                .collect()),
+        }
+    }
+
+    /// Combines the sorted sets or sets at `keys`, scores are multiplied by their key's
+    /// weight (1 by default) then aggregated. The scores of a difference are not changed.
+    fn zcombine(
+        &self,
+        keys: &[&[u8]],
+        weights: Option<&[f64]>,
+        operation: SetOperation,
+        aggregate: Aggregate,
+    ) -> Result<ZSetDS, Error> {
+        let operands = keys
+            .iter()
+            .map(|key| self.scored_members(key))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let weight = |i: usize| match (weights, operation) {
+            (_, SetOperation::Diff) | (None, _) => 1.0,
+            (Some(weights), _) => weights[i],
+        };
+
+        let mut operands = operands.into_iter().enumerate();
+        let mut result: HashMap<Cow<[u8]>, f64> = match operands.next() {
+            Some((_, first)) => first
+                .into_iter()
+                .map(|(member, score)| (member, zero_if_nan(score * weight(0))))
+                .collect(),
+            None => HashMap::new(),
+        };
+        for (i, operand) in operands {
+            match operation {
+                SetOperation::Union => {
+                    for (member, score) in operand {
+                        let score = zero_if_nan(score * weight(i));
+                        result
+                            .entry(member)
+                            .and_modify(|old| *old = aggregate.apply(*old, score))
+                            .or_insert(score);
+                    }
+                }
+                SetOperation::Inter => {
+                    let operand: HashMap<_, _> = operand.into_iter().collect();
+                    result.retain(|member, old| match operand.get(member) {
+                        Some(score) => {
+                            *old = aggregate.apply(*old, zero_if_nan(score * weight(i)));
+                            true
+                        }
+                        None => false,
+                    });
+                }
+                SetOperation::Diff => {
+                    for (member, _) in operand {
+                        result.remove(&member);
+                    }
                 }
             }
         }
+
+        Ok(result
+            .into_iter()
+            .map(|(member, score)| (member.into(), score))
+            .collect::<Vec<_>>()
+            .into())
     }
 
-    pub fn incr(&self, key: &str) -> Result<i64, Error> {
-        let mut lock = self.data.lock().unwrap();
-        let string = lock
-            .entry(key.to_string())
-            .or_insert(MemDS::String(StringDS::from("0")));
-        string.string_mut(key)?.incr()
+    /// Combines the sets at `keys`, missing keys are empty sets
+    fn scombine(&self, keys: &[&[u8]], operation: SetOperation) -> Result<SetDS, Error> {
+        let operands = keys
+            .iter()
+            .map(|key| self.get(key).map(|value| value.set(key)).transpose())
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut operands = operands.into_iter();
+        let mut result: HashSet<Cow<[u8]>> = match operands.next().flatten() {
+            Some(first) => first.iter().collect(),
+            None => HashSet::new(),
+        };
+        for operand in operands {
+            match (operation, operand) {
+                (SetOperation::Union, Some(set)) => result.extend(set.iter()),
+                (SetOperation::Inter, Some(set)) => result.retain(|m| set.contains(m.as_ref())),
+                (SetOperation::Inter, None) => result.clear(),
+                (SetOperation::Diff, Some(set)) => result.retain(|m| !set.contains(m.as_ref())),
+                (_, None) => {}
+            }
+        }
+
+        Ok(SetDS::from_members(result, &self.set_limits))
     }
 
-    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
-        self.data
-            .lock()
-            .unwrap()
-            .get(key)
-            .map(|v| v.string(key).map(StringDS::fetch))
-            .transpose()
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            data: self.data.clone(),
+            expires: self.expires.by_key.clone(),
+        }
     }
 
-    pub fn set(&self, key: &str, value: &str) -> Result<(), Error> {
-        self.data
-            .lock()
-            .unwrap()
-            .insert(key.to_owned(), MemDS::String(StringDS::from(value)));
+    /// Lazily removes the key if its expiry time has passed,
+    /// returns true if the key was removed
+    fn expire_if_needed(&mut self, key: &[u8], now: u64) -> bool {
+        match self.expires.get(key) {
+            Some(when) if when <= now => {
+                self.remove(key);
+                self.notify(EventClass::Expired, "expired", key);
+                true
+            }
+            _ => false,
+        }
+    }
+}
 
-        Ok(())
+impl Database {
+    /// Creates the databases, returns database 0
+    fn with_keyspaces(db_path: String, format: DbFormat, keyspaces: Vec<Keyspace>) -> Self {
+        let pubsub = Arc::new(PubSub::default());
+        let tracking = Arc::new(Tracking::default());
+        let keyspaces = keyspaces
+            .into_iter()
+            .enumerate()
+            .map(|(db, mut keyspace)| {
+                keyspace.notifier = Some(Notifier {
+                    db,
+                    pubsub: pubsub.clone(),
+                    tracking: tracking.clone(),
+                });
+                Mutex::new(keyspace)
+            })
+            .collect();
+
+        Database {
+            index: 0,
+            shared: Arc::new(Shared {
+                db_path,
+                keyspaces,
+                aof: None,
+                saving: Arc::new(Saving::new(format)),
+                access: Arc::new(RwLock::new(())),
+                pubsub,
+                next_blocked_id: AtomicU64::new(0),
+                blocked_clients: AtomicUsize::new(0),
+                tracking,
+                scripts: Scripts::default(),
+            }),
+        }
     }
 
-    pub fn sadd(&self, key: &str, elements: &[&str]) -> Result<usize, Error> {
-        let mut lock = self.data.lock().unwrap();
-        let set = lock
-            .entry(key.to_string())
-            .or_insert_with(|| MemDS::Set(SetDS::default()));
-        let added = set.set_mut(key)?.add(elements.iter());
+    /// Creates the default number of databases with the data loaded from the db file at
+    /// `db_path`, saved in the memds format. Returns database 0.
+    pub fn new(db_path: String) -> Result<Self, Error> {
+        Database::open(db_path, DbFormat::Memds, DEFAULT_DATABASES)
+    }
 
-        Ok(added)
+    /// Creates `databases` databases with the data loaded from the db file at `db_path`,
+    /// in any supported format, and saved in `format`. Returns database 0.
+    /// A db file of an older memds format version is migrated to the current one.
+    /// Fails if the db file can not be loaded or has more databases, instead of discarding
+    /// its data.
+    pub fn open(db_path: String, format: DbFormat, databases: usize) -> Result<Self, Error> {
+        let (snapshots, file_format) = match storage::load(&db_path)? {
+            Some(loaded) => loaded,
+            None => (Vec::new(), FileFormat::Memds(storage::FORMAT_VERSION)),
+        };
+        if snapshots.len() > databases {
+            return Err(Error::Handle(format!(
+                "Failed to load db file with data in database {}, only {} databases are configured",
+                snapshots.len() - 1,
+                databases
+            )));
+        }
+        let mut keyspaces: Vec<Keyspace> = snapshots
+            .into_iter()
+            .map(|snapshot| Keyspace {
+                scan_order: snapshot
+                    .data
+                    .keys()
+                    .map(|key| (scan_hash(key), key.clone()))
+                    .collect(),
+                data: snapshot.data,
+                expires: Expires::from_map(snapshot.expires),
+                ..Default::default()
+            })
+            .collect();
+        keyspaces.resize_with(databases, Default::default);
+        let db = Database::with_keyspaces(db_path, format, keyspaces);
+
+        match (format, file_format) {
+            (DbFormat::Memds, FileFormat::Memds(version)) if version < storage::FORMAT_VERSION => {
+                tracing::info!(
+                    "Migrating db file from format version {} to {}",
+                    version,
+                    storage::FORMAT_VERSION
+                );
+                db.save()?;
+            }
+            _ => {}
+        }
+
+        Ok(db)
     }
 
-    pub fn smembers(&self, key: &str) -> Result<Option<Vec<String>>, Error> {
-        let lock = self.data.lock().unwrap();
+    /// Creates the database from config.
+    /// With append-only enabled, the data is loaded by replaying the AOF if it exists,
+    /// otherwise it is loaded from the snapshot and the AOF is created from it.
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        if !config.appendonly {
+            let db = Database::open(config.db_path.clone(), config.db_format, config.databases)?;
+            db.set_limits(config.set_limits);
+            db.configure_shared(config);
+            return Ok(db);
+        }
 
-        match lock.get(key) {
-            None => Ok(None),
-            Some(set) => Ok(Some(set.set(key)?.members())),
+        let mut db = if Path::new(&config.aof_path).exists() {
+            let db = Database::with_keyspaces(
+                config.db_path.clone(),
+                config.db_format,
+                (0..config.databases)
+                    .map(|_| Keyspace {
+                        set_limits: config.set_limits,
+                        ..Default::default()
+                    })
+                    .collect(),
+            );
+            let mut write_buf = Vec::new();
+            let mut client = ClientState::default();
+            let count = wal::replay(&config.aof_path, |args| {
+                write_buf.clear();
+                if command::parse_and_handle(args, &db, &mut client, &mut write_buf)? {
+                    tracing::warn!(
+                        "Failed to replay command {:?}: {}",
+                        command::DebugArgs(args),
+                        String::from_utf8_lossy(&write_buf)
+                    );
+                }
+                Ok(())
+            })?;
+            tracing::info!("Replayed {} commands from AOF", count);
+            // the replayed writes are already persisted
+            db.shared.saving.dirty.store(0, Ordering::Relaxed);
+
+            db
+        } else {
+            let db = Database::open(config.db_path.clone(), config.db_format, config.databases)?;
+            db.set_limits(config.set_limits);
+            let (snapshots, _) = db.snapshot();
+            wal::rewrite(&config.aof_path, &snapshots)?;
+
+            db
+        };
+
+        // replaying selects other databases with clones that are dropped by now
+        Arc::get_mut(&mut db.shared)
+            .expect("databases are not shared before the AOF is opened")
+            .aof = Some(Arc::new(Mutex::new(Wal::open(
+            &config.aof_path,
+            config.appendfsync,
+        )?)));
+        db.configure_shared(config);
+
+        Ok(db)
+    }
+
+    /// Applies the config of the state shared by all databases, pub/sub and scripts
+    fn configure_shared(&self, config: &Config) {
+        let pubsub = self.pubsub();
+        pubsub.set_buffer_limit(config.pubsub_buffer_limit);
+        pubsub.set_keyspace_events(config.notify_keyspace_events);
+        self.scripts().set_time_limit(config.lua_time_limit);
+    }
+
+    fn set_limits(&self, set_limits: SetLimits) {
+        for keyspace in &self.shared.keyspaces {
+            keyspace.lock().unwrap().set_limits = set_limits;
         }
     }
 
-    pub fn save(&self) -> Result<(), Error> {
-        let lock = self.data.lock().unwrap();
+    /// Number of the database
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Number of databases
+    pub fn databases(&self) -> usize {
+        self.shared.keyspaces.len()
+    }
+
+    /// Gets the database numbered `index`, sharing the keyspaces of this one
+    pub fn select(&self, index: usize) -> Result<Database, Error> {
+        if index >= self.databases() {
+            return Err(Error::Handle("ERR DB index is out of range".to_string()));
+        }
+
+        Ok(Database {
+            index,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Channels subscribed by the clients
+    pub fn pubsub(&self) -> &PubSub {
+        &self.shared.pubsub
+    }
+
+    /// Keys cached by the clients with CLIENT TRACKING
+    pub fn tracking(&self) -> &Tracking {
+        &self.shared.tracking
+    }
+
+    /// Scripts cached by EVAL and SCRIPT LOAD, with the script running
+    pub fn scripts(&self) -> &Scripts {
+        &self.shared.scripts
+    }
+
+    fn keyspace(&self) -> &Mutex<Keyspace> {
+        &self.shared.keyspaces[self.index]
+    }
+
+    /// Locks the keyspaces of databases `a` and `b` in order, `a` and `b` must differ
+    fn lock_two(&self, a: usize, b: usize) -> (MutexGuard<'_, Keyspace>, MutexGuard<'_, Keyspace>) {
+        let keyspaces = &self.shared.keyspaces;
+        if a < b {
+            let lock_a = keyspaces[a].lock().unwrap();
+            (lock_a, keyspaces[b].lock().unwrap())
+        } else {
+            let lock_b = keyspaces[b].lock().unwrap();
+            (keyspaces[a].lock().unwrap(), lock_b)
+        }
+    }
+
+    /// Shares access with other commands, see `lock_exclusive`.
+    /// Waits while another command has exclusive access.
+    pub(crate) async fn lock_shared(&self) -> OwnedRwLockReadGuard<()> {
+        self.shared.access.clone().read_owned().await
+    }
+
+    /// Excludes other commands while a transaction or a script is executed.
+    /// Waits while other commands have access.
+    pub(crate) async fn lock_exclusive(&self) -> OwnedRwLockWriteGuard<()> {
+        self.shared.access.clone().write_owned().await
+    }
+
+    /// Watches `key` for modifications, returns its current version.
+    /// A key expired in the meantime counts as modified.
+    pub fn watch(&self, key: &[u8]) -> u64 {
+        let mut lock = self.lock_for(key);
+        let version = lock.version;
+        let watched = lock.watched.entry(key.into()).or_insert(WatchedVersion {
+            version,
+            watchers: 0,
+        });
+        watched.watchers += 1;
+
+        watched.version
+    }
+
+    /// Stops a watch of `key` started by `watch`
+    pub fn unwatch(&self, key: &[u8]) {
+        let mut lock = self.keyspace().lock().unwrap();
+        if let Some(watched) = lock.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                lock.watched.remove(key);
+            }
+        }
+    }
+
+    /// Whether `key` was modified since `watch` returned `version`
+    pub fn watch_modified(&self, key: &[u8], version: u64) -> bool {
+        self.lock_for(key)
+            .watched
+            .get(key)
+            .is_none_or(|watched| watched.version != version)
+    }
+
+    /// Pops for `pop` from the first of `keys` holding a value. If none does, the client is
+    /// blocked on `keys` until one of them is modified, unless `block` is false.
+    /// Fails if a key holding a value is of the wrong type.
+    pub fn pop_or_block(
+        &self,
+        keys: &[&[u8]],
+        pop: BlockedPop,
+        block: bool,
+    ) -> Result<PopOrBlock, Error> {
+        let mut lock = self.lock_for_keys(keys);
+        for key in keys {
+            if let Some(popped) = lock.blocked_pop(key, &pop)? {
+                return Ok(PopOrBlock::Popped(popped));
+            }
+        }
+        if !block {
+            return Ok(PopOrBlock::Empty);
+        }
+
+        let id = self.shared.next_blocked_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        lock.blocking.add(id, keys, pop, sender);
+        self.shared.blocked_clients.fetch_add(1, Ordering::Relaxed);
+
+        Ok(PopOrBlock::Blocked(Blocked {
+            db: self.index,
+            id,
+            receiver,
+            deadline: None,
+            resp3: false,
+        }))
+    }
+
+    /// Unblocks client `id` blocked by `pop_or_block`,
+    /// returns false if a pop was already done for it
+    pub fn unblock(&self, id: u64) -> bool {
+        let removed = self.keyspace().lock().unwrap().blocking.remove(id);
+        if removed {
+            self.shared.blocked_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        removed
+    }
+
+    /// Pops for the clients blocked on keys modified since the last call, in all databases.
+    /// Called once a command is done, so a client is never served in the middle of a
+    /// command or transaction.
+    pub fn serve_blocked(&self) {
+        if self.shared.blocked_clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        // the pops are logged in the order they are done, like write commands
+        let mut aof = self.aof();
+        for (index, keyspace) in self.shared.keyspaces.iter().enumerate() {
+            let mut lock = keyspace.lock().unwrap();
+            let blocked = lock.blocking.len();
+            let records = lock.serve_blocked();
+            self.shared
+                .blocked_clients
+                .fetch_sub(blocked - lock.blocking.len(), Ordering::Relaxed);
+
+            for record in records {
+                if let Some(aof) = &mut aof {
+                    if let Err(e) = aof.append(index, &record) {
+                        tracing::error!("Failed to log pop of blocked client to AOF: {}", e);
+                    }
+                }
+                self.record_write();
+            }
+        }
+    }
+
+    /// Locks the append-only log, returns None if append-only is disabled
+    pub(crate) fn aof(&self) -> Option<MutexGuard<'_, Wal>> {
+        self.shared.aof.as_ref().map(|aof| aof.lock().unwrap())
+    }
+
+    /// Flushes the append-only log to disk
+    pub fn aof_fsync(&self) -> Result<(), Error> {
+        match self.aof() {
+            Some(mut aof) => aof.fsync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts compacting the append-only log in a background thread.
+    /// The log is rewritten from a snapshot of the keyspace while new writes are buffered,
+    /// the buffered writes are appended to the compacted log before it replaces the current one.
+    pub fn bgrewriteaof(&self) -> Result<JoinHandle<()>, Error> {
+        let aof = self
+            .shared
+            .aof
+            .clone()
+            .ok_or_else(|| Error::Handle("ERR append only file is disabled".to_string()))?;
+
+        let (path, snapshots) = {
+            let mut wal = aof.lock().unwrap();
+            wal.start_rewrite()?;
+            (wal.path().to_owned(), self.snapshot().0)
+        };
+
+        let rewrite_aof = aof.clone();
+        thread::Builder::new()
+            .name("aof-rewrite".to_string())
+            .spawn(move || {
+                let rewritten = wal::write_rewrite(&path, &snapshots);
+                match rewrite_aof.lock().unwrap().finish_rewrite(rewritten) {
+                    Ok(()) => tracing::info!("Background AOF rewrite finished"),
+                    Err(e) => tracing::error!("Background AOF rewrite failed: {}", e),
+                }
+            })
+            .map_err(|e| {
+                let message = format!("ERR failed to start AOF rewrite {}", e);
+                // stop buffering, the rewrite will never finish
+                let _ = aof
+                    .lock()
+                    .unwrap()
+                    .finish_rewrite(Err(Error::Handle(message.clone())));
+                Error::Handle(message)
+            })
+    }
+
+    /// Locks the keyspace and lazily expires `key`
+    fn lock_for(&self, key: &[u8]) -> MutexGuard<'_, Keyspace> {
+        let mut lock = self.keyspace().lock().unwrap();
+        lock.expire_if_needed(key, now_ms());
+
+        lock
+    }
+
+    /// Locks the keyspace with all `keys` lazily expired
+    fn lock_for_keys(&self, keys: &[&[u8]]) -> MutexGuard<'_, Keyspace> {
+        let mut lock = self.keyspace().lock().unwrap();
+        let now = now_ms();
+        for key in keys {
+            lock.expire_if_needed(key, now);
+        }
+
+        lock
+    }
+
+    pub fn incr(&self, key: &[u8]) -> Result<i64, Error> {
+        self.incr_by(key, 1)
+    }
+
+    /// Increments the integer value of `key` by `increment`, a missing key counts as 0
+    pub fn incr_by(&self, key: &[u8], increment: i64) -> Result<i64, Error> {
+        let mut lock = self.lock_for(key);
+        let result = lock.modify_or_insert_with(
+            key,
+            || MemDS::String(StringDS::from("0")),
+            |value| value.string_mut(key)?.incr_by(increment),
+        );
+        if result.is_ok() {
+            lock.notify(EventClass::String, "incrby", key);
+        }
+
+        result
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<ByteString>, Error> {
+        self.lock_for(key)
+            .get(key)
+            .map(|v| v.string(key).map(StringDS::fetch))
+            .transpose()
+    }
+
+    /// Increments the float value of `key` by `increment`, a missing key counts as 0.
+    /// Returns the new value formatted as it is stored.
+    pub fn incr_by_float(&self, key: &[u8], increment: f64) -> Result<String, Error> {
+        let mut lock = self.lock_for(key);
+        let result = lock.modify_or_insert_with(
+            key,
+            || MemDS::String(StringDS::from("0")),
+            |value| value.string_mut(key)?.incr_by_float(increment),
+        );
+        if result.is_ok() {
+            lock.notify(EventClass::String, "incrbyfloat", key);
+        }
+
+        result
+    }
+
+    /// Calls `f` with the string of `key`, returns `default` if the key does not exist
+    fn with_string<T, F>(&self, key: &[u8], default: T, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&StringDS) -> T,
+    {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(default),
+            Some(string) => Ok(f(string.string(key)?)),
+        }
+    }
+
+    /// Appends `value` to the string of `key`, which is created if it does not exist.
+    /// Returns the length after the append.
+    pub fn append(&self, key: &[u8], value: &[u8]) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        let result = lock.modify_or_insert_with(
+            key,
+            || MemDS::String(StringDS::from("")),
+            |string| string.string_mut(key)?.append(value),
+        );
+        if result.is_ok() {
+            lock.notify(EventClass::String, "append", key);
+        }
+
+        result
+    }
+
+    pub fn strlen(&self, key: &[u8]) -> Result<usize, Error> {
+        self.with_string(key, 0, StringDS::len)
+    }
+
+    /// Gets the bytes of the string from `start` to `end` inclusive, see `StringDS::get_range`
+    pub fn getrange(&self, key: &[u8], start: i64, end: i64) -> Result<ByteString, Error> {
+        self.with_string(key, ByteString::new(), |s| s.get_range(start, end))
+    }
+
+    /// Overwrites the string from `offset` with `value`, see `StringDS::set_range`.
+    /// A missing key is created unless `value` is empty.
+    pub fn setrange(&self, key: &[u8], offset: usize, value: &[u8]) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        if value.is_empty() && lock.get(key).is_none() {
+            return Ok(0);
+        }
+        let result = lock.modify_or_insert_with(
+            key,
+            || MemDS::String(StringDS::from("")),
+            |string| string.string_mut(key)?.set_range(offset, value),
+        );
+        if result.is_ok() && !value.is_empty() {
+            lock.notify(EventClass::String, "setrange", key);
+        }
+
+        result
+    }
+
+    /// Gets the values of `keys`, None for keys that are missing or not strings
+    pub fn mget(&self, keys: &[&[u8]]) -> Vec<Option<ByteString>> {
+        let lock = self.lock_for_keys(keys);
+        keys.iter()
+            .map(|key| match lock.get(key) {
+                Some(MemDS::String(s)) => Some(s.fetch()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sets the keys to the string values and removes their expiry.
+    /// If `nx` is true, nothing is set when any key exists.
+    /// Returns whether the keys were set.
+    pub fn mset(&self, key_values: &[(&[u8], &[u8])], nx: bool) -> bool {
+        let keys: Vec<&[u8]> = key_values.iter().map(|(key, _)| *key).collect();
+        let mut lock = self.lock_for_keys(&keys);
+        if nx && keys.iter().any(|key| lock.get(key).is_some()) {
+            return false;
+        }
+
+        for (key, value) in key_values {
+            lock.expires.remove(key);
+            lock.insert(key, MemDS::String(StringDS::from(value)));
+            lock.notify(EventClass::String, "set", key);
+        }
+
+        true
+    }
+
+    /// Gets the string of `key` then deletes the key
+    pub fn getdel(&self, key: &[u8]) -> Result<Option<ByteString>, Error> {
+        let mut lock = self.lock_for(key);
+        let value = match lock.get(key) {
+            Some(value) => value.string(key)?.fetch(),
+            None => return Ok(None),
+        };
+        lock.remove(key);
+        lock.notify(EventClass::Generic, "del", key);
+
+        Ok(Some(value))
+    }
+
+    /// Gets the string of `key` and updates its expiry if `expiry` is given,
+    /// the key is deleted if the expiry time is already in the past
+    pub fn getex(
+        &self,
+        key: &[u8],
+        expiry: Option<SetExpiry>,
+    ) -> Result<Option<ByteString>, Error> {
+        let mut lock = self.lock_for(key);
+        let value = match lock.get(key) {
+            Some(value) => value.string(key)?.fetch(),
+            None => return Ok(None),
+        };
+        match expiry {
+            None | Some(SetExpiry::Keep) => {}
+            Some(SetExpiry::Persist) => {
+                lock.touch(key);
+                if lock.expires.remove(key).is_some() {
+                    lock.notify(EventClass::Generic, "persist", key);
+                }
+            }
+            Some(SetExpiry::At(when)) if when <= now_ms() => {
+                lock.remove(key);
+                lock.notify(EventClass::Generic, "del", key);
+            }
+            Some(SetExpiry::At(when)) => {
+                lock.touch(key);
+                lock.expires.insert(key, when);
+                lock.notify(EventClass::Generic, "expire", key);
+            }
+        }
+
+        Ok(Some(value))
+    }
+
+    /// Computes the longest common subsequence of the strings of `key1` and `key2`,
+    /// see `memds::lcs`. Missing keys are empty strings.
+    pub fn lcs(
+        &self,
+        key1: &[u8],
+        key2: &[u8],
+        min_match_len: usize,
+    ) -> Result<(ByteString, Vec<LcsMatch>), Error> {
+        let lock = self.lock_for_keys(&[key1, key2]);
+        let string = |key| -> Result<Cow<[u8]>, Error> {
+            match lock.get(key) {
+                Some(value) => Ok(value.string(key)?.as_bytes()),
+                None => Ok(Cow::Borrowed(b"")),
+            }
+        };
+        let (result, matches) = memds::lcs(&string(key1)?, &string(key2)?, min_match_len);
+
+        Ok((result.into(), matches))
+    }
+
+    /// Sets `key` to the string `value` if `cond` holds.
+    /// When `get` is true, the previous value is fetched and the key must hold a string.
+    pub fn set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        cond: SetCondition,
+        expiry: SetExpiry,
+        get: bool,
+    ) -> Result<SetOutcome, Error> {
+        let mut lock = self.lock_for(key);
+
+        let existing = lock.get(key);
+        let old = match (&existing, get) {
+            (Some(v), true) => Some(v.string(key)?.fetch()),
+            _ => None,
+        };
+        let applied = match cond {
+            SetCondition::Always => true,
+            SetCondition::NotExists => existing.is_none(),
+            SetCondition::Exists => existing.is_some(),
+        };
+        if !applied {
+            return Ok(SetOutcome { applied, old });
+        }
+
+        match lock.get_mut(key) {
+            Some(MemDS::String(s)) => s.set(value),
+            _ => lock.insert(key, MemDS::String(StringDS::from(value))),
+        }
+        lock.notify(EventClass::String, "set", key);
+        match expiry {
+            SetExpiry::Persist => {
+                lock.expires.remove(key);
+            }
+            SetExpiry::Keep => {}
+            SetExpiry::At(when) => {
+                lock.expires.insert(key, when);
+                lock.notify(EventClass::Generic, "expire", key);
+            }
+        }
+
+        Ok(SetOutcome { applied, old })
+    }
+
+    /// Sets the expiry of `key` to `when` (unix time in milliseconds) if `cond` holds,
+    /// the key is deleted if `when` is already in the past.
+    /// Returns false if the key does not exist or the condition is not met.
+    pub fn expire(&self, key: &[u8], when: i64, cond: ExpiryCondition) -> Result<bool, Error> {
+        let now = now_ms();
+        let mut lock = self.lock_for(key);
+
+        if !lock.data.contains_key(key) {
+            return Ok(false);
+        }
+
+        let current = lock.expires.get(key);
+        let applied = match (cond, current) {
+            (ExpiryCondition::Always, _) => true,
+            (ExpiryCondition::NotVolatile, current) => current.is_none(),
+            (ExpiryCondition::Volatile, current) => current.is_some(),
+            (ExpiryCondition::Greater, Some(current)) => when > current as i64,
+            (ExpiryCondition::Greater, None) => false,
+            (ExpiryCondition::Less, Some(current)) => when < current as i64,
+            (ExpiryCondition::Less, None) => true,
+        };
+        if !applied {
+            return Ok(false);
+        }
+
+        if when <= now as i64 {
+            lock.remove(key);
+            lock.notify(EventClass::Generic, "del", key);
+        } else {
+            lock.touch(key);
+            lock.expires.insert(key, when as u64);
+            lock.notify(EventClass::Generic, "expire", key);
+        }
+
+        Ok(true)
+    }
+
+    /// Gets the expiry time of `key` in unix time milliseconds,
+    /// returns None if the key does not exist, Some(None) if the key has no expiry
+    pub fn expire_time(&self, key: &[u8]) -> Option<Option<u64>> {
+        let lock = self.lock_for(key);
+
+        if lock.data.contains_key(key) {
+            Some(lock.expires.get(key))
+        } else {
+            None
+        }
+    }
+
+    /// Removes the expiry of `key`, returns true if the key had an expiry
+    pub fn persist(&self, key: &[u8]) -> bool {
+        let mut lock = self.lock_for(key);
+        let persisted = lock.expires.remove(key).is_some();
+        if persisted {
+            lock.touch(key);
+            lock.notify(EventClass::Generic, "persist", key);
+        }
+
+        persisted
+    }
+
+    /// Deletes up to `limit` keys whose expiry time has passed, in all databases,
+    /// returns number of deleted keys. The caller holds shared access to the databases, see
+    /// `lock_shared`.
+    pub fn active_expire(&self, limit: usize) -> usize {
+        let now = now_ms();
+
+        let mut count = 0;
+        for keyspace in &self.shared.keyspaces {
+            let mut lock = keyspace.lock().unwrap();
+            while count < limit {
+                match lock.expires.pop_expired(now) {
+                    Some(key) => {
+                        lock.touch(&key);
+                        lock.delete(&key);
+                        lock.notify(EventClass::Expired, "expired", &key);
+                        count += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        count
+    }
+
+    /// Gets the name of the encoding of the value of `key`, see `MemDS::encoding`
+    pub fn object_encoding(&self, key: &[u8]) -> Option<&'static str> {
+        self.lock_for(key).get(key).map(MemDS::encoding)
+    }
+
+    /// Serializes the value of `key` in the RDB format, returns None if the key does not exist
+    pub fn dump(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lock_for(key).get(key).map(rdb::dump)
+    }
+
+    /// Creates `key` with the value deserialized from a DUMP `payload`,
+    /// expiring at `expire_at` (unix time in milliseconds) if given.
+    /// Fails if the key exists, unless `replace` is true.
+    pub fn restore(
+        &self,
+        key: &[u8],
+        payload: &[u8],
+        expire_at: Option<u64>,
+        replace: bool,
+    ) -> Result<(), Error> {
+        let value = rdb::restore(payload)?;
+        let mut lock = self.lock_for(key);
+
+        if !replace && lock.get(key).is_some() {
+            return Err(Error::Handle(
+                "BUSYKEY Target key name already exists.".to_string(),
+            ));
+        }
+
+        lock.remove(key);
+        match expire_at {
+            // already expired, the key is not created
+            Some(when) if when <= now_ms() => {}
+            Some(when) => {
+                lock.insert(key, value);
+                lock.expires.insert(key, when);
+                lock.notify(EventClass::Generic, "restore", key);
+            }
+            None => {
+                lock.insert(key, value);
+                lock.notify(EventClass::Generic, "restore", key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes `keys`, returns the number of keys that existed
+    pub fn del(&self, keys: &[&[u8]]) -> usize {
+        let mut lock = self.lock_for_keys(keys);
+
+        keys.iter()
+            .filter(|key| {
+                let removed = lock.remove(key);
+                if removed {
+                    lock.notify(EventClass::Generic, "del", key);
+                }
+                removed
+            })
+            .count()
+    }
+
+    /// Deletes `keys` like `del`, the values are freed in a background thread
+    pub fn unlink(&self, keys: &[&[u8]]) -> usize {
+        let removed: Vec<_> = {
+            let mut lock = self.lock_for_keys(keys);
+            keys.iter()
+                .filter_map(|key| {
+                    let value = lock.take(key)?;
+                    lock.notify(EventClass::Generic, "del", key);
+                    Some(value)
+                })
+                .collect()
+        };
+
+        let count = removed.len();
+        if count > 0 {
+            drop_in_background(removed);
+        }
+
+        count
+    }
+
+    /// Counts the existing keys of `keys`, a key given several times is counted as many times
+    pub fn exists(&self, keys: &[&[u8]]) -> usize {
+        let lock = self.lock_for_keys(keys);
+
+        keys.iter()
+            .filter(|key| lock.data.contains_key(**key))
+            .count()
+    }
+
+    /// Gets the name of the type of the value of `key`, see `MemDS::type_name`
+    pub fn key_type(&self, key: &[u8]) -> Option<&'static str> {
+        self.lock_for(key).get(key).map(MemDS::type_name)
+    }
+
+    /// Renames `key` to `new_key` with its expiry, overwriting `new_key` unless `nx` is true.
+    /// Returns false if `nx` is true and `new_key` exists.
+    pub fn rename(&self, key: &[u8], new_key: &[u8], nx: bool) -> Result<bool, Error> {
+        let mut lock = self.lock_for_keys(&[key, new_key]);
+
+        if !lock.data.contains_key(key) {
+            return Err(Error::Handle("ERR no such key".to_string()));
+        }
+        if nx && lock.data.contains_key(new_key) {
+            return Ok(false);
+        }
+        if key == new_key {
+            return Ok(true);
+        }
+
+        let expire_at = lock.expires.get(key);
+        if let Some(value) = lock.take(key) {
+            lock.remove(new_key);
+            lock.put(new_key, value);
+            if let Some(when) = expire_at {
+                lock.expires.insert(new_key, when);
+            }
+            lock.notify(EventClass::Generic, "rename_from", key);
+            lock.notify(EventClass::Generic, "rename_to", new_key);
+        }
+
+        Ok(true)
+    }
+
+    /// Copies the value of `source` with its expiry to `destination` in database `db`,
+    /// this database if None, overwriting `destination` only if `replace` is true.
+    /// Returns false if `source` does not exist or `destination` exists and is not replaced.
+    pub fn copy(
+        &self,
+        source: &[u8],
+        destination: &[u8],
+        db: Option<usize>,
+        replace: bool,
+    ) -> Result<bool, Error> {
+        let db = match db {
+            Some(db) if db != self.index => self.select(db)?.index,
+            _ => {
+                let mut lock = self.lock_for_keys(&[source, destination]);
+                if source == destination {
+                    return Ok(false);
+                }
+                return Ok(copy_between(&mut lock, None, source, destination, replace));
+            }
+        };
+
+        let (mut lock, mut target) = self.lock_two(self.index, db);
+        Ok(copy_between(
+            &mut lock,
+            Some(&mut target),
+            source,
+            destination,
+            replace,
+        ))
+    }
+
+    /// Moves `key` with its expiry to database `db`.
+    /// Returns false if `key` does not exist or already exists in `db`.
+    pub fn move_key(&self, key: &[u8], db: usize) -> Result<bool, Error> {
+        let db = self.select(db)?.index;
+        if db == self.index {
+            return Err(Error::Handle(
+                "ERR source and destination objects are the same".to_string(),
+            ));
+        }
+
+        let (mut lock, mut target) = self.lock_two(self.index, db);
+        let now = now_ms();
+        lock.expire_if_needed(key, now);
+        target.expire_if_needed(key, now);
+        if !lock.data.contains_key(key) || target.data.contains_key(key) {
+            return Ok(false);
+        }
+
+        let expire_at = lock.expires.remove(key);
+        let value = lock.take(key).expect("key exists");
+        target.touch(key);
+        target.put(key, value);
+        if let Some(when) = expire_at {
+            target.expires.insert(key, when);
+        }
+        lock.notify(EventClass::Generic, "move_from", key);
+        target.notify(EventClass::Generic, "move_to", key);
+
+        Ok(true)
+    }
+
+    /// Swaps the keys of databases `a` and `b`
+    pub fn swapdb(&self, a: usize, b: usize) -> Result<(), Error> {
+        let (a, b) = (self.select(a)?.index, self.select(b)?.index);
+        if a == b {
+            return Ok(());
+        }
+
+        let (mut lock_a, mut lock_b) = self.lock_two(a, b);
+        std::mem::swap(&mut lock_a.data, &mut lock_b.data);
+        std::mem::swap(&mut lock_a.scan_order, &mut lock_b.scan_order);
+        std::mem::swap(&mut lock_a.expires, &mut lock_b.expires);
+        lock_a.touch_all();
+        lock_b.touch_all();
+
+        Ok(())
+    }
+
+    /// Gets a random key that is not expired, returns None if the keyspace is empty.
+    /// Keys are sampled at random positions of the order of SCAN, so None is also returned
+    /// when all the sampled keys are expired.
+    pub fn randomkey(&self) -> Option<ByteString> {
+        let lock = self.keyspace().lock().unwrap();
+        let now = now_ms();
+        let mut rng = rand::thread_rng();
+
+        (0..RANDOMKEY_SAMPLES).find_map(|_| {
+            let position = (rng.gen::<usize>(), ByteString::default());
+            let (_, key) = lock
+                .scan_order
+                .range(position..)
+                .next()
+                .or_else(|| lock.scan_order.first())?;
+            (!lock.is_expired(key, now)).then(|| key.clone())
+        })
+    }
+
+    /// Number of keys, including expired keys not removed yet
+    pub fn dbsize(&self) -> usize {
+        self.keyspace().lock().unwrap().data.len()
+    }
+
+    /// Removes all keys, the values are freed in a background thread if `asynchronous` is true
+    pub fn flush(&self, asynchronous: bool) {
+        self.flush_keyspace(asynchronous);
+        self.tracking().invalidate_all();
+    }
+
+    /// Removes all keys of all databases, see `flush`
+    pub fn flushall(&self, asynchronous: bool) {
+        for index in 0..self.databases() {
+            self.select(index)
+                .expect("database exists")
+                .flush_keyspace(asynchronous);
+        }
+        self.tracking().invalidate_all();
+    }
+
+    /// Removes all keys without invalidating the keys cached by the clients
+    fn flush_keyspace(&self, asynchronous: bool) {
+        let data = {
+            let mut lock = self.keyspace().lock().unwrap();
+            lock.touch_all();
+            lock.expires = Expires::default();
+            (
+                std::mem::take(&mut lock.data),
+                std::mem::take(&mut lock.scan_order),
+            )
+        };
+
+        if asynchronous {
+            drop_in_background(data);
+        }
+    }
+
+    /// Gets the keys that are not expired and match the glob-style `pattern`
+    pub fn keys(&self, pattern: &[u8]) -> Vec<ByteString> {
+        let lock = self.keyspace().lock().unwrap();
+        let now = now_ms();
+
+        lock.data
+            .keys()
+            .filter(|key| glob_match(pattern, key) && !lock.is_expired(key, now))
+            .cloned()
+            .collect()
+    }
+
+    /// Scans about `count` keys from `cursor`, returns the next cursor, 0 once all keys
+    /// are scanned, and the scanned keys that are not expired, match the glob-style `pattern`
+    /// and whose value is of type `type_name`.
+    ///
+    /// Keys are scanned in the order of a hash of the key that doesn't depend on the layout
+    /// of the keyspace, and the cursor is the hash to resume from. A key that exists during
+    /// the whole scan is returned exactly once, however the keyspace changes between calls.
+    /// The keys are kept in that order, so a call costs O(count + log n).
+    pub fn scan(
+        &self,
+        cursor: usize,
+        count: usize,
+        pattern: Option<&[u8]>,
+        type_name: Option<&str>,
+    ) -> (usize, Vec<ByteString>) {
+        let lock = self.keyspace().lock().unwrap();
+        let now = now_ms();
+
+        let mut keys = Vec::new();
+        let mut last = None;
+        let mut next = 0;
+        let order = lock.scan_order.range((cursor, ByteString::default())..);
+        for (scanned, (hash, key)) in order.enumerate() {
+            // keys of the same hash are scanned together so the cursor never splits them
+            if count > 0 && scanned >= count && last != Some(*hash) {
+                next = *hash;
+                break;
+            }
+            last = Some(*hash);
+
+            let value = &lock.data[key];
+            if pattern.is_none_or(|p| glob_match(p, key))
+                && type_name.is_none_or(|t| value.type_name().eq_ignore_ascii_case(t))
+                && !lock.is_expired(key, now)
+            {
+                keys.push(key.clone());
+            }
+        }
+
+        (next, keys)
+    }
+
+    pub fn sadd(&self, key: &[u8], elements: &[&[u8]]) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        let limits = lock.set_limits;
+        let added = lock.modify_or_insert_with(
+            key,
+            || MemDS::Set(SetDS::default()),
+            |set| Ok(set.set_mut(key)?.add(elements.iter(), &limits)),
+        )?;
+        if added > 0 {
+            lock.notify(EventClass::Set, "sadd", key);
+        }
+
+        Ok(added)
+    }
+
+    pub fn smembers(&self, key: &[u8]) -> Result<Option<Vec<ByteString>>, Error> {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(None),
+            Some(set) => Ok(Some(set.set(key)?.members())),
+        }
+    }
+
+    /// Calls `f` with the set of `key`, returns `default` if the key does not exist
+    fn with_set<T, F>(&self, key: &[u8], default: T, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&SetDS) -> T,
+    {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(default),
+            Some(set) => Ok(f(set.set(key)?)),
+        }
+    }
+
+    /// Calls `f` with the set of `key` to remove members, `event` is notified if any is
+    /// removed and the key is removed if the set becomes empty.
+    /// Returns `default` if the key does not exist.
+    fn with_set_mut<T, F>(&self, key: &[u8], default: T, event: &str, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut SetDS) -> T,
+    {
+        let mut lock = self.lock_for(key);
+        let set = match lock.get_mut(key) {
+            Some(value) => value.set_mut(key)?,
+            None => return Ok(default),
+        };
+
+        let len = set.len();
+        let result = f(set);
+        let (removed, empty) = (set.len() < len, set.is_empty());
+        if removed {
+            lock.notify(EventClass::Set, event, key);
+        }
+        if empty {
+            lock.remove_emptied(key);
+        }
+
+        Ok(result)
+    }
+
+    pub fn srem(&self, key: &[u8], members: &[&[u8]]) -> Result<usize, Error> {
+        self.with_set_mut(key, 0, "srem", |set| {
+            members.iter().filter(|m| set.remove(m)).count()
+        })
+    }
+
+    pub fn scard(&self, key: &[u8]) -> Result<usize, Error> {
+        self.with_set(key, 0, SetDS::len)
+    }
+
+    /// Checks whether each of `members` is in the set
+    pub fn smismember(&self, key: &[u8], members: &[&[u8]]) -> Result<Vec<bool>, Error> {
+        self.with_set(key, vec![false; members.len()], |set| {
+            members.iter().map(|m| set.contains(m)).collect()
+        })
+    }
+
+    /// Removes and returns up to `count` random members
+    pub fn spop(&self, key: &[u8], count: usize) -> Result<Vec<ByteString>, Error> {
+        self.with_set_mut(key, Vec::new(), "spop", |set| {
+            let mut rng = rand::thread_rng();
+            let popped: Vec<ByteString> = set
+                .iter()
+                .choose_multiple(&mut rng, count.min(set.len()))
+                .into_iter()
+                .map(ByteString::from)
+                .collect();
+            for member in &popped {
+                set.remove(member);
+            }
+            popped
+        })
+    }
+
+    /// Gets `count` distinct random members, or `-count` members that may repeat
+    /// if `count` is negative
+    pub fn srandmember(&self, key: &[u8], count: i64) -> Result<Vec<ByteString>, Error> {
+        check_random_count(count)?;
+        self.with_set(key, Vec::new(), |set| {
+            let mut rng = rand::thread_rng();
+
+            if count >= 0 {
+                let mut members = set
+                    .iter()
+                    .choose_multiple(&mut rng, distinct_count(count, set.len()));
+                members.shuffle(&mut rng);
+                members.into_iter().map(ByteString::from).collect()
+            } else {
+                let members: Vec<_> = set.iter().collect();
+                (0..count.unsigned_abs())
+                    .filter_map(|_| members.choose(&mut rng))
+                    .map(|member| ByteString::from(member.as_ref()))
+                    .collect()
+            }
+        })
+    }
+
+    /// Moves `member` from the set at `source` to the set at `destination`,
+    /// returns false if it is not in the source set
+    pub fn smove(&self, source: &[u8], destination: &[u8], member: &[u8]) -> Result<bool, Error> {
+        let mut lock = self.lock_for_keys(&[source, destination]);
+        if let Some(value) = lock.get(destination) {
+            value.set(destination)?;
+        }
+        let set = match lock.get_mut(source) {
+            Some(value) => value.set_mut(source)?,
+            None => return Ok(false),
+        };
+        if !set.contains(member) {
+            return Ok(false);
+        }
+        if source == destination {
+            return Ok(true);
+        }
+
+        set.remove(member);
+        let empty = set.is_empty();
+        lock.notify(EventClass::Set, "srem", source);
+        if empty {
+            lock.remove_emptied(source);
+        }
+        let limits = lock.set_limits;
+        lock.modify_or_insert_with(
+            destination,
+            || MemDS::Set(SetDS::default()),
+            |set| {
+                Ok(set
+                    .set_mut(destination)?
+                    .add(std::iter::once(member), &limits))
+            },
+        )?;
+        lock.notify(EventClass::Set, "sadd", destination);
+
+        Ok(true)
+    }
+
+    /// Combines the sets at `keys`, see `SetOperation`
+    pub fn scombine(
+        &self,
+        keys: &[&[u8]],
+        operation: SetOperation,
+    ) -> Result<Vec<ByteString>, Error> {
+        let set = self.lock_for_keys(keys).scombine(keys, operation)?;
+
+        Ok(set.members())
+    }
+
+    /// Stores the combination of the sets at `keys` to `destination`,
+    /// returns the number of members stored
+    pub fn scombinestore(
+        &self,
+        destination: &[u8],
+        keys: &[&[u8]],
+        operation: SetOperation,
+    ) -> Result<usize, Error> {
+        let mut lock = self.lock_for_keys(keys);
+        lock.expire_if_needed(destination, now_ms());
+
+        let set = lock.scombine(keys, operation)?;
+        let stored = set.len();
+        if stored > 0 {
+            let event = match operation {
+                SetOperation::Union => "sunionstore",
+                SetOperation::Inter => "sinterstore",
+                SetOperation::Diff => "sdiffstore",
+            };
+            lock.expires.remove(destination);
+            lock.insert(destination, MemDS::Set(set));
+            lock.notify(EventClass::Set, event, destination);
+        } else if lock.remove(destination) {
+            lock.notify(EventClass::Generic, "del", destination);
+        }
+
+        Ok(stored)
+    }
+
+    /// Counts the members in all the sets at `keys`, up to `limit` if not 0
+    pub fn sintercard(&self, keys: &[&[u8]], limit: usize) -> Result<usize, Error> {
+        let count = self
+            .lock_for_keys(keys)
+            .scombine(keys, SetOperation::Inter)?
+            .len();
+
+        Ok(match limit {
+            0 => count,
+            limit => count.min(limit),
+        })
+    }
+
+    /// Scans about `count` members of the set from `cursor`, returns the next cursor,
+    /// 0 once all members are scanned, and the scanned members matching the glob-style
+    /// `pattern`. Members are scanned in the same order as keys by `scan`.
+    pub fn sscan(
+        &self,
+        key: &[u8],
+        cursor: usize,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<(usize, Vec<ByteString>), Error> {
+        self.with_set(key, (0, Vec::new()), |set| {
+            let (next, members) = set.scan(cursor, count);
+            let members = members
+                .into_iter()
+                .filter(|m| pattern.is_none_or(|p| glob_match(p, m)))
+                .map(ByteString::from)
+                .collect();
+
+            (next, members)
+        })
+    }
+
+    /// Pushes `elements` one by one to `end` of the list,
+    /// if `existing_only` is true nothing is pushed unless the list exists.
+    /// Returns the length of the list after the push.
+    pub fn push(
+        &self,
+        key: &[u8],
+        elements: &[&[u8]],
+        end: ListEnd,
+        existing_only: bool,
+    ) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        if existing_only && lock.get(key).is_none() {
+            return Ok(0);
+        }
+
+        let len = lock.modify_or_insert_with(
+            key,
+            || MemDS::List(ListDS::default()),
+            |list| {
+                let list = list.list_mut(key)?;
+                for element in elements {
+                    match end {
+                        ListEnd::Head => list.push_front((*element).into()),
+                        ListEnd::Tail => list.push_back((*element).into()),
+                    }
+                }
+                Ok(list.len())
+            },
+        )?;
+        lock.notify(EventClass::List, end.push_event(), key);
+
+        Ok(len)
+    }
+
+    /// Pops up to `count` elements from `end` of the list, the key is removed when the list
+    /// becomes empty. Returns None if the key does not exist.
+    pub fn pop(
+        &self,
+        key: &[u8],
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Option<Vec<ByteString>>, Error> {
+        self.lock_for(key).pop(key, end, count)
+    }
+
+    pub fn llen(&self, key: &[u8]) -> Result<usize, Error> {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(0),
+            Some(list) => Ok(list.list(key)?.len()),
+        }
+    }
+
+    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<ByteString>, Error> {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(Vec::new()),
+            Some(list) => Ok(list.list(key)?.range(start, stop)),
+        }
+    }
+
+    pub fn lindex(&self, key: &[u8], index: i64) -> Result<Option<ByteString>, Error> {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(None),
+            Some(list) => Ok(list.list(key)?.get(index).cloned()),
+        }
+    }
+
+    pub fn lset(&self, key: &[u8], index: i64, element: &[u8]) -> Result<(), Error> {
+        let mut lock = self.lock_for(key);
+        let list = match lock.get_mut(key) {
+            Some(value) => value.list_mut(key)?,
+            None => return Err(Error::Handle("ERR no such key".to_string())),
+        };
+
+        if list.set(index, element.into()) {
+            lock.notify(EventClass::List, "lset", key);
+            Ok(())
+        } else {
+            Err(Error::Handle("ERR index out of range".to_string()))
+        }
+    }
+
+    /// Inserts `element` before or after `pivot`, returns the length of the list after
+    /// the insert, -1 if `pivot` is not found or 0 if the key does not exist
+    pub fn linsert(
+        &self,
+        key: &[u8],
+        pivot: &[u8],
+        after: bool,
+        element: &[u8],
+    ) -> Result<i64, Error> {
+        let mut lock = self.lock_for(key);
+        let list = match lock.get_mut(key) {
+            Some(value) => value.list_mut(key)?,
+            None => return Ok(0),
+        };
+
+        if list.insert(pivot, after, element.into()) {
+            let len = list.len() as i64;
+            lock.notify(EventClass::List, "linsert", key);
+            Ok(len)
+        } else {
+            Ok(-1)
+        }
+    }
+
+    pub fn lrem(&self, key: &[u8], count: i64, element: &[u8]) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        let list = match lock.get_mut(key) {
+            Some(value) => value.list_mut(key)?,
+            None => return Ok(0),
+        };
+
+        let removed = list.remove(count, element);
+        let empty = list.is_empty();
+        if removed > 0 {
+            lock.notify(EventClass::List, "lrem", key);
+        }
+        if empty {
+            lock.remove_emptied(key);
+        }
+
+        Ok(removed)
+    }
+
+    pub fn ltrim(&self, key: &[u8], start: i64, stop: i64) -> Result<(), Error> {
+        let mut lock = self.lock_for(key);
+        let list = match lock.get_mut(key) {
+            Some(value) => value.list_mut(key)?,
+            None => return Ok(()),
+        };
+
+        list.trim(start, stop);
+        let empty = list.is_empty();
+        lock.notify(EventClass::List, "ltrim", key);
+        if empty {
+            lock.remove_emptied(key);
+        }
+
+        Ok(())
+    }
+
+    /// Finds the indexes of `element` in the list, see `ListDS::positions`
+    pub fn lpos(
+        &self,
+        key: &[u8],
+        element: &[u8],
+        rank: i64,
+        count: usize,
+        maxlen: usize,
+    ) -> Result<Vec<usize>, Error> {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(Vec::new()),
+            Some(list) => Ok(list.list(key)?.positions(element, rank, count, maxlen)),
+        }
+    }
+
+    /// Pops an element from `from` of the `source` list and pushes it to `to` of
+    /// the `destination` list, returns None if `source` does not exist
+    pub fn lmove(
+        &self,
+        source: &[u8],
+        destination: &[u8],
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<ByteString>, Error> {
+        self.lock_for(source).lmove(source, destination, from, to)
+    }
+
+    /// Sets the fields of the hash, returns the number of new fields
+    pub fn hset(&self, key: &[u8], field_values: &[(&[u8], &[u8])]) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        let added = lock.modify_or_insert_with(
+            key,
+            || MemDS::Hash(HashDS::default()),
+            |hash| {
+                let hash = hash.hash_mut(key)?;
+                Ok(field_values
+                    .iter()
+                    .filter(|&&(field, value)| hash.set(field, value))
+                    .count())
+            },
+        )?;
+        lock.notify(EventClass::Hash, "hset", key);
+
+        Ok(added)
+    }
+
+    /// Sets the field of the hash only if it does not exist, returns true if it was set
+    pub fn hsetnx(&self, key: &[u8], field: &[u8], value: &[u8]) -> Result<bool, Error> {
+        let mut lock = self.lock_for(key);
+        if let Some(hash) = lock.get(key) {
+            if hash.hash(key)?.contains(field) {
+                return Ok(false);
+            }
+        }
+        let added = lock.modify_or_insert_with(
+            key,
+            || MemDS::Hash(HashDS::default()),
+            |hash| Ok(hash.hash_mut(key)?.set(field, value)),
+        )?;
+        lock.notify(EventClass::Hash, "hset", key);
+
+        Ok(added)
+    }
+
+    /// Gets the values of `fields`, None for missing fields
+    pub fn hmget(&self, key: &[u8], fields: &[&[u8]]) -> Result<Vec<Option<ByteString>>, Error> {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(vec![None; fields.len()]),
+            Some(hash) => {
+                let hash = hash.hash(key)?;
+                Ok(fields.iter().map(|f| hash.get(f).cloned()).collect())
+            }
+        }
+    }
+
+    /// Removes the fields of the hash, the key is removed when the hash becomes empty.
+    /// Returns the number of removed fields.
+    pub fn hdel(&self, key: &[u8], fields: &[&[u8]]) -> Result<usize, Error> {
+        let mut lock = self.lock_for(key);
+        let hash = match lock.get_mut(key) {
+            Some(value) => value.hash_mut(key)?,
+            None => return Ok(0),
+        };
+
+        let removed = fields.iter().filter(|f| hash.remove(f)).count();
+        let empty = hash.is_empty();
+        if removed > 0 {
+            lock.notify(EventClass::Hash, "hdel", key);
+        }
+        if empty {
+            lock.remove_emptied(key);
+        }
+
+        Ok(removed)
+    }
+
+    /// Calls `f` with the hash of `key`, or returns `default` if the key does not exist
+    fn with_hash<T, F>(&self, key: &[u8], default: T, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&HashDS) -> T,
+    {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(default),
+            Some(hash) => Ok(f(hash.hash(key)?)),
+        }
+    }
+
+    pub fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool, Error> {
+        self.with_hash(key, false, |hash| hash.contains(field))
+    }
+
+    pub fn hlen(&self, key: &[u8]) -> Result<usize, Error> {
+        self.with_hash(key, 0, HashDS::len)
+    }
+
+    pub fn hstrlen(&self, key: &[u8], field: &[u8]) -> Result<usize, Error> {
+        self.with_hash(key, 0, |hash| {
+            hash.get(field).map_or(0, |value| value.len())
+        })
+    }
+
+    pub fn hgetall(&self, key: &[u8]) -> Result<Vec<(ByteString, ByteString)>, Error> {
+        self.with_hash(key, Vec::new(), |hash| {
+            hash.iter().map(|(f, v)| (f.clone(), v.clone())).collect()
+        })
+    }
+
+    pub fn hincrby(&self, key: &[u8], field: &[u8], increment: i64) -> Result<i64, Error> {
+        let mut lock = self.lock_for(key);
+        let result = lock.modify_or_insert_with(
+            key,
+            || MemDS::Hash(HashDS::default()),
+            |hash| hash.hash_mut(key)?.incr_by(field, increment),
+        );
+        if result.is_ok() {
+            lock.notify(EventClass::Hash, "hincrby", key);
+        }
+
+        result
+    }
+
+    pub fn hincrbyfloat(&self, key: &[u8], field: &[u8], increment: f64) -> Result<String, Error> {
+        let mut lock = self.lock_for(key);
+        let result = lock.modify_or_insert_with(
+            key,
+            || MemDS::Hash(HashDS::default()),
+            |hash| hash.hash_mut(key)?.incr_by_float(field, increment),
+        );
+        if result.is_ok() {
+            lock.notify(EventClass::Hash, "hincrbyfloat", key);
+        }
+
+        result
+    }
+
+    /// Gets random fields and values of the hash:
+    /// `count` distinct fields if it is positive, or `-count` fields possibly repeated if it is
+    /// negative
+    pub fn hrandfield(
+        &self,
+        key: &[u8],
+        count: i64,
+    ) -> Result<Vec<(ByteString, ByteString)>, Error> {
+        check_random_count(count)?;
+        self.with_hash(key, Vec::new(), |hash| {
+            let mut rng = rand::thread_rng();
+            let entry = |(f, v): (&ByteString, &ByteString)| (f.clone(), v.clone());
+
+            if count >= 0 {
+                let mut fields = hash
+                    .iter()
+                    .choose_multiple(&mut rng, distinct_count(count, hash.len()));
+                fields.shuffle(&mut rng);
+                fields.into_iter().map(entry).collect()
+            } else {
+                let fields: Vec<_> = hash.iter().collect();
+                (0..count.unsigned_abs())
+                    .filter_map(|_| fields.choose(&mut rng).copied())
+                    .map(entry)
+                    .collect()
+            }
+        })
+    }
+
+    /// Scans about `count` fields of the hash from `cursor`, returns the next cursor,
+    /// 0 once all fields are scanned, and the scanned fields matching the glob-style `pattern`
+    /// with their values. Fields are scanned in the same order as keys by `scan`.
+    pub fn hscan(
+        &self,
+        key: &[u8],
+        cursor: usize,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<(usize, Vec<(ByteString, ByteString)>), Error> {
+        self.with_hash(key, (0, Vec::new()), |hash| {
+            let (next, entries) = hash.scan(cursor, count);
+            let entries = entries
+                .into_iter()
+                .filter(|(f, _)| pattern.is_none_or(|p| glob_match(p, f)))
+                .map(|(f, v)| (f.clone(), v.clone()))
+                .collect();
+
+            (next, entries)
+        })
+    }
+
+    /// Adds members with scores to the sorted set, or updates their scores
+    pub fn zadd(
+        &self,
+        key: &[u8],
+        elements: &[(f64, &[u8])],
+        options: ZaddOptions,
+    ) -> Result<ZaddOutcome, Error> {
+        let mut lock = self.lock_for(key);
+        let outcome = lock.modify_or_insert_with(
+            key,
+            || MemDS::SortedSet(ZSetDS::default()),
+            |value| {
+                let zset = value.zset_mut(key)?;
+                let mut outcome = ZaddOutcome::default();
+                for &(score, member) in elements {
+                    let current = zset.score(member);
+                    match (options.exists, current) {
+                        (SetCondition::NotExists, Some(_)) | (SetCondition::Exists, None) => {
+                            outcome.score = None;
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    let score = match (options.incr, current) {
+                        (true, Some(current)) => current + score,
+                        _ => score,
+                    };
+                    // only INCR, with a single member, gives NaN: nothing was changed
+                    if score.is_nan() {
+                        return Err(Error::Handle(
+                            "ERR resulting score is not a number (NaN)".to_string(),
+                        ));
+                    }
+                    let skip = match (options.score, current) {
+                        (ScoreCondition::Greater, Some(current)) => score <= current,
+                        (ScoreCondition::Less, Some(current)) => score >= current,
+                        _ => false,
+                    };
+                    if skip {
+                        outcome.score = None;
+                        continue;
+                    }
+
+                    if zset.insert(member, score) {
+                        outcome.added += 1;
+                    } else if current != Some(score) {
+                        outcome.updated += 1;
+                    }
+                    outcome.score = Some(score);
+                }
+
+                Ok(outcome)
+            },
+        )?;
+        if outcome.added + outcome.updated > 0 {
+            let event = if options.incr { "zincr" } else { "zadd" };
+            lock.notify(EventClass::SortedSet, event, key);
+        }
+
+        Ok(outcome)
+    }
+
+    /// Calls `f` with the sorted set of `key`, or returns `default` if the key does not exist
+    fn with_zset<T, F>(&self, key: &[u8], default: T, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&ZSetDS) -> T,
+    {
+        let lock = self.lock_for(key);
+
+        match lock.get(key) {
+            None => Ok(default),
+            Some(zset) => Ok(f(zset.zset(key)?)),
+        }
+    }
+
+    /// Calls `f` with the sorted set of `key` to remove members, `event` is notified if any
+    /// is removed and the key is removed if the sorted set becomes empty.
+    /// Returns `default` if the key does not exist.
+    fn with_zset_mut<T, F>(&self, key: &[u8], default: T, event: &str, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut ZSetDS) -> T,
+    {
+        self.lock_for(key).with_zset_mut(key, default, event, f)
+    }
+
+    pub fn zrem(&self, key: &[u8], members: &[&[u8]]) -> Result<usize, Error> {
+        self.with_zset_mut(key, 0, "zrem", |zset| {
+            members.iter().filter(|m| zset.remove(m)).count()
+        })
+    }
+
+    /// Gets the scores of `members`, None for missing members
+    pub fn zscores(&self, key: &[u8], members: &[&[u8]]) -> Result<Vec<Option<f64>>, Error> {
+        self.with_zset(key, vec![None; members.len()], |zset| {
+            members.iter().map(|m| zset.score(m)).collect()
+        })
+    }
+
+    pub fn zcard(&self, key: &[u8]) -> Result<usize, Error> {
+        self.with_zset(key, 0, ZSetDS::len)
+    }
+
+    pub fn zcount(&self, key: &[u8], range: &ZRange) -> Result<usize, Error> {
+        self.with_zset(key, 0, |zset| zset.ranks(range).len())
+    }
+
+    /// Gets the rank of `member` with its score, ranks are from the highest score if `rev`
+    pub fn zrank(
+        &self,
+        key: &[u8],
+        member: &[u8],
+        rev: bool,
+    ) -> Result<Option<(usize, f64)>, Error> {
+        self.with_zset(key, None, |zset| {
+            let rank = zset.rank(member)?;
+            let rank = if rev { zset.len() - 1 - rank } else { rank };
+            Some((rank, zset.score(member)?))
+        })
+    }
+
+    /// Gets the members with scores in `range`, see `ZSetDS::range`
+    pub fn zrange(
+        &self,
+        key: &[u8],
+        range: &ZRange,
+        rev: bool,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<Vec<(ByteString, f64)>, Error> {
+        self.with_zset(key, Vec::new(), |zset| {
+            zset.range(range, rev, offset, count)
+        })
+    }
+
+    /// Stores the members with scores in `range` of `source` to `destination`,
+    /// returns the number of stored members
+    pub fn zrangestore(
+        &self,
+        destination: &[u8],
+        source: &[u8],
+        range: &ZRange,
+        rev: bool,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<usize, Error> {
+        let mut lock = self.lock_for(source);
+        lock.expire_if_needed(destination, now_ms());
+
+        let elements = match lock.get(source) {
+            Some(value) => value.zset(source)?.range(range, rev, offset, count),
+            None => Vec::new(),
+        };
+        let stored = elements.len();
+        if stored > 0 {
+            lock.expires.remove(destination);
+            lock.insert(destination, MemDS::SortedSet(elements.into()));
+            lock.notify(EventClass::SortedSet, "zrangestore", destination);
+        } else if lock.remove(destination) {
+            lock.notify(EventClass::Generic, "del", destination);
+        }
+
+        Ok(stored)
+    }
+
+    /// Removes and returns up to `count` members with the lowest scores,
+    /// or the highest scores if `max` is true
+    pub fn zpop(
+        &self,
+        key: &[u8],
+        count: usize,
+        max: bool,
+    ) -> Result<Vec<(ByteString, f64)>, Error> {
+        self.lock_for(key).zpop(key, count, max)
+    }
+
+    /// Removes the members in `range`, returns the number of removed members
+    pub fn zremrange(&self, key: &[u8], range: &ZRange) -> Result<usize, Error> {
+        let event = match range {
+            ZRange::Rank(..) => "zremrangebyrank",
+            ZRange::Score(..) => "zremrangebyscore",
+            ZRange::Lex(..) => "zremrangebylex",
+        };
+        self.with_zset_mut(key, 0, event, |zset| {
+            let removed = zset.range(range, false, 0, None);
+            for (member, _) in &removed {
+                zset.remove(member);
+            }
+            removed.len()
+        })
+    }
+
+    /// Combines the sorted sets or sets at `keys`, see `SetOperation` and `Aggregate`.
+    /// Returns the members in ascending order of scores.
+    pub fn zcombine(
+        &self,
+        keys: &[&[u8]],
+        weights: Option<&[f64]>,
+        operation: SetOperation,
+        aggregate: Aggregate,
+    ) -> Result<Vec<(ByteString, f64)>, Error> {
+        let zset = self
+            .lock_for_keys(keys)
+            .zcombine(keys, weights, operation, aggregate)?;
+
+        Ok(zset.range(&ZRange::Rank(0, -1), false, 0, None))
+    }
+
+    /// Stores the combination of the sorted sets or sets at `keys` to `destination`,
+    /// returns the number of members stored
+    pub fn zcombinestore(
+        &self,
+        destination: &[u8],
+        keys: &[&[u8]],
+        weights: Option<&[f64]>,
+        operation: SetOperation,
+        aggregate: Aggregate,
+    ) -> Result<usize, Error> {
+        let mut lock = self.lock_for_keys(keys);
+        lock.expire_if_needed(destination, now_ms());
+
+        let zset = lock.zcombine(keys, weights, operation, aggregate)?;
+        let stored = zset.len();
+        if stored > 0 {
+            let event = match operation {
+                SetOperation::Union => "zunionstore",
+                SetOperation::Inter => "zinterstore",
+                SetOperation::Diff => "zdiffstore",
+            };
+            lock.expires.remove(destination);
+            lock.insert(destination, MemDS::SortedSet(zset));
+            lock.notify(EventClass::SortedSet, event, destination);
+        } else if lock.remove(destination) {
+            lock.notify(EventClass::Generic, "del", destination);
+        }
+
+        Ok(stored)
+    }
+
+    /// Counts the members in all the sorted sets or sets at `keys`, up to `limit` if not 0
+    pub fn zintercard(&self, keys: &[&[u8]], limit: usize) -> Result<usize, Error> {
+        let count = self
+            .lock_for_keys(keys)
+            .zcombine(keys, None, SetOperation::Inter, Aggregate::Sum)?
+            .len();
+
+        Ok(match limit {
+            0 => count,
+            limit => count.min(limit),
+        })
+    }
+
+    /// Calls `f` with the stream of `key` for modification, `f` returns its result with
+    /// the keyspace event to notify if the stream changed.
+    /// Streams are not removed when empty, they keep their last ID and consumer groups.
+    /// Returns None if the key does not exist.
+    fn with_stream_mut<T, F>(&self, key: &[u8], f: F) -> Result<Option<T>, Error>
+    where
+        F: FnOnce(&mut StreamDS) -> Result<(T, Option<&'static str>), Error>,
+    {
+        let mut lock = self.lock_for(key);
+        let (result, event) = match lock.get_mut(key) {
+            Some(value) => f(value.stream_mut(key)?)?,
+            None => return Ok(None),
+        };
+        if let Some(event) = event {
+            lock.notify(EventClass::Stream, event, key);
+        }
+
+        Ok(Some(result))
+    }
+
+    /// Calls `f` with the stream of `key`, returns None if the key does not exist
+    pub fn inspect_stream<T, F>(&self, key: &[u8], f: F) -> Result<Option<T>, Error>
+    where
+        F: FnOnce(&StreamDS) -> T,
+    {
+        let lock = self.lock_for(key);
+        match lock.get(key) {
+            Some(value) => Ok(Some(f(value.stream(key)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds an entry to the stream then trims it, returns the ID of the entry.
+    /// Returns None if the key does not exist and `nomkstream` is true.
+    pub fn xadd(
+        &self,
+        key: &[u8],
+        id: NewStreamId,
+        fields: &[(&[u8], &[u8])],
+        nomkstream: bool,
+        trim: Option<(StreamTrim, Option<usize>)>,
+    ) -> Result<Option<StreamId>, Error> {
+        let mut lock = self.lock_for(key);
+        // the ID is checked before the key is created
+        let id = match lock.get(key) {
+            Some(value) => value.stream(key)?.next_id(id, now_ms())?,
+            None if nomkstream => return Ok(None),
+            None => StreamDS::default().next_id(id, now_ms())?,
+        };
+
+        let fields = fields
+            .iter()
+            .map(|&(field, value)| (field.into(), value.into()))
+            .collect();
+        let trimmed = lock.modify_or_insert_with(
+            key,
+            || MemDS::Stream(StreamDS::default()),
+            |value| {
+                let stream = value.stream_mut(key)?;
+                stream.add(id, fields);
+                Ok(trim.is_some_and(|(trim, limit)| stream.trim(trim, limit) > 0))
+            },
+        )?;
+        lock.notify(EventClass::Stream, "xadd", key);
+        if trimmed {
+            lock.notify(EventClass::Stream, "xtrim", key);
+        }
+
+        Ok(Some(id))
+    }
+
+    pub fn xlen(&self, key: &[u8]) -> Result<usize, Error> {
+        Ok(self.inspect_stream(key, StreamDS::len)?.unwrap_or(0))
+    }
+
+    /// Entries between `start` and `end`, in descending order of IDs if `rev` is true
+    pub fn xrange(
+        &self,
+        key: &[u8],
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Result<StreamEntries, Error> {
+        let entries = self.inspect_stream(key, |stream| stream.range(start, end, rev, count))?;
+
+        Ok(entries.unwrap_or_default())
+    }
+
+    pub fn xdel(&self, key: &[u8], ids: &[StreamId]) -> Result<usize, Error> {
+        let removed = self.with_stream_mut(key, |stream| {
+            let removed = stream.remove(ids);
+            Ok((removed, (removed > 0).then_some("xdel")))
+        })?;
+
+        Ok(removed.unwrap_or(0))
+    }
+
+    pub fn xtrim(
+        &self,
+        key: &[u8],
+        trim: StreamTrim,
+        limit: Option<usize>,
+    ) -> Result<usize, Error> {
+        let removed = self.with_stream_mut(key, |stream| {
+            let removed = stream.trim(trim, limit);
+            Ok((removed, (removed > 0).then_some("xtrim")))
+        })?;
+
+        Ok(removed.unwrap_or(0))
+    }
+
+    pub fn xsetid(
+        &self,
+        key: &[u8],
+        last_id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<StreamId>,
+    ) -> Result<(), Error> {
+        self.with_stream_mut(key, |stream| {
+            stream.set_id(last_id, entries_added, max_deleted_id)?;
+            Ok(((), Some("xsetid")))
+        })?
+        .ok_or_else(|| Error::Handle("ERR no such key".to_string()))
+    }
+
+    /// Reads the entries after the start of each stream, up to `count` per stream.
+    /// Returns the keys of the streams with entries and their entries.
+    pub fn xread(
+        &self,
+        streams: &[(&[u8], StreamStart)],
+        count: Option<usize>,
+    ) -> Result<Vec<(ByteString, StreamEntries)>, Error> {
+        let keys: Vec<&[u8]> = streams.iter().map(|(key, _)| *key).collect();
+        let lock = self.lock_for_keys(&keys);
+
+        let mut result = Vec::new();
+        for (key, start) in streams {
+            let stream = match lock.get(key) {
+                Some(value) => value.stream(key)?,
+                None => continue,
+            };
+            let after = match start {
+                StreamStart::After(id) => *id,
+                _ => continue,
+            };
+            let entries = stream.range(Excluded(after), Included(StreamId::MAX), false, count);
+            if !entries.is_empty() {
+                result.push(((*key).into(), entries));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Reads the streams for `consumer` of `group`, see `StreamDS::read_group_new` and
+    /// `StreamDS::read_group_pending`. Returns the keys of the streams and their entries,
+    /// streams without undelivered entries are skipped.
+    pub fn xreadgroup(
+        &self,
+        group: &str,
+        consumer: &str,
+        streams: &[(&[u8], StreamStart)],
+        count: Option<usize>,
+        noack: bool,
+    ) -> Result<Vec<(ByteString, GroupEntries)>, Error> {
+        let keys: Vec<&[u8]> = streams.iter().map(|(key, _)| *key).collect();
+        let mut lock = self.lock_for_keys(&keys);
+        let now = now_ms();
+
+        for key in &keys {
+            let stream = match lock.get(key) {
+                Some(value) => Some(value.stream(key)?),
+                None => None,
+            };
+            if stream.and_then(|stream| stream.group(group)).is_none() {
+                return Err(Error::Handle(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    String::from_utf8_lossy(key),
+                    group
+                )));
+            }
+        }
+
+        let mut result = Vec::new();
+        for (key, start) in streams {
+            let stream = lock.get_mut(key).unwrap().stream_mut(key)?;
+            let entries = match start {
+                StreamStart::After(id) => {
+                    stream.read_group_pending(group, consumer, *id, count, now)
+                }
+                _ => stream
+                    .read_group_new(group, consumer, count, noack, now)
+                    .map(|entries| {
+                        entries
+                            .into_iter()
+                            .map(|(id, fields)| (id, Some(fields)))
+                            .collect()
+                    }),
+            };
+            let entries: GroupEntries = entries.unwrap_or_default();
+            if !entries.is_empty() || matches!(start, StreamStart::After(_)) {
+                result.push(((*key).into(), entries));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Creates a consumer group whose last delivered ID is `id`, or the last ID of the stream
+    /// if None. The stream is created if it does not exist and `mkstream` is true.
+    pub fn xgroup_create(
+        &self,
+        key: &[u8],
+        group: &str,
+        id: Option<StreamId>,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    ) -> Result<(), Error> {
+        let mut lock = self.lock_for(key);
+        if lock.get(key).is_none() && !mkstream {
+            return Err(xgroup_no_key());
+        }
+
+        lock.modify_or_insert_with(
+            key,
+            || MemDS::Stream(StreamDS::default()),
+            |value| {
+                let stream = value.stream_mut(key)?;
+                let id = id.unwrap_or_else(|| stream.last_id());
+                if stream.create_group(group, id, entries_read) {
+                    Ok(())
+                } else {
+                    Err(Error::Handle(
+                        "BUSYGROUP Consumer Group name already exists".to_string(),
+                    ))
+                }
+            },
+        )?;
+        lock.notify(EventClass::Stream, "xgroup-create", key);
+
+        Ok(())
+    }
+
+    /// Destroys the consumer group, returns false if it does not exist
+    pub fn xgroup_destroy(&self, key: &[u8], group: &str) -> Result<bool, Error> {
+        self.with_stream_mut(key, |stream| {
+            let destroyed = stream.destroy_group(group);
+            Ok((destroyed, destroyed.then_some("xgroup-destroy")))
+        })?
+        .ok_or_else(xgroup_no_key)
+    }
+
+    /// Sets the last delivered ID of the group to `id`, or the last ID of the stream if None
+    pub fn xgroup_setid(
+        &self,
+        key: &[u8],
+        group: &str,
+        id: Option<StreamId>,
+        entries_read: Option<u64>,
+    ) -> Result<(), Error> {
+        self.with_stream_mut(key, |stream| {
+            let id = id.unwrap_or_else(|| stream.last_id());
+            if stream.set_group_id(group, id, entries_read) {
+                Ok(((), Some("xgroup-setid")))
+            } else {
+                Err(no_group(key, group))
+            }
+        })?
+        .ok_or_else(xgroup_no_key)
+    }
+
+    /// Creates a consumer in the group, returns false if it exists
+    pub fn xgroup_createconsumer(
+        &self,
+        key: &[u8],
+        group: &str,
+        consumer: &str,
+    ) -> Result<bool, Error> {
+        self.with_stream_mut(key, |stream| match stream.group_mut(group) {
+            Some(g) => {
+                let created = g.create_consumer(consumer, now_ms());
+                Ok((created, created.then_some("xgroup-createconsumer")))
+            }
+            None => Err(no_group(key, group)),
+        })?
+        .ok_or_else(xgroup_no_key)
+    }
+
+    /// Deletes a consumer of the group, returns the number of pending entries it had
+    pub fn xgroup_delconsumer(
+        &self,
+        key: &[u8],
+        group: &str,
+        consumer: &str,
+    ) -> Result<usize, Error> {
+        self.with_stream_mut(key, |stream| match stream.group_mut(group) {
+            Some(g) => Ok((
+                g.delete_consumer(consumer).unwrap_or(0),
+                Some("xgroup-delconsumer"),
+            )),
+            None => Err(no_group(key, group)),
+        })?
+        .ok_or_else(xgroup_no_key)
+    }
+
+    /// Acknowledges pending entries of the group, returns the number of acknowledged entries
+    pub fn xack(&self, key: &[u8], group: &str, ids: &[StreamId]) -> Result<usize, Error> {
+        let acked = self.with_stream_mut(key, |stream| {
+            Ok((stream.group_mut(group).map_or(0, |g| g.ack(ids)), None))
+        })?;
+
+        Ok(acked.unwrap_or(0))
+    }
+
+    /// Calls `f` with the consumer group of the stream at `key`
+    pub fn inspect_group<T, F>(&self, key: &[u8], group: &str, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&StreamDS, &ConsumerGroup) -> T,
+    {
+        self.inspect_stream(key, |stream| stream.group(group).map(|g| f(stream, g)))?
+            .flatten()
+            .ok_or_else(|| {
+                Error::Handle(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}'",
+                    String::from_utf8_lossy(key),
+                    group
+                ))
+            })
+    }
+
+    /// Claims pending entries of the group for `consumer`, see `StreamDS::claim`.
+    /// Returns the claimed entries, without fields if `options.justid` is true.
+    pub fn xclaim(
+        &self,
+        key: &[u8],
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+    ) -> Result<StreamEntries, Error> {
+        self.with_stream_mut(key, |stream| {
+            let claimed = stream
+                .claim(group, consumer, min_idle, ids, options, now_ms())
+                .ok_or_else(|| no_group(key, group))?;
+            Ok((with_fields(stream, claimed, options.justid), None))
+        })?
+        .ok_or_else(|| no_group(key, group))
+    }
+
+    /// Claims idle pending entries of the group for `consumer`, see `StreamDS::autoclaim`.
+    /// Returns the ID to continue from, the claimed entries (without fields if `justid`
+    /// is true) and the IDs of deleted entries removed from the pending entries.
+    #[allow(clippy::too_many_arguments)]
+    pub fn xautoclaim(
+        &self,
+        key: &[u8],
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        justid: bool,
+    ) -> Result<(StreamId, StreamEntries, Vec<StreamId>), Error> {
+        self.with_stream_mut(key, |stream| {
+            let (next, claimed, deleted) = stream
+                .autoclaim(group, consumer, min_idle, start, count, justid, now_ms())
+                .ok_or_else(|| no_group(key, group))?;
+            Ok(((next, with_fields(stream, claimed, justid), deleted), None))
+        })?
+        .ok_or_else(|| no_group(key, group))
+    }
+
+    /// Counts a write for the save policies
+    pub(crate) fn record_write(&self) {
+        self.shared.saving.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes a snapshot of the keyspaces of all databases, by number, with the number of
+    /// writes it includes.
+    /// All keyspaces are locked together so the snapshot is of a single point in time.
+    fn snapshot(&self) -> (Vec<Snapshot>, u64) {
+        let locks: Vec<_> = self
+            .shared
+            .keyspaces
+            .iter()
+            .map(|keyspace| keyspace.lock().unwrap())
+            .collect();
+
+        (
+            locks.iter().map(|lock| lock.snapshot()).collect(),
+            self.shared.saving.dirty.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Saves the keyspaces of all databases to the db file.
+    /// The keyspaces are only locked to take a snapshot, other clients are not blocked
+    /// while it is written.
+    pub fn save(&self) -> Result<(), Error> {
+        let (snapshots, dirty) = self.snapshot();
+
+        self.shared.saving.save_snapshot(
+            &self.shared.db_path,
+            snapshots,
+            dirty,
+            &AtomicUsize::new(0),
+        )
+    }
+
+    /// Starts saving a snapshot of all databases to the db file in a background thread
+    pub fn bgsave(&self) -> Result<JoinHandle<()>, Error> {
+        let (snapshots, dirty, progress) = {
+            let mut status = self.shared.saving.status.lock().unwrap();
+            if status.bgsave.is_some() {
+                return Err(Error::Handle(
+                    "ERR Background save already in progress".to_string(),
+                ));
+            }
+
+            let (snapshots, dirty) = self.snapshot();
+            let progress = SaveProgress {
+                keys_total: snapshots.iter().map(|snapshot| snapshot.data.len()).sum(),
+                keys_processed: Default::default(),
+            };
+            status.bgsave = Some(progress.clone());
+            status.last_bgsave_try = now_secs();
+
+            (snapshots, dirty, progress)
+        };
+
+        let saving = self.shared.saving.clone();
+        let db_path = self.shared.db_path.clone();
+        thread::Builder::new()
+            .name("bgsave".to_string())
+            .spawn(move || {
+                let result =
+                    saving.save_snapshot(&db_path, snapshots, dirty, &progress.keys_processed);
+
+                let mut status = saving.status.lock().unwrap();
+                status.bgsave = None;
+                status.last_bgsave_ok = result.is_ok();
+                match result {
+                    Ok(()) => tracing::info!("Background saving finished"),
+                    Err(e) => tracing::error!("Background saving failed: {}", e),
+                }
+            })
+            .map_err(|e| {
+                self.shared.saving.status.lock().unwrap().bgsave = None;
+                Error::Handle(format!("ERR failed to start background saving {}", e))
+            })
+    }
+
+    /// Starts a background save if any of `policies` is met,
+    /// returns None if no save is started
+    pub fn bgsave_if_needed(&self, policies: &[SavePolicy]) -> Option<JoinHandle<()>> {
+        let now = now_secs();
+        let dirty = self.shared.saving.dirty.load(Ordering::Relaxed);
+        {
+            let status = self.shared.saving.status.lock().unwrap();
+            let retry_later =
+                !status.last_bgsave_ok && now < status.last_bgsave_try + BGSAVE_RETRY_DELAY;
+            if status.bgsave.is_some() || retry_later {
+                return None;
+            }
+            let due = policies.iter().any(|policy| {
+                dirty >= policy.changes && now.saturating_sub(status.last_save) >= policy.seconds
+            });
+            if !due {
+                return None;
+            }
+        }
+
+        tracing::info!("{} changes since last save, saving", dirty);
+        match self.bgsave() {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                tracing::error!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Unix time in seconds of the last successful save
+    pub fn lastsave(&self) -> u64 {
+        self.shared.saving.status.lock().unwrap().last_save
+    }
+
+    pub fn persistence_info(&self) -> PersistenceInfo {
+        let (last_save, last_bgsave_ok, bgsave) = {
+            let status = self.shared.saving.status.lock().unwrap();
+            (
+                status.last_save,
+                status.last_bgsave_ok,
+                status.bgsave.clone(),
+            )
+        };
+
+        PersistenceInfo {
+            changes_since_last_save: self.shared.saving.dirty.load(Ordering::Relaxed),
+            last_save,
+            last_bgsave_ok,
+            bgsave_in_progress: bgsave.is_some(),
+            save_keys_processed: bgsave
+                .as_ref()
+                .map(|p| p.keys_processed.load(Ordering::Relaxed))
+                .unwrap_or(0),
+            save_keys_total: bgsave.as_ref().map(|p| p.keys_total).unwrap_or(0),
+            aof_enabled: self.shared.aof.is_some(),
+            aof_rewrite_in_progress: self
+                .aof()
+                .map(|aof| aof.rewrite_in_progress())
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::KeyspaceEvents;
+
+    #[test]
+    fn test_set_conditions() {
+        let db = Database::new(String::new()).unwrap();
+
+        let outcome = db
+            .set(b"k", b"v1", SetCondition::Exists, SetExpiry::Persist, false)
+            .unwrap();
+        assert!(!outcome.applied);
+        assert_eq!(db.get(b"k").unwrap(), None);
+
+        let outcome = db
+            .set(
+                b"k",
+                b"v1",
+                SetCondition::NotExists,
+                SetExpiry::Persist,
+                false,
+            )
+            .unwrap();
+        assert!(outcome.applied);
+
+        let outcome = db
+            .set(
+                b"k",
+                b"v2",
+                SetCondition::NotExists,
+                SetExpiry::Persist,
+                true,
+            )
+            .unwrap();
+        assert_eq!(
+            outcome,
+            SetOutcome {
+                applied: false,
+                old: Some("v1".into())
+            }
+        );
+
+        let outcome = db
+            .set(b"k", b"v2", SetCondition::Exists, SetExpiry::Persist, true)
+            .unwrap();
+        assert!(outcome.applied);
+        assert_eq!(outcome.old, Some("v1".into()));
+        assert_eq!(db.get(b"k").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn test_set_get_wrong_type() {
+        let db = Database::new(String::new()).unwrap();
+        db.sadd(b"s", &[b"a"]).unwrap();
+
+        assert!(db
+            .set(b"s", b"v", SetCondition::Always, SetExpiry::Persist, true)
+            .is_err());
+        assert_eq!(db.smembers(b"s").unwrap(), Some(vec!["a".into()]));
+    }
+
+    #[test]
+    fn test_set_expiry() {
+        let db = Database::new(String::new()).unwrap();
+
+        db.set(b"k", b"v", SetCondition::Always, SetExpiry::At(1), false)
+            .unwrap();
+        assert_eq!(db.get(b"k").unwrap(), None);
+
+        let later = now_ms() + 100_000;
+        db.set(
+            b"k",
+            b"v",
+            SetCondition::Always,
+            SetExpiry::At(later),
+            false,
+        )
+        .unwrap();
+        db.set(b"k", b"v2", SetCondition::Always, SetExpiry::Keep, false)
+            .unwrap();
+        assert_eq!(db.expire_time(b"k"), Some(Some(later)));
+
+        db.set(b"k", b"v3", SetCondition::Always, SetExpiry::Persist, false)
+            .unwrap();
+        assert_eq!(db.expire_time(b"k"), Some(None));
+        assert_eq!(db.get(b"k").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn test_expire_conditions() {
+        let db = Database::new(String::new()).unwrap();
+        let later = (now_ms() + 100_000) as i64;
+
+        assert!(!db.expire(b"k", later, ExpiryCondition::Always).unwrap());
+        db.incr(b"k").unwrap();
+
+        assert!(!db.expire(b"k", later, ExpiryCondition::Volatile).unwrap());
+        assert!(!db.expire(b"k", later, ExpiryCondition::Greater).unwrap());
+        assert!(db.expire(b"k", later, ExpiryCondition::Less).unwrap());
+        assert!(!db
+            .expire(b"k", later, ExpiryCondition::NotVolatile)
+            .unwrap());
+        assert!(!db
+            .expire(b"k", later - 1, ExpiryCondition::Greater)
+            .unwrap());
+        assert!(db
+            .expire(b"k", later + 1, ExpiryCondition::Greater)
+            .unwrap());
+        assert_eq!(db.expire_time(b"k"), Some(Some(later as u64 + 1)));
+
+        assert!(db.persist(b"k"));
+        assert!(!db.persist(b"k"));
+        assert_eq!(db.expire_time(b"k"), Some(None));
+
+        assert!(db.expire(b"k", -1, ExpiryCondition::Always).unwrap());
+        assert_eq!(db.expire_time(b"k"), None);
+        assert_eq!(db.get(b"k").unwrap(), None);
+    }
+
+    #[test]
+    fn test_active_expire() {
+        let db = Database::new(String::new()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            db.set(key, b"v", SetCondition::Always, SetExpiry::At(1), false)
+                .unwrap();
+        }
+        let later = now_ms() + 100_000;
+        db.set(
+            b"d",
+            b"v",
+            SetCondition::Always,
+            SetExpiry::At(later),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(db.active_expire(2), 2);
+        assert_eq!(db.active_expire(2), 1);
+        assert_eq!(db.active_expire(2), 0);
+        assert_eq!(db.keyspace().lock().unwrap().data.len(), 1);
+        assert_eq!(db.expire_time(b"d"), Some(Some(later)));
+    }
+
+    #[test]
+    fn test_scan() {
+        let db = Database::new(String::new()).unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            db.set(
+                key.as_bytes(),
+                b"v",
+                SetCondition::Always,
+                SetExpiry::Persist,
+                false,
+            )
+            .unwrap();
+        }
+        db.sadd(b"s", &[b"x"]).unwrap();
+
+        // keys added or removed during the scan don't change what the cursor covers
+        let mut cursor = 0;
+        let mut scanned = Vec::new();
+        let mut calls = 0;
+        loop {
+            let (next, batch) = db.scan(cursor, 7, Some(b"k*"), None);
+            scanned.extend(batch);
+            db.set(
+                format!("new{}", calls).as_bytes(),
+                b"v",
+                SetCondition::Always,
+                SetExpiry::Persist,
+                false,
+            )
+            .unwrap();
+            calls += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(calls > 10);
+        scanned.sort();
+        let mut expected: Vec<ByteString> = keys.iter().map(ByteString::from).collect();
+        expected.sort();
+        assert_eq!(scanned, expected);
+
+        assert_eq!(db.scan(0, 1000, None, Some("SET")), (0, vec!["s".into()]));
+    }
+
+    #[test]
+    fn test_scan_order_follows_keys() {
+        let db = Database::new(String::new()).unwrap();
+        let db1 = db.select(1).unwrap();
+        let scan_all = |db: &Database| {
+            let mut keys = db.scan(0, 0, None, None).1;
+            keys.sort();
+            keys
+        };
+        for key in ["a", "b", "c", "d"] {
+            db.incr(key.as_bytes()).unwrap();
+        }
+        db.rename(b"a", b"e", false).unwrap();
+        db.del(&[b"b"]);
+        db.move_key(b"c", 1).unwrap();
+        db.copy(b"d", b"f", None, false).unwrap();
+        assert_eq!(scan_all(&db), ["d", "e", "f"]);
+        assert_eq!(scan_all(&db1), ["c"]);
+        assert!(["d", "e", "f"].contains(&db.randomkey().unwrap().to_string().as_str()));
+
+        db.swapdb(0, 1).unwrap();
+        assert_eq!(scan_all(&db), ["c"]);
+        assert_eq!(db.randomkey().unwrap(), "c");
+        db.flush(false);
+        assert!(scan_all(&db).is_empty());
+        assert_eq!(db.randomkey(), None);
+        assert_eq!(scan_all(&db1), ["d", "e", "f"]);
+    }
+
+    #[test]
+    fn test_select_move_swapdb() {
+        let db = Database::new(String::new()).unwrap();
+        assert_eq!(db.databases(), DEFAULT_DATABASES);
+        assert!(db.select(DEFAULT_DATABASES).is_err());
+        let db1 = db.select(1).unwrap();
+        assert_eq!(db1.index(), 1);
+
+        db.incr(b"a").unwrap();
+        db.expire(b"a", (now_ms() + 10_000) as i64, ExpiryCondition::Always)
+            .unwrap();
+        assert!(db.move_key(b"a", 1).unwrap());
+        assert_eq!(db.exists(&[b"a"]), 0);
+        assert_eq!(db1.get(b"a").unwrap(), Some("1".into()));
+        assert!(matches!(db1.expire_time(b"a"), Some(Some(_))));
+        assert!(!db.move_key(b"a", 1).unwrap());
+        assert!(db.move_key(b"a", 0).is_err());
+        db.incr(b"a").unwrap();
+        // the key already exists in the destination database
+        assert!(!db.move_key(b"a", 1).unwrap());
+
+        assert!(db.copy(b"a", b"b", Some(1), false).unwrap());
+        assert!(!db.copy(b"a", b"b", Some(1), false).unwrap());
+        assert!(!db.copy(b"a", b"a", None, true).unwrap());
+        assert!(db.copy(b"a", b"a", Some(1), true).unwrap());
+        assert_eq!(db1.get(b"a").unwrap(), Some("1".into()));
+        assert!(!db1.persist(b"a"));
+        assert!(db.copy(b"a", b"b", Some(99), false).is_err());
+
+        db.swapdb(0, 1).unwrap();
+        assert_eq!(db.dbsize(), 2);
+        assert_eq!(db1.dbsize(), 1);
+        assert_eq!(db.get(b"b").unwrap(), Some("1".into()));
+        assert!(db.swapdb(0, DEFAULT_DATABASES).is_err());
+        db.swapdb(1, 1).unwrap();
+
+        db1.incr(b"c").unwrap();
+        db1.flush(false);
+        assert_eq!(db.dbsize(), 2);
+        db1.incr(b"c").unwrap();
+        db.flushall(false);
+        assert_eq!(db.dbsize() + db1.dbsize(), 0);
+    }
+
+    #[test]
+    fn test_keyspace_events() {
+        let db = Database::new(String::new()).unwrap();
+        let pubsub = db.pubsub();
+        let mut subscriber = pubsub.register();
+        pubsub.psubscribe(&mut subscriber, b"__keyevent@*");
+        pubsub.subscribe(&mut subscriber, b"__keyspace@1__:l");
+        let mut events = || {
+            std::iter::from_fn(|| subscriber.try_recv())
+                .map(|message| format!("{} {}", message.channel, message.payload))
+                .collect::<Vec<_>>()
+        };
+
+        db.sadd(b"s", &[b"a"]).unwrap();
+        assert!(events().is_empty());
+
+        pubsub.set_keyspace_events(KeyspaceEvents::parse("KEA").unwrap());
+        db.sadd(b"s", &[b"a"]).unwrap();
+        db.sadd(b"s", &[b"b"]).unwrap();
+        db.srem(b"s", &[b"a", b"b"]).unwrap();
+        db.srem(b"s", &[b"a"]).unwrap();
+        assert_eq!(
+            events(),
+            [
+                "__keyevent@0__:sadd s",
+                "__keyevent@0__:srem s",
+                "__keyevent@0__:del s",
+            ]
+        );
+
+        let db1 = db.select(1).unwrap();
+        db1.push(b"l", &[b"x"], ListEnd::Tail, false).unwrap();
+        db1.lmove(b"l", b"m", ListEnd::Head, ListEnd::Head).unwrap();
+        db1.rename(b"m", b"n", false).unwrap();
+        db1.move_key(b"n", 0).unwrap();
+        assert_eq!(
+            events(),
+            [
+                "__keyspace@1__:l rpush",
+                "__keyevent@1__:rpush l",
+                "__keyspace@1__:l lpop",
+                "__keyevent@1__:lpop l",
+                "__keyspace@1__:l del",
+                "__keyevent@1__:del l",
+                "__keyevent@1__:lpush m",
+                "__keyevent@1__:rename_from m",
+                "__keyevent@1__:rename_to n",
+                "__keyevent@1__:move_from n",
+                "__keyevent@0__:move_to n",
+            ]
+        );
+
+        pubsub.set_keyspace_events(KeyspaceEvents::parse("Egx$n").unwrap());
+        let past = SetExpiry::At(now_ms() - 1);
+        db.set(b"a", b"1", SetCondition::Always, SetExpiry::Keep, false)
+            .unwrap();
+        db.set(b"b", b"1", SetCondition::Always, past, false)
+            .unwrap();
+        db.incr(b"a").unwrap();
+        assert_eq!(db.get(b"b").unwrap(), None);
+        db.set(b"c", b"1", SetCondition::Always, past, false)
+            .unwrap();
+        db.active_expire(10);
+        db.del(&[b"a", b"missing"]);
+        assert_eq!(
+            events(),
+            [
+                "__keyevent@0__:new a",
+                "__keyevent@0__:set a",
+                "__keyevent@0__:new b",
+                "__keyevent@0__:set b",
+                "__keyevent@0__:expire b",
+                "__keyevent@0__:incrby a",
+                "__keyevent@0__:expired b",
+                "__keyevent@0__:new c",
+                "__keyevent@0__:set c",
+                "__keyevent@0__:expire c",
+                "__keyevent@0__:expired c",
+                "__keyevent@0__:del a",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_writes_leave_keys_untouched() {
+        let db = Database::new(String::new()).unwrap();
+        let pubsub = db.pubsub();
+        let mut subscriber = pubsub.register();
+        pubsub.psubscribe(&mut subscriber, b"__keyevent@*");
+        pubsub.set_keyspace_events(KeyspaceEvents::parse("KEA").unwrap());
+        db.set(b"s", b"x", SetCondition::Always, SetExpiry::Keep, false)
+            .unwrap();
+        db.hset(b"h", &[(b"f", b"x")]).unwrap();
+        while subscriber.try_recv().is_some() {}
+
+        let (s_version, h_version) = (db.watch(b"s"), db.watch(b"h"));
+        assert!(db.push(b"s", &[b"a"], ListEnd::Head, false).is_err());
+        assert!(db.hincrby(b"h", b"f", 1).is_err());
+        assert!(!db.watch_modified(b"s", s_version));
+        assert!(!db.watch_modified(b"h", h_version));
+
+        let xx = ZaddOptions {
+            exists: SetCondition::Exists,
+            score: ScoreCondition::Always,
+            incr: false,
+        };
+        let outcome = db.zadd(b"z", &[(1.0, b"a")], xx).unwrap();
+        assert_eq!(outcome.added, 0);
+        assert_eq!(db.exists(&[b"z"]), 0);
+        assert!(subscriber.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_save_load_databases() {
+        let path = std::env::temp_dir().join(format!("memds-databases-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        for format in [DbFormat::Memds, DbFormat::Rdb] {
+            let _ = std::fs::remove_file(&path);
+            let db = Database::open(path.clone(), format, 4).unwrap();
+            db.incr(b"a").unwrap();
+            db.select(3).unwrap().incr_by(b"a", 3).unwrap();
+            db.save().unwrap();
+
+            let db = Database::open(path.clone(), format, 4).unwrap();
+            assert_eq!(db.get(b"a").unwrap(), Some("1".into()));
+            assert_eq!(db.select(1).unwrap().dbsize(), 0);
+            assert_eq!(db.select(3).unwrap().get(b"a").unwrap(), Some("3".into()));
+            // the file has data in more databases than configured
+            assert!(Database::open(path.clone(), format, 3).is_err());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_load_expires() {
+        let path = std::env::temp_dir().join(format!("memds-expires-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let later = now_ms() + 100_000;
+
+        let db = Database::new(path.clone()).unwrap();
+        db.set(
+            b"k",
+            b"v",
+            SetCondition::Always,
+            SetExpiry::At(later),
+            false,
+        )
+        .unwrap();
+        db.save().unwrap();
+
+        let db = Database::new(path.clone()).unwrap();
+        assert_eq!(db.expire_time(b"k"), Some(Some(later)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_migrate_and_corrupted() {
+        let path = std::env::temp_dir().join(format!("memds-migrate-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let mut data = Data::new();
+        data.insert("k".into(), Arc::new(MemDS::String(StringDS::from("v"))));
+        std::fs::write(&path, bincode::serialize(&data).unwrap()).unwrap();
+
+        let db = Database::new(path.clone()).unwrap();
+        assert_eq!(db.get(b"k").unwrap(), Some("v".into()));
+        let (_, version) = storage::load(&path).unwrap().unwrap();
+        assert_eq!(version, storage::FileFormat::Memds(storage::FORMAT_VERSION));
+
+        let mut content = std::fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        std::fs::write(&path, &content).unwrap();
+        assert!(Database::new(path.clone()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let db = Database::new(String::new()).unwrap();
+        db.sadd(b"s", &[b"a", b"b"]).unwrap();
+        assert_eq!(db.dump(b"missing"), None);
+        let payload = db.dump(b"s").unwrap();
+
+        let err = db.restore(b"s", &payload, None, false).unwrap_err();
+        assert!(matches!(err, Error::Handle(e) if e.starts_with("BUSYKEY")));
+        db.restore(b"copy", &payload, Some(now_ms() + 10_000), false)
+            .unwrap();
+        let mut members = db.smembers(b"copy").unwrap().unwrap();
+        members.sort();
+        assert_eq!(members, vec!["a", "b"]);
+        assert!(matches!(db.expire_time(b"copy"), Some(Some(_))));
+
+        // restoring an already expired key deletes it
+        db.restore(b"s", &payload, Some(1), true).unwrap();
+        assert_eq!(db.smembers(b"s").unwrap(), None);
+        assert!(db.restore(b"bad", b"garbage", None, false).is_err());
+    }
+
+    #[test]
+    fn test_bgsave() {
+        let path = std::env::temp_dir().join(format!("memds-bgsave-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let policies = [SavePolicy {
+            seconds: 0,
+            changes: 2,
+        }];
+
+        let db = Database::new(path.clone()).unwrap();
+        db.incr(b"a").unwrap();
+        db.record_write();
+        assert!(db.bgsave_if_needed(&policies).is_none());
+        db.incr(b"a").unwrap();
+        db.record_write();
+
+        let handle = db.bgsave_if_needed(&policies).unwrap();
+        // writes after the snapshot are not saved
+        db.incr(b"a").unwrap();
+        db.record_write();
+        handle.join().unwrap();
+
+        let info = db.persistence_info();
+        assert_eq!(info.changes_since_last_save, 1);
+        assert!(info.last_bgsave_ok);
+        assert!(!info.bgsave_in_progress);
+        assert_eq!(info.last_save, db.lastsave());
+        assert_eq!(
+            Database::new(path.clone()).unwrap().get(b"a").unwrap(),
+            Some("2".into())
+        );
+
+        let handle = db.bgsave().unwrap();
+        handle.join().unwrap();
+        assert_eq!(db.persistence_info().changes_since_last_save, 0);
+        assert_eq!(
+            Database::new(path.clone()).unwrap().get(b"a").unwrap(),
+            Some("3".into())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_aof_databases() {
+        let aof_path =
+            std::env::temp_dir().join(format!("memds-aof-select-{}.aof", std::process::id()));
+        let aof_path = aof_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&aof_path);
+        let config = Config {
+            db_path: String::new(),
+            appendonly: true,
+            aof_path: aof_path.clone(),
+            ..Default::default()
+        };
+        let run = |db: &Database, client: &mut ClientState, args: &[&str]| {
+            let mut write_buf = Vec::new();
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            assert!(!command::parse_and_handle(&args, db, client, &mut write_buf).unwrap());
+        };
+
+        let db = Database::from_config(&config).unwrap();
+        let mut client = ClientState::default();
+        let mut other = ClientState::default();
+        run(&db, &mut client, &["SELECT", "3"]);
+        run(&db, &mut client, &["INCR", "a"]);
+        run(&db, &mut other, &["INCR", "a"]);
+        run(&db, &mut client, &["INCR", "a"]);
+        run(&db, &mut client, &["MOVE", "a", "5"]);
+        run(&db, &mut other, &["SWAPDB", "0", "1"]);
+        db.bgrewriteaof().unwrap().join().unwrap();
+        run(&db, &mut client, &["INCR", "b"]);
+        db.aof_fsync().unwrap();
+        drop(db);
+
+        let db = Database::from_config(&config).unwrap();
+        assert_eq!(db.dbsize(), 0);
+        let get = |index: usize, key: &[u8]| db.select(index).unwrap().get(key).unwrap();
+        assert_eq!(get(1, b"a"), Some("1".into()));
+        assert_eq!(get(5, b"a"), Some("2".into()));
+        assert_eq!(get(3, b"b"), Some("1".into()));
+        std::fs::remove_file(&aof_path).unwrap();
+    }
+
+    #[test]
+    fn test_bgrewriteaof() {
+        let aof_path =
+            std::env::temp_dir().join(format!("memds-rewrite-{}.aof", std::process::id()));
+        let aof_path = aof_path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&aof_path);
+        let config = Config {
+            db_path: String::new(),
+            appendonly: true,
+            aof_path: aof_path.clone(),
+            ..Default::default()
+        };
+        let run = |db: &Database, args: &[&str]| {
+            let mut write_buf = Vec::new();
+            let mut client = ClientState::default();
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            assert!(!command::parse_and_handle(&args, db, &mut client, &mut write_buf).unwrap());
+        };
+
+        let db = Database::from_config(&config).unwrap();
+        for _ in 0..100 {
+            run(&db, &["INCR", "a"]);
+        }
+        run(&db, &["SADD", "s", "x", "y"]);
+        let log_len = std::fs::metadata(&aof_path).unwrap().len();
+
+        let handle = db.bgrewriteaof().unwrap();
+        // writes keep being accepted during the rewrite
+        run(&db, &["INCR", "a"]);
+        run(&db, &["SADD", "s", "z"]);
+        handle.join().unwrap();
+        run(&db, &["INCR", "b"]);
+        db.aof_fsync().unwrap();
+        drop(db);
+
+        assert!(std::fs::metadata(&aof_path).unwrap().len() < log_len);
+        let db = Database::from_config(&config).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some("101".into()));
+        assert_eq!(db.get(b"b").unwrap(), Some("1".into()));
+        let mut members = db.smembers(b"s").unwrap().unwrap();
+        members.sort();
+        assert_eq!(members, vec!["x", "y", "z"]);
+        std::fs::remove_file(&aof_path).unwrap();
+    }
+
+    #[test]
+    fn test_serve_blocked() {
+        let db = Database::new(String::new()).unwrap();
+        let block = |keys: &[&[u8]], pop| match db.pop_or_block(keys, pop, true).unwrap() {
+            PopOrBlock::Blocked(blocked) => blocked,
+            outcome => panic!("not blocked: {:?}", outcome),
+        };
+        let mut first = block(&[b"a", b"l"], BlockedPop::List(ListEnd::Head));
+        let mut second = block(&[b"l"], BlockedPop::List(ListEnd::Tail));
+        let mut third = block(&[b"l", b"z"], BlockedPop::SortedSet { max: false });
+
+        // a key of another type keeps its clients blocked
+        db.sadd(b"l", &[b"x"]).unwrap();
+        db.serve_blocked();
+        assert!(first.receiver.try_recv().is_err());
+        db.del(&[b"l"]);
+        db.push(b"l", &[b"1", b"2", b"3"], ListEnd::Tail, false)
+            .unwrap();
+        db.serve_blocked();
+        assert_eq!(
+            first.receiver.try_recv().unwrap().unwrap(),
+            Popped::List {
+                key: "l".into(),
+                element: "1".into()
+            }
+        );
+        assert_eq!(
+            second.receiver.try_recv().unwrap().unwrap(),
+            Popped::List {
+                key: "l".into(),
+                element: "3".into()
+            }
+        );
+        assert!(third.receiver.try_recv().is_err());
+        assert_eq!(db.lrange(b"l", 0, -1).unwrap(), vec![ByteString::from("2")]);
+
+        // an element moved to a list serves the clients blocked on it
+        let move_pop = BlockedPop::Move {
+            destination: "d".into(),
+            from: ListEnd::Head,
+            to: ListEnd::Tail,
+        };
+        let mut mover = block(&[b"s"], move_pop);
+        let mut popper = block(&[b"d"], BlockedPop::List(ListEnd::Head));
+        db.push(b"s", &[b"m"], ListEnd::Tail, false).unwrap();
+        db.serve_blocked();
+        assert_eq!(
+            mover.receiver.try_recv().unwrap().unwrap(),
+            Popped::Moved {
+                key: "s".into(),
+                element: "m".into()
+            }
+        );
+        assert_eq!(
+            popper.receiver.try_recv().unwrap().unwrap(),
+            Popped::List {
+                key: "d".into(),
+                element: "m".into()
+            }
+        );
+        assert_eq!(db.key_type(b"d"), None);
 
-        storage::save(&self.db_path, &lock)
+        assert!(db.unblock(third.id));
+        assert!(!db.unblock(first.id));
+        assert_eq!(db.shared.blocked_clients.load(Ordering::Relaxed), 0);
     }
 }
@@ -0,0 +1,165 @@
+/// Matches `string` against a glob-style `pattern` as Redis does for MATCH options and KEYS:
+/// `*` matches any sequence, `?` any single character, `[abc]`, `[^abc]` and `[a-z]`
+/// a set of characters, and `\` escapes the next character.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match_from(pattern, string) == Outcome::Match
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Match,
+    NoMatch,
+    /// The string ran out before the pattern, so no shorter string matches either
+    Exhausted,
+}
+
+fn match_from(pattern: &[u8], string: &[u8]) -> Outcome {
+    let (first, rest) = match pattern.split_first() {
+        None if string.is_empty() => return Outcome::Match,
+        None => return Outcome::NoMatch,
+        Some((b'*', rest)) => {
+            // consecutive stars match the same as one
+            let rest = trim_stars(rest);
+            if rest.is_empty() {
+                return Outcome::Match;
+            }
+            // once the rest of the pattern runs out of string, matching it against shorter
+            // suffixes is bound to fail, which keeps patterns with many stars linear
+            for i in 0..=string.len() {
+                match match_from(rest, &string[i..]) {
+                    Outcome::NoMatch => {}
+                    outcome => return outcome,
+                }
+            }
+            return Outcome::Exhausted;
+        }
+        Some((&first, rest)) => (first, rest),
+    };
+    let Some((&c, string)) = string.split_first() else {
+        return Outcome::Exhausted;
+    };
+
+    let (matched, rest) = match first {
+        b'?' => (true, rest),
+        b'[' => match match_class(rest) {
+            Some((class, rest)) => (class_contains(class, c), rest),
+            // an unterminated class matches the remaining pattern literally
+            None => (c == b'[', rest),
+        },
+        b'\\' if !rest.is_empty() => (c == rest[0], &rest[1..]),
+        p => (c == p, rest),
+    };
+
+    match matched {
+        true => match_from(rest, string),
+        false => Outcome::NoMatch,
+    }
+}
+
+fn trim_stars(mut pattern: &[u8]) -> &[u8] {
+    while let Some((b'*', rest)) = pattern.split_first() {
+        pattern = rest;
+    }
+    pattern
+}
+
+/// Splits the pattern after `[` into the class and the rest of the pattern after `]`
+fn match_class(pattern: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'\\' => i += 2,
+            b']' if i > 0 => return Some((&pattern[..i], &pattern[i + 1..])),
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+fn class_contains(class: &[u8], c: u8) -> bool {
+    let (negate, mut class) = match class.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+
+    let mut found = false;
+    while let Some((&first, rest)) = class.split_first() {
+        match (first, rest) {
+            (b'\\', [escaped, rest @ ..]) => {
+                found |= *escaped == c;
+                class = rest;
+            }
+            (start, [b'-', end, rest @ ..]) => {
+                let (low, high) = if start <= *end {
+                    (start, *end)
+                } else {
+                    (*end, start)
+                };
+                found |= (low..=high).contains(&c);
+                class = rest;
+            }
+            (other, rest) => {
+                found |= other == c;
+                class = rest;
+            }
+        }
+    }
+
+    found != negate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h**o", "ho", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("user:*:name", "user:1:name", true),
+            ("user:*:name", "user:1:age", false),
+            ("[", "[", true),
+            ("a*", "", false),
+            ("*a*b", "xaxxb", true),
+            ("*a*b", "xaxxbx", false),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                *expected,
+                "{} {}",
+                pattern,
+                string
+            );
+        }
+    }
+
+    #[test]
+    fn test_glob_match_many_stars() {
+        // exponential without giving up once the string is exhausted
+        let string = [b'a'; 60];
+        assert!(!glob_match(
+            b"*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b",
+            &string
+        ));
+        assert!(glob_match(
+            b"*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*",
+            &string
+        ));
+    }
+}
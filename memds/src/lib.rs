@@ -1,10 +1,19 @@
+pub mod blocking;
+pub mod byte_string;
 pub mod client;
 pub mod command;
+pub mod config;
 pub mod connection;
 pub mod database;
+mod glob;
 pub mod memds;
+pub mod pubsub;
+pub mod rdb;
+pub mod resp;
+pub mod scripting;
 mod server;
 pub mod storage;
+pub mod tracking;
 mod wal;
 
 pub use server::Server;
@@ -1,11 +1,12 @@
 use anyhow::Result;
-use memds::Server;
+use memds::{config::Config, Server};
 use tokio::signal;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let server = Server::new(6901, "db.bin".into());
+    let config = Config::from_args(std::env::args().skip(1))?;
+    let server = Server::from_config(config)?;
 
     tracing::info!("Listening...");
     let (_addr, server_service) = server.service().await?;
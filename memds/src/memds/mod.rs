@@ -1,112 +1,825 @@
-use std::collections::HashSet;
-use std::fmt::Write;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::Error;
+use crate::{byte_string::ByteString, Error};
 
-#[derive(Serialize, Deserialize)]
+mod set;
+mod stream;
+mod string;
+mod zset;
+
+pub use set::{SetDS, SetLimits};
+pub(crate) use stream::invalid_id;
+pub use stream::{
+    ClaimOptions, Consumer, ConsumerGroup, NewStreamId, PendingEntry, StreamDS, StreamFields,
+    StreamId, StreamTrim,
+};
+pub use string::{StringDS, STRING_MAX_LEN};
+pub use zset::{LexBound, ScoreBound, ZRange, ZSetDS};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum MemDS {
     String(StringDS),
     Set(SetDS),
+    List(ListDS),
+    Hash(HashDS),
+    SortedSet(ZSetDS),
+    Stream(StreamDS),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct StringDS {
-    s: String,
+#[derive(Clone, Serialize, Deserialize, Default)]
+#[serde(from = "HashRepr", into = "HashRepr")]
+pub struct HashDS {
+    h: HashMap<ByteString, ByteString>,
+    /// Fields in the order of HSCAN
+    order: ScanOrder,
 }
 
+/// Serialized form of a hash, the fields and values without their scan order
 #[derive(Serialize, Deserialize)]
-pub struct SetDS {
-    s: HashSet<String>,
+struct HashRepr {
+    h: HashMap<ByteString, ByteString>,
+}
+
+impl From<HashRepr> for HashDS {
+    fn from(repr: HashRepr) -> Self {
+        let order = repr.h.keys().map(|field| field.as_bytes()).collect();
+
+        HashDS { h: repr.h, order }
+    }
+}
+
+impl From<HashDS> for HashRepr {
+    fn from(hash: HashDS) -> Self {
+        HashRepr { h: hash.h }
+    }
+}
+
+/// Position of `element` in the order of SCAN-like commands, a hash that is the same for
+/// every run
+pub fn scan_hash(element: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    element.hash(&mut hasher);
+
+    hasher.finish() as usize
+}
+
+/// Elements of a collection ordered by their `scan_hash`, kept along the collection so that
+/// a scan resumes from its cursor, the hash of the next element, in O(count + log n)
+#[derive(Clone, Default)]
+pub(crate) struct ScanOrder(BTreeSet<(usize, ByteString)>);
+
+impl<'a> FromIterator<&'a [u8]> for ScanOrder {
+    fn from_iter<I: IntoIterator<Item = &'a [u8]>>(iter: I) -> Self {
+        let mut order = ScanOrder::default();
+        iter.into_iter().for_each(|element| order.insert(element));
+        order
+    }
+}
+
+impl ScanOrder {
+    pub fn insert(&mut self, element: &[u8]) {
+        self.0.insert((scan_hash(element), element.into()));
+    }
+
+    pub fn remove(&mut self, element: &[u8]) {
+        self.0.remove(&(scan_hash(element), element.into()));
+    }
+
+    /// Scans about `count` elements from `cursor`, returns the next cursor, 0 once all
+    /// elements are scanned, and the scanned elements. An element that is kept during the
+    /// whole scan is returned exactly once.
+    pub fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<&ByteString>) {
+        let mut scanned = Vec::new();
+        let mut last = None;
+        for (hash, element) in self.0.range((cursor, ByteString::default())..) {
+            // elements of the same hash are scanned together so the cursor never splits them
+            if scanned.len() >= count && last != Some(*hash) {
+                return (*hash, scanned);
+            }
+            last = Some(*hash);
+            scanned.push(element);
+        }
+
+        (0, scanned)
+    }
+}
+
+/// Max number of elements of a list node
+const LIST_NODE_SIZE: usize = 128;
+
+/// List stored as a deque of small nodes, like the quicklist of Redis:
+/// pushes and pops at both ends are cheap and inserting in the middle only moves
+/// the elements of one node.
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct ListDS {
+    nodes: VecDeque<VecDeque<ByteString>>,
+    len: usize,
 }
 
 impl MemDS {
-    pub fn string(&self, key: &str) -> Result<&StringDS, Error> {
+    /// Name of the encoding of the value, as reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            MemDS::String(s) => s.encoding(),
+            MemDS::Set(s) => s.encoding(),
+            MemDS::List(_) => "quicklist",
+            MemDS::Hash(_) => "hashtable",
+            MemDS::SortedSet(z) => z.encoding(),
+            MemDS::Stream(_) => "stream",
+        }
+    }
+
+    /// Whether the value is a collection left without elements, which is not kept in the
+    /// keyspace. Streams are kept even when they have no entries.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            MemDS::Set(s) => s.is_empty(),
+            MemDS::List(l) => l.is_empty(),
+            MemDS::Hash(h) => h.is_empty(),
+            MemDS::SortedSet(z) => z.is_empty(),
+            MemDS::String(_) | MemDS::Stream(_) => false,
+        }
+    }
+
+    /// Name of the type of the value, as reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            MemDS::String(_) => "string",
+            MemDS::Set(_) => "set",
+            MemDS::List(_) => "list",
+            MemDS::Hash(_) => "hash",
+            MemDS::SortedSet(_) => "zset",
+            MemDS::Stream(_) => "stream",
+        }
+    }
+
+    pub fn string(&self, key: &[u8]) -> Result<&StringDS, Error> {
         match self {
             MemDS::String(s) => Ok(s),
-            _ => Err(Error::Handle(format!("ERR key {} is not string", key))),
+            _ => Err(type_error(key, "string")),
         }
     }
 
-    pub fn string_mut(&mut self, key: &str) -> Result<&mut StringDS, Error> {
+    pub fn string_mut(&mut self, key: &[u8]) -> Result<&mut StringDS, Error> {
         match self {
             MemDS::String(s) => Ok(s),
-            _ => Err(Error::Handle(format!("ERR key {} is not string", key))),
+            _ => Err(type_error(key, "string")),
         }
     }
 
-    pub fn set(&self, key: &str) -> Result<&SetDS, Error> {
+    pub fn set(&self, key: &[u8]) -> Result<&SetDS, Error> {
         match self {
             MemDS::Set(s) => Ok(s),
-            _ => Err(Error::Handle(format!("ERR key {} is not set", key))),
+            _ => Err(type_error(key, "set")),
         }
     }
 
-    pub fn set_mut(&mut self, key: &str) -> Result<&mut SetDS, Error> {
+    pub fn set_mut(&mut self, key: &[u8]) -> Result<&mut SetDS, Error> {
         match self {
             MemDS::Set(s) => Ok(s),
-            _ => Err(Error::Handle(format!("ERR key {} is not set", key))),
+            _ => Err(type_error(key, "set")),
+        }
+    }
+
+    pub fn list(&self, key: &[u8]) -> Result<&ListDS, Error> {
+        match self {
+            MemDS::List(l) => Ok(l),
+            _ => Err(type_error(key, "list")),
+        }
+    }
+
+    pub fn list_mut(&mut self, key: &[u8]) -> Result<&mut ListDS, Error> {
+        match self {
+            MemDS::List(l) => Ok(l),
+            _ => Err(type_error(key, "list")),
+        }
+    }
+
+    pub fn hash(&self, key: &[u8]) -> Result<&HashDS, Error> {
+        match self {
+            MemDS::Hash(h) => Ok(h),
+            _ => Err(type_error(key, "hash")),
+        }
+    }
+
+    pub fn hash_mut(&mut self, key: &[u8]) -> Result<&mut HashDS, Error> {
+        match self {
+            MemDS::Hash(h) => Ok(h),
+            _ => Err(type_error(key, "hash")),
+        }
+    }
+
+    pub fn zset(&self, key: &[u8]) -> Result<&ZSetDS, Error> {
+        match self {
+            MemDS::SortedSet(z) => Ok(z),
+            _ => Err(type_error(key, "sorted set")),
+        }
+    }
+
+    pub fn zset_mut(&mut self, key: &[u8]) -> Result<&mut ZSetDS, Error> {
+        match self {
+            MemDS::SortedSet(z) => Ok(z),
+            _ => Err(type_error(key, "sorted set")),
+        }
+    }
+
+    pub fn stream(&self, key: &[u8]) -> Result<&StreamDS, Error> {
+        match self {
+            MemDS::Stream(s) => Ok(s),
+            _ => Err(type_error(key, "stream")),
+        }
+    }
+
+    pub fn stream_mut(&mut self, key: &[u8]) -> Result<&mut StreamDS, Error> {
+        match self {
+            MemDS::Stream(s) => Ok(s),
+            _ => Err(type_error(key, "stream")),
         }
     }
 }
 
-impl StringDS {
-    pub fn from<S: ToString>(s: S) -> Self {
-        Self { s: s.to_string() }
+/// Error of a command on a key holding another type than `kind`
+fn type_error(key: &[u8], kind: &str) -> Error {
+    Error::Handle(format!(
+        "ERR key {} is not {}",
+        String::from_utf8_lossy(key),
+        kind
+    ))
+}
+
+/// Converts an inclusive range of possibly negative indexes, counting from the end,
+/// to a range of indexes from the start, returns None if the range is empty
+fn range_bounds(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+
+    (start <= stop && start < len).then_some((start as usize, stop as usize))
+}
+
+/// A match of `lcs`: the inclusive ranges of the matched bytes in both strings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LcsMatch {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+}
+
+impl LcsMatch {
+    /// Length of the matched range, the same in both strings
+    pub fn match_len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
     }
+}
 
-    pub fn fetch(&self) -> String {
-        self.s.to_owned()
+/// Computes the longest common subsequence of `a` and `b`, with the matched ranges
+/// from the end of the strings, skipping ranges shorter than `min_match_len`.
+/// The ranges are found the same way as Redis does.
+pub fn lcs(a: &[u8], b: &[u8], min_match_len: usize) -> (Vec<u8>, Vec<LcsMatch>) {
+    let (alen, blen) = (a.len(), b.len());
+    let width = blen + 1;
+    // dp[i * width + j] is the length of the LCS of a[..i] and b[..j]
+    let mut dp = vec![0u32; (alen + 1) * width];
+    for i in 1..=alen {
+        for j in 1..=blen {
+            dp[i * width + j] = if a[i - 1] == b[j - 1] {
+                dp[(i - 1) * width + j - 1] + 1
+            } else {
+                dp[(i - 1) * width + j].max(dp[i * width + j - 1])
+            };
+        }
     }
 
-    pub fn incr(&mut self) -> Result<i64, Error> {
-        match self.s.parse::<i64>() {
-            Ok(mut num) => {
-                num = num + 1;
-                self.s.clear();
-                write!(self.s, "{}", num).unwrap();
+    let mut idx = dp[alen * width + blen] as usize;
+    let mut result = vec![0u8; idx];
+    let mut matches = Vec::new();
+    // no current range when the start is alen
+    let (mut arange_start, mut arange_end) = (alen, 0);
+    let (mut brange_start, mut brange_end) = (0, 0);
+    let (mut i, mut j) = (alen, blen);
+    while i > 0 && j > 0 {
+        let mut emit_range = false;
+        if a[i - 1] == b[j - 1] {
+            result[idx - 1] = a[i - 1];
+            if arange_start == alen {
+                arange_start = i - 1;
+                arange_end = i - 1;
+                brange_start = j - 1;
+                brange_end = j - 1;
+            } else if arange_start == i && brange_start == j {
+                arange_start -= 1;
+                brange_start -= 1;
+            } else {
+                emit_range = true;
+            }
+            if arange_start == 0 || brange_start == 0 {
+                emit_range = true;
+            }
+            idx -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if dp[(i - 1) * width + j] > dp[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            if arange_start != alen {
+                emit_range = true;
+            }
+        }
 
-                Ok(num)
+        if emit_range {
+            let m = LcsMatch {
+                a: (arange_start, arange_end),
+                b: (brange_start, brange_end),
+            };
+            if m.match_len() >= min_match_len {
+                matches.push(m);
             }
-            Err(_) => Err(Error::Handle(format!(
-                "value is not an integer or out of range"
-            ))),
+            arange_start = alen;
+        }
+    }
+
+    (result, matches)
+}
+
+impl HashDS {
+    pub fn len(&self) -> usize {
+        self.h.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.h.is_empty()
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&ByteString> {
+        self.h.get(field)
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool {
+        self.h.contains_key(field)
+    }
+
+    /// Sets `field` to `value`, returns true if the field is new
+    pub fn set<F: Into<ByteString>, V: Into<ByteString>>(&mut self, field: F, value: V) -> bool {
+        let field = field.into();
+        let new = !self.h.contains_key(&field);
+        if new {
+            self.order.insert(field.as_bytes());
         }
+        self.h.insert(field, value.into());
+
+        new
+    }
+
+    /// Removes `field`, returns true if it existed
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        let removed = self.h.remove(field).is_some();
+        if removed {
+            self.order.remove(field);
+        }
+
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ByteString, &ByteString)> + '_ {
+        self.h.iter()
+    }
+
+    /// Scans about `count` fields with their values from `cursor`, see `ScanOrder::scan`
+    pub fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<(&ByteString, &ByteString)>) {
+        let (next, fields) = self.order.scan(cursor, count);
+        let entries = fields
+            .into_iter()
+            .filter_map(|field| self.h.get_key_value(field.as_bytes()))
+            .collect();
+
+        (next, entries)
+    }
+
+    /// Increments the integer value of `field` by `increment`, a missing field counts as 0
+    pub fn incr_by(&mut self, field: &[u8], increment: i64) -> Result<i64, Error> {
+        let current = match self.h.get(field) {
+            Some(value) => value
+                .parse::<i64>()
+                .ok_or_else(|| Error::Handle("ERR hash value is not an integer".to_string()))?,
+            None => 0,
+        };
+        let value = current.checked_add(increment).ok_or_else(|| {
+            Error::Handle("ERR increment or decrement would overflow".to_string())
+        })?;
+        self.set(field, value.to_string());
+
+        Ok(value)
+    }
+
+    /// Increments the float value of `field` by `increment`, a missing field counts as 0.
+    /// Returns the new value formatted as it is stored.
+    pub fn incr_by_float(&mut self, field: &[u8], increment: f64) -> Result<String, Error> {
+        let current = match self.h.get(field) {
+            Some(value) => value
+                .parse::<f64>()
+                .filter(|f| f.is_finite())
+                .ok_or_else(|| Error::Handle("ERR hash value is not a float".to_string()))?,
+            None => 0.0,
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(Error::Handle(
+                "ERR increment would produce NaN or Infinity".to_string(),
+            ));
+        }
+        let value = value.to_string();
+        self.set(field, value.as_str());
+
+        Ok(value)
     }
 }
 
-impl SetDS {
-    pub fn from<S: ToString>(s: S) -> Self {
-        let mut set = HashSet::new();
-        set.insert(s.to_string());
-        Self { s: set }
+impl ListDS {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ByteString> + '_ {
+        self.nodes.iter().flatten()
+    }
+
+    pub fn push_front(&mut self, element: ByteString) {
+        match self.nodes.front_mut() {
+            Some(node) if node.len() < LIST_NODE_SIZE => node.push_front(element),
+            _ => self.nodes.push_front(VecDeque::from([element])),
+        }
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, element: ByteString) {
+        match self.nodes.back_mut() {
+            Some(node) if node.len() < LIST_NODE_SIZE => node.push_back(element),
+            _ => self.nodes.push_back(VecDeque::from([element])),
+        }
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<ByteString> {
+        let node = self.nodes.front_mut()?;
+        let element = node.pop_front();
+        if node.is_empty() {
+            self.nodes.pop_front();
+        }
+        self.len -= 1;
+
+        element
+    }
+
+    pub fn pop_back(&mut self) -> Option<ByteString> {
+        let node = self.nodes.back_mut()?;
+        let element = node.pop_back();
+        if node.is_empty() {
+            self.nodes.pop_back();
+        }
+        self.len -= 1;
+
+        element
+    }
+
+    /// Converts a possibly negative index (counted from the tail) to an index from the head
+    fn index(&self, index: i64) -> Option<usize> {
+        let index = if index < 0 {
+            self.len as i64 + index
+        } else {
+            index
+        };
+
+        (0..self.len as i64)
+            .contains(&index)
+            .then_some(index as usize)
+    }
+
+    /// Finds the node holding the element at `index`, and the offset of the element in it
+    fn locate(&self, mut index: usize) -> (usize, usize) {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if index < node.len() {
+                return (i, index);
+            }
+            index -= node.len();
+        }
+
+        unreachable!("index out of range of the list")
+    }
+
+    /// Gets the element at `index`, negative indexes count from the tail
+    pub fn get(&self, index: i64) -> Option<&ByteString> {
+        let (node, offset) = self.locate(self.index(index)?);
+
+        Some(&self.nodes[node][offset])
+    }
+
+    /// Replaces the element at `index`, returns false if the index is out of range
+    pub fn set(&mut self, index: i64, element: ByteString) -> bool {
+        match self.index(index) {
+            Some(index) => {
+                let (node, offset) = self.locate(index);
+                self.nodes[node][offset] = element;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts `element` before or after the first occurrence of `pivot`,
+    /// returns false if `pivot` is not found
+    pub fn insert(&mut self, pivot: &[u8], after: bool, element: ByteString) -> bool {
+        let index = match self.iter().position(|e| e.as_bytes() == pivot) {
+            Some(index) => index + after as usize,
+            None => return false,
+        };
+
+        if index == self.len {
+            self.push_back(element);
+            return true;
+        }
+        let (node, offset) = self.locate(index);
+        self.nodes[node].insert(offset, element);
+        self.len += 1;
+        if self.nodes[node].len() > LIST_NODE_SIZE {
+            let tail = self.nodes[node].split_off(LIST_NODE_SIZE / 2);
+            self.nodes.insert(node + 1, tail);
+        }
+
+        true
+    }
+
+    /// Removes the first `count` occurrences of `element` from the head,
+    /// or from the tail if `count` is negative, or all occurrences if `count` is 0.
+    /// Returns the number of removed elements.
+    pub fn remove(&mut self, count: i64, element: &[u8]) -> usize {
+        let matches: Vec<usize> = self
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| (e.as_bytes() == element).then_some(i))
+            .collect();
+        let limit = match count {
+            0 => matches.len(),
+            count => (count.unsigned_abs() as usize).min(matches.len()),
+        };
+        let indexes = if count < 0 {
+            &matches[matches.len() - limit..]
+        } else {
+            &matches[..limit]
+        };
+
+        let mut index = 0;
+        let mut next = indexes.iter().peekable();
+        for node in self.nodes.iter_mut() {
+            node.retain(|_| {
+                let keep = next.peek() != Some(&&index);
+                if !keep {
+                    next.next();
+                }
+                index += 1;
+                keep
+            });
+        }
+        self.nodes.retain(|node| !node.is_empty());
+        self.len -= indexes.len();
+
+        indexes.len()
     }
 
-    pub fn add<E, S>(&mut self, elements: E) -> usize
-    where
-        E: Iterator<Item = S>,
-        S: ToString,
-    {
-        let mut count = 0;
-        for e in elements {
-            if self.s.insert(e.to_string()) {
-                count += 1;
+    /// Converts an inclusive range of possibly negative indexes to a range from the head,
+    /// returns None if the range is empty
+    fn range_bounds(&self, start: i64, stop: i64) -> Option<(usize, usize)> {
+        range_bounds(start, stop, self.len)
+    }
+
+    /// Gets the elements from `start` to `stop` inclusive, negative indexes count from the tail
+    pub fn range(&self, start: i64, stop: i64) -> Vec<ByteString> {
+        match self.range_bounds(start, stop) {
+            Some((start, stop)) => self
+                .iter()
+                .skip(start)
+                .take(stop - start + 1)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Keeps only the elements from `start` to `stop` inclusive
+    pub fn trim(&mut self, start: i64, stop: i64) {
+        let (start, stop) = match self.range_bounds(start, stop) {
+            Some(bounds) => bounds,
+            None => {
+                *self = ListDS::default();
+                return;
             }
+        };
+
+        let tail = self.len - stop - 1;
+        for _ in 0..tail {
+            self.pop_back();
         }
+        for _ in 0..start {
+            self.pop_front();
+        }
+    }
+
+    /// Finds the indexes of `element`, skipping the first `rank - 1` matches,
+    /// searching from the tail if `rank` is negative.
+    /// At most `count` indexes are returned and `maxlen` elements compared, 0 means unlimited.
+    pub fn positions(&self, element: &[u8], rank: i64, count: usize, maxlen: usize) -> Vec<usize> {
+        let maxlen = if maxlen == 0 { self.len } else { maxlen };
+        let count = if count == 0 { usize::MAX } else { count };
+        let skip = rank.unsigned_abs() as usize - 1;
+        let matches = |(i, e): (usize, &ByteString)| (e.as_bytes() == element).then_some(i);
 
-        count
+        if rank < 0 {
+            let last = self.len.saturating_sub(1);
+            self.iter()
+                .rev()
+                .enumerate()
+                .take(maxlen)
+                .filter_map(matches)
+                .skip(skip)
+                .take(count)
+                .map(|i| last - i)
+                .collect()
+        } else {
+            self.iter()
+                .enumerate()
+                .take(maxlen)
+                .filter_map(matches)
+                .skip(skip)
+                .take(count)
+                .collect()
+        }
     }
+}
+
+impl<S: Into<ByteString>> FromIterator<S> for ListDS {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        let mut list = ListDS::default();
+        for e in iter {
+            list.push_back(e.into());
+        }
 
-    pub fn members(&self) -> Vec<String> {
-        self.s.iter().cloned().collect()
+        list
     }
 }
 
-impl Default for SetDS {
-    fn default() -> Self {
-        Self {
-            s: Default::default(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lcs() {
+        let (result, matches) = lcs(b"ohmytext", b"mynewtext", 0);
+        assert_eq!(result, b"mytext");
+        assert_eq!(
+            matches,
+            vec![
+                LcsMatch {
+                    a: (4, 7),
+                    b: (5, 8)
+                },
+                LcsMatch {
+                    a: (2, 3),
+                    b: (0, 1)
+                },
+            ]
+        );
+        let (_, matches) = lcs(b"ohmytext", b"mynewtext", 4);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(lcs(b"", b"abc", 0), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn test_hash_incr() {
+        let mut hash = HashDS::default();
+        assert_eq!(hash.incr_by(b"n", 5).unwrap(), 5);
+        assert_eq!(hash.incr_by(b"n", -7).unwrap(), -2);
+        assert!(hash.incr_by(b"n", i64::MIN).is_err());
+        assert_eq!(hash.get(b"n").unwrap(), "-2");
+
+        assert_eq!(hash.incr_by_float(b"f", 10.5).unwrap(), "10.5");
+        assert_eq!(hash.incr_by_float(b"f", 0.1).unwrap(), "10.6");
+        assert_eq!(hash.incr_by_float(b"n", 2.0).unwrap(), "0");
+        assert!(hash.incr_by_float(b"f", f64::INFINITY).is_err());
+
+        hash.set("s", "abc");
+        assert!(hash.incr_by(b"s", 1).is_err());
+        assert!(hash.incr_by_float(b"s", 1.0).is_err());
+        assert_eq!(hash.len(), 3);
+    }
+
+    #[test]
+    fn test_hash_scan_order() {
+        let mut hash = HashDS::default();
+        for i in 0..100 {
+            hash.set(format!("f{}", i), "v");
+        }
+        for i in 0..50 {
+            assert!(hash.remove(format!("f{}", i).as_bytes()));
+        }
+        hash.set("f99", "updated");
+
+        let mut scanned = Vec::new();
+        let (mut cursor, entries) = hash.scan(0, 10);
+        scanned.extend(entries);
+        while cursor != 0 {
+            let (next, entries) = hash.scan(cursor, 10);
+            assert!(entries.len() < 20);
+            scanned.extend(entries);
+            cursor = next;
         }
+        assert_eq!(scanned.len(), 50);
+        assert!(scanned.contains(&(&"f99".into(), &"updated".into())));
+
+        let hash: HashDS = bincode::deserialize(&bincode::serialize(&hash).unwrap()).unwrap();
+        assert_eq!(hash.scan(0, 100).1.len(), 50);
+    }
+
+    fn elements(list: &ListDS) -> Vec<&str> {
+        list.iter().map(|e| e.as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_list_nodes() {
+        let mut list: ListDS = (0..1000).map(|i| i.to_string()).collect();
+        list.push_front("-1".into());
+        assert_eq!(list.len(), 1001);
+        assert!(list.nodes.len() > 1);
+        assert_eq!(list.get(0).unwrap(), "-1");
+        assert_eq!(list.get(500).unwrap(), "499");
+        assert_eq!(list.get(-1).unwrap(), "999");
+        assert_eq!(list.get(1001), None);
+        assert_eq!(list.get(-1002), None);
+
+        for _ in 0..300 {
+            assert!(list.insert(b"10", false, "x".into()));
+        }
+        assert!(list.nodes.iter().all(|node| node.len() <= LIST_NODE_SIZE));
+        assert_eq!(list.get(11).unwrap(), "x");
+        assert_eq!(list.get(311).unwrap(), "10");
+        assert_eq!(list.remove(0, b"x"), 300);
+        assert_eq!(list.len(), 1001);
+        assert_eq!(list.iter().count(), 1001);
+
+        while list.pop_back().is_some() {}
+        assert!(list.is_empty());
+        assert!(list.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_list_range_trim() {
+        let mut list: ListDS = ["a", "b", "c", "d"].into_iter().collect();
+        assert_eq!(list.range(0, -1), vec!["a", "b", "c", "d"]);
+        assert_eq!(list.range(-2, 100), vec!["c", "d"]);
+        assert_eq!(list.range(-100, 0), vec!["a"]);
+        assert!(list.range(3, 1).is_empty());
+        assert!(list.range(5, 10).is_empty());
+
+        list.trim(1, -2);
+        assert_eq!(elements(&list), vec!["b", "c"]);
+        list.trim(5, 10);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_list_remove_positions() {
+        let mut list: ListDS = ["a", "b", "a", "c", "a"].into_iter().collect();
+        assert_eq!(list.positions(b"a", 1, 0, 0), vec![0, 2, 4]);
+        assert_eq!(list.positions(b"a", -1, 2, 0), vec![4, 2]);
+        assert_eq!(list.positions(b"a", 2, 1, 0), vec![2]);
+        assert_eq!(list.positions(b"a", 1, 0, 2), vec![0]);
+        assert!(list.positions(b"z", 1, 0, 0).is_empty());
+
+        assert_eq!(list.remove(-2, b"a"), 2);
+        assert_eq!(elements(&list), vec!["a", "b", "c"]);
+        assert_eq!(list.remove(1, b"a"), 1);
+        assert_eq!(elements(&list), vec!["b", "c"]);
+        assert!(list.set(-1, "d".into()));
+        assert!(!list.set(2, "e".into()));
+        assert!(list.insert(b"d", true, "e".into()));
+        assert!(!list.insert(b"z", true, "e".into()));
+        assert_eq!(elements(&list), vec!["b", "d", "e"]);
     }
 }
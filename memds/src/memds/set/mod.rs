@@ -0,0 +1,392 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::ScanOrder;
+use crate::byte_string::{self, ByteString};
+
+/// Thresholds of the compact encodings of sets, past them a set is converted to a hash table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SetLimits {
+    /// Max number of members of an intset
+    pub max_intset_entries: usize,
+    /// Max number of members of a listpack
+    pub max_listpack_entries: usize,
+    /// Max length of the members of a listpack
+    pub max_listpack_value: usize,
+}
+
+impl Default for SetLimits {
+    fn default() -> Self {
+        SetLimits {
+            max_intset_entries: 512,
+            max_listpack_entries: 128,
+            max_listpack_value: 64,
+        }
+    }
+}
+
+/// Set with Redis-like encodings: small sets are stored compactly and converted to a hash
+/// table when they grow past `SetLimits`. A set is never converted back to a compact encoding.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "SetRepr", into = "SetRepr")]
+pub struct SetDS {
+    s: Encoding,
+}
+
+#[derive(Clone)]
+enum Encoding {
+    /// Sorted integers, for sets whose members are all integers
+    IntSet(Vec<i64>),
+    Listpack(Listpack),
+    /// Members with their order for SSCAN
+    HashTable(HashSet<ByteString>, ScanOrder),
+}
+
+/// Members packed in a single buffer, each prefixed by its length as a varint.
+/// Lookups are linear scans, which is fast enough for small sets.
+#[derive(Clone, Default)]
+struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+/// Serialized form of a set, the same as the former `HashSet` field
+#[derive(Serialize, Deserialize)]
+struct SetRepr {
+    s: Vec<ByteString>,
+}
+
+impl From<SetRepr> for SetDS {
+    fn from(repr: SetRepr) -> Self {
+        repr.s.into_iter().collect()
+    }
+}
+
+impl From<SetDS> for SetRepr {
+    fn from(set: SetDS) -> Self {
+        SetRepr { s: set.members() }
+    }
+}
+
+impl Default for SetDS {
+    fn default() -> Self {
+        SetDS {
+            s: Encoding::IntSet(Vec::new()),
+        }
+    }
+}
+
+impl FromIterator<ByteString> for SetDS {
+    fn from_iter<I: IntoIterator<Item = ByteString>>(iter: I) -> Self {
+        SetDS::from_members(iter, &SetLimits::default())
+    }
+}
+
+/// Parses a member stored in an intset, only integers in their canonical form are,
+/// so that the member is written back the same
+fn as_int(member: &[u8]) -> Option<i64> {
+    byte_string::parse::<i64>(member).filter(|int| int.to_string().as_bytes() == member)
+}
+
+impl Listpack {
+    fn iter(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.entries().map(|(_, _, member)| member)
+    }
+
+    /// Entries with their start and end offsets in the buffer
+    fn entries(&self) -> impl Iterator<Item = (usize, usize, &[u8])> + '_ {
+        let mut pos = 0;
+        std::iter::from_fn(move || {
+            if pos >= self.buf.len() {
+                return None;
+            }
+            let start = pos;
+            let mut len = 0;
+            let mut shift = 0;
+            loop {
+                let byte = self.buf[pos];
+                pos += 1;
+                len |= ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            let member = &self.buf[pos..pos + len];
+            pos += len;
+            Some((start, pos, member))
+        })
+    }
+
+    fn contains(&self, member: &[u8]) -> bool {
+        self.iter().any(|m| m == member)
+    }
+
+    fn push(&mut self, member: &[u8]) {
+        let mut len = member.len();
+        while len >= 0x80 {
+            self.buf.push((len & 0x7f) as u8 | 0x80);
+            len >>= 7;
+        }
+        self.buf.push(len as u8);
+        self.buf.extend_from_slice(member);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, member: &[u8]) -> bool {
+        let found = self
+            .entries()
+            .find(|(_, _, m)| *m == member)
+            .map(|(start, end, _)| (start, end));
+        match found {
+            Some((start, end)) => {
+                self.buf.drain(start..end);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl SetDS {
+    pub fn from<S: AsRef<[u8]>>(s: S) -> Self {
+        SetDS::from_members([s], &SetLimits::default())
+    }
+
+    /// Creates a set of `members` in the most compact encoding allowed by `limits`
+    pub fn from_members<I, S>(members: I, limits: &SetLimits) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let mut set = SetDS::default();
+        set.add(members.into_iter(), limits);
+        set
+    }
+
+    /// Adds the members, converting the set to another encoding if they do not fit
+    /// in the current one. Returns the number of members added.
+    pub fn add<E, S>(&mut self, elements: E, limits: &SetLimits) -> usize
+    where
+        E: Iterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        elements
+            .filter(|e| self.add_one(e.as_ref(), limits))
+            .count()
+    }
+
+    fn add_one(&mut self, member: &[u8], limits: &SetLimits) -> bool {
+        let fits_listpack = |len: usize| {
+            len < limits.max_listpack_entries && member.len() <= limits.max_listpack_value
+        };
+
+        let to_listpack = match &mut self.s {
+            Encoding::IntSet(ints) => match as_int(member) {
+                Some(int) => {
+                    let pos = match ints.binary_search(&int) {
+                        Ok(_) => return false,
+                        Err(pos) => pos,
+                    };
+                    if ints.len() < limits.max_intset_entries {
+                        ints.insert(pos, int);
+                        return true;
+                    }
+                    false
+                }
+                None => fits_listpack(ints.len()),
+            },
+            Encoding::Listpack(listpack) => {
+                if listpack.contains(member) {
+                    return false;
+                }
+                if fits_listpack(listpack.len) {
+                    listpack.push(member);
+                    return true;
+                }
+                false
+            }
+            Encoding::HashTable(set, order) => {
+                let added = set.insert(member.into());
+                if added {
+                    order.insert(member);
+                }
+                return added;
+            }
+        };
+
+        self.convert(to_listpack);
+        self.add_one(member, limits)
+    }
+
+    /// Converts the set to a listpack, or to a hash table if `to_listpack` is false
+    fn convert(&mut self, to_listpack: bool) {
+        let members = self
+            .iter()
+            .map(|member| ByteString::from(member.into_owned()));
+        self.s = if to_listpack {
+            let mut listpack = Listpack::default();
+            members.for_each(|member| listpack.push(&member));
+            Encoding::Listpack(listpack)
+        } else {
+            let set: HashSet<ByteString> = members.collect();
+            let order = set.iter().map(|member| member.as_bytes()).collect();
+            Encoding::HashTable(set, order)
+        };
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match &mut self.s {
+            Encoding::IntSet(ints) => match as_int(member).map(|int| ints.binary_search(&int)) {
+                Some(Ok(pos)) => {
+                    ints.remove(pos);
+                    true
+                }
+                _ => false,
+            },
+            Encoding::Listpack(listpack) => listpack.remove(member),
+            Encoding::HashTable(set, order) => {
+                let removed = set.remove(member);
+                if removed {
+                    order.remove(member);
+                }
+                removed
+            }
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match &self.s {
+            Encoding::IntSet(ints) => {
+                as_int(member).is_some_and(|int| ints.binary_search(&int).is_ok())
+            }
+            Encoding::Listpack(listpack) => listpack.contains(member),
+            Encoding::HashTable(set, _) => set.contains(member),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.s {
+            Encoding::IntSet(ints) => ints.len(),
+            Encoding::Listpack(listpack) => listpack.len,
+            Encoding::HashTable(set, _) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn members(&self) -> Vec<ByteString> {
+        self.iter()
+            .map(|member| member.into_owned().into())
+            .collect()
+    }
+
+    /// Iterates the members, integers of an intset are formatted on the fly
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match &self.s {
+            Encoding::IntSet(ints) => Box::new(
+                ints.iter()
+                    .map(|int| Cow::Owned(int.to_string().into_bytes())),
+            ),
+            Encoding::Listpack(listpack) => Box::new(listpack.iter().map(Cow::Borrowed)),
+            Encoding::HashTable(set, _) => {
+                Box::new(set.iter().map(|m| Cow::Borrowed(m.as_bytes())))
+            }
+        }
+    }
+
+    /// Scans about `count` members from `cursor`, see `ScanOrder::scan`. The order of the
+    /// compact encodings is computed on each call, they are small.
+    pub fn scan(&self, cursor: usize, count: usize) -> (usize, Vec<Cow<'_, [u8]>>) {
+        let compact_order;
+        let order = match &self.s {
+            Encoding::HashTable(_, order) => order,
+            _ => {
+                let members: Vec<_> = self.iter().collect();
+                compact_order = members.iter().map(|member| &member[..]).collect();
+                &compact_order
+            }
+        };
+        let (next, members) = order.scan(cursor, count);
+        let members = members
+            .into_iter()
+            .map(|member| Cow::Owned(member.to_vec()))
+            .collect();
+
+        (next, members)
+    }
+
+    /// Name of the encoding, as reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match &self.s {
+            Encoding::IntSet(_) => "intset",
+            Encoding::Listpack(_) => "listpack",
+            Encoding::HashTable(..) => "hashtable",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(set: &SetDS) -> Vec<ByteString> {
+        let mut members = set.members();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn test_set_encodings() {
+        let limits = SetLimits {
+            max_intset_entries: 3,
+            max_listpack_entries: 4,
+            max_listpack_value: 5,
+        };
+
+        let mut set = SetDS::default();
+        assert_eq!(set.add(["3", "1", "2", "1"].iter(), &limits), 3);
+        assert_eq!(set.encoding(), "intset");
+        assert_eq!(set.members(), vec!["1", "2", "3"]);
+        assert!(set.contains(b"2"));
+        assert!(!set.contains(b"02"));
+        assert!(!set.remove(b"02"));
+        assert!(set.remove(b"2"));
+        // not in canonical form
+        assert_eq!(set.add(["+4"].iter(), &limits), 1);
+        assert_eq!(set.encoding(), "listpack");
+        assert_eq!(sorted(&set), vec!["+4", "1", "3"]);
+        assert_eq!(set.add(["3", "x"].iter(), &limits), 1);
+        assert_eq!(set.len(), 4);
+        assert!(set.remove(b"1"));
+        assert!(!set.remove(b"1"));
+        assert_eq!(sorted(&set), vec!["+4", "3", "x"]);
+        assert_eq!(set.add(["long value"].iter(), &limits), 1);
+        assert_eq!(set.encoding(), "hashtable");
+        assert_eq!(sorted(&set), vec!["+4", "3", "long value", "x"]);
+        // never converted back
+        set.remove(b"long value");
+        assert_eq!(set.encoding(), "hashtable");
+
+        let ints = SetDS::from_members((0..4).map(|i| i.to_string()), &limits);
+        assert_eq!(ints.encoding(), "hashtable");
+        assert_eq!(ints.len(), 4);
+        let strings = SetDS::from_members((0..5).map(|i| format!("m{}", i)), &limits);
+        assert_eq!(strings.encoding(), "hashtable");
+
+        let mut listpack = SetDS::default();
+        let long = "x".repeat(200);
+        let limits = SetLimits {
+            max_listpack_value: 300,
+            ..limits
+        };
+        listpack.add(["a", long.as_str(), "b"].iter(), &limits);
+        assert_eq!(listpack.encoding(), "listpack");
+        assert!(listpack.remove(long.as_bytes()));
+        assert_eq!(listpack.members(), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,842 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::Bound::{self, Excluded, Included},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{byte_string::ByteString, Error};
+
+/// ID of a stream entry: the unix time in milliseconds when it was added,
+/// and a sequence number for entries added in the same millisecond
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    /// Parses `ms-seq`, or `ms` with `missing_seq` as the sequence
+    pub fn parse(s: &str, missing_seq: u64) -> Result<Self, Error> {
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, Some(seq)),
+            None => (s, None),
+        };
+        let ms = ms.parse().map_err(|_| invalid_id())?;
+        let seq = match seq {
+            Some(seq) => seq.parse().map_err(|_| invalid_id())?,
+            None => missing_seq,
+        };
+
+        Ok(StreamId { ms, seq })
+    }
+
+    /// Parses a bound of a range: `-` and `+` for the min and max IDs,
+    /// or an ID prefixed by `(` to exclude it.
+    /// An ID without sequence is the first of its millisecond as a start, the last as an end.
+    pub fn parse_bound(s: &str, start: bool) -> Result<Bound<Self>, Error> {
+        let missing_seq = if start { 0 } else { u64::MAX };
+        match s {
+            "-" => Ok(Included(StreamId::MIN)),
+            "+" => Ok(Included(StreamId::MAX)),
+            _ => match s.strip_prefix('(') {
+                Some(id) => Ok(Excluded(StreamId::parse(id, missing_seq)?)),
+                None => Ok(Included(StreamId::parse(s, missing_seq)?)),
+            },
+        }
+    }
+
+    /// The smallest ID greater than this one
+    pub fn next(self) -> Option<Self> {
+        match (self.ms, self.seq) {
+            (u64::MAX, u64::MAX) => None,
+            (ms, u64::MAX) => Some(StreamId::new(ms + 1, 0)),
+            (ms, seq) => Some(StreamId::new(ms, seq + 1)),
+        }
+    }
+
+    /// Encodes the ID as 16 bytes that sort in the same order
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.ms.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 16]) -> Self {
+        StreamId {
+            ms: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+pub(crate) fn invalid_id() -> Error {
+    Error::Handle("ERR Invalid stream ID specified as stream command argument".to_string())
+}
+
+/// ID requested for a new entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewStreamId {
+    /// `*`: the current time, or the next sequence of the last ID
+    Auto,
+    /// `ms-*`: the next sequence in the millisecond
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewStreamId {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        if s == "*" {
+            return Ok(NewStreamId::Auto);
+        }
+        match s.strip_suffix("-*") {
+            Some(ms) => Ok(NewStreamId::AutoSeq(ms.parse().map_err(|_| invalid_id())?)),
+            None => Ok(NewStreamId::Explicit(StreamId::parse(s, 0)?)),
+        }
+    }
+}
+
+/// Trimming strategy of a stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamTrim {
+    /// Keep at most this number of entries
+    MaxLen(usize),
+    /// Remove entries with an ID lower than this one
+    MinId(StreamId),
+}
+
+pub type StreamFields = Vec<(ByteString, ByteString)>;
+
+/// Entry delivered to a consumer of a group and not acknowledged yet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Consumer {
+    /// Unix time in milliseconds of the last read or claim attempt
+    pub seen_time: u64,
+    /// Unix time in milliseconds of the last successful read or claim
+    pub active_time: Option<u64>,
+}
+
+impl Consumer {
+    fn new(now: u64) -> Self {
+        Consumer {
+            seen_time: now,
+            active_time: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    /// Number of entries of the stream read by the group, if it is known
+    pub entries_read: Option<u64>,
+    /// Pending entries list of the group, of all consumers
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    /// Creates the consumer if it does not exist, returns true if it was created
+    pub fn create_consumer(&mut self, name: &str, now: u64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumers.insert(name.to_string(), Consumer::new(now));
+        true
+    }
+
+    /// Deletes the consumer and its pending entries,
+    /// returns the number of pending entries it had
+    pub fn delete_consumer(&mut self, name: &str) -> Option<usize> {
+        self.consumers.remove(name)?;
+        let before = self.pending.len();
+        self.pending.retain(|_, entry| entry.consumer != name);
+
+        Some(before - self.pending.len())
+    }
+
+    fn touch_consumer(&mut self, name: &str, now: u64, active: bool) {
+        let consumer = self
+            .consumers
+            .entry(name.to_string())
+            .or_insert_with(|| Consumer::new(now));
+        consumer.seen_time = now;
+        if active {
+            consumer.active_time = Some(now);
+        }
+    }
+
+    /// Acknowledges the entries, returns the number of entries that were pending
+    pub fn ack(&mut self, ids: &[StreamId]) -> usize {
+        ids.iter()
+            .filter(|id| self.pending.remove(id).is_some())
+            .count()
+    }
+
+    /// Number of pending entries of each consumer with pending entries
+    pub fn pending_by_consumer(&self) -> BTreeMap<&String, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.pending.values() {
+            *counts.entry(&entry.consumer).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Pending entries between `start` and `end`, optionally of a consumer
+    /// and idle for at least `min_idle` milliseconds
+    pub fn pending_range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        count: usize,
+        consumer: Option<&str>,
+        min_idle: u64,
+        now: u64,
+    ) -> Vec<(StreamId, &PendingEntry)> {
+        if !is_valid_range(start, end) {
+            return Vec::new();
+        }
+        self.pending
+            .range((start, end))
+            .filter(|(_, entry)| consumer.is_none_or(|c| entry.consumer == c))
+            .filter(|(_, entry)| now.saturating_sub(entry.delivery_time) >= min_idle)
+            .take(count)
+            .map(|(id, entry)| (*id, entry))
+            .collect()
+    }
+}
+
+/// Options of XCLAIM
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClaimOptions {
+    /// Idle time in milliseconds to set on claimed entries
+    pub idle: Option<u64>,
+    /// Delivery time to set on claimed entries
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    /// Claim entries of the stream that are not pending
+    pub force: bool,
+    /// Do not increment the delivery count
+    pub justid: bool,
+    /// Last delivered ID to set on the group if greater than the current one
+    pub last_id: Option<StreamId>,
+}
+
+/// Entries of a stream ordered by ID, with the consumer groups reading it
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StreamDS {
+    entries: BTreeMap<StreamId, StreamFields>,
+    last_id: StreamId,
+    max_deleted_id: StreamId,
+    /// Number of entries ever added
+    entries_added: u64,
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+fn is_valid_range(start: Bound<StreamId>, end: Bound<StreamId>) -> bool {
+    match (start, end) {
+        (Included(s) | Excluded(s), Included(e) | Excluded(e)) if s > e => false,
+        (Excluded(s), Excluded(e)) => s != e,
+        _ => true,
+    }
+}
+
+impl StreamDS {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    pub fn first_entry(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.iter().next()
+    }
+
+    pub fn last_entry(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.iter().next_back()
+    }
+
+    pub fn get(&self, id: &StreamId) -> Option<&StreamFields> {
+        self.entries.get(id)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> + '_ {
+        self.entries.iter()
+    }
+
+    /// Resolves the ID of a new entry added at `now`, it must be greater than the last ID
+    pub fn next_id(&self, id: NewStreamId, now: u64) -> Result<StreamId, Error> {
+        let too_small = || {
+            Error::Handle(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string(),
+            )
+        };
+        match id {
+            NewStreamId::Auto if now > self.last_id.ms => Ok(StreamId::new(now, 0)),
+            NewStreamId::Auto => self.last_id.next().ok_or_else(|| {
+                Error::Handle(
+                    "ERR The stream has exhausted the last possible ID, unable to add more items"
+                        .to_string(),
+                )
+            }),
+            NewStreamId::AutoSeq(ms) if ms > self.last_id.ms => {
+                // 0-0 is never a valid ID
+                Ok(StreamId::new(ms, if ms == 0 { 1 } else { 0 }))
+            }
+            NewStreamId::AutoSeq(ms) if ms == self.last_id.ms && self.last_id.seq < u64::MAX => {
+                Ok(StreamId::new(ms, self.last_id.seq + 1))
+            }
+            NewStreamId::AutoSeq(_) => Err(too_small()),
+            NewStreamId::Explicit(StreamId::MIN) => Err(Error::Handle(
+                "ERR The ID specified in XADD must be greater than 0-0".to_string(),
+            )),
+            NewStreamId::Explicit(id) if id <= self.last_id => Err(too_small()),
+            NewStreamId::Explicit(id) => Ok(id),
+        }
+    }
+
+    /// Adds an entry with an ID greater than the last one
+    pub fn add(&mut self, id: StreamId, fields: StreamFields) {
+        debug_assert!(id > self.last_id);
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Sets the last ID, and optionally the number of entries added and the max deleted ID,
+    /// as XSETID does
+    pub fn set_id(
+        &mut self,
+        last_id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<StreamId>,
+    ) -> Result<(), Error> {
+        if let Some((top, _)) = self.last_entry() {
+            if last_id < *top {
+                return Err(Error::Handle(
+                    "ERR The ID specified in XSETID is smaller than the target stream top item"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(entries_added) = entries_added {
+            if entries_added < self.len() as u64 {
+                return Err(Error::Handle(
+                    "ERR The entries_added specified in XSETID is smaller than the target stream length".to_string(),
+                ));
+            }
+        }
+        if max_deleted_id.is_some_and(|max_deleted| last_id < max_deleted) {
+            return Err(Error::Handle(
+                "ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id"
+                    .to_string(),
+            ));
+        }
+
+        self.last_id = last_id;
+        if let Some(entries_added) = entries_added {
+            self.entries_added = entries_added;
+        }
+        if let Some(max_deleted_id) = max_deleted_id {
+            self.max_deleted_id = max_deleted_id;
+        }
+
+        Ok(())
+    }
+
+    /// Entries between `start` and `end`, in descending order of IDs if `rev` is true
+    pub fn range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Vec<(StreamId, StreamFields)> {
+        if !is_valid_range(start, end) {
+            return Vec::new();
+        }
+        let range = self.entries.range((start, end));
+        let count = count.unwrap_or(usize::MAX);
+        let clone = |(id, fields): (&StreamId, &StreamFields)| (*id, fields.clone());
+        if rev {
+            range.rev().take(count).map(clone).collect()
+        } else {
+            range.take(count).map(clone).collect()
+        }
+    }
+
+    /// Removes the entries, returns the number of removed entries
+    pub fn remove(&mut self, ids: &[StreamId]) -> usize {
+        let mut removed = 0;
+        for id in ids {
+            if self.entries.remove(id).is_some() {
+                self.max_deleted_id = self.max_deleted_id.max(*id);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes the oldest entries according to `trim`, at most `limit` of them,
+    /// returns the number of removed entries
+    pub fn trim(&mut self, trim: StreamTrim, limit: Option<usize>) -> usize {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut removed = 0;
+        while removed < limit {
+            let first = match self.entries.first_key_value() {
+                Some((id, _)) => *id,
+                None => break,
+            };
+            let remove = match trim {
+                StreamTrim::MaxLen(max_len) => self.entries.len() > max_len,
+                StreamTrim::MinId(min_id) => first < min_id,
+            };
+            if !remove {
+                break;
+            }
+            self.entries.remove(&first);
+            self.max_deleted_id = self.max_deleted_id.max(first);
+            removed += 1;
+        }
+        removed
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&String, &ConsumerGroup)> + '_ {
+        self.groups.iter()
+    }
+
+    pub fn group(&self, name: &str) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &str) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Creates a group whose last delivered entry is `id`, returns false if it exists
+    pub fn create_group(&mut self, name: &str, id: StreamId, entries_read: Option<u64>) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        let entries_read = entries_read.or_else(|| self.estimate_entries_read(id));
+        self.groups.insert(
+            name.to_string(),
+            ConsumerGroup {
+                last_delivered: id,
+                entries_read,
+                ..Default::default()
+            },
+        );
+        true
+    }
+
+    /// Inserts a group as it was saved
+    pub fn insert_group(&mut self, name: String, group: ConsumerGroup) {
+        self.groups.insert(name, group);
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Sets the last delivered entry of the group, returns false if the group does not exist
+    pub fn set_group_id(&mut self, name: &str, id: StreamId, entries_read: Option<u64>) -> bool {
+        let entries_read = entries_read.or_else(|| self.estimate_entries_read(id));
+        match self.groups.get_mut(name) {
+            Some(group) => {
+                group.last_delivered = id;
+                group.entries_read = entries_read;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of entries read by a group that has read up to `id`,
+    /// known if it is the last ID or if no entry was ever deleted
+    fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if id >= self.last_id {
+            Some(self.entries_added)
+        } else if self.max_deleted_id == StreamId::MIN && self.entries_added == self.len() as u64 {
+            Some(self.entries.range(..=id).count() as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Number of entries of the stream not read by the group yet, if it is known
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        group
+            .entries_read
+            .map(|read| self.entries_added.saturating_sub(read))
+    }
+
+    /// Delivers to `consumer` the entries never delivered to the group, adding them to the
+    /// pending entries unless `noack` is true. Returns None if the group does not exist.
+    pub fn read_group_new(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: Option<usize>,
+        noack: bool,
+        now: u64,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let group = self.groups.get_mut(group)?;
+        let entries: Vec<_> = self
+            .entries
+            .range((Excluded(group.last_delivered), Included(StreamId::MAX)))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+
+        group.touch_consumer(consumer, now, !entries.is_empty());
+        if let Some((last, _)) = entries.last() {
+            group.last_delivered = *last;
+            group.entries_read = if *last == self.last_id {
+                Some(self.entries_added)
+            } else {
+                group.entries_read.map(|read| read + entries.len() as u64)
+            };
+        }
+        if !noack {
+            for (id, _) in &entries {
+                group.pending.insert(
+                    *id,
+                    PendingEntry {
+                        consumer: consumer.to_string(),
+                        delivery_time: now,
+                        delivery_count: 1,
+                    },
+                );
+            }
+        }
+
+        Some(entries)
+    }
+
+    /// Pending entries of `consumer` after `after`, with None for the entries deleted from
+    /// the stream. Returns None if the group does not exist.
+    pub fn read_group_pending(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: StreamId,
+        count: Option<usize>,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer, now, false);
+        let entries = group
+            .pending
+            .range((Excluded(after), Included(StreamId::MAX)))
+            .filter(|(_, entry)| entry.consumer == consumer)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, _)| (*id, self.entries.get(id).cloned()))
+            .collect();
+
+        Some(entries)
+    }
+
+    /// Changes the owner of the pending entries idle for at least `min_idle` milliseconds
+    /// to `consumer`, pending entries deleted from the stream are removed.
+    /// Returns the claimed IDs, or None if the group does not exist.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Option<Vec<StreamId>> {
+        let group = self.groups.get_mut(group)?;
+        let delivery_time = match (options.time, options.idle) {
+            (Some(time), _) => time,
+            (None, Some(idle)) => now.saturating_sub(idle),
+            (None, None) => now,
+        };
+        if let Some(last_id) = options.last_id {
+            group.last_delivered = group.last_delivered.max(last_id);
+        }
+
+        let mut claimed = Vec::new();
+        for id in ids {
+            let exists = self.entries.contains_key(id);
+            match group.pending.get(id) {
+                Some(_) if !exists => {
+                    group.pending.remove(id);
+                    continue;
+                }
+                Some(entry) if now.saturating_sub(entry.delivery_time) < min_idle => continue,
+                Some(_) => {}
+                None if options.force && exists => {
+                    group.pending.insert(
+                        *id,
+                        PendingEntry {
+                            consumer: consumer.to_string(),
+                            delivery_time,
+                            delivery_count: 1,
+                        },
+                    );
+                }
+                None => continue,
+            }
+
+            let entry = group.pending.get_mut(id).unwrap();
+            entry.consumer = consumer.to_string();
+            entry.delivery_time = delivery_time;
+            match options.retry_count {
+                Some(count) => entry.delivery_count = count,
+                None if !options.justid => entry.delivery_count += 1,
+                None => {}
+            }
+            claimed.push(*id);
+        }
+        group.touch_consumer(consumer, now, !claimed.is_empty());
+
+        Some(claimed)
+    }
+
+    /// Claims up to `count` pending entries from `start` idle for at least `min_idle`
+    /// milliseconds, checking at most 10 times `count` entries.
+    /// Returns the ID to continue from (0-0 when all were checked), the claimed IDs
+    /// and the IDs of pending entries deleted from the stream,
+    /// or None if the group does not exist.
+    #[allow(clippy::too_many_arguments)]
+    pub fn autoclaim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        justid: bool,
+        now: u64,
+    ) -> Option<(StreamId, Vec<StreamId>, Vec<StreamId>)> {
+        let group = self.groups.get_mut(group)?;
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut next = StreamId::MIN;
+        let mut attempts = count.saturating_mul(10);
+
+        let ids: Vec<StreamId> = group.pending.range(start..).map(|(id, _)| *id).collect();
+        for id in &ids {
+            if attempts == 0 || claimed.len() >= count {
+                next = *id;
+                break;
+            }
+            attempts -= 1;
+
+            let entry = group.pending.get_mut(id).unwrap();
+            if now.saturating_sub(entry.delivery_time) < min_idle {
+                continue;
+            }
+            if !self.entries.contains_key(id) {
+                group.pending.remove(id);
+                deleted.push(*id);
+                continue;
+            }
+            entry.consumer = consumer.to_string();
+            entry.delivery_time = now;
+            if !justid {
+                entry.delivery_count += 1;
+            }
+            claimed.push(*id);
+        }
+        group.touch_consumer(consumer, now, !claimed.is_empty());
+
+        Some((next, claimed, deleted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(value: &str) -> StreamFields {
+        vec![("f".into(), value.into())]
+    }
+
+    #[test]
+    fn test_stream_ids() {
+        let mut stream = StreamDS::default();
+        let id = stream.next_id(NewStreamId::Auto, 5).unwrap();
+        assert_eq!(id, StreamId::new(5, 0));
+        stream.add(id, fields("a"));
+        // the clock went backward
+        let id = stream.next_id(NewStreamId::Auto, 3).unwrap();
+        assert_eq!(id, StreamId::new(5, 1));
+        stream.add(id, fields("b"));
+        assert_eq!(
+            stream.next_id(NewStreamId::AutoSeq(5), 0).unwrap(),
+            StreamId::new(5, 2)
+        );
+        assert!(stream.next_id(NewStreamId::AutoSeq(4), 0).is_err());
+        assert!(stream
+            .next_id(NewStreamId::Explicit(StreamId::new(5, 1)), 0)
+            .is_err());
+        assert_eq!(
+            StreamDS::default()
+                .next_id(NewStreamId::AutoSeq(0), 0)
+                .unwrap(),
+            StreamId::new(0, 1)
+        );
+        assert!(StreamDS::default()
+            .next_id(NewStreamId::Explicit(StreamId::MIN), 0)
+            .is_err());
+
+        assert_eq!(NewStreamId::parse("7-*").unwrap(), NewStreamId::AutoSeq(7));
+        assert_eq!(
+            StreamId::parse_bound("(7", false).unwrap(),
+            Excluded(StreamId::new(7, u64::MAX))
+        );
+        assert!(StreamId::parse("7-x", 0).is_err());
+        let id = StreamId::new(1, 2);
+        assert_eq!(StreamId::from_be_bytes(id.to_be_bytes()), id);
+    }
+
+    #[test]
+    fn test_stream_range_trim() {
+        let mut stream = StreamDS::default();
+        for i in 1..=10 {
+            stream.add(StreamId::new(i, 0), fields(&i.to_string()));
+        }
+        let ids = |entries: Vec<(StreamId, StreamFields)>| -> Vec<u64> {
+            entries.into_iter().map(|(id, _)| id.ms).collect()
+        };
+        assert_eq!(
+            ids(stream.range(
+                Excluded(StreamId::new(2, 0)),
+                Included(StreamId::new(5, 0)),
+                false,
+                None
+            )),
+            vec![3, 4, 5]
+        );
+        assert_eq!(
+            ids(stream.range(
+                Included(StreamId::MIN),
+                Included(StreamId::MAX),
+                true,
+                Some(2)
+            )),
+            vec![10, 9]
+        );
+        assert!(stream
+            .range(
+                Excluded(StreamId::new(2, 0)),
+                Excluded(StreamId::new(2, 0)),
+                false,
+                None
+            )
+            .is_empty());
+
+        assert_eq!(
+            stream.remove(&[StreamId::new(10, 0), StreamId::new(11, 0)]),
+            1
+        );
+        assert_eq!(stream.max_deleted_id(), StreamId::new(10, 0));
+        assert_eq!(stream.trim(StreamTrim::MaxLen(5), Some(2)), 2);
+        assert_eq!(stream.trim(StreamTrim::MinId(StreamId::new(6, 0)), None), 3);
+        assert_eq!(stream.len(), 4);
+        assert_eq!(stream.entries_added(), 10);
+        assert_eq!(stream.last_id(), StreamId::new(10, 0));
+    }
+
+    #[test]
+    fn test_consumer_group() {
+        let mut stream = StreamDS::default();
+        for i in 1..=4 {
+            stream.add(StreamId::new(i, 0), fields(&i.to_string()));
+        }
+        assert!(stream.create_group("g", StreamId::MIN, None));
+        assert!(!stream.create_group("g", StreamId::MIN, None));
+        assert_eq!(stream.lag(stream.group("g").unwrap()), Some(4));
+
+        let read = stream
+            .read_group_new("g", "alice", Some(3), false, 100)
+            .unwrap();
+        assert_eq!(read.len(), 3);
+        let read = stream.read_group_new("g", "bob", None, false, 200).unwrap();
+        assert_eq!(read, vec![(StreamId::new(4, 0), fields("4"))]);
+        assert_eq!(stream.lag(stream.group("g").unwrap()), Some(0));
+        assert!(stream
+            .read_group_new("x", "bob", None, false, 200)
+            .is_none());
+
+        stream.remove(&[StreamId::new(2, 0)]);
+        let pending = stream
+            .read_group_pending("g", "alice", StreamId::MIN, None, 300)
+            .unwrap();
+        assert_eq!(pending[1], (StreamId::new(2, 0), None));
+
+        // entries of alice idle for 250ms are claimed by bob, the deleted one is dropped
+        let (next, claimed, deleted) = stream
+            .autoclaim("g", "bob", 250, StreamId::MIN, 10, false, 400)
+            .unwrap();
+        assert_eq!(next, StreamId::MIN);
+        assert_eq!(claimed, vec![StreamId::new(1, 0), StreamId::new(3, 0)]);
+        assert_eq!(deleted, vec![StreamId::new(2, 0)]);
+        let group = stream.group("g").unwrap();
+        assert_eq!(group.pending[&StreamId::new(1, 0)].delivery_count, 2);
+        assert_eq!(group.pending_by_consumer()[&"bob".to_string()], 3);
+
+        let options = ClaimOptions {
+            force: true,
+            justid: true,
+            ..Default::default()
+        };
+        let claimed = stream
+            .claim("g", "alice", 0, &[StreamId::new(4, 0)], &options, 500)
+            .unwrap();
+        assert_eq!(claimed, vec![StreamId::new(4, 0)]);
+        let group = stream.group_mut("g").unwrap();
+        assert_eq!(group.pending[&StreamId::new(4, 0)].consumer, "alice");
+        assert_eq!(group.ack(&[StreamId::new(4, 0), StreamId::new(9, 0)]), 1);
+        assert_eq!(group.delete_consumer("bob"), Some(2));
+        assert!(group.pending.is_empty());
+    }
+}
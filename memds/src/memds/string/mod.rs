@@ -0,0 +1,346 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use super::range_bounds;
+use crate::{byte_string::ByteString, Error};
+
+/// Max length of a string value, like the default proto-max-bulk-len of Redis
+pub const STRING_MAX_LEN: usize = 512 * 1024 * 1024;
+
+/// Max length of a string stored inline, like the `embstr` encoding of Redis
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// Max length of the decimal representation of an `i64`
+const INT_MAX_LEN: usize = 20;
+
+/// String with Redis-like encodings: integers are kept as `i64` so counters are updated
+/// without parsing and formatting, short strings are stored inline and strings that are
+/// long or modified in place (APPEND, SETRANGE) are stored in a `Vec`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "StringRepr", into = "StringRepr")]
+pub struct StringDS {
+    s: Encoding,
+}
+
+#[derive(Clone)]
+enum Encoding {
+    /// Strings that are the canonical representation of an integer
+    Int(i64),
+    Embstr(Embstr),
+    Raw(Vec<u8>),
+}
+
+/// String stored inline, without a heap allocation
+#[derive(Clone)]
+struct Embstr {
+    len: u8,
+    buf: [u8; EMBSTR_SIZE_LIMIT],
+}
+
+/// Serialized form of a string, the same as the former `String` field
+#[derive(Serialize, Deserialize)]
+struct StringRepr {
+    s: ByteString,
+}
+
+impl From<StringRepr> for StringDS {
+    fn from(repr: StringRepr) -> Self {
+        StringDS {
+            s: Encoding::new(Cow::Owned(repr.s.into_bytes())),
+        }
+    }
+}
+
+impl From<StringDS> for StringRepr {
+    fn from(string: StringDS) -> Self {
+        StringRepr { s: string.fetch() }
+    }
+}
+
+impl Embstr {
+    fn new(s: &[u8]) -> Self {
+        let mut buf = [0; EMBSTR_SIZE_LIMIT];
+        buf[..s.len()].copy_from_slice(s);
+
+        Embstr {
+            len: s.len() as u8,
+            buf,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+impl Encoding {
+    /// Picks the most compact encoding of `s`
+    fn new(s: Cow<[u8]>) -> Self {
+        if let Some(value) = parse_canonical_int(&s) {
+            Encoding::Int(value)
+        } else if s.len() <= EMBSTR_SIZE_LIMIT {
+            Encoding::Embstr(Embstr::new(&s))
+        } else {
+            Encoding::Raw(s.into_owned())
+        }
+    }
+}
+
+/// Formats `value` in decimal at the end of `buf`
+fn format_int(value: i64, buf: &mut [u8; INT_MAX_LEN]) -> &[u8] {
+    let mut n = value.unsigned_abs();
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+
+    &buf[start..]
+}
+
+/// Parses `s` if it is an integer formatted the way it would be formatted back,
+/// so the integer encoding doesn't change the string (e.g. "+1", "01" or "-0")
+fn parse_canonical_int(s: &[u8]) -> Option<i64> {
+    if s.is_empty() || s.len() > INT_MAX_LEN {
+        return None;
+    }
+    let value = crate::byte_string::parse::<i64>(s)?;
+
+    (format_int(value, &mut [0; INT_MAX_LEN]) == s).then_some(value)
+}
+
+impl StringDS {
+    pub fn from<S: AsRef<[u8]>>(s: S) -> Self {
+        StringDS {
+            s: Encoding::new(Cow::Borrowed(s.as_ref())),
+        }
+    }
+
+    pub fn fetch(&self) -> ByteString {
+        self.as_bytes().into_owned().into()
+    }
+
+    /// The string value, only integers need to be formatted
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.s {
+            Encoding::Int(value) => Cow::Owned(format_int(*value, &mut [0; INT_MAX_LEN]).into()),
+            Encoding::Embstr(embstr) => Cow::Borrowed(embstr.as_bytes()),
+            Encoding::Raw(s) => Cow::Borrowed(s),
+        }
+    }
+
+    pub fn set<S: AsRef<[u8]>>(&mut self, s: S) {
+        let s = s.as_ref();
+        match &mut self.s {
+            // reuse the allocation for values that would be raw anyway
+            Encoding::Raw(raw) if s.len() > EMBSTR_SIZE_LIMIT => {
+                raw.clear();
+                raw.extend_from_slice(s);
+            }
+            _ => self.s = Encoding::new(Cow::Borrowed(s)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.s {
+            Encoding::Int(value) => format_int(*value, &mut [0; INT_MAX_LEN]).len(),
+            Encoding::Embstr(embstr) => embstr.len as usize,
+            Encoding::Raw(s) => s.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Name of the encoding, as reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self.s {
+            Encoding::Int(_) => "int",
+            Encoding::Embstr(_) => "embstr",
+            Encoding::Raw(_) => "raw",
+        }
+    }
+
+    /// Converts to the raw encoding, for modifications in place
+    fn raw_mut(&mut self) -> &mut Vec<u8> {
+        if !matches!(self.s, Encoding::Raw(_)) {
+            self.s = Encoding::Raw(self.as_bytes().into_owned());
+        }
+        match &mut self.s {
+            Encoding::Raw(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Appends `value`, returns the length after the append
+    pub fn append(&mut self, value: &[u8]) -> Result<usize, Error> {
+        check_len(self.len() + value.len())?;
+        let s = self.raw_mut();
+        s.extend_from_slice(value);
+
+        Ok(s.len())
+    }
+
+    /// Gets the bytes from `start` to `end` inclusive,
+    /// negative offsets are from the end of the string
+    pub fn get_range(&self, start: i64, end: i64) -> ByteString {
+        let bytes = self.as_bytes();
+        match range_bounds(start, end, bytes.len()) {
+            Some((start, end)) => bytes[start..=end].into(),
+            None => ByteString::new(),
+        }
+    }
+
+    /// Overwrites the bytes from `offset` with `value`, padding with zero bytes if the
+    /// string is shorter than `offset`. Returns the length after the write.
+    pub fn set_range(&mut self, offset: usize, value: &[u8]) -> Result<usize, Error> {
+        if value.is_empty() {
+            return Ok(self.len());
+        }
+        let end = offset.saturating_add(value.len());
+        check_len(end)?;
+
+        let s = self.raw_mut();
+        if s.len() < end {
+            s.resize(end, 0);
+        }
+        s[offset..end].copy_from_slice(value);
+
+        Ok(s.len())
+    }
+
+    pub fn incr(&mut self) -> Result<i64, Error> {
+        self.incr_by(1)
+    }
+
+    /// Increments the integer value by `increment`
+    pub fn incr_by(&mut self, increment: i64) -> Result<i64, Error> {
+        let current = match self.s {
+            Encoding::Int(value) => value,
+            _ => crate::byte_string::parse::<i64>(&self.as_bytes()).ok_or_else(|| {
+                Error::Handle("ERR value is not an integer or out of range".to_string())
+            })?,
+        };
+        let value = current.checked_add(increment).ok_or_else(|| {
+            Error::Handle("ERR increment or decrement would overflow".to_string())
+        })?;
+        self.s = Encoding::Int(value);
+
+        Ok(value)
+    }
+
+    /// Increments the float value by `increment`, returns the new value as it is stored
+    pub fn incr_by_float(&mut self, increment: f64) -> Result<String, Error> {
+        let current = crate::byte_string::parse::<f64>(&self.as_bytes())
+            .filter(|f| f.is_finite())
+            .ok_or_else(|| Error::Handle("ERR value is not a valid float".to_string()))?;
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(Error::Handle(
+                "ERR increment would produce NaN or Infinity".to_string(),
+            ));
+        }
+        let value = value.to_string();
+        self.s = Encoding::new(Cow::Borrowed(value.as_bytes()));
+
+        Ok(value)
+    }
+}
+
+fn check_len(len: usize) -> Result<(), Error> {
+    if len > STRING_MAX_LEN {
+        return Err(Error::Handle(
+            "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_ops() {
+        let mut s = StringDS::from("Hello");
+        assert_eq!(s.append(b" World").unwrap(), 11);
+        assert_eq!(s.get_range(0, 4), "Hello");
+        assert_eq!(s.get_range(-5, -1), "World");
+        assert_eq!(s.get_range(-100, 100), "Hello World");
+        assert_eq!(s.get_range(5, 3), "");
+        assert_eq!(s.set_range(6, b"Redis").unwrap(), 11);
+        assert_eq!(s.fetch(), "Hello Redis");
+        assert_eq!(s.set_range(13, b"!").unwrap(), 14);
+        assert_eq!(s.fetch(), "Hello Redis\0\0!");
+        assert!(s.set_range(STRING_MAX_LEN, b"x").is_err());
+        assert_eq!(s.set_range(0, b"\xff").unwrap(), 14);
+        assert_eq!(s.get_range(0, 1), &b"\xffe"[..]);
+
+        let mut n = StringDS::from("10");
+        assert_eq!(n.incr_by(-15).unwrap(), -5);
+        assert!(n.incr_by(i64::MIN).is_err());
+        assert_eq!(n.fetch(), "-5");
+        assert_eq!(n.incr_by_float(0.5).unwrap(), "-4.5");
+        assert!(n.incr_by(1).is_err());
+        assert!(StringDS::from(i64::MAX.to_string()).incr().is_err());
+        assert!(StringDS::from("abc").incr_by_float(1.0).is_err());
+        assert!(StringDS::from("1").incr_by_float(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        let cases = [
+            ("0", "int"),
+            ("-9223372036854775808", "int"),
+            ("9223372036854775808", "embstr"),
+            ("+1", "embstr"),
+            ("01", "embstr"),
+            ("-0", "embstr"),
+            ("", "embstr"),
+            ("héllo", "embstr"),
+        ];
+        for (value, encoding) in cases {
+            let s = StringDS::from(value);
+            assert_eq!(s.encoding(), encoding, "{}", value);
+            assert_eq!(s.fetch(), value);
+            assert_eq!(s.len(), value.len());
+        }
+        let long = "x".repeat(EMBSTR_SIZE_LIMIT + 1);
+        assert_eq!(StringDS::from(&long).encoding(), "raw");
+
+        let mut s = StringDS::from("01");
+        assert_eq!(s.incr().unwrap(), 2);
+        assert_eq!(s.encoding(), "int");
+        assert_eq!(s.append(b"0").unwrap(), 2);
+        assert_eq!(s.encoding(), "raw");
+        assert_eq!(s.fetch(), "20");
+        assert_eq!(s.incr_by(-20).unwrap(), 0);
+        assert_eq!(s.get_range(0, -1), "0");
+        assert_eq!(s.incr_by_float(1.5).unwrap(), "1.5");
+        assert_eq!(s.encoding(), "embstr");
+        assert_eq!(s.incr_by_float(1.5).unwrap(), "3");
+        assert_eq!(s.encoding(), "int");
+        s.set(&long);
+        assert_eq!(s.fetch(), long);
+
+        // serialized the same way for every encoding
+        for value in ["12", "abc", &long] {
+            let serialized = bincode::serialize(&StringDS::from(value)).unwrap();
+            assert_eq!(serialized, bincode::serialize(value).unwrap());
+            let s: StringDS = bincode::deserialize(&serialized).unwrap();
+            assert_eq!(s.fetch(), value);
+            assert_eq!(s.encoding(), StringDS::from(value).encoding());
+        }
+    }
+}
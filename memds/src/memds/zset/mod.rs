@@ -0,0 +1,561 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    byte_string::{self, ByteString},
+    Error,
+};
+
+/// Index of a missing node
+const NIL: usize = usize::MAX;
+
+#[derive(Clone)]
+struct Node {
+    score: f64,
+    member: ByteString,
+    priority: u64,
+    /// Number of nodes in the subtree rooted at this node
+    size: usize,
+    left: usize,
+    right: usize,
+}
+
+/// Elements ordered by score then member, stored in a treap whose nodes know the size of
+/// their subtree, so finding the rank of an element or the element at a rank is O(log n).
+/// Nodes are kept in a vector and linked by index.
+#[derive(Clone)]
+struct RankTree {
+    nodes: Vec<Node>,
+    /// Indexes of removed nodes to be reused
+    free: Vec<usize>,
+    root: usize,
+}
+
+impl Default for RankTree {
+    fn default() -> Self {
+        RankTree {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+        }
+    }
+}
+
+fn cmp_element(score: f64, member: &[u8], other_score: f64, other_member: &[u8]) -> Ordering {
+    score
+        .partial_cmp(&other_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| member.cmp(other_member))
+}
+
+impl RankTree {
+    fn size(&self, t: usize) -> usize {
+        if t == NIL {
+            0
+        } else {
+            self.nodes[t].size
+        }
+    }
+
+    fn update(&mut self, t: usize) {
+        let node = &self.nodes[t];
+        self.nodes[t].size = 1 + self.size(node.left) + self.size(node.right);
+    }
+
+    /// Splits the subtree `t` into the leading elements for which `before` holds, and the rest
+    fn split<F>(&mut self, t: usize, before: &F) -> (usize, usize)
+    where
+        F: Fn(f64, &[u8]) -> bool,
+    {
+        if t == NIL {
+            return (NIL, NIL);
+        }
+
+        if before(self.nodes[t].score, &self.nodes[t].member) {
+            let (l, r) = self.split(self.nodes[t].right, before);
+            self.nodes[t].right = l;
+            self.update(t);
+            (t, r)
+        } else {
+            let (l, r) = self.split(self.nodes[t].left, before);
+            self.nodes[t].left = r;
+            self.update(t);
+            (l, t)
+        }
+    }
+
+    /// Merges two subtrees, all elements of `a` are before the elements of `b`
+    fn merge(&mut self, a: usize, b: usize) -> usize {
+        if a == NIL {
+            return b;
+        }
+        if b == NIL {
+            return a;
+        }
+
+        if self.nodes[a].priority > self.nodes[b].priority {
+            let right = self.merge(self.nodes[a].right, b);
+            self.nodes[a].right = right;
+            self.update(a);
+            a
+        } else {
+            let left = self.merge(a, self.nodes[b].left);
+            self.nodes[b].left = left;
+            self.update(b);
+            b
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.size(self.root)
+    }
+
+    fn insert(&mut self, score: f64, member: ByteString) {
+        let (l, r) = self.split(self.root, &|s, m| {
+            cmp_element(s, m, score, &member) == Ordering::Less
+        });
+        let node = Node {
+            score,
+            member,
+            priority: rand::random(),
+            size: 1,
+            left: NIL,
+            right: NIL,
+        };
+        let t = match self.free.pop() {
+            Some(t) => {
+                self.nodes[t] = node;
+                t
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        let l = self.merge(l, t);
+        self.root = self.merge(l, r);
+    }
+
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (l, r) = self.split(self.root, &|s, m| {
+            cmp_element(s, m, score, member) == Ordering::Less
+        });
+        let (found, r) = self.split(r, &|s, m| {
+            cmp_element(s, m, score, member) != Ordering::Greater
+        });
+        if found != NIL {
+            self.nodes[found].member = ByteString::new();
+            self.free.push(found);
+        }
+        self.root = self.merge(l, r);
+
+        found != NIL
+    }
+
+    /// Counts the leading elements for which `before` holds
+    fn count_before<F>(&self, before: F) -> usize
+    where
+        F: Fn(f64, &[u8]) -> bool,
+    {
+        let mut t = self.root;
+        let mut count = 0;
+        while t != NIL {
+            let node = &self.nodes[t];
+            if before(node.score, &node.member) {
+                count += self.size(node.left) + 1;
+                t = node.right;
+            } else {
+                t = node.left;
+            }
+        }
+
+        count
+    }
+
+    /// Gets the element at `rank`, from 0
+    fn get(&self, mut rank: usize) -> Option<(&ByteString, f64)> {
+        let mut t = self.root;
+        while t != NIL {
+            let node = &self.nodes[t];
+            let left = self.size(node.left);
+            match rank.cmp(&left) {
+                Ordering::Less => t = node.left,
+                Ordering::Equal => return Some((&node.member, node.score)),
+                Ordering::Greater => {
+                    rank -= left + 1;
+                    t = node.right;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Bound of a score range, parsed from `1.5`, `(1.5` (exclusive), `-inf` or `+inf`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ScoreBound {
+    pub fn parse(s: &[u8]) -> Result<Self, Error> {
+        let (value, exclusive) = match s.strip_prefix(b"(") {
+            Some(value) => (value, true),
+            None => (s, false),
+        };
+        let value = byte_string::parse::<f64>(value)
+            .filter(|v| !v.is_nan())
+            .ok_or_else(|| Error::Handle("ERR min or max is not a float".to_string()))?;
+
+        Ok(if exclusive {
+            ScoreBound::Exclusive(value)
+        } else {
+            ScoreBound::Inclusive(value)
+        })
+    }
+}
+
+/// Bound of a lexicographical range, parsed from `[a`, `(a` (exclusive), `-` or `+`
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(ByteString),
+    Exclusive(ByteString),
+}
+
+impl LexBound {
+    pub fn parse(s: &[u8]) -> Result<Self, Error> {
+        match s.split_first() {
+            Some((b'-', [])) => Ok(LexBound::Min),
+            Some((b'+', [])) => Ok(LexBound::Max),
+            Some((b'[', member)) => Ok(LexBound::Inclusive(member.into())),
+            Some((b'(', member)) => Ok(LexBound::Exclusive(member.into())),
+            _ => Err(Error::Handle(
+                "ERR min or max not valid string range item".to_string(),
+            )),
+        }
+    }
+}
+
+/// Range of elements of a sorted set
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZRange {
+    /// Inclusive ranks, negative ranks count from the last element
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    /// Members range, only meaningful when all elements have the same score
+    Lex(LexBound, LexBound),
+}
+
+/// Max number of elements of a sorted set reported as a listpack
+const MAX_LISTPACK_ENTRIES: usize = 128;
+/// Max length of the members of a sorted set reported as a listpack
+const MAX_LISTPACK_VALUE: usize = 64;
+
+/// Sorted set of unique members ordered by score, with O(1) score lookup of a member and
+/// O(log n) rank operations
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(ByteString, f64)>", into = "Vec<(ByteString, f64)>")]
+pub struct ZSetDS {
+    scores: HashMap<ByteString, f64>,
+    tree: RankTree,
+    /// Whether the set grew past the listpack thresholds, like Redis it is never converted
+    /// back to a listpack
+    skiplist: bool,
+}
+
+impl From<Vec<(ByteString, f64)>> for ZSetDS {
+    fn from(elements: Vec<(ByteString, f64)>) -> Self {
+        let mut zset = ZSetDS::default();
+        for (member, score) in elements {
+            zset.insert(&member, score);
+        }
+
+        zset
+    }
+}
+
+impl From<ZSetDS> for Vec<(ByteString, f64)> {
+    fn from(zset: ZSetDS) -> Self {
+        zset.iter().map(|(m, s)| (m.clone(), s)).collect()
+    }
+}
+
+impl ZSetDS {
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of `member`, returns true if the member is new
+    pub fn insert(&mut self, member: &[u8], score: f64) -> bool {
+        // -0.0 and 0.0 are the same score
+        let score = score + 0.0;
+        match self.scores.get_mut(member) {
+            Some(old) if *old == score => false,
+            Some(old) => {
+                self.tree.remove(*old, member);
+                *old = score;
+                self.tree.insert(score, member.into());
+                false
+            }
+            None => {
+                self.scores.insert(member.into(), score);
+                self.tree.insert(score, member.into());
+                if self.len() > MAX_LISTPACK_ENTRIES || member.len() > MAX_LISTPACK_VALUE {
+                    self.skiplist = true;
+                }
+                true
+            }
+        }
+    }
+
+    /// Removes `member`, returns true if it existed
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.tree.remove(score, member),
+            None => false,
+        }
+    }
+
+    /// Name of the encoding, as reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        if self.skiplist {
+            "skiplist"
+        } else {
+            "listpack"
+        }
+    }
+
+    /// Rank of `member` from 0, in ascending order of scores
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+
+        Some(
+            self.tree
+                .count_before(|s, m| cmp_element(s, m, score, member) == Ordering::Less),
+        )
+    }
+
+    /// Gets the element at `rank`, from 0 in ascending order of scores
+    pub fn get(&self, rank: usize) -> Option<(&ByteString, f64)> {
+        self.tree.get(rank)
+    }
+
+    /// Iterates elements in ascending order of scores
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&ByteString, f64)> + '_ {
+        (0..self.len()).filter_map(|rank| self.get(rank))
+    }
+
+    /// Counts the elements with a score at or above `min`
+    fn score_lower_rank(&self, min: ScoreBound) -> usize {
+        match min {
+            ScoreBound::Inclusive(min) => self.tree.count_before(|s, _| s < min),
+            ScoreBound::Exclusive(min) => self.tree.count_before(|s, _| s <= min),
+        }
+    }
+
+    fn score_upper_rank(&self, max: ScoreBound) -> usize {
+        match max {
+            ScoreBound::Inclusive(max) => self.tree.count_before(|s, _| s <= max),
+            ScoreBound::Exclusive(max) => self.tree.count_before(|s, _| s < max),
+        }
+    }
+
+    fn lex_rank(&self, bound: &LexBound, upper: bool) -> usize {
+        match bound {
+            LexBound::Min => 0,
+            LexBound::Max => self.len(),
+            LexBound::Inclusive(b) if upper => self.tree.count_before(|_, m| m <= b.as_bytes()),
+            LexBound::Inclusive(b) => self.tree.count_before(|_, m| m < b.as_bytes()),
+            LexBound::Exclusive(b) if upper => self.tree.count_before(|_, m| m < b.as_bytes()),
+            LexBound::Exclusive(b) => self.tree.count_before(|_, m| m <= b.as_bytes()),
+        }
+    }
+
+    /// Ranks of the elements in `range`, as a range from the first rank to after the last rank
+    pub fn ranks(&self, range: &ZRange) -> std::ops::Range<usize> {
+        let (start, end) = match range {
+            ZRange::Rank(start, stop) => {
+                let len = self.len() as i64;
+                let start = if *start < 0 { len + start } else { *start }.max(0);
+                let stop = if *stop < 0 { len + stop } else { *stop }.min(len - 1);
+                (start as usize, (stop + 1).max(0) as usize)
+            }
+            ZRange::Score(min, max) => (self.score_lower_rank(*min), self.score_upper_rank(*max)),
+            ZRange::Lex(min, max) => (self.lex_rank(min, false), self.lex_rank(max, true)),
+        };
+
+        start..end.max(start)
+    }
+
+    /// Gets the elements in `range`, from the highest rank if `rev` is true,
+    /// skipping the first `offset` elements and returning at most `count` elements
+    pub fn range(
+        &self,
+        range: &ZRange,
+        rev: bool,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Vec<(ByteString, f64)> {
+        let ranks = match (range, rev) {
+            // reversed ranks count from the last element
+            (ZRange::Rank(..), true) => {
+                let reversed = self.ranks(range);
+                self.len() - reversed.end..self.len() - reversed.start
+            }
+            _ => self.ranks(range),
+        };
+        let count = count.unwrap_or(usize::MAX);
+        let element = |rank| self.get(rank).map(|(m, s)| (m.clone(), s));
+
+        if rev {
+            ranks
+                .rev()
+                .skip(offset)
+                .take(count)
+                .filter_map(element)
+                .collect()
+        } else {
+            ranks.skip(offset).take(count).filter_map(element).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(elements: &[(ByteString, f64)]) -> Vec<&[u8]> {
+        elements.iter().map(|(m, _)| m.as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_rank_tree() {
+        let mut zset = ZSetDS::default();
+        for i in 0..1000 {
+            assert!(zset.insert(format!("m{}", i).as_bytes(), (i % 100) as f64));
+        }
+        assert_eq!(zset.len(), 1000);
+        assert_eq!(zset.tree.len(), 1000);
+        assert!(!zset.insert(b"m5", 5.0));
+        assert_eq!(zset.rank(b"m0"), Some(0));
+        assert_eq!(zset.rank(b"m100"), Some(1));
+        assert_eq!(zset.rank(b"m99"), Some(998));
+        assert_eq!(zset.get(999).unwrap().0, "m999");
+
+        // update the score, moving the member to the end
+        assert!(!zset.insert(b"m0", 1000.0));
+        assert_eq!(zset.rank(b"m0"), Some(999));
+        for i in 0..500 {
+            assert!(zset.remove(format!("m{}", i).as_bytes()));
+        }
+        assert!(!zset.remove(b"m0"));
+        assert_eq!(zset.len(), 500);
+        assert_eq!(zset.tree.len(), 500);
+        assert_eq!(zset.tree.nodes.len(), 1000);
+        let scores: Vec<f64> = zset.iter().map(|(_, s)| s).collect();
+        assert!(scores.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_zset_encoding() {
+        let mut zset = ZSetDS::default();
+        for i in 0..MAX_LISTPACK_ENTRIES {
+            zset.insert(format!("m{}", i).as_bytes(), i as f64);
+        }
+        assert_eq!(zset.encoding(), "listpack");
+        zset.insert(b"m", 0.0);
+        assert_eq!(zset.encoding(), "skiplist");
+        zset.remove(b"m");
+        assert_eq!(zset.encoding(), "skiplist");
+
+        let mut zset = ZSetDS::default();
+        zset.insert(&[b'm'; MAX_LISTPACK_VALUE], 1.0);
+        assert_eq!(zset.encoding(), "listpack");
+        zset.insert(&[b'm'; MAX_LISTPACK_VALUE + 1], 1.0);
+        assert_eq!(zset.encoding(), "skiplist");
+    }
+
+    #[test]
+    fn test_ranges() {
+        let zset: ZSetDS = vec![
+            ("a".into(), 1.0),
+            ("b".into(), 2.0),
+            ("c".into(), 2.0),
+            ("d".into(), 3.0),
+        ]
+        .into();
+
+        let range = |range: ZRange, rev| zset.range(&range, rev, 0, None);
+        assert_eq!(
+            members(&range(ZRange::Rank(0, -1), false)),
+            [&b"a"[..], &b"b"[..], &b"c"[..], &b"d"[..]]
+        );
+        assert_eq!(
+            members(&range(ZRange::Rank(-2, 100), false)),
+            [&b"c"[..], &b"d"[..]]
+        );
+        assert_eq!(
+            members(&range(ZRange::Rank(0, 1), true)),
+            [&b"d"[..], &b"c"[..]]
+        );
+        assert!(range(ZRange::Rank(3, 1), false).is_empty());
+        assert!(range(ZRange::Rank(-100, -50), false).is_empty());
+
+        let score = |min, max| ZRange::Score(min, max);
+        let inclusive = ScoreBound::Inclusive;
+        let exclusive = ScoreBound::Exclusive;
+        assert_eq!(
+            members(&range(score(inclusive(2.0), inclusive(3.0)), false)),
+            [&b"b"[..], &b"c"[..], &b"d"[..]]
+        );
+        assert_eq!(
+            members(&range(score(exclusive(1.0), exclusive(3.0)), true)),
+            [&b"c"[..], &b"b"[..]]
+        );
+        assert_eq!(
+            members(&range(
+                score(inclusive(f64::NEG_INFINITY), exclusive(2.0)),
+                false
+            )),
+            [&b"a"[..]]
+        );
+        assert!(range(score(inclusive(3.0), inclusive(1.0)), false).is_empty());
+
+        let same: ZSetDS = ["a", "b", "c", "d"]
+            .iter()
+            .map(|&m| (m.into(), 0.0))
+            .collect::<Vec<_>>()
+            .into();
+        let lex = |min: &[u8], max: &[u8]| {
+            ZRange::Lex(LexBound::parse(min).unwrap(), LexBound::parse(max).unwrap())
+        };
+        assert_eq!(
+            members(&same.range(&lex(b"[b", b"(d"), false, 0, None)),
+            [&b"b"[..], &b"c"[..]]
+        );
+        assert_eq!(
+            members(&same.range(&lex(b"-", b"+"), true, 1, Some(2))),
+            [&b"c"[..], &b"b"[..]]
+        );
+        assert!(LexBound::parse(b"b").is_err());
+        assert!(ScoreBound::parse(b"(x").is_err());
+        assert_eq!(
+            ScoreBound::parse(b"(-inf").unwrap(),
+            ScoreBound::Exclusive(f64::NEG_INFINITY)
+        );
+    }
+}
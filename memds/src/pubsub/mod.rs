@@ -0,0 +1,578 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::mpsc;
+
+use crate::{byte_string::ByteString, glob::glob_match};
+
+/// Limit of the messages queued for a subscriber unless configured, in bytes
+pub const DEFAULT_BUFFER_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Channels and patterns subscribed by the clients, shared by all sessions
+pub struct PubSub {
+    registry: Mutex<Registry>,
+    next_id: AtomicU64,
+    /// Limit of the messages queued for a subscriber not reading them, in bytes, 0 for no
+    /// limit. A subscriber over the limit is unsubscribed from everything and disconnected.
+    buffer_limit: AtomicUsize,
+    /// Flags of the enabled keyspace events, see `KeyspaceEvents`
+    keyspace_events: AtomicU32,
+}
+
+/// Class of a keyspace event, each class is enabled by a character of
+/// `notify-keyspace-events`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventClass {
+    /// `g`: commands on keys of any type like DEL, EXPIRE and RENAME
+    Generic,
+    /// `$`
+    String,
+    /// `l`
+    List,
+    /// `s`
+    Set,
+    /// `h`
+    Hash,
+    /// `z`
+    SortedSet,
+    /// `t`
+    Stream,
+    /// `x`: keys deleted once their expiry time passed
+    Expired,
+    /// `e`: keys evicted for memory, memds does not evict keys
+    Evicted,
+    /// `n`: keys created, not included in `A`
+    New,
+}
+
+const CLASS_FLAGS: [(char, EventClass); 10] = [
+    ('g', EventClass::Generic),
+    ('$', EventClass::String),
+    ('l', EventClass::List),
+    ('s', EventClass::Set),
+    ('h', EventClass::Hash),
+    ('z', EventClass::SortedSet),
+    ('t', EventClass::Stream),
+    ('x', EventClass::Expired),
+    ('e', EventClass::Evicted),
+    ('n', EventClass::New),
+];
+
+impl EventClass {
+    fn flag(self) -> u32 {
+        1 << (self as u32 + 2)
+    }
+}
+
+/// Keyspace events published to subscribers, parsed from the characters of the
+/// `notify-keyspace-events` setting as Redis does: `K` publishes to `__keyspace@<db>__:<key>`
+/// channels, `E` to `__keyevent@<db>__:<event>` channels, and the other characters enable
+/// classes of events, see `EventClass`, with `A` for all classes but `n`.
+/// Nothing is published unless `K` or `E` is given.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct KeyspaceEvents(u32);
+
+impl KeyspaceEvents {
+    const KEYSPACE: u32 = 1 << 0;
+    const KEYEVENT: u32 = 1 << 1;
+
+    pub fn parse(flags: &str) -> Option<Self> {
+        let mut events = 0;
+        for c in flags.chars() {
+            events |= match c {
+                'K' => Self::KEYSPACE,
+                'E' => Self::KEYEVENT,
+                'A' => CLASS_FLAGS
+                    .iter()
+                    .filter(|(_, class)| *class != EventClass::New)
+                    .fold(0, |flags, (_, class)| flags | class.flag()),
+                c => CLASS_FLAGS.iter().find(|(flag, _)| *flag == c)?.1.flag(),
+            };
+        }
+
+        Some(KeyspaceEvents(events))
+    }
+
+    /// Whether events of `class` are published to any channel
+    pub fn enabled(self, class: EventClass) -> bool {
+        self.0 & (Self::KEYSPACE | Self::KEYEVENT) != 0 && self.0 & class.flag() != 0
+    }
+}
+
+impl std::fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (c, class) in CLASS_FLAGS {
+            if self.0 & class.flag() != 0 {
+                write!(f, "{}", c)?;
+            }
+        }
+        if self.0 & Self::KEYSPACE != 0 {
+            write!(f, "K")?;
+        }
+        if self.0 & Self::KEYEVENT != 0 {
+            write!(f, "E")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    subscribers: HashMap<u64, Subscriber>,
+    /// Ids of the subscribers of each channel
+    channels: HashMap<ByteString, HashSet<u64>>,
+    /// Ids of the subscribers of each pattern
+    patterns: HashMap<ByteString, HashSet<u64>>,
+}
+
+struct Subscriber {
+    sender: mpsc::UnboundedSender<Message>,
+    state: Arc<SubscriberState>,
+}
+
+/// State of a subscriber shared between the publishers and its session
+#[derive(Default)]
+struct SubscriberState {
+    /// Size of the messages sent and not received yet
+    queued: AtomicUsize,
+    /// Whether the subscriber went over the buffer limit
+    overflowed: AtomicBool,
+}
+
+/// Message published to a channel, received through `pattern` if it is a pattern
+/// subscription
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub pattern: Option<ByteString>,
+    pub channel: ByteString,
+    pub payload: ByteString,
+}
+
+impl Message {
+    /// Size accounted against the buffer limit
+    fn size(&self) -> usize {
+        self.pattern.as_ref().map_or(0, |pattern| pattern.len())
+            + self.channel.len()
+            + self.payload.len()
+    }
+}
+
+/// Subscriptions of a client, with the messages published to them
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<Message>,
+    state: Arc<SubscriberState>,
+    channels: HashSet<ByteString>,
+    patterns: HashSet<ByteString>,
+}
+
+impl std::fmt::Debug for SubscriberState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscriberState")
+            .field("queued", &self.queued.load(Ordering::Relaxed))
+            .field("overflowed", &self.overflowed.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Subscription {
+    /// Number of channels and patterns subscribed
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &ByteString> {
+        self.channels.iter()
+    }
+
+    pub fn patterns(&self) -> impl Iterator<Item = &ByteString> {
+        self.patterns.iter()
+    }
+
+    /// Waits for the next message published to the subscriptions.
+    /// Returns None once the subscriber went over the buffer limit, it is to be disconnected.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let message = self.receiver.recv().await?;
+        self.received(message)
+    }
+
+    /// Takes the next message published to the subscriptions, if there is one already.
+    /// Returns None as well once the subscriber went over the buffer limit.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let message = self.receiver.try_recv().ok()?;
+        self.received(message)
+    }
+
+    fn received(&self, message: Message) -> Option<Message> {
+        if self.overflowed() {
+            return None;
+        }
+        self.state
+            .queued
+            .fetch_sub(message.size(), Ordering::Relaxed);
+
+        Some(message)
+    }
+
+    /// Whether the subscriber went over the buffer limit
+    pub fn overflowed(&self) -> bool {
+        self.state.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub {
+            registry: Mutex::new(Registry::default()),
+            next_id: AtomicU64::new(0),
+            buffer_limit: AtomicUsize::new(DEFAULT_BUFFER_LIMIT),
+            keyspace_events: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Registry {
+    /// Removes the subscriber from the subscribers of `name`,
+    /// and `name` from `subscriptions` once it has no subscriber
+    fn remove(subscriptions: &mut HashMap<ByteString, HashSet<u64>>, name: &[u8], id: u64) {
+        if let Some(ids) = subscriptions.get_mut(name) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                subscriptions.remove(name);
+            }
+        }
+    }
+
+    /// Removes the subscriber from all its channels and patterns,
+    /// dropping the sender of its messages
+    fn remove_subscriber<'a>(
+        &mut self,
+        id: u64,
+        channels: impl Iterator<Item = &'a ByteString>,
+        patterns: impl Iterator<Item = &'a ByteString>,
+    ) {
+        self.subscribers.remove(&id);
+        for channel in channels {
+            Registry::remove(&mut self.channels, channel, id);
+        }
+        for pattern in patterns {
+            Registry::remove(&mut self.patterns, pattern, id);
+        }
+    }
+}
+
+impl PubSub {
+    pub fn set_buffer_limit(&self, limit: usize) {
+        self.buffer_limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn set_keyspace_events(&self, events: KeyspaceEvents) {
+        self.keyspace_events.store(events.0, Ordering::Relaxed);
+    }
+
+    pub fn keyspace_events(&self) -> KeyspaceEvents {
+        KeyspaceEvents(self.keyspace_events.load(Ordering::Relaxed))
+    }
+
+    /// Publishes the `event` of `key` in database `db` if events of `class` are enabled,
+    /// with the event to the keyspace channel of the key and the key to the keyevent
+    /// channel of the event
+    pub fn notify_keyspace_event(&self, db: usize, class: EventClass, event: &str, key: &[u8]) {
+        let events = self.keyspace_events();
+        if !events.enabled(class) {
+            return;
+        }
+
+        if events.0 & KeyspaceEvents::KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+            channel.extend_from_slice(key);
+            self.publish(&channel, event.as_bytes());
+        }
+        if events.0 & KeyspaceEvents::KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            self.publish(channel.as_bytes(), key);
+        }
+    }
+
+    /// Registers a subscriber, without any subscription yet
+    pub fn register(&self) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let state = Arc::new(SubscriberState::default());
+        self.registry.lock().unwrap().subscribers.insert(
+            id,
+            Subscriber {
+                sender,
+                state: state.clone(),
+            },
+        );
+
+        Subscription {
+            id,
+            receiver,
+            state,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    /// Removes all the subscriptions of a subscriber, when its connection ends
+    pub fn unregister(&self, subscription: &Subscription) {
+        self.registry.lock().unwrap().remove_subscriber(
+            subscription.id,
+            subscription.channels.iter(),
+            subscription.patterns.iter(),
+        );
+    }
+
+    /// Subscribes to `channel`, returns the number of subscriptions of the subscriber
+    pub fn subscribe(&self, subscription: &mut Subscription, channel: &[u8]) -> usize {
+        if subscription.channels.insert(channel.into()) {
+            let mut registry = self.registry.lock().unwrap();
+            // an overflowed subscriber is not registered anymore and gets no messages
+            if registry.subscribers.contains_key(&subscription.id) {
+                registry
+                    .channels
+                    .entry(channel.into())
+                    .or_default()
+                    .insert(subscription.id);
+            }
+        }
+
+        subscription.count()
+    }
+
+    /// Unsubscribes from `channel`, returns the number of subscriptions left
+    pub fn unsubscribe(&self, subscription: &mut Subscription, channel: &[u8]) -> usize {
+        if subscription.channels.remove(channel) {
+            let mut registry = self.registry.lock().unwrap();
+            Registry::remove(&mut registry.channels, channel, subscription.id);
+        }
+
+        subscription.count()
+    }
+
+    /// Subscribes to the channels matching the glob-style `pattern`,
+    /// returns the number of subscriptions of the subscriber
+    pub fn psubscribe(&self, subscription: &mut Subscription, pattern: &[u8]) -> usize {
+        if subscription.patterns.insert(pattern.into()) {
+            let mut registry = self.registry.lock().unwrap();
+            if registry.subscribers.contains_key(&subscription.id) {
+                registry
+                    .patterns
+                    .entry(pattern.into())
+                    .or_default()
+                    .insert(subscription.id);
+            }
+        }
+
+        subscription.count()
+    }
+
+    /// Unsubscribes from `pattern`, returns the number of subscriptions left
+    pub fn punsubscribe(&self, subscription: &mut Subscription, pattern: &[u8]) -> usize {
+        if subscription.patterns.remove(pattern) {
+            let mut registry = self.registry.lock().unwrap();
+            Registry::remove(&mut registry.patterns, pattern, subscription.id);
+        }
+
+        subscription.count()
+    }
+
+    /// Publishes `payload` to the subscribers of `channel` and of the patterns matching it,
+    /// returns the number of subscriptions that received it.
+    /// Subscribers going over the buffer limit are unsubscribed from everything.
+    pub fn publish(&self, channel: &[u8], payload: &[u8]) -> usize {
+        let limit = self.buffer_limit.load(Ordering::Relaxed);
+        let mut registry = self.registry.lock().unwrap();
+
+        let mut receivers = Vec::new();
+        if let Some(ids) = registry.channels.get(channel) {
+            receivers.extend(ids.iter().map(|&id| (id, None)));
+        }
+        for (pattern, ids) in &registry.patterns {
+            if glob_match(pattern, channel) {
+                receivers.extend(ids.iter().map(|&id| (id, Some(pattern.clone()))));
+            }
+        }
+
+        let mut overflowed = Vec::new();
+        for (id, pattern) in &receivers {
+            let Some(subscriber) = registry.subscribers.get(id) else {
+                continue;
+            };
+            let message = Message {
+                pattern: pattern.clone(),
+                channel: channel.into(),
+                payload: payload.into(),
+            };
+            let queued = subscriber
+                .state
+                .queued
+                .fetch_add(message.size(), Ordering::Relaxed)
+                + message.size();
+            if subscriber.sender.send(message).is_err() || (limit != 0 && queued > limit) {
+                overflowed.push(*id);
+            }
+        }
+
+        for id in overflowed {
+            if let Some(subscriber) = registry.subscribers.remove(&id) {
+                subscriber.state.overflowed.store(true, Ordering::Relaxed);
+                tracing::warn!("Subscriber {} is over the output buffer limit", id);
+            }
+            for ids in registry.channels.values_mut() {
+                ids.remove(&id);
+            }
+            for ids in registry.patterns.values_mut() {
+                ids.remove(&id);
+            }
+            registry.channels.retain(|_, ids| !ids.is_empty());
+            registry.patterns.retain(|_, ids| !ids.is_empty());
+        }
+
+        receivers.len()
+    }
+
+    /// Channels with at least one subscriber, matching the glob-style `pattern` if given
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<ByteString> {
+        let registry = self.registry.lock().unwrap();
+
+        registry
+            .channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    /// Number of subscribers of `channel`, not counting pattern subscriptions
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        let registry = self.registry.lock().unwrap();
+
+        registry.channels.get(channel).map_or(0, HashSet::len)
+    }
+
+    /// Number of patterns subscribed by any client
+    pub fn numpat(&self) -> usize {
+        self.registry.lock().unwrap().patterns.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(pattern: Option<&str>, channel: &str, payload: &str) -> Message {
+        Message {
+            pattern: pattern.map(ByteString::from),
+            channel: channel.into(),
+            payload: payload.into(),
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let pubsub = PubSub::default();
+        let mut a = pubsub.register();
+        let mut b = pubsub.register();
+
+        assert_eq!(pubsub.subscribe(&mut a, b"news"), 1);
+        assert_eq!(pubsub.subscribe(&mut a, b"news"), 1);
+        assert_eq!(pubsub.psubscribe(&mut a, b"n*"), 2);
+        assert_eq!(pubsub.subscribe(&mut b, b"news"), 1);
+        assert_eq!(pubsub.subscribe(&mut b, b"sport"), 2);
+
+        assert_eq!(pubsub.publish(b"news", b"hello"), 3);
+        assert_eq!(pubsub.publish(b"weather", b"sun"), 0);
+        assert_eq!(a.try_recv(), Some(message(None, "news", "hello")));
+        assert_eq!(a.try_recv(), Some(message(Some("n*"), "news", "hello")));
+        assert_eq!(a.try_recv(), None);
+        assert_eq!(b.try_recv(), Some(message(None, "news", "hello")));
+        assert_eq!(b.try_recv(), None);
+
+        let mut channels = pubsub.channels(None);
+        channels.sort();
+        assert_eq!(channels, ["news", "sport"]);
+        assert_eq!(pubsub.channels(Some(b"s*")), ["sport"]);
+        assert_eq!(pubsub.numsub(b"news"), 2);
+        assert_eq!(pubsub.numpat(), 1);
+
+        assert_eq!(pubsub.unsubscribe(&mut a, b"news"), 1);
+        assert_eq!(pubsub.punsubscribe(&mut a, b"n*"), 0);
+        assert_eq!(pubsub.numsub(b"news"), 1);
+        assert_eq!(pubsub.numpat(), 0);
+
+        pubsub.unregister(&b);
+        assert_eq!(pubsub.channels(None), Vec::<ByteString>::new());
+        assert_eq!(pubsub.publish(b"news", b"bye"), 0);
+    }
+
+    #[test]
+    fn test_keyspace_events() {
+        assert_eq!(KeyspaceEvents::parse(""), Some(KeyspaceEvents::default()));
+        assert_eq!(
+            KeyspaceEvents::parse("KEA").unwrap().to_string(),
+            "g$lshztxeKE"
+        );
+        assert_eq!(KeyspaceEvents::parse("Ex$n").unwrap().to_string(), "$xnE");
+        assert_eq!(KeyspaceEvents::parse("KEm"), None);
+        assert!(!KeyspaceEvents::parse("A").unwrap().enabled(EventClass::Set));
+        assert!(!KeyspaceEvents::parse("KA")
+            .unwrap()
+            .enabled(EventClass::New));
+
+        let pubsub = PubSub::default();
+        let mut subscriber = pubsub.register();
+        pubsub.psubscribe(&mut subscriber, b"__key*@*");
+        pubsub.notify_keyspace_event(0, EventClass::Set, "sadd", b"k");
+        assert_eq!(subscriber.try_recv(), None);
+
+        pubsub.set_keyspace_events(KeyspaceEvents::parse("KEs").unwrap());
+        pubsub.notify_keyspace_event(0, EventClass::Generic, "del", b"k");
+        pubsub.notify_keyspace_event(3, EventClass::Set, "sadd", b"k");
+        assert_eq!(
+            subscriber.try_recv(),
+            Some(message(Some("__key*@*"), "__keyspace@3__:k", "sadd"))
+        );
+        assert_eq!(
+            subscriber.try_recv(),
+            Some(message(Some("__key*@*"), "__keyevent@3__:sadd", "k"))
+        );
+        assert_eq!(subscriber.try_recv(), None);
+    }
+
+    #[test]
+    fn test_buffer_limit() {
+        let pubsub = PubSub::default();
+        pubsub.set_buffer_limit(20);
+        let mut slow = pubsub.register();
+        let mut fast = pubsub.register();
+        pubsub.subscribe(&mut slow, b"ch");
+        pubsub.subscribe(&mut fast, b"ch");
+
+        assert_eq!(pubsub.publish(b"ch", b"12345678"), 2);
+        assert_eq!(fast.try_recv(), Some(message(None, "ch", "12345678")));
+        assert_eq!(pubsub.publish(b"ch", b"12345678"), 2);
+        assert_eq!(fast.try_recv(), Some(message(None, "ch", "12345678")));
+        assert!(!slow.overflowed());
+        // the slow subscriber is over the limit with 30 bytes queued
+        assert_eq!(pubsub.publish(b"ch", b"12345678"), 2);
+        assert!(slow.overflowed());
+        assert_eq!(slow.try_recv(), None);
+
+        assert_eq!(pubsub.numsub(b"ch"), 1);
+        assert_eq!(pubsub.publish(b"ch", b"12345678"), 1);
+        assert_eq!(fast.try_recv(), Some(message(None, "ch", "12345678")));
+        // resubscribing does not register it again
+        pubsub.subscribe(&mut slow, b"other");
+        assert_eq!(pubsub.publish(b"other", b"x"), 0);
+    }
+}
@@ -0,0 +1,1304 @@
+//! Reader and writer of the Redis RDB format, for db files and DUMP/RESTORE payloads.
+//!
+//! Only plain encodings are written (e.g. a set is written as a list of members,
+//! never as an intset or a listpack, a list is never written as a quicklist,
+//! a sorted set is written with binary scores, a stream is written in the format of
+//! Redis 5 to 6),
+//! which any Redis version since 5.0 can load.
+//! Compact encodings written by Redis are read and converted.
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crc::{Crc, Digest, CRC_64_REDIS};
+
+use crate::{
+    byte_string::ByteString,
+    config::MAX_DATABASES,
+    memds::{
+        Consumer, ConsumerGroup, HashDS, ListDS, MemDS, PendingEntry, SetDS, StreamDS, StreamId,
+        StringDS, ZSetDS,
+    },
+    storage::{self, Snapshot},
+    Error,
+};
+
+/// First bytes of a RDB file, followed by the version as 4 ascii digits
+pub const MAGIC: &[u8; 5] = b"REDIS";
+/// Version of the RDB files and DUMP payloads written
+pub const RDB_VERSION: u16 = 9;
+/// Max version of RDB files and DUMP payloads that can be read
+const MAX_RDB_VERSION: u16 = 12;
+
+const OPCODE_SLOT_INFO: u8 = 244;
+const OPCODE_FUNCTION2: u8 = 245;
+const OPCODE_MODULE_AUX: u8 = 247;
+const OPCODE_IDLE: u8 = 248;
+const OPCODE_FREQ: u8 = 249;
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Containers of quicklist nodes
+const QUICKLIST_NODE_PLAIN: usize = 1;
+const QUICKLIST_NODE_PACKED: usize = 2;
+
+/// Flags of the entries of a stream node
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+/// Max number of entries per stream node written
+const STREAM_NODE_ENTRIES: usize = 100;
+
+/// Special encodings of strings, in place of the length
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Max number of bytes preallocated from a length read from the input,
+/// larger collections grow as their elements are read
+const MAX_PREALLOC: usize = 4096;
+
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+fn invalid(message: impl std::fmt::Display) -> Error {
+    Error::Handle(format!("Invalid RDB: {}", message))
+}
+
+/// Computes the CRC64 of the bytes written through it
+struct CrcWriter<W> {
+    inner: W,
+    digest: Digest<'static, u64>,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_length<W: Write>(w: &mut W, len: u64) -> io::Result<()> {
+    if len < 1 << 6 {
+        w.write_all(&[len as u8])
+    } else if len < 1 << 14 {
+        w.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+    } else if len <= u32::MAX as u64 {
+        w.write_all(&[0x80])?;
+        w.write_all(&(len as u32).to_be_bytes())
+    } else {
+        w.write_all(&[0x81])?;
+        w.write_all(&len.to_be_bytes())
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    write_length(w, s.len() as u64)?;
+    w.write_all(s)
+}
+
+/// Writes the type of the value followed by the value
+fn write_value<W: Write>(w: &mut W, value: &MemDS) -> io::Result<()> {
+    match value {
+        MemDS::String(s) => {
+            w.write_all(&[TYPE_STRING])?;
+            write_string(w, &s.as_bytes())
+        }
+        MemDS::Set(s) => {
+            let members = s.members();
+            w.write_all(&[TYPE_SET])?;
+            write_length(w, members.len() as u64)?;
+            for member in members {
+                write_string(w, member.as_bytes())?;
+            }
+            Ok(())
+        }
+        MemDS::Hash(h) => {
+            w.write_all(&[TYPE_HASH])?;
+            write_length(w, h.len() as u64)?;
+            for (field, value) in h.iter() {
+                write_string(w, field.as_bytes())?;
+                write_string(w, value.as_bytes())?;
+            }
+            Ok(())
+        }
+        MemDS::SortedSet(z) => {
+            w.write_all(&[TYPE_ZSET_2])?;
+            write_length(w, z.len() as u64)?;
+            for (member, score) in z.iter() {
+                write_string(w, member.as_bytes())?;
+                w.write_all(&score.to_le_bytes())?;
+            }
+            Ok(())
+        }
+        MemDS::Stream(s) => {
+            w.write_all(&[TYPE_STREAM_LISTPACKS])?;
+            write_stream(w, s)
+        }
+        MemDS::List(l) => {
+            w.write_all(&[TYPE_LIST])?;
+            write_length(w, l.len() as u64)?;
+            for element in l.iter() {
+                write_string(w, element.as_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Builds a listpack: total bytes, number of elements, then the elements each followed by
+/// its length, then an end marker
+#[derive(Default)]
+struct ListpackWriter {
+    buf: Vec<u8>,
+    count: usize,
+}
+
+impl ListpackWriter {
+    fn push_entry(&mut self, entry: &[u8]) {
+        self.buf.extend_from_slice(entry);
+        // the length is written with 7 bits per byte, the first byte last
+        let len = entry.len();
+        let backlen_len = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        for i in (0..backlen_len).rev() {
+            let byte = ((len >> (7 * i)) & 127) as u8;
+            self.buf.push(if i == backlen_len - 1 {
+                byte
+            } else {
+                byte | 128
+            });
+        }
+        self.count += 1;
+    }
+
+    fn int(&mut self, value: i64) {
+        match value {
+            0..=127 => self.push_entry(&[value as u8]),
+            -4096..=4095 => {
+                let value = value as u16 & 0x1fff;
+                self.push_entry(&[0xc0 | (value >> 8) as u8, value as u8])
+            }
+            _ => {
+                let mut entry = vec![0xf4];
+                entry.extend_from_slice(&value.to_le_bytes());
+                self.push_entry(&entry)
+            }
+        }
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        let mut entry = match s.len() {
+            0..=63 => vec![0x80 | s.len() as u8],
+            64..=4095 => vec![0xe0 | (s.len() >> 8) as u8, s.len() as u8],
+            _ => {
+                let mut header = vec![0xf0];
+                header.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                header
+            }
+        };
+        entry.extend_from_slice(s);
+        self.push_entry(&entry)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.buf.len() + 1;
+        let mut listpack = Vec::with_capacity(total);
+        listpack.extend_from_slice(&(total as u32).to_le_bytes());
+        listpack.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        listpack.extend_from_slice(&self.buf);
+        listpack.push(0xff);
+        listpack
+    }
+}
+
+/// Writes a stream as nodes of entries keyed by the ID of their first entry, then its
+/// length, last ID and consumer groups.
+/// Each node is a listpack of a master entry with the fields of the first entry, then the
+/// entries with their IDs relative to the node ID, and only their values when they have
+/// the same fields as the master entry.
+fn write_stream<W: Write>(w: &mut W, stream: &StreamDS) -> io::Result<()> {
+    let entries: Vec<_> = stream.iter().collect();
+    write_length(w, entries.chunks(STREAM_NODE_ENTRIES).len() as u64)?;
+    for node in entries.chunks(STREAM_NODE_ENTRIES) {
+        let (master_id, master_fields) = node[0];
+        let mut listpack = ListpackWriter::default();
+        listpack.int(node.len() as i64);
+        // no deleted entries
+        listpack.int(0);
+        listpack.int(master_fields.len() as i64);
+        for (field, _) in master_fields {
+            listpack.string(field.as_bytes());
+        }
+        listpack.int(0);
+
+        for (id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields
+                    .iter()
+                    .zip(master_fields)
+                    .all(|((a, _), (b, _))| a == b);
+            listpack.int(if same_fields {
+                STREAM_ITEM_FLAG_SAMEFIELDS
+            } else {
+                0
+            });
+            listpack.int(id.ms.wrapping_sub(master_id.ms) as i64);
+            listpack.int(id.seq.wrapping_sub(master_id.seq) as i64);
+            if same_fields {
+                for (_, value) in fields.iter() {
+                    listpack.string(value.as_bytes());
+                }
+                listpack.int(fields.len() as i64 + 3);
+            } else {
+                listpack.int(fields.len() as i64);
+                for (field, value) in fields.iter() {
+                    listpack.string(field.as_bytes());
+                    listpack.string(value.as_bytes());
+                }
+                listpack.int(fields.len() as i64 * 2 + 4);
+            }
+        }
+
+        write_string(w, &master_id.to_be_bytes())?;
+        write_string(w, &listpack.finish())?;
+    }
+
+    write_length(w, stream.len() as u64)?;
+    write_length(w, stream.last_id().ms)?;
+    write_length(w, stream.last_id().seq)?;
+
+    write_length(w, stream.groups().count() as u64)?;
+    for (name, group) in stream.groups() {
+        write_string(w, name.as_bytes())?;
+        write_length(w, group.last_delivered.ms)?;
+        write_length(w, group.last_delivered.seq)?;
+        write_length(w, group.pending.len() as u64)?;
+        for (id, entry) in &group.pending {
+            w.write_all(&id.to_be_bytes())?;
+            w.write_all(&entry.delivery_time.to_le_bytes())?;
+            write_length(w, entry.delivery_count)?;
+        }
+        write_length(w, group.consumers.len() as u64)?;
+        for (name, consumer) in &group.consumers {
+            write_string(w, name.as_bytes())?;
+            w.write_all(&consumer.seen_time.to_le_bytes())?;
+            let owned: Vec<_> = group
+                .pending
+                .iter()
+                .filter(|(_, entry)| entry.consumer == *name)
+                .collect();
+            write_length(w, owned.len() as u64)?;
+            for (id, _) in owned {
+                w.write_all(&id.to_be_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the snapshots of the databases, by number, as a RDB file,
+/// `progress` is incremented by the number of keys written so far
+pub fn write<W: Write>(
+    writer: W,
+    snapshots: &[Snapshot],
+    progress: &AtomicUsize,
+) -> Result<W, Error> {
+    let error = |e| Error::Handle(format!("Failed to write RDB {}", e));
+    let mut w = CrcWriter {
+        inner: writer,
+        digest: CRC64.digest(),
+    };
+
+    write!(w, "REDIS{:04}", RDB_VERSION).map_err(error)?;
+    for (index, snapshot) in storage::non_empty(snapshots) {
+        w.write_all(&[OPCODE_SELECTDB]).map_err(error)?;
+        write_length(&mut w, index as u64).map_err(error)?;
+        w.write_all(&[OPCODE_RESIZEDB]).map_err(error)?;
+        write_length(&mut w, snapshot.data.len() as u64).map_err(error)?;
+        write_length(&mut w, snapshot.expires.len() as u64).map_err(error)?;
+
+        for (key, value) in snapshot.data.iter() {
+            if let Some(when) = snapshot.expires.get(key) {
+                w.write_all(&[OPCODE_EXPIRETIME_MS]).map_err(error)?;
+                w.write_all(&when.to_le_bytes()).map_err(error)?;
+            }
+            let mut value_buf = Vec::new();
+            write_value(&mut value_buf, value).map_err(error)?;
+            // the type comes before the key
+            w.write_all(&value_buf[..1]).map_err(error)?;
+            write_string(&mut w, key.as_bytes()).map_err(error)?;
+            w.write_all(&value_buf[1..]).map_err(error)?;
+            progress.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    w.write_all(&[OPCODE_EOF]).map_err(error)?;
+    let CrcWriter { mut inner, digest } = w;
+    inner
+        .write_all(&digest.finalize().to_le_bytes())
+        .map_err(error)?;
+
+    Ok(inner)
+}
+
+/// Serializes a value for DUMP: the value, the RDB version and the CRC64 of both
+pub fn dump(value: &MemDS) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_value(&mut payload, value).expect("writing to a Vec never fails");
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = CRC64.checksum(&payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+
+    payload
+}
+
+/// Deserializes a DUMP payload, checking its version and checksum
+pub fn restore(payload: &[u8]) -> Result<MemDS, Error> {
+    let wrong = || Error::Handle("ERR DUMP payload version or checksum are wrong".to_string());
+    if payload.len() < 10 {
+        return Err(wrong());
+    }
+
+    let (rest, crc) = payload.split_at(payload.len() - 8);
+    let (value, version) = rest.split_at(rest.len() - 2);
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    let crc = u64::from_le_bytes(crc.try_into().unwrap());
+    if version > MAX_RDB_VERSION || crc != CRC64.checksum(rest) {
+        return Err(wrong());
+    }
+
+    let bad_format = |_| Error::Handle("ERR Bad data format".to_string());
+    let mut reader = Reader { buf: value, pos: 0 };
+    let value_type = reader.byte().map_err(bad_format)?;
+    let value = reader.value(value_type).map_err(bad_format)?;
+    if !reader.is_empty() {
+        return Err(Error::Handle("ERR Bad data format".to_string()));
+    }
+
+    Ok(value)
+}
+
+/// Length or special encoding of a string
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+/// Reads RDB encoded data from a byte slice
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| invalid(format!("unexpected end at offset {}", self.pos)))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length, Error> {
+        let first = self.byte()?;
+        let len = match first >> 6 {
+            0 => (first & 0x3f) as u64,
+            1 => ((first & 0x3f) as u64) << 8 | self.byte()? as u64,
+            2 => match first {
+                0x80 => u32::from_be_bytes(self.array()?) as u64,
+                0x81 => u64::from_be_bytes(self.array()?),
+                _ => return Err(invalid(format!("unknown length encoding {}", first))),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3f)),
+        };
+
+        Ok(Length::Len(len))
+    }
+
+    fn length(&mut self) -> Result<usize, Error> {
+        usize::try_from(self.length_u64()?).map_err(invalid)
+    }
+
+    fn length_u64(&mut self) -> Result<u64, Error> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(invalid("unexpected string encoding")),
+        }
+    }
+
+    fn stream_id(&mut self) -> Result<StreamId, Error> {
+        Ok(StreamId::new(self.length_u64()?, self.length_u64()?))
+    }
+
+    /// Reads a stream of `value_type`: the nodes of entries, the metadata, then the
+    /// consumer groups with their pending entries and consumers
+    fn stream(&mut self, value_type: u8) -> Result<StreamDS, Error> {
+        let mut stream = StreamDS::default();
+        let nodes = self.length()?;
+        for _ in 0..nodes {
+            let master_id = self.raw_string()?;
+            let master_id = StreamId::from_be_bytes(
+                master_id
+                    .try_into()
+                    .map_err(|_| invalid("invalid stream node key"))?,
+            );
+            let listpack = read_listpack(&self.raw_string()?)?;
+            read_stream_node(&mut stream, master_id, &listpack)?;
+        }
+
+        // the length is known from the entries
+        self.length()?;
+        let last_id = self.stream_id()?;
+        let (entries_added, max_deleted_id) = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // the first ID is known from the entries
+            self.stream_id()?;
+            let max_deleted_id = self.stream_id()?;
+            (Some(self.length_u64()?), Some(max_deleted_id))
+        } else {
+            (None, None)
+        };
+        stream
+            .set_id(last_id, entries_added, max_deleted_id)
+            .map_err(invalid)?;
+
+        let groups = self.length()?;
+        for _ in 0..groups {
+            let name = self.text()?;
+            let last_delivered = self.stream_id()?;
+            // unknown is written as -1
+            let entries_read = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+                Some(self.length_u64()?).filter(|read| *read != u64::MAX)
+            } else {
+                None
+            };
+            let mut group = ConsumerGroup {
+                last_delivered,
+                entries_read,
+                ..Default::default()
+            };
+
+            let pending = self.length()?;
+            for _ in 0..pending {
+                let id = StreamId::from_be_bytes(self.array()?);
+                let delivery_time = u64::from_le_bytes(self.array()?);
+                let delivery_count = self.length_u64()?;
+                group.pending.insert(
+                    id,
+                    PendingEntry {
+                        consumer: String::new(),
+                        delivery_time,
+                        delivery_count,
+                    },
+                );
+            }
+
+            let consumers = self.length()?;
+            for _ in 0..consumers {
+                let name = self.text()?;
+                let seen_time = u64::from_le_bytes(self.array()?);
+                let active_time = if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                    Some(u64::from_le_bytes(self.array()?)).filter(|time| *time != u64::MAX)
+                } else {
+                    None
+                };
+                let owned = self.length()?;
+                for _ in 0..owned {
+                    let id = StreamId::from_be_bytes(self.array()?);
+                    let entry = group
+                        .pending
+                        .get_mut(&id)
+                        .ok_or_else(|| invalid("consumer pending entry not in its group"))?;
+                    entry.consumer = name.clone();
+                }
+                group.consumers.insert(
+                    name,
+                    Consumer {
+                        seen_time,
+                        active_time,
+                    },
+                );
+            }
+            stream.insert_group(name, group);
+        }
+
+        Ok(stream)
+    }
+
+    fn raw_string(&mut self) -> Result<Vec<u8>, Error> {
+        let len = match self.length_or_encoding()? {
+            Length::Len(len) => usize::try_from(len).map_err(invalid)?,
+            Length::Encoded(ENC_INT8) => {
+                return Ok((self.byte()? as i8).to_string().into_bytes());
+            }
+            Length::Encoded(ENC_INT16) => {
+                return Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes());
+            }
+            Length::Encoded(ENC_INT32) => {
+                return Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes());
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.length()?;
+                let len = self.length()?;
+                let compressed = self.bytes(compressed_len)?;
+                return lzf_decompress(compressed, len)
+                    .ok_or_else(|| invalid("invalid LZF compressed string"));
+            }
+            Length::Encoded(encoding) => {
+                return Err(invalid(format!("unknown string encoding {}", encoding)))
+            }
+        };
+
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<ByteString, Error> {
+        Ok(self.raw_string()?.into())
+    }
+
+    /// Reads a string holding a name, like a consumer group
+    fn text(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.raw_string()?).map_err(|_| invalid("non UTF-8 name"))
+    }
+
+    /// Reads a score of the first sorted set encoding: a length prefixed string,
+    /// or 253, 254 and 255 for NaN, +inf and -inf
+    fn string_score(&mut self) -> Result<f64, Error> {
+        let score = match self.byte()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => {
+                let s = std::str::from_utf8(self.bytes(len as usize)?).map_err(invalid)?;
+                s.parse().map_err(invalid)?
+            }
+        };
+
+        Ok(score)
+    }
+
+    /// Reads a value of `value_type`
+    fn value(&mut self, value_type: u8) -> Result<MemDS, Error> {
+        match value_type {
+            TYPE_STRING => Ok(MemDS::String(StringDS::from(self.string()?))),
+            TYPE_SET => {
+                let len = self.length()?;
+                let mut members = Vec::with_capacity(len.min(MAX_PREALLOC));
+                for _ in 0..len {
+                    members.push(self.string()?);
+                }
+                Ok(MemDS::Set(set_from(members)))
+            }
+            TYPE_SET_INTSET => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::Set(set_from(read_intset(&blob)?)))
+            }
+            TYPE_SET_LISTPACK => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::Set(set_from(read_listpack(&blob)?)))
+            }
+            TYPE_HASH => {
+                let len = self.length()?;
+                let mut hash = HashDS::default();
+                for _ in 0..len {
+                    hash.set(self.string()?, self.string()?);
+                }
+                Ok(MemDS::Hash(hash))
+            }
+            TYPE_HASH_ZIPLIST => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::Hash(hash_from(read_ziplist(&blob)?)?))
+            }
+            TYPE_HASH_LISTPACK => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::Hash(hash_from(read_listpack(&blob)?)?))
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.length()?;
+                let mut zset = ZSetDS::default();
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.array()?)
+                    } else {
+                        self.string_score()?
+                    };
+                    zset.insert(&member, score);
+                }
+                Ok(MemDS::SortedSet(zset))
+            }
+            TYPE_ZSET_ZIPLIST => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::SortedSet(zset_from(read_ziplist(&blob)?)?))
+            }
+            TYPE_ZSET_LISTPACK => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::SortedSet(zset_from(read_listpack(&blob)?)?))
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                Ok(MemDS::Stream(self.stream(value_type)?))
+            }
+            TYPE_LIST => {
+                let len = self.length()?;
+                let mut list = ListDS::default();
+                for _ in 0..len {
+                    list.push_back(self.string()?);
+                }
+                Ok(MemDS::List(list))
+            }
+            TYPE_LIST_ZIPLIST => {
+                let blob = self.raw_string()?;
+                Ok(MemDS::List(read_ziplist(&blob)?.into_iter().collect()))
+            }
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.length()?;
+                let mut list = ListDS::default();
+                for _ in 0..nodes {
+                    let container = if value_type == TYPE_LIST_QUICKLIST_2 {
+                        self.length()?
+                    } else {
+                        QUICKLIST_NODE_PACKED
+                    };
+                    let blob = self.raw_string()?;
+                    let elements = match container {
+                        QUICKLIST_NODE_PLAIN => {
+                            vec![blob.into()]
+                        }
+                        QUICKLIST_NODE_PACKED if value_type == TYPE_LIST_QUICKLIST => {
+                            read_ziplist(&blob)?
+                        }
+                        QUICKLIST_NODE_PACKED => read_listpack(&blob)?,
+                        _ => {
+                            return Err(invalid(format!(
+                                "unknown quicklist container {}",
+                                container
+                            )))
+                        }
+                    };
+                    for element in elements {
+                        list.push_back(element);
+                    }
+                }
+                Ok(MemDS::List(list))
+            }
+            _ => Err(invalid(format!("unsupported value type {}", value_type))),
+        }
+    }
+}
+
+fn set_from(members: Vec<ByteString>) -> SetDS {
+    members.into_iter().collect()
+}
+
+/// Builds a hash from a flat list of fields and values
+fn hash_from(entries: Vec<ByteString>) -> Result<HashDS, Error> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(invalid("odd number of hash entries"));
+    }
+
+    let mut hash = HashDS::default();
+    let mut entries = entries.into_iter();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        hash.set(field, value);
+    }
+
+    Ok(hash)
+}
+
+/// Reads the entries of a stream node, see `write_stream`
+fn read_stream_node(
+    stream: &mut StreamDS,
+    master_id: StreamId,
+    listpack: &[ByteString],
+) -> Result<(), Error> {
+    let mut items = listpack.iter();
+    let mut next = || items.next().ok_or_else(|| invalid("truncated stream node"));
+    fn int(item: &ByteString) -> Result<i64, Error> {
+        item.parse()
+            .ok_or_else(|| invalid("invalid integer in stream node"))
+    }
+    fn count(item: &ByteString) -> Result<usize, Error> {
+        usize::try_from(int(item)?).map_err(invalid)
+    }
+
+    let entries = count(next()?)? + count(next()?)?;
+    let master_fields = (0..count(next()?)?)
+        .map(|_| next().cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+    // end of the master entry
+    next()?;
+
+    for _ in 0..entries {
+        let flags = int(next()?)?;
+        let ms = master_id.ms.wrapping_add(int(next()?)? as u64);
+        let seq = master_id.seq.wrapping_add(int(next()?)? as u64);
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next()?.clone())))
+                .collect::<Result<Vec<_>, Error>>()?
+        } else {
+            (0..count(next()?)?)
+                .map(|_| Ok((next()?.clone(), next()?.clone())))
+                .collect::<Result<Vec<_>, Error>>()?
+        };
+        // number of items of the entry
+        next()?;
+
+        let id = StreamId::new(ms, seq);
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            if id <= stream.last_id() {
+                return Err(invalid("unordered stream entries"));
+            }
+            stream.add(id, fields);
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds a sorted set from a flat list of members and scores
+fn zset_from(entries: Vec<ByteString>) -> Result<ZSetDS, Error> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(invalid("odd number of sorted set entries"));
+    }
+
+    let mut zset = ZSetDS::default();
+    let mut entries = entries.into_iter();
+    while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+        let score = score
+            .parse()
+            .ok_or_else(|| invalid("invalid sorted set score"))?;
+        zset.insert(&member, score);
+    }
+
+    Ok(zset)
+}
+
+/// Decompresses LZF data of `len` bytes
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len.min(MAX_PREALLOC));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // literal run
+            let run = input.get(i..i + ctrl + 1)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i)? as usize;
+                i += 1;
+            }
+            run += 2;
+            let distance = ((ctrl & 0x1f) << 8) + *input.get(i)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(distance)?;
+            for k in start..start + run {
+                out.push(out[k]);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+/// Reads the members of an intset: encoding (bytes per integer), count, then sorted integers,
+/// all little endian
+fn read_intset(blob: &[u8]) -> Result<Vec<ByteString>, Error> {
+    let mut reader = Reader { buf: blob, pos: 0 };
+    let encoding = u32::from_le_bytes(reader.array()?) as usize;
+    let count = u32::from_le_bytes(reader.array()?) as usize;
+
+    let mut members = Vec::with_capacity(count.min(MAX_PREALLOC));
+    for _ in 0..count {
+        let member = match encoding {
+            2 => i16::from_le_bytes(reader.array()?) as i64,
+            4 => i32::from_le_bytes(reader.array()?) as i64,
+            8 => i64::from_le_bytes(reader.array()?),
+            _ => return Err(invalid(format!("unknown intset encoding {}", encoding))),
+        };
+        members.push(member.to_string().into());
+    }
+
+    Ok(members)
+}
+
+/// Reads the entries of a listpack: total bytes, count, then entries each followed by
+/// its length, then an end marker
+fn read_listpack(blob: &[u8]) -> Result<Vec<ByteString>, Error> {
+    let mut reader = Reader { buf: blob, pos: 0 };
+    reader.bytes(6)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let start = reader.pos;
+        let encoding = reader.byte()?;
+        let entry: ByteString = if encoding == 0xff {
+            break;
+        } else if encoding & 0x80 == 0 {
+            (encoding & 0x7f).to_string().into()
+        } else if encoding & 0xc0 == 0x80 {
+            let len = (encoding & 0x3f) as usize;
+            ByteString::from(reader.bytes(len)?)
+        } else if encoding & 0xe0 == 0xc0 {
+            // 13 bits signed integer
+            let value = ((encoding & 0x1f) as i64) << 8 | reader.byte()? as i64;
+            (if value >= 1 << 12 {
+                value - (1 << 13)
+            } else {
+                value
+            })
+            .to_string()
+            .into()
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = ((encoding & 0x0f) as usize) << 8 | reader.byte()? as usize;
+            ByteString::from(reader.bytes(len)?)
+        } else {
+            match encoding {
+                0xf0 => {
+                    let len = u32::from_le_bytes(reader.array()?) as usize;
+                    ByteString::from(reader.bytes(len)?)
+                }
+                0xf1 => i16::from_le_bytes(reader.array()?).to_string().into(),
+                0xf2 => {
+                    let [a, b, c] = reader.array()?;
+                    (i32::from_le_bytes([0, a, b, c]) >> 8).to_string().into()
+                }
+                0xf3 => i32::from_le_bytes(reader.array()?).to_string().into(),
+                0xf4 => i64::from_le_bytes(reader.array()?).to_string().into(),
+                _ => return Err(invalid(format!("unknown listpack encoding {}", encoding))),
+            }
+        };
+        entries.push(entry);
+
+        // skip the length of the entry, stored in 1 to 5 bytes
+        let entry_len = reader.pos - start;
+        let backlen_len = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        reader.bytes(backlen_len)?;
+    }
+
+    Ok(entries)
+}
+
+/// Reads the entries of a ziplist: total bytes, offset of the tail, count, then entries
+/// each preceded by the length of the previous entry, then an end marker
+fn read_ziplist(blob: &[u8]) -> Result<Vec<ByteString>, Error> {
+    let mut reader = Reader { buf: blob, pos: 0 };
+    reader.bytes(10)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let prev_len = reader.byte()?;
+        if prev_len == 0xff {
+            break;
+        }
+        if prev_len == 0xfe {
+            reader.bytes(4)?;
+        }
+
+        let encoding = reader.byte()?;
+        let entry: ByteString = match encoding >> 6 {
+            0 => {
+                let len = (encoding & 0x3f) as usize;
+                ByteString::from(reader.bytes(len)?)
+            }
+            1 => {
+                let len = ((encoding & 0x3f) as usize) << 8 | reader.byte()? as usize;
+                ByteString::from(reader.bytes(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.array()?) as usize;
+                ByteString::from(reader.bytes(len)?)
+            }
+            _ => match encoding {
+                0xc0 => i16::from_le_bytes(reader.array()?).to_string().into(),
+                0xd0 => i32::from_le_bytes(reader.array()?).to_string().into(),
+                0xe0 => i64::from_le_bytes(reader.array()?).to_string().into(),
+                0xf0 => {
+                    let [a, b, c] = reader.array()?;
+                    (i32::from_le_bytes([0, a, b, c]) >> 8).to_string().into()
+                }
+                0xfe => (reader.byte()? as i8).to_string().into(),
+                // 4 bits immediate integer, from 1 to 13 for 0 to 12
+                0xf1..=0xfd => ((encoding & 0x0f) - 1).to_string().into(),
+                _ => return Err(invalid(format!("unknown ziplist encoding {}", encoding))),
+            },
+        };
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Reads a RDB file into the snapshots of the databases, by number,
+/// omitting databases after the last one with keys
+pub fn load(content: &[u8]) -> Result<(Vec<Snapshot>, u16), Error> {
+    let mut reader = Reader {
+        buf: content,
+        pos: 0,
+    };
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid("wrong signature"));
+    }
+    let version = std::str::from_utf8(reader.bytes(4)?)
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+        .ok_or_else(|| invalid("wrong version"))?;
+    if version > MAX_RDB_VERSION {
+        return Err(invalid(format!("unsupported version {}", version)));
+    }
+
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+    let mut db = 0;
+    let mut expire_at = None;
+    loop {
+        let opcode = reader.byte()?;
+        match opcode {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => {
+                db = reader.length()?;
+                if db >= MAX_DATABASES {
+                    return Err(invalid(format!("invalid database number {}", db)));
+                }
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_AUX => {
+                let key = reader.raw_string()?;
+                let value = reader.raw_string()?;
+                tracing::debug!(
+                    "RDB aux field {}: {}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+            OPCODE_EXPIRETIME_MS => expire_at = Some(u64::from_le_bytes(reader.array()?)),
+            OPCODE_EXPIRETIME => {
+                expire_at = Some(u32::from_le_bytes(reader.array()?) as u64 * 1000)
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OPCODE_FUNCTION2 => {
+                reader.raw_string()?;
+                tracing::warn!("Skipping function library, functions are not supported");
+            }
+            OPCODE_MODULE_AUX => return Err(invalid("modules are not supported")),
+            value_type => {
+                let key = reader.string()?;
+                let value = reader.value(value_type)?;
+                if snapshots.len() <= db {
+                    snapshots.resize_with(db + 1, Snapshot::default);
+                }
+                let snapshot = &mut snapshots[db];
+                if let Some(when) = expire_at {
+                    snapshot.expires.insert(key.clone(), when);
+                }
+                snapshot.data.insert(key, Arc::new(value));
+                expire_at = None;
+            }
+        }
+    }
+
+    // the checksum is 0 if the file was written with checksum disabled
+    if version >= 5 {
+        let end = reader.pos;
+        let checksum = u64::from_le_bytes(reader.array()?);
+        if checksum != 0 && checksum != CRC64.checksum(&content[..end]) {
+            return Err(invalid("checksum mismatch"));
+        }
+    }
+
+    Ok((snapshots, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_redis_payload() {
+        // `DUMP` of a key set to 10 by Redis 6
+        let payload = b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbb";
+        let value = restore(payload).unwrap();
+        assert_eq!(value.string(b"k").unwrap().fetch(), "10");
+
+        let mut corrupted = payload.to_vec();
+        corrupted[1] = 0xc1;
+        assert!(restore(&corrupted).is_err());
+        assert!(restore(b"\x00").is_err());
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let value = MemDS::Set(set_from(vec!["a".into(), "b".into()]));
+        let restored = restore(&dump(&value)).unwrap();
+        let mut members = restored.set(b"k").unwrap().members();
+        members.sort();
+        assert_eq!(members, vec!["a", "b"]);
+
+        let long = "x".repeat(20000);
+        let value = MemDS::String(StringDS::from(&long));
+        let restored = restore(&dump(&value)).unwrap();
+        assert_eq!(restored.string(b"k").unwrap().fetch(), long);
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // literal "ab" then a back reference of 4 bytes at distance 2
+        assert_eq!(
+            lzf_decompress(&[1, b'a', b'b', 0x40, 1], 6).unwrap(),
+            b"ababab"
+        );
+        assert!(lzf_decompress(&[1, b'a', b'b', 0x40, 1], 5).is_none());
+        assert!(lzf_decompress(&[0x40, 1], 4).is_none());
+    }
+
+    #[test]
+    fn test_compact_set_encodings() {
+        // intset of 16 bits integers
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 0xff, 0xff, 7, 0];
+        assert_eq!(read_intset(&intset).unwrap(), vec!["-1", "7"]);
+
+        // listpack of "a", 5 and -2 (13 bits integer)
+        let listpack = [0, 0, 0, 0, 3, 0, 0x81, b'a', 2, 5, 1, 0xdf, 0xfe, 2, 0xff];
+        assert_eq!(read_listpack(&listpack).unwrap(), vec!["a", "5", "-2"]);
+    }
+
+    #[test]
+    fn test_compact_list_encodings() {
+        // ziplist of "ab", 7 (4 bits immediate) and -300 (16 bits integer)
+        let ziplist = [
+            0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 2, b'a', b'b', 4, 0xf8, 2, 0xc0, 0xd4, 0xfe, 0xff,
+        ];
+        assert_eq!(read_ziplist(&ziplist).unwrap(), vec!["ab", "7", "-300"]);
+
+        // quicklist of a listpack node and a plain node
+        let mut quicklist = vec![2, QUICKLIST_NODE_PACKED as u8, 15];
+        quicklist.extend_from_slice(&[0, 0, 0, 0, 3, 0, 0x81, b'a', 2, 5, 1, 0xdf, 0xfe, 2, 0xff]);
+        quicklist.extend_from_slice(&[QUICKLIST_NODE_PLAIN as u8, 1, b'q']);
+        let mut reader = Reader {
+            buf: &quicklist,
+            pos: 0,
+        };
+        let list = reader.value(TYPE_LIST_QUICKLIST_2).unwrap();
+        let elements: Vec<_> = list.list(b"l").unwrap().iter().collect();
+        assert_eq!(elements, vec!["a", "5", "-2", "q"]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_sorted_set_encodings() {
+        // first encoding with scores as strings: "a" 1.5 and "b" -inf
+        let zset = [2, 1, b'a', 3, b'1', b'.', b'5', 1, b'b', 255];
+        let mut reader = Reader { buf: &zset, pos: 0 };
+        let value = reader.value(TYPE_ZSET).unwrap();
+        let zset: Vec<_> = value
+            .zset(b"z")
+            .unwrap()
+            .iter()
+            .map(|(m, s)| (m.as_str().unwrap(), s))
+            .collect();
+        assert_eq!(zset, vec![("b", f64::NEG_INFINITY), ("a", 1.5)]);
+
+        // listpack of "a" 2 and "b" 1
+        let listpack = [
+            0, 0, 0, 0, 4, 0, 0x81, b'a', 2, 2, 1, 0x81, b'b', 2, 1, 1, 0xff,
+        ];
+        let zset = zset_from(read_listpack(&listpack).unwrap()).unwrap();
+        let zset: Vec<_> = zset.iter().map(|(m, s)| (m.as_str().unwrap(), s)).collect();
+        assert_eq!(zset, vec![("b", 1.0), ("a", 2.0)]);
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream = StreamDS::default();
+        for i in 1..=150 {
+            let field = if i % 3 == 0 { "other" } else { "f" };
+            stream.add(
+                StreamId::new(1000 + i / 2, i),
+                vec![(field.into(), i.to_string().into())],
+            );
+        }
+        stream.remove(&[StreamId::new(1075, 150)]);
+        stream.create_group("g", StreamId::MIN, None);
+        stream.read_group_new("g", "alice", Some(2), false, 5000);
+        stream.read_group_new("g", "bob", Some(1), false, 6000);
+        stream
+            .group_mut("g")
+            .unwrap()
+            .create_consumer("carol", 7000);
+
+        let mut payload = Vec::new();
+        write_value(&mut payload, &MemDS::Stream(stream.clone())).unwrap();
+        let mut reader = Reader {
+            buf: &payload[1..],
+            pos: 0,
+        };
+        let loaded = reader.value(payload[0]).unwrap();
+        assert!(reader.is_empty());
+        let loaded = loaded.stream(b"x").unwrap();
+        assert_eq!(loaded.len(), 149);
+        assert!(loaded.iter().eq(stream.iter()));
+        assert_eq!(loaded.last_id(), StreamId::new(1075, 150));
+        let group = loaded.group("g").unwrap();
+        assert_eq!(group.last_delivered, StreamId::new(1001, 3));
+        assert_eq!(group.pending, stream.group("g").unwrap().pending);
+        assert_eq!(group.consumers.len(), 3);
+        assert_eq!(group.consumers["carol"].seen_time, 7000);
+    }
+
+    #[test]
+    fn test_write_load() {
+        let mut snapshot = Snapshot::default();
+        snapshot
+            .data
+            .insert("s".into(), Arc::new(MemDS::Set(set_from(vec!["x".into()]))));
+        snapshot
+            .data
+            .insert("k".into(), Arc::new(MemDS::String(StringDS::from("v"))));
+        snapshot.expires.insert("k".into(), 1234);
+        snapshot.data.insert(
+            b"\xff\x00".into(),
+            Arc::new(MemDS::String(StringDS::from(b"\x00\xfe\r\n"))),
+        );
+        snapshot.data.insert(
+            "l".into(),
+            Arc::new(MemDS::List(["b", "a"].into_iter().collect())),
+        );
+        let mut hash = HashDS::default();
+        hash.set("f", "v");
+        snapshot
+            .data
+            .insert("h".into(), Arc::new(MemDS::Hash(hash)));
+        let mut zset = ZSetDS::default();
+        zset.insert(b"m", 1.5);
+        zset.insert(b"n", f64::NEG_INFINITY);
+        snapshot
+            .data
+            .insert("z".into(), Arc::new(MemDS::SortedSet(zset)));
+
+        let content = write(Vec::new(), &[snapshot], &AtomicUsize::new(0)).unwrap();
+        assert!(content.starts_with(b"REDIS0009"));
+        let (loaded, version) = load(&content).unwrap();
+        assert_eq!(version, RDB_VERSION);
+        assert_eq!(loaded.len(), 1);
+        let loaded = &loaded[0];
+        assert_eq!(loaded.data[&b"k"[..]].string(b"k").unwrap().fetch(), "v");
+        assert_eq!(
+            loaded.data[&b"\xff\x00"[..]]
+                .string(b"\xff\x00")
+                .unwrap()
+                .fetch(),
+            &b"\x00\xfe\r\n"[..]
+        );
+        assert_eq!(
+            loaded.data[&b"s"[..]].set(b"s").unwrap().members(),
+            vec!["x"]
+        );
+        assert_eq!(
+            loaded.data[&b"l"[..]].list(b"l").unwrap().range(0, -1),
+            vec!["b", "a"]
+        );
+        assert_eq!(
+            loaded.data[&b"h"[..]]
+                .hash(b"h")
+                .unwrap()
+                .get(b"f")
+                .unwrap(),
+            "v"
+        );
+        let zset: Vec<_> = loaded.data[&b"z"[..]]
+            .zset(b"z")
+            .unwrap()
+            .iter()
+            .map(|(m, s)| (m.as_str().unwrap(), s))
+            .collect();
+        assert_eq!(zset, vec![("n", f64::NEG_INFINITY), ("m", 1.5)]);
+        assert_eq!(loaded.expires.get(&b"k"[..]), Some(&1234));
+        assert_eq!(loaded.expires.len(), 1);
+
+        let mut corrupted = content.clone();
+        corrupted[12] ^= 1;
+        assert!(load(&corrupted).is_err());
+        assert!(load(&content[..content.len() - 1]).is_err());
+    }
+}
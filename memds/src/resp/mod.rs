@@ -0,0 +1,667 @@
+use std::{cell::Cell, io::Write};
+
+use deseresp::Error;
+use serde::{
+    ser::{
+        SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+        SerializeTupleStruct, SerializeTupleVariant,
+    },
+    Serialize,
+};
+
+/// RESP serializer of replies, writing the same as `deseresp::Serializer` except for
+/// strings: they are written as simple strings like the other replies, or as blob strings
+/// when they are binary (not UTF-8, or containing CR or LF). Simple errors are kept on one
+/// line and doubles are formatted like Redis, see `format_double`.
+///
+/// With RESP2 the types missing from it are written as Redis does: nulls as `$-1`, or `*-1`
+/// for a `NullArray`, maps as flat arrays of keys and values, booleans as integers and
+/// doubles as blob strings.
+pub struct Serializer<W> {
+    writer: W,
+    /// Whether the client speaks RESP3
+    resp3: bool,
+    /// Whether strings are written as blob strings, see `with_blob_strings`
+    blob_strings: bool,
+    /// Kind of the string to be written next, set by the string types of `deseresp::types`
+    kind: Option<StringKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StringKind {
+    SimpleString,
+    BlobString,
+    SimpleError,
+    BlobError,
+}
+
+impl StringKind {
+    /// Kind of the newtype structs of `deseresp::types`, by their name
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "$SimpleString" => Some(StringKind::SimpleString),
+            "$BulkString" => Some(StringKind::BlobString),
+            "$SimpleError" => Some(StringKind::SimpleError),
+            "$BulkError" => Some(StringKind::BlobError),
+            _ => None,
+        }
+    }
+}
+
+/// Name of the unit struct serialized by `NullArray`
+const NULL_ARRAY: &str = "$NullArray";
+
+/// Null reply in place of an array, written `*-1` with RESP2 and `_` with RESP3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NullArray;
+
+impl Serialize for NullArray {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_unit_struct(NULL_ARRAY)
+    }
+}
+
+/// Array reply, or a `NullArray` for None
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrayOrNull<T>(pub Option<T>);
+
+impl<T: Serialize> Serialize for ArrayOrNull<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match &self.0 {
+            Some(array) => array.serialize(serializer),
+            None => NullArray.serialize(serializer),
+        }
+    }
+}
+
+thread_local! {
+    static BLOB_STRINGS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with the serializers created by the thread writing strings as blob strings,
+/// only the simple strings of `deseresp::types` are still written as such. Scripts call
+/// commands this way to tell their status replies from their strings.
+pub fn with_blob_strings<T>(f: impl FnOnce() -> T) -> T {
+    let previous = BLOB_STRINGS.with(|blob_strings| blob_strings.replace(true));
+    let result = f();
+    BLOB_STRINGS.with(|blob_strings| blob_strings.set(previous));
+
+    result
+}
+
+/// Creates a `Serializer` writing to `w` for a client speaking RESP3 if `resp3` is true,
+/// RESP2 otherwise
+pub fn from_write<W: Write>(w: W, resp3: bool) -> Serializer<W> {
+    Serializer {
+        writer: w,
+        resp3,
+        blob_strings: BLOB_STRINGS.with(Cell::get),
+        kind: None,
+    }
+}
+
+/// Serializes `value` to a Vec
+pub fn to_vec<S: Serialize>(value: &S, resp3: bool) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::new();
+    value.serialize(&mut from_write(&mut result, resp3))?;
+
+    Ok(result)
+}
+
+/// Writes a command as a RESP array of blob strings, the way clients send commands
+pub fn write_command<A: AsRef<[u8]>>(args: &[A], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        let arg = arg.as_ref();
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Formats a double as Redis replies it, like `%.17g` in C: 17 significant digits without
+/// trailing zeros, in scientific notation for exponents below -4 or above 16
+pub fn format_double(v: f64) -> String {
+    if v.is_infinite() {
+        return if v > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    // the exponent is the one of the value rounded to 17 digits
+    let scientific = format!("{:.16e}", v);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("formatted with an exponent");
+    let exponent: i32 = exponent.parse().expect("formatted exponent");
+    let trim = |s: &str| {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s.to_string()
+        }
+    };
+
+    if (-4..17).contains(&exponent) {
+        trim(&format!("{:.*}", (16 - exponent) as usize, v))
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    }
+}
+
+/// Whether `s` can't be written as a simple string
+fn is_binary(s: &[u8]) -> bool {
+    std::str::from_utf8(s).is_err() || s.contains(&b'\r') || s.contains(&b'\n')
+}
+
+/// Replaces CR and LF with spaces as Redis does for error messages, which may quote keys
+/// and would otherwise end the line of a simple error early
+fn single_line(s: &[u8]) -> Vec<u8> {
+    s.iter()
+        .map(|&c| if c == b'\r' || c == b'\n' { b' ' } else { c })
+        .collect()
+}
+
+impl<W: Write> Serializer<W> {
+    fn write(&mut self, parts: &[&[u8]]) -> Result<(), Error> {
+        for part in parts {
+            self.writer.write_all(part).map_err(Error::io)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, marker: &[u8], content: &[u8]) -> Result<(), Error> {
+        self.write(&[marker, content, b"\r\n"])
+    }
+
+    fn write_blob(&mut self, marker: &[u8], content: &[u8]) -> Result<(), Error> {
+        let len = content.len().to_string();
+        self.write(&[marker, len.as_bytes(), b"\r\n", content, b"\r\n"])
+    }
+
+    fn write_string(&mut self, s: &[u8]) -> Result<(), Error> {
+        match self.kind.take() {
+            None if self.blob_strings || is_binary(s) => self.write_blob(b"$", s),
+            None | Some(StringKind::SimpleString) => self.write_line(b"+", s),
+            Some(StringKind::BlobString) => self.write_blob(b"$", s),
+            Some(StringKind::SimpleError) => self.write_line(b"-", &single_line(s)),
+            Some(StringKind::BlobError) => self.write_blob(b"!", s),
+        }
+    }
+
+    fn write_len(&mut self, marker: &[u8], len: Option<usize>) -> Result<(), Error> {
+        match len {
+            Some(len) => self.write_line(marker, len.to_string().as_bytes()),
+            None => self.write_line(marker, b"?"),
+        }
+    }
+
+    fn write_null(&mut self, marker: &[u8]) -> Result<(), Error> {
+        if self.resp3 {
+            self.write_line(b"_", b"")
+        } else {
+            self.write_line(marker, b"-1")
+        }
+    }
+
+    /// Writes `variant` as the key of a map of one entry, the value is written next
+    fn write_variant(&mut self, variant: &str) -> Result<(), Error> {
+        if self.resp3 {
+            self.write_line(b"%", b"1")?;
+        } else {
+            self.write_line(b"*", b"2")?;
+        }
+        self.write_string(variant.as_bytes())
+    }
+}
+
+/// Serializer of the elements of arrays and maps
+pub struct Compound<'a, W> {
+    se: &'a mut Serializer<W>,
+    /// Whether the length was unknown, the end of the elements is then written
+    unknown_length: bool,
+}
+
+impl<'a, W: Write> Compound<'a, W> {
+    fn new(se: &'a mut Serializer<W>, marker: &[u8], len: Option<usize>) -> Result<Self, Error> {
+        se.write_len(marker, len)?;
+
+        Ok(Compound {
+            se,
+            unknown_length: len.is_none(),
+        })
+    }
+
+    fn element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.se)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if self.unknown_length {
+            self.se.write_line(b".", b"")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> serde::Serializer for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, W>;
+    type SerializeTuple = Compound<'a, W>;
+    type SerializeTupleStruct = Compound<'a, W>;
+    type SerializeTupleVariant = Compound<'a, W>;
+    type SerializeMap = Compound<'a, W>;
+    type SerializeStruct = Compound<'a, W>;
+    type SerializeStructVariant = Compound<'a, W>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        if self.resp3 {
+            self.write_line(b"#", if v { b"t" } else { b"f" })
+        } else {
+            self.write_line(b":", if v { b"1" } else { b"0" })
+        }
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.write_line(b":", v.to_string().as_bytes())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.write_line(b":", v.to_string().as_bytes())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        if v.is_nan() {
+            return Err(Error::nan());
+        }
+        let v = format_double(v);
+
+        if self.resp3 {
+            self.write_line(b",", v.as_bytes())
+        } else {
+            self.write_blob(b"$", v.as_bytes())
+        }
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write_string(v.to_string().as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_string(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_string(v)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.write_null(b"$")
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), Error> {
+        if name == NULL_ARRAY {
+            self.write_null(b"*")
+        } else {
+            self.serialize_none()
+        }
+    }
+
+    /// Serialize as { variant => null }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.write_variant(variant)?;
+        self.serialize_none()
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.kind = StringKind::from_name(name);
+        let result = value.serialize(&mut *self);
+        self.kind = None;
+
+        result
+    }
+
+    /// Serialize as { variant => T }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_variant(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Compound::new(self, b"*", len)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    /// Serialize as { variant => [tuple ele, .. ] }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        self.write_variant(variant)?;
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        match len {
+            Some(len) if !self.resp3 => Compound::new(self, b"*", Some(len * 2)),
+            len => Compound::new(self, b"%", len),
+        }
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    /// Serialize as { variant => { struct .. } }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        self.write_variant(variant)?;
+        self.serialize_map(Some(len))
+    }
+}
+
+impl<'a, W: Write> SerializeSeq for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> SerializeTuple for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> SerializeTupleStruct for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> SerializeTupleVariant for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> SerializeMap for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> SerializeStruct for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.element(key)?;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a, W: Write> SerializeStructVariant for Compound<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.element(key)?;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use deseresp::types::{
+        owned::{BlobError, BlobString, SimpleError},
+        OkResponse,
+    };
+
+    use super::*;
+    use crate::byte_string::ByteString;
+
+    #[test]
+    fn test_format_double() {
+        let cases = [
+            (3.5, "3.5"),
+            (-12.0, "-12"),
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (0.1, "0.10000000000000001"),
+            (1e16, "10000000000000000"),
+            (1e17, "1e+17"),
+            (1e20, "1e+20"),
+            (1.5e-5, "1.5e-05"),
+            (0.0001, "0.0001"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (v, formatted) in cases {
+            assert_eq!(format_double(v), formatted);
+        }
+    }
+
+    #[test]
+    fn test_serialize_same_as_deseresp() {
+        fn same<S: Serialize>(value: S) {
+            assert_eq!(
+                to_vec(&value, true).unwrap(),
+                deseresp::to_vec(&value).unwrap()
+            );
+        }
+
+        same(true);
+        same(-12345);
+        same(12.5);
+        same(f64::NEG_INFINITY);
+        same('c');
+        same("hello world");
+        same(Some("a"));
+        same(None::<i64>);
+        same(());
+        same((1, "abc", 10.5, vec![Some(1), None]));
+        same(BTreeMap::from([("a", 1), ("b", 2)]));
+        same(OkResponse);
+        same(SimpleError("ERR error".to_string()));
+        same(BlobError("ERR error".to_string()));
+        same(BlobString("hello".to_string()));
+
+        #[derive(Serialize)]
+        enum Enum {
+            Struct { a: usize },
+            Tuple(usize, String),
+            Unit,
+        }
+        same(Enum::Struct { a: 1 });
+        same(Enum::Tuple(1, "a".to_string()));
+        same(Enum::Unit);
+    }
+
+    #[test]
+    fn test_serialize_error_on_one_line() {
+        let error = SimpleError("ERR key a\rb\nc is not string".to_string());
+        assert_eq!(
+            to_vec(&error, false).unwrap(),
+            b"-ERR key a b c is not string\r\n"
+        );
+    }
+
+    #[test]
+    fn test_serialize_resp2() {
+        fn resp2<S: Serialize>(value: S) -> String {
+            String::from_utf8(to_vec(&value, false).unwrap()).unwrap()
+        }
+
+        assert_eq!(resp2(None::<i64>), "$-1\r\n");
+        assert_eq!(resp2(NullArray), "*-1\r\n");
+        assert_eq!(resp2(ArrayOrNull(None::<Vec<i64>>)), "*-1\r\n");
+        assert_eq!(resp2(ArrayOrNull(Some(vec![1]))), "*1\r\n:1\r\n");
+        assert_eq!(resp2(true), ":1\r\n");
+        assert_eq!(resp2(2.5), "$3\r\n2.5\r\n");
+        assert_eq!(
+            resp2(BTreeMap::from([("a", 1), ("b", 2)])),
+            "*4\r\n+a\r\n:1\r\n+b\r\n:2\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(to_vec(&NullArray, true).unwrap()).unwrap(),
+            "_\r\n"
+        );
+    }
+
+    #[test]
+    fn test_serialize_bytes() {
+        let values = vec![
+            ByteString::from("text"),
+            ByteString::from(&b"\xff\x00"[..]),
+            ByteString::from("a\r\nb"),
+        ];
+        assert_eq!(
+            to_vec(&values, false).unwrap(),
+            b"*3\r\n+text\r\n$2\r\n\xff\x00\r\n$4\r\na\r\nb\r\n"
+        );
+
+        let mut buf = Vec::new();
+        write_command(&[&b"SET"[..], b"k", b"\xff"], &mut buf);
+        assert_eq!(buf, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\n\xff\r\n");
+    }
+}
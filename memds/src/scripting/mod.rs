@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{futures::Notified, Notify};
+
+use crate::{byte_string::ByteString, Error};
+
+/// Time a script runs before other clients are replied BUSY and it can be killed, in
+/// milliseconds
+pub const DEFAULT_TIME_LIMIT: u64 = 5000;
+
+/// SHA1 digest of a script in hex, which names it in the script cache
+pub fn sha1hex(script: &[u8]) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+/// Scripts loaded by EVAL and SCRIPT LOAD by their SHA1 digest, with the script running.
+/// Shared by all databases.
+pub struct Scripts {
+    cache: Mutex<HashMap<String, ByteString>>,
+    /// Script running, scripts run one at a time with exclusive access to the databases
+    running: Mutex<Option<Arc<Run>>>,
+    /// See `DEFAULT_TIME_LIMIT`
+    time_limit: AtomicU64,
+    /// Notified when the running script passes the time limit, to reply BUSY to the commands
+    /// waiting for it
+    busy: Notify,
+}
+
+/// The script only read so far
+const READING: u8 = 0;
+/// The script called a write command, it can not be killed anymore
+const WRITING: u8 = 1;
+/// The script was killed by SCRIPT KILL, it fails at its next check
+const KILLED: u8 = 2;
+
+/// State of a running script, checked by the script as it runs
+#[derive(Debug)]
+pub struct Run {
+    started: Instant,
+    state: AtomicU8,
+    /// Whether the script was seen past the time limit
+    busy: AtomicBool,
+}
+
+impl Run {
+    pub fn killed(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == KILLED
+    }
+
+    /// Marks that the script writes, before its first write command.
+    /// Returns false if it was killed, it must not write then.
+    pub fn mark_written(&self) -> bool {
+        match self
+            .state
+            .compare_exchange(READING, WRITING, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => true,
+            Err(state) => state == WRITING,
+        }
+    }
+}
+
+/// Running script, until dropped
+pub struct RunGuard<'a> {
+    scripts: &'a Scripts,
+    run: Arc<Run>,
+}
+
+impl RunGuard<'_> {
+    pub fn run(&self) -> &Arc<Run> {
+        &self.run
+    }
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        self.scripts.running.lock().unwrap().take();
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Scripts {
+            cache: Mutex::new(HashMap::new()),
+            running: Mutex::new(None),
+            time_limit: AtomicU64::new(DEFAULT_TIME_LIMIT),
+            busy: Notify::new(),
+        }
+    }
+}
+
+impl Scripts {
+    pub fn set_time_limit(&self, millis: u64) {
+        self.time_limit.store(millis, Ordering::Relaxed);
+    }
+
+    pub fn time_limit(&self) -> Duration {
+        Duration::from_millis(self.time_limit.load(Ordering::Relaxed))
+    }
+
+    /// Adds `script` to the cache, returns its SHA1 digest
+    pub fn load(&self, script: &[u8]) -> String {
+        let sha = sha1hex(script);
+        self.cache
+            .lock()
+            .unwrap()
+            .entry(sha.clone())
+            .or_insert_with(|| script.into());
+
+        sha
+    }
+
+    /// Gets the script with digest `sha`, in any case
+    pub fn get(&self, sha: &str) -> Option<ByteString> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(&sha.to_ascii_lowercase())
+            .cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.cache
+            .lock()
+            .unwrap()
+            .contains_key(&sha.to_ascii_lowercase())
+    }
+
+    /// Removes all scripts from the cache
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Starts running a script, until the guard is dropped
+    pub fn start(&self) -> RunGuard<'_> {
+        let run = Arc::new(Run {
+            started: Instant::now(),
+            state: AtomicU8::new(READING),
+            busy: AtomicBool::new(false),
+        });
+        *self.running.lock().unwrap() = Some(run.clone());
+
+        RunGuard { scripts: self, run }
+    }
+
+    /// Whether a script runs for longer than the time limit, other commands are then
+    /// rejected instead of waiting for it
+    pub fn busy(&self) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|run| run.started.elapsed() >= self.time_limit())
+    }
+
+    /// Completes once a script runs past the time limit, to be called before checking `busy`
+    pub fn busy_notified(&self) -> Notified<'_> {
+        self.busy.notified()
+    }
+
+    /// Checks if `run` passed the time limit as the script runs, the first time it did the
+    /// commands waiting for it are notified
+    pub fn check_time_limit(&self, run: &Run) {
+        if !run.busy.load(Ordering::Relaxed)
+            && run.started.elapsed() >= self.time_limit()
+            && !run.busy.swap(true, Ordering::Relaxed)
+        {
+            self.busy.notify_waiters();
+        }
+    }
+
+    /// Kills the running script, unless it wrote to the databases
+    pub fn kill(&self) -> Result<(), Error> {
+        let running = self.running.lock().unwrap();
+        let run = running.as_ref().ok_or_else(|| {
+            Error::Handle("NOTBUSY No scripts in execution right now.".to_string())
+        })?;
+        match run
+            .state
+            .compare_exchange(READING, KILLED, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Err(WRITING) => Err(Error::Handle(
+                "UNKILLABLE Sorry the script already executed write commands against the \
+                 dataset. You can either wait the script termination or kill the server in a \
+                 hard way using the SHUTDOWN NOSAVE command."
+                    .to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_cache() {
+        let scripts = Scripts::default();
+        let sha = scripts.load(b"return 1");
+        assert_eq!(sha, "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+        assert!(scripts.exists(&sha.to_ascii_uppercase()));
+        assert_eq!(scripts.get(&sha).unwrap(), "return 1");
+
+        scripts.flush();
+        assert!(!scripts.exists(&sha));
+        assert_eq!(scripts.get(&sha), None);
+    }
+
+    #[test]
+    fn test_kill() {
+        let scripts = Scripts::default();
+        assert!(scripts.kill().is_err());
+
+        scripts.set_time_limit(0);
+        let guard = scripts.start();
+        assert!(scripts.busy());
+        scripts.kill().unwrap();
+        assert!(guard.run().killed());
+        assert!(!guard.run().mark_written());
+        drop(guard);
+        assert!(!scripts.busy());
+
+        let guard = scripts.start();
+        assert!(guard.run().mark_written());
+        assert!(guard.run().mark_written());
+        assert!(scripts.kill().is_err());
+        assert!(!guard.run().killed());
+    }
+}
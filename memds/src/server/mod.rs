@@ -1,22 +1,37 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::{future, stream::FuturesUnordered, StreamExt};
 use tokio::{
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
     sync::broadcast,
 };
 
 use crate::{
+    blocking::{Blocked, Wakeup},
+    command::{Access, ClientState, DebugArgs},
+    config::{AppendFsync, Config, SavePolicy},
     connection::{flush, FrameReader},
     database::Database,
-    Terminator,
+    pubsub::{Message, Subscription},
+    tracking::{Inbox, Push},
+    Error, Terminator,
 };
 
 /// 100KB buffer size for pipelined write
 const WRITE_BUF_SIZE_LIMIT: usize = 1024 * 100;
+/// Interval between active expire cycles
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+/// Max number of expired keys deleted per active expire cycle,
+/// bounds the time the keyspace is locked
+const ACTIVE_EXPIRE_KEYS_PER_CYCLE: usize = 200;
+/// Interval between fsync of the append-only file with `appendfsync everysec`
+const AOF_FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval between checks of the save policies
+const SAVE_POLICY_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Server {
-    port: u16,
+    config: Config,
     db: Arc<Database>,
 }
 
@@ -64,23 +79,125 @@ async fn accept_loop(
     tracing::info!("All sessions ended.");
 }
 
-impl Server {
-    pub fn new(port: u16, db_path: String) -> Self {
-        Server {
-            port,
-            db: Arc::new(Database::new(db_path)),
+/// Periodically deletes expired keys that are not accessed anymore
+async fn active_expire_loop(db: Arc<Database>, mut shutdown_rx: broadcast::Receiver<()>) {
+    let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                // keep going without waiting while there are more expired keys
+                loop {
+                    // shared like a command, so keys don't expire in the middle of a
+                    // transaction or a script
+                    let access = db.lock_shared().await;
+                    let expired = db.active_expire(ACTIVE_EXPIRE_KEYS_PER_CYCLE);
+                    drop(access);
+                    if expired < ACTIVE_EXPIRE_KEYS_PER_CYCLE {
+                        break;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                break
+            }
+        }
+    }
+}
+
+/// Periodically fsyncs the append-only file
+async fn aof_fsync_loop(db: Arc<Database>, mut shutdown_rx: broadcast::Receiver<()>) {
+    let mut interval = tokio::time::interval(AOF_FSYNC_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = db.aof_fsync() {
+                    tracing::error!("Failed to fsync AOF: {}", e);
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                break
+            }
+        }
+    }
+}
+
+/// Periodically starts a background save when a save policy is met
+async fn save_policy_loop(
+    db: Arc<Database>,
+    policies: Vec<SavePolicy>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let mut interval = tokio::time::interval(SAVE_POLICY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                db.bgsave_if_needed(&policies);
+            }
+            _ = shutdown_rx.recv() => {
+                break
+            }
         }
     }
+}
+
+impl Server {
+    pub fn new(port: u16, db_path: String) -> Result<Self, Error> {
+        Ok(Server {
+            db: Arc::new(Database::new(db_path.clone())?),
+            config: Config {
+                port,
+                db_path,
+                ..Default::default()
+            },
+        })
+    }
+
+    pub fn from_config(config: Config) -> Result<Self, Error> {
+        Ok(Server {
+            db: Arc::new(Database::from_config(&config)?),
+            config,
+        })
+    }
 
     pub async fn service(self) -> anyhow::Result<(SocketAddr, Terminator)> {
-        let listener = TcpListener::bind(("127.0.0.1", self.port)).await?;
+        let listener = TcpListener::bind(("127.0.0.1", self.config.port)).await?;
 
         let addr = listener.local_addr().unwrap();
 
         let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
         let shutdown_tx_terminator = shutdown_tx.clone();
+        let expire_shutdown_rx = shutdown_tx.subscribe();
+        let fsync_shutdown_rx = shutdown_tx.subscribe();
+        let save_shutdown_rx = shutdown_tx.subscribe();
+        let save_policies = self.config.save.clone();
+        let fsync_everysec =
+            self.config.appendonly && self.config.appendfsync == AppendFsync::EverySec;
         let service_handler = tokio::spawn(async move {
-            accept_loop(self.db.clone(), listener, addr, shutdown_tx, shutdown_rx).await;
+            let fsync_db = self.db.clone();
+            let save_db = self.db.clone();
+            future::join4(
+                accept_loop(self.db.clone(), listener, addr, shutdown_tx, shutdown_rx),
+                active_expire_loop(self.db.clone(), expire_shutdown_rx),
+                async move {
+                    if fsync_everysec {
+                        aof_fsync_loop(fsync_db, fsync_shutdown_rx).await;
+                    }
+                },
+                async move {
+                    if !save_policies.is_empty() {
+                        save_policy_loop(save_db, save_policies, save_shutdown_rx).await;
+                    }
+                },
+            )
+            .await;
+
+            if let Err(e) = self.db.aof_fsync() {
+                tracing::error!("Failed to fsync AOF: {}", e);
+            }
 
             tracing::info!("Saving DB");
             if let Err(e) = self.db.save() {
@@ -116,45 +233,181 @@ impl Session {
         }
     }
 
-    async fn handle(mut self) -> anyhow::Result<()> {
-        let (reader, mut writer) = self.socket.into_split();
+    async fn handle(self) -> anyhow::Result<()> {
+        let Session {
+            socket,
+            db,
+            mut shutdown,
+        } = self;
+        let mut client = ClientState::default();
+        crate::command::client::connect(&db, &mut client);
+
+        let result = Session::serve(socket, &db, &mut shutdown, &mut client).await;
+        crate::command::transaction::reset(&db, &mut client);
+        crate::command::pubsub::reset(&db, &mut client);
+        crate::command::blocking::reset(&db, &mut client);
+        crate::command::client::reset(&db, &mut client);
+
+        result
+    }
+
+    async fn serve(
+        socket: TcpStream,
+        db: &Arc<Database>,
+        shutdown: &mut broadcast::Receiver<()>,
+        client: &mut ClientState,
+    ) -> anyhow::Result<()> {
+        let (reader, mut writer) = socket.into_split();
         let mut write_buf = Vec::new();
         let mut connection = FrameReader::new(reader);
 
         'main: loop {
-            while let Some(frame) = connection.next_buffered_frame::<Vec<&str>>()? {
-                tracing::info!("Received frame: {:?}", frame);
-                match crate::command::parse_and_handle(&frame[..], &self.db, &mut write_buf) {
+            // the commands of a blocked client stay buffered until it is unblocked
+            while client.blocked.is_none() {
+                let Some(frame) = connection.next_buffered_frame::<Vec<&[u8]>>()? else {
+                    break;
+                };
+                tracing::info!("Received frame: {:?}", DebugArgs(&frame));
+                let result = handle_frame(db, &frame, client, &mut write_buf).await;
+                match result {
                     Ok(need_flush) => {
                         if need_flush || write_buf.len() > WRITE_BUF_SIZE_LIMIT {
                             flush(&mut writer, &mut write_buf).await;
                         }
                     }
                     Err(e) => {
-                        tracing::error!("Error handling command: {:?}, e: {}", &frame, e);
+                        tracing::error!(
+                            "Error handling command: {:?}, e: {}",
+                            DebugArgs(&frame),
+                            e
+                        );
                         return Err(e.into());
                     }
                 }
             }
 
-            let read_write =
-                future::join(connection.read_to_buf(), flush(&mut writer, &mut write_buf));
-            tokio::pin!(read_write);
-
+            // all the branches are cancel safe: reading and writing stop at what is done,
+            // a message is only taken out of the subscription or the pushes when it is received,
+            // a blocked client waits until the same deadline
+            let resp3 = client.resp3();
             tokio::select! {
-                _ = self.shutdown.recv() => {
+                _ = shutdown.recv() => {
                     tracing::info!("Receive shutdown request, end session.");
                     break 'main;
                 }
-                (read_bytes, ()) = &mut read_write => {
+                read_bytes = connection.read_to_buf() => {
                     if read_bytes? == 0 {
                         tracing::info!("Session ended by client.");
                         break 'main;
                     }
                 }
+                written = writer.write(&write_buf), if !write_buf.is_empty() => {
+                    write_buf.drain(..written?);
+                }
+                message = next_message(client.subscription.as_mut()),
+                    if write_buf.len() <= WRITE_BUF_SIZE_LIMIT =>
+                {
+                    match message {
+                        Some(message) => {
+                            crate::command::pubsub::write_message(&message, resp3, &mut write_buf)?
+                        }
+                        None => {
+                            tracing::warn!("Subscriber over the output buffer limit, end session.");
+                            break 'main;
+                        }
+                    }
+                }
+                push = next_push(client.pushes.as_mut()),
+                    if write_buf.len() <= WRITE_BUF_SIZE_LIMIT =>
+                {
+                    crate::command::client::write_push(client, &push, &mut write_buf)?;
+                }
+                wakeup = wait_unblocked(client.blocked.as_mut()) => {
+                    crate::command::blocking::unblock(db, client, wakeup, &mut write_buf)?;
+                }
             }
         }
 
         Ok(())
     }
 }
+
+/// Handles a command once it has access to the databases, waiting for it without holding up
+/// the thread while another command has exclusive access
+async fn handle_frame(
+    db: &Arc<Database>,
+    args: &[&[u8]],
+    client: &mut ClientState,
+    write_buf: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    if let Some(result) = crate::command::handle_before_access(args, db, client, write_buf) {
+        return result;
+    }
+
+    match crate::command::wait_access(args, db).await {
+        Ok(access @ Access::Exclusive(_)) => {
+            handle_exclusive(db, args, client, write_buf, access).await
+        }
+        access => crate::command::handle_with_access(args, db, client, write_buf, access),
+    }
+}
+
+/// Handles a command with exclusive access to the databases on a thread of its own, so the
+/// other sessions are still served while it runs, if only to be replied BUSY
+async fn handle_exclusive(
+    db: &Arc<Database>,
+    args: &[&[u8]],
+    client: &mut ClientState,
+    write_buf: &mut Vec<u8>,
+    access: Access,
+) -> Result<bool, Error> {
+    let db = db.clone();
+    let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.to_vec()).collect();
+    let mut state = std::mem::take(client);
+    let mut buf = std::mem::take(write_buf);
+    let handled = tokio::task::spawn_blocking(move || {
+        let args: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+        let result =
+            crate::command::handle_with_access(&args, &db, &mut state, &mut buf, Ok(access));
+        (result, state, buf)
+    })
+    .await;
+
+    match handled {
+        Ok((result, state, buf)) => {
+            *client = state;
+            *write_buf = buf;
+            result
+        }
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Waits for the next message published to the subscriptions of the client, forever if it
+/// has none. Returns None once the subscriber is over the output buffer limit.
+async fn next_message(subscription: Option<&mut Subscription>) -> Option<Message> {
+    match subscription {
+        Some(subscription) => subscription.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// Waits for the next message pushed to the client for client-side caching, forever if its
+/// connection is not registered
+async fn next_push(pushes: Option<&mut Inbox>) -> Push {
+    match pushes {
+        Some(pushes) => match pushes.recv().await {
+            Some(push) => push,
+            None => future::pending().await,
+        },
+        None => future::pending().await,
+    }
+}
+
+/// Waits until the blocked client is woken up, forever if it is not blocked
+async fn wait_unblocked(blocked: Option<&mut Blocked>) -> Wakeup {
+    match blocked {
+        Some(blocked) => blocked.wait().await,
+        None => future::pending().await,
+    }
+}
@@ -1,24 +1,516 @@
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{BufReader, BufWriter},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
-use crate::{memds::MemDS, Error};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    byte_string::ByteString,
+    config::{DbFormat, MAX_DATABASES},
+    memds::MemDS,
+    rdb, Error,
+};
+
+/// Values of keys.
+/// A persistent map with shared values, so the keyspace can be snapshotted in constant time
+/// and a value is only copied when it is modified while a snapshot holds it.
+pub type Data = im::HashMap<ByteString, Arc<MemDS>>;
+/// Expiry times of volatile keys, in unix time milliseconds
+pub type ExpireTimes = im::HashMap<ByteString, u64>;
+
+/// Point-in-time copy of the keyspace, shares unmodified values with the live keyspace
+#[derive(Default)]
+pub struct Snapshot {
+    pub data: Data,
+    pub expires: ExpireTimes,
+}
+
+impl Snapshot {
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Numbers and snapshots of the databases with keys, expiry times are only set on keys
+pub fn non_empty(snapshots: &[Snapshot]) -> impl Iterator<Item = (usize, &Snapshot)> {
+    snapshots
+        .iter()
+        .enumerate()
+        .filter(|(_, snapshot)| !snapshot.is_empty())
+}
+
+/// First bytes of a db file
+const MAGIC: &[u8; 5] = b"MEMDS";
+/// Version of the db file format written by `save`.
+///
+/// - 0: no header nor checksum, the bincode encoded keyspace optionally followed by the
+///   expiry times
+/// - 1: `MAGIC`, version (u16 little endian), the keyspace and the expiry times,
+///   then the CRC32 of all preceding bytes (u32 little endian)
+/// - 2: as 1 with numbered databases, the number of databases with keys (u64), then the
+///   number (u64), the keyspace and the expiry times of each of them by increasing number
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Format and version of a loaded db file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    Memds(u16),
+    Rdb(u16),
+}
+
+/// Computes the CRC32 of the bytes written through it
+struct CrcWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Computes the CRC32 of the bytes read through it
+struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn save_error(e: impl std::fmt::Display) -> Error {
+    Error::Handle(format!("Failed to save {}", e))
+}
+
+fn load_error(e: impl std::fmt::Display) -> Error {
+    Error::Handle(format!("Failed to load {}", e))
+}
+
+/// Writes the snapshots of the databases, by number, in the current format,
+/// `progress` is incremented by the number of keys written so far
+fn write_snapshot<W: Write>(
+    writer: W,
+    snapshots: &[Snapshot],
+    progress: &AtomicUsize,
+) -> Result<W, Error> {
+    let mut writer = CrcWriter {
+        inner: writer,
+        hasher: crc32fast::Hasher::new(),
+    };
+
+    writer.write_all(MAGIC).map_err(save_error)?;
+    writer
+        .write_all(&FORMAT_VERSION.to_le_bytes())
+        .map_err(save_error)?;
+    let databases = non_empty(snapshots).count() as u64;
+    bincode::serialize_into(&mut writer, &databases).map_err(save_error)?;
+    for (index, snapshot) in non_empty(snapshots) {
+        bincode::serialize_into(&mut writer, &(index as u64)).map_err(save_error)?;
+        // same encoding as serializing the whole map, one entry at a time to report progress
+        bincode::serialize_into(&mut writer, &(snapshot.data.len() as u64)).map_err(save_error)?;
+        for (key, value) in snapshot.data.iter() {
+            bincode::serialize_into(&mut writer, key).map_err(save_error)?;
+            bincode::serialize_into(&mut writer, value).map_err(save_error)?;
+            progress.fetch_add(1, Ordering::Relaxed);
+        }
+        bincode::serialize_into(&mut writer, &snapshot.expires).map_err(save_error)?;
+    }
+
+    let CrcWriter { mut inner, hasher } = writer;
+    inner
+        .write_all(&hasher.finalize().to_le_bytes())
+        .map_err(save_error)?;
+
+    Ok(inner)
+}
+
+fn write_file<W: Write>(
+    writer: W,
+    format: DbFormat,
+    snapshots: &[Snapshot],
+    progress: &AtomicUsize,
+) -> Result<W, Error> {
+    match format {
+        DbFormat::Memds => write_snapshot(writer, snapshots, progress),
+        DbFormat::Rdb => rdb::write(writer, snapshots, progress),
+    }
+}
 
 // TODO: change to storage Error
-pub fn save(db_path: &str, db: &HashMap<String, MemDS>) -> Result<(), Error> {
-    let file = File::create(db_path)
+/// Writes the snapshots of the databases, by number, to `db_path` in `format`,
+/// `progress` is incremented by the number of keys written so far.
+/// The file is written to a temp file, fsync-ed then renamed,
+/// so `db_path` holds either the previous or the new snapshot if the process crashes.
+pub fn save(
+    db_path: &str,
+    format: DbFormat,
+    snapshots: &[Snapshot],
+    progress: &AtomicUsize,
+) -> Result<(), Error> {
+    // renaming over a special file like /dev/null would replace it, write to it directly
+    let special = fs::metadata(db_path).map(|m| !m.is_file()).unwrap_or(false);
+    if special {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(db_path)
+            .map_err(|e| Error::Handle(format!("Failed to open db file {}", e)))?;
+        return write_file(BufWriter::new(file), format, snapshots, progress)?
+            .flush()
+            .map_err(save_error);
+    }
+
+    let tmp_path = format!("{}.tmp", db_path);
+    let file = File::create(&tmp_path)
         .map_err(|e| Error::Handle(format!("Failed to open db file {}", e)))?;
-    let writer = BufWriter::new(file);
+    let result = write_file(BufWriter::new(file), format, snapshots, progress).and_then(|writer| {
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, db_path))
+            .map_err(save_error)
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    // persist the rename
+    let dir = match Path::new(db_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(save_error)
+}
 
-    bincode::serialize_into(writer, db).map_err(|e| Error::Handle(format!("Failed to save {}", e)))
+/// Options of `bincode::deserialize_from` with a limit of bytes read,
+/// so a corrupted length can not make it allocate more than the size of the file
+fn bincode_options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
 }
 
-pub fn load(db_path: &str) -> Result<HashMap<String, MemDS>, Error> {
-    let file =
-        File::open(db_path).map_err(|e| Error::Handle(format!("Failed to open db file {}", e)))?;
-    let reader = BufReader::new(file);
+/// Reads a bincode encoded map.
+/// Decoded as a std map then converted, since the decoder of `im::HashMap` preallocates
+/// by the encoded length, which can be huge in a corrupted file.
+fn deserialize_map<R, V>(reader: &mut R, limit: u64) -> bincode::Result<im::HashMap<ByteString, V>>
+where
+    R: Read,
+    V: DeserializeOwned + Clone,
+{
+    let map: HashMap<ByteString, V> = bincode_options(limit).deserialize_from(reader)?;
+
+    Ok(map.into_iter().collect())
+}
+
+/// Reads a file without header, written before versioning
+fn load_v0<R: Read>(mut reader: R, file_len: u64) -> Result<Snapshot, Error> {
+    let data = deserialize_map(&mut reader, file_len).map_err(load_error)?;
+    // db files written before expiry support end right after the keyspace
+    let expires = match deserialize_map(&mut reader, file_len) {
+        Ok(expires) => expires,
+        Err(e) => match *e {
+            bincode::ErrorKind::Io(ref io) if io.kind() == ErrorKind::UnexpectedEof => {
+                ExpireTimes::new()
+            }
+            _ => return Err(load_error(e)),
+        },
+    };
+    let mut rest = [0];
+    if reader.read(&mut rest).map_err(load_error)? != 0 {
+        return Err(load_error("unexpected bytes at the end of db file"));
+    }
+
+    Ok(Snapshot { data, expires })
+}
+
+/// Size of the checksum at the end of a file of version 1 or later
+const CHECKSUM_LEN: usize = 4;
+
+/// Reads the rest of a file of version 1 or later, after the header, and checks the checksum
+/// at its end before anything is decoded. Returns the bytes between the header and the
+/// checksum.
+fn read_checked<R: Read>(reader: CrcReader<R>) -> Result<Vec<u8>, Error> {
+    let CrcReader {
+        inner: mut reader,
+        mut hasher,
+    } = reader;
+    let mut payload = Vec::new();
+    reader.read_to_end(&mut payload).map_err(load_error)?;
+    let payload_len = payload
+        .len()
+        .checked_sub(CHECKSUM_LEN)
+        .ok_or_else(|| load_error("truncated db file"))?;
+    let checksum = payload.split_off(payload_len);
+    hasher.update(&payload);
+    if checksum[..] != hasher.finalize().to_le_bytes() {
+        return Err(load_error("checksum mismatch"));
+    }
+
+    Ok(payload)
+}
+
+/// Checks that the whole payload of a file of version 1 or later was decoded
+fn check_end(rest: &[u8]) -> Result<(), Error> {
+    if !rest.is_empty() {
+        return Err(load_error("unexpected bytes at the end of db file"));
+    }
+
+    Ok(())
+}
+
+/// Reads the keyspace and the expiry times of a database
+fn load_keyspace<R: Read>(reader: &mut R, limit: u64) -> Result<Snapshot, Error> {
+    let data = deserialize_map(reader, limit).map_err(load_error)?;
+    let expires = deserialize_map(reader, limit).map_err(load_error)?;
+
+    Ok(Snapshot { data, expires })
+}
+
+/// Reads the rest of a file of version 1, after the header
+fn load_v1<R: Read>(reader: CrcReader<R>) -> Result<Snapshot, Error> {
+    let payload = read_checked(reader)?;
+    let mut rest = &payload[..];
+    let snapshot = load_keyspace(&mut rest, payload.len() as u64)?;
+    check_end(rest)?;
+
+    Ok(snapshot)
+}
+
+/// Reads the rest of a file of version 2, after the header
+fn load_v2<R: Read>(reader: CrcReader<R>) -> Result<Vec<Snapshot>, Error> {
+    let payload = read_checked(reader)?;
+    let limit = payload.len() as u64;
+    let mut rest = &payload[..];
+    let databases: u64 = bincode_options(limit)
+        .deserialize_from(&mut rest)
+        .map_err(load_error)?;
+    let mut snapshots = Vec::new();
+    for _ in 0..databases {
+        let index: u64 = bincode_options(limit)
+            .deserialize_from(&mut rest)
+            .map_err(load_error)?;
+        if index < snapshots.len() as u64 || index >= MAX_DATABASES as u64 {
+            return Err(load_error(format!("invalid database number {}", index)));
+        }
+        snapshots.resize_with(index as usize, Snapshot::default);
+        snapshots.push(load_keyspace(&mut rest, limit)?);
+    }
+    check_end(rest)?;
+
+    Ok(snapshots)
+}
+
+/// Loads the keyspaces and the expiry times of volatile keys of the databases, by number,
+/// with the format of the file, either memds or Redis RDB format.
+/// Databases after the last one with keys are omitted, files without numbered databases
+/// hold database 0.
+/// Returns None if the file does not exist, an empty file holds no keys.
+/// A truncated or corrupted file is an error.
+pub fn load(db_path: &str) -> Result<Option<(Vec<Snapshot>, FileFormat)>, Error> {
+    let file = match File::open(db_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Handle(format!("Failed to open db file {}", e))),
+    };
+    let file_len = file.metadata().map_err(load_error)?.len();
+    let mut reader = CrcReader {
+        inner: BufReader::new(file),
+        hasher: crc32fast::Hasher::new(),
+    };
+
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    (&mut reader)
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .map_err(load_error)?;
+    if magic.is_empty() {
+        return Ok(Some((Vec::new(), FileFormat::Memds(FORMAT_VERSION))));
+    }
+    if magic == rdb::MAGIC {
+        let mut content = magic;
+        reader.inner.read_to_end(&mut content).map_err(load_error)?;
+        let (snapshots, version) = rdb::load(&content)?;
+        return Ok(Some((snapshots, FileFormat::Rdb(version))));
+    }
+    if magic != MAGIC {
+        // the bytes read are the beginning of the keyspace of a file without header
+        let reader = io::Cursor::new(magic).chain(reader.inner);
+        return Ok(Some((
+            vec![load_v0(reader, file_len)?],
+            FileFormat::Memds(0),
+        )));
+    }
+
+    let mut version = [0; 2];
+    reader.read_exact(&mut version).map_err(load_error)?;
+    match u16::from_le_bytes(version) {
+        1 => Ok(Some((vec![load_v1(reader)?], FileFormat::Memds(1)))),
+        2 => Ok(Some((load_v2(reader)?, FileFormat::Memds(2)))),
+        version => Err(load_error(format!(
+            "unsupported db file format version {}",
+            version
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memds::{SetDS, StringDS};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("memds-{}-{}.bin", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn snapshot() -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        snapshot
+            .data
+            .insert("a".into(), Arc::new(MemDS::String(StringDS::from("1"))));
+        snapshot
+            .data
+            .insert("s".into(), Arc::new(MemDS::Set(SetDS::from("x"))));
+        snapshot.expires.insert("a".into(), 100);
+        snapshot
+    }
+
+    fn assert_loaded(loaded: Option<(Vec<Snapshot>, FileFormat)>, format: FileFormat) {
+        let (loaded, loaded_format) = loaded.unwrap();
+        assert_eq!(loaded_format, format);
+        assert_eq!(loaded.len(), 1);
+        let loaded = &loaded[0];
+        assert_eq!(loaded.data.len(), 2);
+        assert_eq!(loaded.data[&b"a"[..]].string(b"a").unwrap().fetch(), "1");
+        assert_eq!(
+            loaded.data[&b"s"[..]].set(b"s").unwrap().members(),
+            vec!["x"]
+        );
+        assert_eq!(loaded.expires.get(&b"a"[..]), Some(&100));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = temp_path("storage");
+        let progress = AtomicUsize::new(0);
+        save(&path, DbFormat::Memds, &[snapshot()], &progress).unwrap();
+        assert_eq!(progress.load(Ordering::Relaxed), 2);
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert_loaded(load(&path).unwrap(), FileFormat::Memds(FORMAT_VERSION));
+
+        save(&path, DbFormat::Rdb, &[snapshot()], &AtomicUsize::new(0)).unwrap();
+        assert_loaded(load(&path).unwrap(), FileFormat::Rdb(rdb::RDB_VERSION));
+
+        assert!(load(&temp_path("storage-missing")).unwrap().is_none());
+        fs::write(&path, b"").unwrap();
+        let (empty, _) = load(&path).unwrap().unwrap();
+        assert!(empty.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_load_databases() {
+        let path = temp_path("storage-databases");
+        let snapshots = [
+            Snapshot::default(),
+            snapshot(),
+            Snapshot::default(),
+            snapshot(),
+            Snapshot::default(),
+        ];
+        for (format, file_format) in [
+            (DbFormat::Memds, FileFormat::Memds(FORMAT_VERSION)),
+            (DbFormat::Rdb, FileFormat::Rdb(rdb::RDB_VERSION)),
+        ] {
+            let progress = AtomicUsize::new(0);
+            save(&path, format, &snapshots, &progress).unwrap();
+            assert_eq!(progress.load(Ordering::Relaxed), 4);
+
+            let (loaded, loaded_format) = load(&path).unwrap().unwrap();
+            assert_eq!(loaded_format, file_format);
+            let sizes: Vec<usize> = loaded.iter().map(|s| s.data.len()).collect();
+            assert_eq!(sizes, [0, 2, 0, 2]);
+            assert_eq!(loaded[3].expires.get(&b"a"[..]), Some(&100));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupted() {
+        let path = temp_path("storage-corrupted");
+        save(&path, DbFormat::Memds, &[snapshot()], &AtomicUsize::new(0)).unwrap();
+        let content = fs::read(&path).unwrap();
+
+        // flipping any byte or truncating the file is detected, the checksum before decoding
+        for i in 0..content.len() {
+            let mut corrupted = content.clone();
+            corrupted[i] ^= 0x01;
+            fs::write(&path, &corrupted).unwrap();
+            match load(&path) {
+                Err(Error::Handle(e)) if i >= MAGIC.len() + 2 => {
+                    assert!(e.ends_with("checksum mismatch"), "flipped byte {}", i)
+                }
+                result => assert!(result.is_err(), "flipped byte {}", i),
+            }
+        }
+        for len in 1..content.len() {
+            fs::write(&path, &content[..len]).unwrap();
+            assert!(load(&path).is_err(), "truncated to {}", len);
+        }
+
+        let mut unknown_version = content.clone();
+        unknown_version[MAGIC.len()] = 99;
+        fs::write(&path, &unknown_version).unwrap();
+        assert!(load(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_v0() {
+        let path = temp_path("storage-v0");
+        let snapshot = snapshot();
+
+        let mut content = bincode::serialize(&snapshot.data).unwrap();
+        content.extend(bincode::serialize(&snapshot.expires).unwrap());
+        fs::write(&path, &content).unwrap();
+        assert_loaded(load(&path).unwrap(), FileFormat::Memds(0));
+
+        // written before expiry support
+        fs::write(&path, bincode::serialize(&snapshot.data).unwrap()).unwrap();
+        let (loaded, format) = load(&path).unwrap().unwrap();
+        assert_eq!(format, FileFormat::Memds(0));
+        assert_eq!(loaded[0].data.len(), 2);
+        assert!(loaded[0].expires.is_empty());
 
-    bincode::deserialize_from(reader).map_err(|e| Error::Handle(format!("Failed to load {}", e)))
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,475 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::mpsc;
+
+use crate::{byte_string::ByteString, Error};
+
+/// Channel of the invalidations written to a RESP2 client, which gets them as messages
+/// when subscribed to it
+pub const INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+thread_local! {
+    /// Command handled by the thread, see `Tracking::as_client`
+    static CURRENT_COMMAND: RefCell<Option<Command>> = const { RefCell::new(None) };
+}
+
+/// Keys modified by the command of a client, invalidated once it is done
+struct Command {
+    client: u64,
+    modified: Vec<ByteString>,
+    seen: HashSet<ByteString>,
+}
+
+/// Message pushed to a client connection for client-side caching
+#[derive(Debug, Clone, PartialEq)]
+pub enum Push {
+    /// Keys modified since a tracking client read them, None when all keys were flushed
+    Invalidate(Option<Vec<ByteString>>),
+    /// The client the invalidations are redirected to is gone
+    RedirectBroken(u64),
+}
+
+/// Client-side caching options of a client, given to CLIENT TRACKING ON
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackingOptions {
+    /// Client receiving the invalidations instead of this one
+    pub redirect: Option<u64>,
+    /// Broadcast mode: invalidations of all the keys starting with `prefixes`, whether they
+    /// were read or not
+    pub bcast: bool,
+    /// Prefixes of the keys invalidated in broadcast mode, all keys if empty
+    pub prefixes: Vec<ByteString>,
+    /// Keys are only tracked for the commands following CLIENT CACHING YES
+    pub optin: bool,
+    /// Keys are tracked except for the commands following CLIENT CACHING NO
+    pub optout: bool,
+    /// Keys modified by the client itself are not invalidated
+    pub noloop: bool,
+}
+
+impl TrackingOptions {
+    /// Whether the keys read by a command are tracked, `caching` is the flag given by
+    /// CLIENT CACHING for the command, if any
+    pub fn tracks_reads(&self, caching: Option<bool>) -> bool {
+        if self.bcast {
+            false
+        } else if self.optin {
+            caching == Some(true)
+        } else if self.optout {
+            caching != Some(false)
+        } else {
+            true
+        }
+    }
+}
+
+/// Keys read by the clients caching them, with the connections of all the clients to send
+/// them invalidations. Shared by all sessions.
+pub struct Tracking {
+    table: Mutex<Table>,
+    next_id: AtomicU64,
+    /// Number of clients with tracking on, nothing is invalidated while there is none
+    tracking_clients: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Table {
+    clients: HashMap<u64, Connection>,
+    /// Ids of the clients that read each key since it was last invalidated
+    keys: HashMap<ByteString, HashSet<u64>>,
+    /// Ids of the clients in broadcast mode by prefix, the empty prefix for all keys
+    prefixes: HashMap<ByteString, HashSet<u64>>,
+}
+
+struct Connection {
+    sender: mpsc::UnboundedSender<Push>,
+    /// None unless tracking is on
+    options: Option<TrackingOptions>,
+}
+
+/// Connection of a client registered by `Tracking::connect`, with the messages pushed to it
+#[derive(Debug)]
+pub struct Inbox {
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<Push>,
+}
+
+impl Inbox {
+    /// Id of the client, given to CLIENT TRACKING REDIRECT by other clients
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits for the next message pushed to the connection
+    pub async fn recv(&mut self) -> Option<Push> {
+        self.receiver.recv().await
+    }
+
+    /// Takes the next message pushed to the connection, if there is one already
+    pub fn try_recv(&mut self) -> Option<Push> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Default for Tracking {
+    fn default() -> Self {
+        Tracking {
+            table: Mutex::new(Table::default()),
+            // 0 is the id of no client
+            next_id: AtomicU64::new(1),
+            tracking_clients: AtomicUsize::new(0),
+        }
+    }
+}
+
+fn overlap(a: &[u8], b: &[u8]) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+impl Table {
+    /// Stops tracking for client `id`, the keys it read are forgotten once invalidated
+    fn disable(&mut self, id: u64) -> bool {
+        let Some(options) = self
+            .clients
+            .get_mut(&id)
+            .and_then(|connection| connection.options.take())
+        else {
+            return false;
+        };
+
+        if options.bcast {
+            let prefixes = match options.prefixes.is_empty() {
+                true => vec![ByteString::new()],
+                false => options.prefixes,
+            };
+            for prefix in prefixes {
+                if let Some(ids) = self.prefixes.get_mut(&prefix) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        self.prefixes.remove(&prefix);
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Sends `keys` invalidated to client `id`, or to the client it redirects to
+    fn send(&self, id: u64, keys: Option<Vec<ByteString>>) {
+        let Some(options) = self.clients.get(&id).and_then(|c| c.options.as_ref()) else {
+            return;
+        };
+        let target = options.redirect.unwrap_or(id);
+        if let Some(connection) = self.clients.get(&target) {
+            let _ = connection.sender.send(Push::Invalidate(keys));
+        }
+    }
+}
+
+impl Tracking {
+    /// Registers the connection of a new client, it gets an id other clients can redirect
+    /// their invalidations to
+    pub fn connect(&self) -> Inbox {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.table.lock().unwrap().clients.insert(
+            id,
+            Connection {
+                sender,
+                options: None,
+            },
+        );
+
+        Inbox { id, receiver }
+    }
+
+    /// Removes the connection of a client when it ends,
+    /// the clients redirecting to it are told their redirection is broken
+    pub fn disconnect(&self, id: u64) {
+        let mut table = self.table.lock().unwrap();
+        if table.disable(id) {
+            self.tracking_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+        table.clients.remove(&id);
+
+        for connection in table.clients.values() {
+            let redirect = connection.options.as_ref().and_then(|o| o.redirect);
+            if redirect == Some(id) {
+                let _ = connection.sender.send(Push::RedirectBroken(id));
+            }
+        }
+    }
+
+    /// Turns tracking on for client `id` with `options`. If it is already on, the mode can
+    /// not change and the prefixes are added to the current ones.
+    pub fn enable(&self, id: u64, mut options: TrackingOptions) -> Result<(), Error> {
+        let mut table = self.table.lock().unwrap();
+        if let Some(redirect) = options.redirect {
+            if !table.clients.contains_key(&redirect) {
+                return Err(Error::Handle(
+                    "ERR The client ID you want redirect to does not exist".to_string(),
+                ));
+            }
+        }
+        let current = table
+            .clients
+            .get(&id)
+            .ok_or_else(|| {
+                Error::Handle("ERR CLIENT TRACKING requires a client connection".to_string())
+            })?
+            .options
+            .clone();
+
+        if let Some(current) = &current {
+            if current.bcast != options.bcast {
+                return Err(Error::Handle(
+                    "ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_string(),
+                ));
+            }
+            if (current.optin, current.optout) != (options.optin, options.optout) {
+                return Err(Error::Handle(
+                    "ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".to_string(),
+                ));
+            }
+        }
+        let existing = current.iter().flat_map(|current| &current.prefixes);
+        for (i, prefix) in options.prefixes.iter().enumerate() {
+            let other = existing
+                .clone()
+                .chain(&options.prefixes[i + 1..])
+                .find(|other| overlap(prefix, other));
+            if let Some(other) = other {
+                return Err(Error::Handle(format!(
+                    "ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.",
+                    prefix.to_str_lossy(),
+                    other.to_str_lossy()
+                )));
+            }
+        }
+
+        if let Some(current) = current {
+            let mut prefixes = current.prefixes;
+            prefixes.append(&mut options.prefixes);
+            options.prefixes = prefixes;
+        }
+        if table.disable(id) {
+            self.tracking_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+        if options.bcast {
+            let prefixes = match options.prefixes.is_empty() {
+                true => vec![ByteString::new()],
+                false => options.prefixes.clone(),
+            };
+            for prefix in prefixes {
+                table.prefixes.entry(prefix).or_default().insert(id);
+            }
+        }
+        if let Some(connection) = table.clients.get_mut(&id) {
+            connection.options = Some(options);
+        }
+        self.tracking_clients.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Turns tracking off for client `id`
+    pub fn disable(&self, id: u64) {
+        if self.table.lock().unwrap().disable(id) {
+            self.tracking_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Tracking options of client `id`, None if tracking is off
+    pub fn options(&self, id: u64) -> Option<TrackingOptions> {
+        let table = self.table.lock().unwrap();
+
+        table.clients.get(&id)?.options.clone()
+    }
+
+    /// Remembers that client `id` read `keys`, it is sent an invalidation when one of them is
+    /// modified
+    pub fn track(&self, id: u64, keys: &[&[u8]]) {
+        let mut table = self.table.lock().unwrap();
+        for &key in keys {
+            table.keys.entry(key.into()).or_default().insert(id);
+        }
+    }
+
+    /// Calls `f` handling a command of client `id`. The keys modified by `f` are invalidated
+    /// once it returns, once each, and not for the client itself if it tracks keys with NOLOOP.
+    pub fn as_client<T>(&self, id: u64, f: impl FnOnce() -> T) -> T {
+        let command = Command {
+            client: id,
+            modified: Vec::new(),
+            seen: HashSet::new(),
+        };
+        let previous = CURRENT_COMMAND.with(|current| current.replace(Some(command)));
+        let result = f();
+        let command = CURRENT_COMMAND.with(|current| current.replace(previous));
+
+        if let Some(command) = command {
+            for key in command.modified {
+                self.send_invalidation(&key, command.client);
+            }
+        }
+
+        result
+    }
+
+    /// Invalidates modified `key` for the clients that read it since it was last invalidated
+    /// and for the clients broadcasting a prefix of it, once the current command is done
+    pub fn invalidate(&self, key: &[u8]) {
+        if self.tracking_clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let deferred = CURRENT_COMMAND.with(|current| match &mut *current.borrow_mut() {
+            Some(command) => {
+                if command.seen.insert(key.into()) {
+                    command.modified.push(key.into());
+                }
+                true
+            }
+            None => false,
+        });
+        if !deferred {
+            self.send_invalidation(key, 0);
+        }
+    }
+
+    /// Sends the invalidation of `key` modified by client `writer`, 0 for no client
+    fn send_invalidation(&self, key: &[u8], writer: u64) {
+        let mut table = self.table.lock().unwrap();
+
+        let mut ids = table.keys.remove(key).unwrap_or_default();
+        for (prefix, prefix_ids) in &table.prefixes {
+            if key.starts_with(prefix) {
+                ids.extend(prefix_ids);
+            }
+        }
+        for id in ids {
+            let noloop = table
+                .clients
+                .get(&id)
+                .and_then(|connection| connection.options.as_ref())
+                .is_some_and(|options| options.noloop);
+            if !(noloop && id == writer) {
+                table.send(id, Some(vec![key.into()]));
+            }
+        }
+    }
+
+    /// Sends the invalidation of all keys to all tracking clients, when the keys are flushed
+    pub fn invalidate_all(&self) {
+        if self.tracking_clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut table = self.table.lock().unwrap();
+
+        table.keys.clear();
+        let ids: Vec<u64> = table
+            .clients
+            .iter()
+            .filter(|(_, connection)| connection.options.is_some())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            table.send(id, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidate() {
+        let tracking = Tracking::default();
+        let mut reader = tracking.connect();
+        let mut target = tracking.connect();
+        let mut broadcaster = tracking.connect();
+        tracking
+            .enable(reader.id(), TrackingOptions::default())
+            .unwrap();
+        let options = TrackingOptions {
+            redirect: Some(target.id()),
+            bcast: true,
+            prefixes: vec!["user:".into()],
+            ..Default::default()
+        };
+        tracking.enable(broadcaster.id(), options).unwrap();
+
+        tracking.track(reader.id(), &[b"user:1", b"other"]);
+        tracking.invalidate(b"user:1");
+        tracking.invalidate(b"user:1");
+        let invalidation = |key: &str| Push::Invalidate(Some(vec![key.into()]));
+        assert_eq!(reader.try_recv(), Some(invalidation("user:1")));
+        assert_eq!(reader.try_recv(), None);
+        assert_eq!(target.try_recv(), Some(invalidation("user:1")));
+        assert_eq!(target.try_recv(), Some(invalidation("user:1")));
+        assert_eq!(broadcaster.try_recv(), None);
+
+        // no invalidation of the keys modified by the client itself with NOLOOP
+        let noloop = TrackingOptions {
+            noloop: true,
+            ..Default::default()
+        };
+        tracking.disable(reader.id());
+        tracking.enable(reader.id(), noloop).unwrap();
+        tracking.track(reader.id(), &[b"a", b"b"]);
+        tracking.as_client(reader.id(), || {
+            tracking.invalidate(b"a");
+            tracking.invalidate(b"a");
+        });
+        tracking.invalidate(b"b");
+        assert_eq!(reader.try_recv(), Some(invalidation("b")));
+        assert_eq!(reader.try_recv(), None);
+
+        tracking.invalidate_all();
+        assert_eq!(reader.try_recv(), Some(Push::Invalidate(None)));
+        assert_eq!(target.try_recv(), Some(Push::Invalidate(None)));
+
+        tracking.disconnect(target.id());
+        assert_eq!(
+            broadcaster.try_recv(),
+            Some(Push::RedirectBroken(target.id()))
+        );
+        tracking.disconnect(reader.id());
+        tracking.disconnect(broadcaster.id());
+        assert_eq!(tracking.tracking_clients.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_enable_errors() {
+        let tracking = Tracking::default();
+        let client = tracking.connect();
+        let bcast = |prefixes: &[&str]| TrackingOptions {
+            bcast: true,
+            prefixes: prefixes.iter().map(|&prefix| prefix.into()).collect(),
+            ..Default::default()
+        };
+
+        let redirect = TrackingOptions {
+            redirect: Some(100),
+            ..Default::default()
+        };
+        assert!(tracking.enable(client.id(), redirect).is_err());
+        assert!(tracking.enable(client.id(), bcast(&["a", "ab"])).is_err());
+        tracking.enable(client.id(), bcast(&["a"])).unwrap();
+        assert!(tracking.enable(client.id(), bcast(&["abc"])).is_err());
+        assert!(tracking
+            .enable(client.id(), TrackingOptions::default())
+            .is_err());
+        tracking.enable(client.id(), bcast(&["b"])).unwrap();
+        assert_eq!(tracking.options(client.id()).unwrap().prefixes.len(), 2);
+        assert!(tracking.enable(0, TrackingOptions::default()).is_err());
+    }
+}
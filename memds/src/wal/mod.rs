@@ -1 +1,782 @@
-struct WalEntry {}
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+};
+
+use command_args::CommandArgs;
+
+use crate::{
+    command::{
+        connection::SelectCommand,
+        hash::{FieldValue, HsetCommand},
+        keyspace::{ExpireCondition, PexpireatCommand},
+        list::RpushCommand,
+        set::SaddCommand,
+        sorted_set::{ScoreMember, ZaddCommand},
+        stream::{
+            ClaimTime, EntriesAdded, EntriesRead, Force, IdArg, Justid, MaxDeletedId, RetryCount,
+            TrimLimit, TrimOperator, TrimOption, XaddCommand, XclaimCommand, XgroupCommand,
+            XgroupSubcommand, XsetidCommand,
+        },
+        string::{Exists, ExpireOption, SetCommand},
+    },
+    config::AppendFsync,
+    memds::{MemDS, StreamDS, StreamId},
+    resp,
+    storage::{self, Data, ExpireTimes, Snapshot},
+    Error,
+};
+
+/// Max number of elements per command when writing a collection to the log
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// Append-only log of write commands,
+/// each record is a RESP array of bulk strings, the same as a command sent by clients
+pub struct Wal {
+    path: String,
+    file: File,
+    fsync: AppendFsync,
+    /// Whether there are appended records not fsync-ed yet
+    dirty: bool,
+    buf: Vec<u8>,
+    /// Database selected by the last SELECT record, None if unknown
+    selected: Option<usize>,
+    /// Records appended since a background rewrite started,
+    /// they are appended to the rewritten log before it replaces the current one
+    rewrite_buf: Option<Vec<u8>>,
+    /// Database selected by the last SELECT record in `rewrite_buf`, None if there is none
+    rewrite_selected: Option<usize>,
+}
+
+/// Encodes a command as a RESP array of bulk strings
+fn encode_record(args: &[Vec<u8>], buf: &mut Vec<u8>) {
+    resp::write_command(args, buf);
+}
+
+/// Decoding result of a record
+#[derive(Debug, PartialEq)]
+enum Record<'a> {
+    /// A complete record and the number of bytes it takes
+    Complete(Vec<&'a [u8]>, usize),
+    /// Not enough bytes for a complete record
+    Incomplete,
+}
+
+/// Reads a `\r\n` terminated line starting at `pos`, returns the line and the position after it
+fn read_line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let len = buf.get(pos..)?.windows(2).position(|w| w == b"\r\n")?;
+
+    Some((&buf[pos..pos + len], pos + len + 2))
+}
+
+/// Reads a line of `marker` followed by a length, returns None if the line is incomplete
+fn read_length(buf: &[u8], pos: usize, marker: u8) -> Result<Option<(usize, usize)>, Error> {
+    let invalid = || Error::Handle(format!("Invalid AOF record at offset {}", pos));
+    let (line, next) = match read_line(buf, pos) {
+        Some(l) => l,
+        None => {
+            // a partial line is only valid if it is a prefix of a length line
+            let partial = &buf[pos.min(buf.len())..];
+            let valid_prefix = match partial.split_first() {
+                None => true,
+                Some((m, rest)) => {
+                    *m == marker
+                        && rest.iter().enumerate().all(|(i, c)| {
+                            c.is_ascii_digit() || (*c == b'\r' && i == rest.len() - 1)
+                        })
+                }
+            };
+            return if valid_prefix {
+                Ok(None)
+            } else {
+                Err(invalid())
+            };
+        }
+    };
+    let len = match line.split_first() {
+        Some((m, len)) if *m == marker => std::str::from_utf8(len)
+            .ok()
+            .and_then(|len| len.parse().ok()),
+        _ => None,
+    };
+
+    len.map(|len| Some((len, next))).ok_or_else(invalid)
+}
+
+fn decode_record(buf: &[u8]) -> Result<Record<'_>, Error> {
+    let (count, mut pos) = match read_length(buf, 0, b'*')? {
+        Some(l) => l,
+        None => return Ok(Record::Incomplete),
+    };
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (len, start) = match read_length(buf, pos, b'$')? {
+            Some(l) => l,
+            None => return Ok(Record::Incomplete),
+        };
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return Ok(Record::Incomplete);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(Error::Handle(format!(
+                "Invalid AOF record at offset {}",
+                end
+            )));
+        }
+        args.push(&buf[start..end]);
+        pos = end + 2;
+    }
+
+    Ok(Record::Complete(args, pos))
+}
+
+/// Reads the log at `path` and calls `f` with each command in order.
+/// A truncated record at the end of the log, left by a crash in the middle of an append,
+/// is removed from the file.
+pub fn replay<F>(path: &str, mut f: F) -> Result<usize, Error>
+where
+    F: FnMut(&[&[u8]]) -> Result<(), Error>,
+{
+    let mut content = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut content))
+        .map_err(|e| Error::Handle(format!("Failed to read AOF {}", e)))?;
+
+    let mut pos = 0;
+    let mut count = 0;
+    while pos < content.len() {
+        match decode_record(&content[pos..])
+            .map_err(|e| Error::Handle(format!("Corrupted AOF at offset {}: {}", pos, e)))?
+        {
+            Record::Complete(args, len) => {
+                f(&args)?;
+                pos += len;
+                count += 1;
+            }
+            Record::Incomplete => {
+                tracing::warn!(
+                    "AOF truncated at offset {}, removing the last {} bytes",
+                    pos,
+                    content.len() - pos
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .and_then(|file| file.set_len(pos as u64))
+                    .map_err(|e| Error::Handle(format!("Failed to truncate AOF {}", e)))?;
+                break;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Encodes a SELECT record of database `index`
+fn encode_select(index: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let mut args = Vec::new();
+    SelectCommand { index }
+        .encode(&mut args)
+        .map_err(Error::Parse)?;
+    encode_record(&args, buf);
+
+    Ok(())
+}
+
+/// Calls `f` with commands that recreate the keyspaces of the databases, by number,
+/// preceded by SELECT for databases other than 0
+fn databases_commands<F>(snapshots: &[Snapshot], mut f: F) -> Result<(), Error>
+where
+    F: FnMut(&[Vec<u8>]) -> Result<(), Error>,
+{
+    let mut args = Vec::new();
+    for (index, snapshot) in storage::non_empty(snapshots) {
+        if index != 0 {
+            args.clear();
+            SelectCommand { index }
+                .encode(&mut args)
+                .map_err(Error::Parse)?;
+            f(&args)?;
+        }
+        keyspace_commands(&snapshot.data, &snapshot.expires, &mut f)?;
+    }
+
+    Ok(())
+}
+
+/// Calls `f` with commands that recreate the keyspace
+fn keyspace_commands<F>(data: &Data, expires: &ExpireTimes, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(&[Vec<u8>]) -> Result<(), Error>,
+{
+    let mut args = Vec::new();
+    for (key, value) in data.iter() {
+        let expire_at = expires.get(key).copied();
+
+        match &**value {
+            MemDS::String(s) => {
+                let value = s.fetch();
+                args.clear();
+                SetCommand {
+                    key,
+                    value: &value,
+                    exists: Exists::Any,
+                    get: None,
+                    expire: expire_at.map(|when| ExpireOption::ExpireAtMs(when as usize)),
+                }
+                .encode(&mut args)
+                .map_err(Error::Parse)?;
+                f(&args)?;
+                // expiry is already set by SET
+                continue;
+            }
+            MemDS::Set(s) => {
+                let members = s.members();
+                for chunk in members.chunks(ITEMS_PER_COMMAND) {
+                    args.clear();
+                    SaddCommand {
+                        key,
+                        elements: chunk.iter().map(|member| member.as_bytes()).collect(),
+                    }
+                    .encode(&mut args)
+                    .map_err(Error::Parse)?;
+                    f(&args)?;
+                }
+            }
+            MemDS::Hash(h) => {
+                let entries: Vec<_> = h.iter().collect();
+                for chunk in entries.chunks(ITEMS_PER_COMMAND) {
+                    args.clear();
+                    HsetCommand {
+                        key,
+                        field_values: chunk
+                            .iter()
+                            .map(|(field, value)| FieldValue { field, value })
+                            .collect(),
+                    }
+                    .encode(&mut args)
+                    .map_err(Error::Parse)?;
+                    f(&args)?;
+                }
+            }
+            MemDS::List(l) => {
+                let elements: Vec<&[u8]> = l.iter().map(|element| element.as_bytes()).collect();
+                for chunk in elements.chunks(ITEMS_PER_COMMAND) {
+                    args.clear();
+                    RpushCommand {
+                        key,
+                        elements: chunk.to_vec(),
+                    }
+                    .encode(&mut args)
+                    .map_err(Error::Parse)?;
+                    f(&args)?;
+                }
+            }
+            MemDS::SortedSet(z) => {
+                let elements: Vec<_> = z.iter().collect();
+                for chunk in elements.chunks(ITEMS_PER_COMMAND) {
+                    args.clear();
+                    ZaddCommand {
+                        key,
+                        flags: Vec::new(),
+                        score_members: chunk
+                            .iter()
+                            .map(|(member, score)| ScoreMember {
+                                score: *score,
+                                member,
+                            })
+                            .collect(),
+                    }
+                    .encode(&mut args)
+                    .map_err(Error::Parse)?;
+                    f(&args)?;
+                }
+            }
+            MemDS::Stream(s) => stream_commands(key, s, &mut args, &mut f)?,
+        }
+
+        if let Some(when) = expire_at {
+            args.clear();
+            PexpireatCommand {
+                key,
+                unix_time_milliseconds: when as i64,
+                condition: ExpireCondition::Always,
+            }
+            .encode(&mut args)
+            .map_err(Error::Parse)?;
+            f(&args)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Calls `f` with commands that recreate the stream at `key`,
+/// with its IDs, consumer groups and pending entries
+fn stream_commands<F>(
+    key: &[u8],
+    s: &StreamDS,
+    args: &mut Vec<Vec<u8>>,
+    f: &mut F,
+) -> Result<(), Error>
+where
+    F: FnMut(&[Vec<u8>]) -> Result<(), Error>,
+{
+    let mut add = |id: &str, field_values: Vec<FieldValue>, trim: Option<TrimOption>| {
+        args.clear();
+        XaddCommand {
+            key,
+            nomkstream: None,
+            trim,
+            id,
+            field_values,
+        }
+        .encode(args)
+        .map_err(Error::Parse)?;
+        f(args)
+    };
+    if s.is_empty() {
+        // add then trim an entry to create the empty stream
+        let id = s.last_id().max(StreamId::new(0, 1)).to_string();
+        add(
+            &id,
+            vec![FieldValue {
+                field: b"x",
+                value: b"y",
+            }],
+            Some(TrimOption::MaxLen {
+                operator: TrimOperator::Exact,
+                threshold: "0",
+                limit: None::<TrimLimit>,
+            }),
+        )?;
+    }
+    for (id, fields) in s.iter() {
+        let field_values = fields
+            .iter()
+            .map(|(field, value)| FieldValue { field, value })
+            .collect();
+        add(&id.to_string(), field_values, None)?;
+    }
+
+    let last_id = s.last_id().to_string();
+    let max_deleted_id = s.max_deleted_id().to_string();
+    args.clear();
+    XsetidCommand {
+        key,
+        last_id: &last_id,
+        entries_added: Some(EntriesAdded {
+            entries_added: s.entries_added() as usize,
+        }),
+        max_deleted_id: Some(MaxDeletedId {
+            id: &max_deleted_id,
+        }),
+    }
+    .encode(args)
+    .map_err(Error::Parse)?;
+    f(args)?;
+
+    for (group_name, group) in s.groups() {
+        let last_delivered = group.last_delivered.to_string();
+        args.clear();
+        XgroupCommand {
+            subcommand: XgroupSubcommand::Create {
+                key,
+                group: group_name,
+                id: &last_delivered,
+                mkstream: None,
+                entries_read: group.entries_read.map(|entries_read| EntriesRead {
+                    entries_read: entries_read as usize,
+                }),
+            },
+        }
+        .encode(args)
+        .map_err(Error::Parse)?;
+        f(args)?;
+
+        for consumer in group.consumers.keys() {
+            args.clear();
+            XgroupCommand {
+                subcommand: XgroupSubcommand::CreateConsumer {
+                    key,
+                    group: group_name,
+                    consumer,
+                },
+            }
+            .encode(args)
+            .map_err(Error::Parse)?;
+            f(args)?;
+        }
+
+        for (id, entry) in group.pending.iter() {
+            args.clear();
+            XclaimCommand {
+                key,
+                group: group_name,
+                consumer: &entry.consumer,
+                min_idle_time: 0,
+                ids: vec![IdArg(*id)],
+                idle: None,
+                time: Some(ClaimTime {
+                    unix_time_milliseconds: entry.delivery_time as usize,
+                }),
+                retry_count: Some(RetryCount {
+                    count: entry.delivery_count as usize,
+                }),
+                force: Some(Force),
+                justid: Some(Justid),
+                last_id: None,
+            }
+            .encode(args)
+            .map_err(Error::Parse)?;
+            f(args)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the commands to recreate the databases to a new file at `path`
+fn write_databases(path: &str, snapshots: &[Snapshot]) -> Result<File, Error> {
+    let file =
+        File::create(path).map_err(|e| Error::Handle(format!("Failed to create AOF {}", e)))?;
+    let mut writer = BufWriter::new(file);
+
+    let mut buf = Vec::new();
+    databases_commands(snapshots, |args| {
+        buf.clear();
+        encode_record(args, &mut buf);
+        writer
+            .write_all(&buf)
+            .map_err(|e| Error::Handle(format!("Failed to write AOF {}", e)))
+    })?;
+
+    writer
+        .into_inner()
+        .map_err(|e| Error::Handle(format!("Failed to write AOF {}", e.into_error())))
+}
+
+/// Writes a new log at `path` with the commands to recreate the databases, by number.
+/// The log is written to a temp file then renamed, so `path` always holds a complete log.
+pub fn rewrite(path: &str, snapshots: &[Snapshot]) -> Result<(), Error> {
+    let tmp_path = format!("{}.tmp", path);
+
+    write_databases(&tmp_path, snapshots)?
+        .sync_all()
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| Error::Handle(format!("Failed to write AOF {}", e)))
+}
+
+/// Path of the temp file of a background rewrite of the log at `path`
+fn rewrite_tmp_path(path: &str) -> String {
+    format!("{}.rewrite.tmp", path)
+}
+
+/// Writes the compacted log of a background rewrite to a temp file,
+/// the temp file replaces the log on `Wal::finish_rewrite`.
+/// Does not need the lock of the log, so it can run while new writes are appended.
+pub fn write_rewrite(path: &str, snapshots: &[Snapshot]) -> Result<File, Error> {
+    write_databases(&rewrite_tmp_path(path), snapshots)
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending, creates it if it does not exist
+    pub fn open(path: &str, fsync: AppendFsync) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::Handle(format!("Failed to open AOF {}", e)))?;
+
+        Ok(Wal {
+            path: path.to_owned(),
+            file,
+            fsync,
+            dirty: false,
+            buf: Vec::new(),
+            selected: None,
+            rewrite_buf: None,
+            rewrite_selected: None,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite_buf.is_some()
+    }
+
+    /// Starts buffering appended records for a background rewrite.
+    /// The keyspace snapshot for the rewrite must be taken while holding the lock of the log,
+    /// so every write after the snapshot is in the buffer.
+    pub fn start_rewrite(&mut self) -> Result<(), Error> {
+        if self.rewrite_in_progress() {
+            return Err(Error::Handle(
+                "ERR Background append only file rewriting already in progress".to_string(),
+            ));
+        }
+        self.rewrite_buf = Some(Vec::new());
+        self.rewrite_selected = None;
+
+        Ok(())
+    }
+
+    /// Completes a background rewrite with the result of `write_rewrite`:
+    /// appends the buffered records to the rewritten log,
+    /// then atomically replaces the current log with it.
+    /// On failure, the current log is kept and the rewritten one is removed.
+    pub fn finish_rewrite(&mut self, rewritten: Result<File, Error>) -> Result<(), Error> {
+        let rewrite_buf = self.rewrite_buf.take().unwrap_or_default();
+        let tmp_path = rewrite_tmp_path(&self.path);
+
+        let result = rewritten.and_then(|mut file| {
+            file.write_all(&rewrite_buf)
+                .and_then(|_| file.sync_all())
+                .and_then(|_| fs::rename(&tmp_path, &self.path))
+                .map_err(|e| Error::Handle(format!("Failed to write AOF {}", e)))
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        // the old file is unlinked, switch to the new one
+        let reopened = Wal::open(&self.path, self.fsync)?;
+        self.file = reopened.file;
+        self.dirty = false;
+        // the database selected at the end of the rewritten commands is not tracked
+        self.selected = self.rewrite_selected.take();
+
+        Ok(())
+    }
+
+    /// Appends a command handled in database `db` to the log,
+    /// preceded by a SELECT record if the log has another database selected
+    pub fn append(&mut self, db: usize, args: &[Vec<u8>]) -> Result<(), Error> {
+        self.buf.clear();
+        if self.selected != Some(db) {
+            encode_select(db, &mut self.buf)?;
+        }
+        let record_start = self.buf.len();
+        encode_record(args, &mut self.buf);
+
+        self.file
+            .write_all(&self.buf)
+            .map_err(|e| Error::Handle(format!("ERR failed to append to AOF {}", e)))?;
+        self.dirty = true;
+        self.selected = Some(db);
+        if let Some(rewrite_buf) = &mut self.rewrite_buf {
+            if self.rewrite_selected != Some(db) {
+                encode_select(db, rewrite_buf)?;
+                self.rewrite_selected = Some(db);
+            }
+            rewrite_buf.extend_from_slice(&self.buf[record_start..]);
+        }
+
+        if self.fsync == AppendFsync::Always {
+            self.fsync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes appended records to disk
+    pub fn fsync(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            self.file
+                .sync_data()
+                .map_err(|e| Error::Handle(format!("ERR failed to fsync AOF {}", e)))?;
+            self.dirty = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use crate::memds::StringDS;
+
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    fn join<A: AsRef<[u8]>>(args: &[A]) -> String {
+        let args: Vec<_> = args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg.as_ref()))
+            .collect();
+        args.join(" ")
+    }
+
+    #[test]
+    fn test_encode_decode_record() {
+        let mut buf = Vec::new();
+        encode_record(&to_args(&["SET", "a", "hello\r\nworld"]), &mut buf);
+        assert_eq!(
+            buf,
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$12\r\nhello\r\nworld\r\n"
+        );
+
+        assert_eq!(
+            decode_record(&buf).unwrap(),
+            Record::Complete(vec![b"SET", b"a", b"hello\r\nworld"], buf.len())
+        );
+        for len in 0..buf.len() {
+            assert_eq!(decode_record(&buf[..len]).unwrap(), Record::Incomplete);
+        }
+
+        assert!(decode_record(b"*1\r\n$1\r\nab\r\n").is_err());
+        assert!(decode_record(b"$1\r\na\r\n").is_err());
+    }
+
+    #[test]
+    fn test_replay_truncated() {
+        let path = std::env::temp_dir().join(format!("memds-wal-{}.aof", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut wal = Wal::open(&path, AppendFsync::Always).unwrap();
+        wal.append(0, &to_args(&["INCR", "a"])).unwrap();
+        wal.append(0, &to_args(&["SADD", "s", "x", "y"])).unwrap();
+        let complete_len = fs::metadata(&path).unwrap().len();
+        wal.file.write_all(b"*2\r\n$4\r\nINCR\r\n$1").unwrap();
+
+        let mut commands = Vec::new();
+        let count = replay(&path, |args| {
+            commands.push(join(args));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(commands, vec!["SELECT 0", "INCR a", "SADD s x y"]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len);
+
+        fs::write(&path, b"*1\r\n$4\r\nINCR\r\nxxxx").unwrap();
+        assert!(replay(&path, |_| Ok(())).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_buffers_appends() {
+        let path =
+            std::env::temp_dir().join(format!("memds-wal-rewrite-{}.aof", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut wal = Wal::open(&path, AppendFsync::No).unwrap();
+        wal.append(0, &to_args(&["INCR", "a"])).unwrap();
+        wal.start_rewrite().unwrap();
+        assert!(wal.start_rewrite().is_err());
+        wal.append(0, &to_args(&["INCR", "b"])).unwrap();
+
+        let mut snapshot = Snapshot::default();
+        snapshot
+            .data
+            .insert("a".into(), Arc::new(MemDS::String(StringDS::from("1"))));
+        let rewritten = write_rewrite(&path, &[snapshot]);
+        wal.finish_rewrite(rewritten).unwrap();
+        assert!(!wal.rewrite_in_progress());
+        wal.append(0, &to_args(&["INCR", "c"])).unwrap();
+
+        let mut commands = Vec::new();
+        replay(&path, |args| {
+            commands.push(join(args));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(commands, vec!["SET a 1", "SELECT 0", "INCR b", "INCR c"]);
+        assert!(!Path::new(&rewrite_tmp_path(&path)).exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_select_databases() {
+        let path =
+            std::env::temp_dir().join(format!("memds-wal-select-{}.aof", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut wal = Wal::open(&path, AppendFsync::No).unwrap();
+        wal.append(2, &to_args(&["INCR", "a"])).unwrap();
+        wal.append(2, &to_args(&["INCR", "b"])).unwrap();
+        wal.start_rewrite().unwrap();
+        wal.append(0, &to_args(&["INCR", "c"])).unwrap();
+
+        let snapshots: Vec<_> = ["a", "", "b"]
+            .into_iter()
+            .map(|key| {
+                let mut snapshot = Snapshot::default();
+                if !key.is_empty() {
+                    snapshot
+                        .data
+                        .insert(key.into(), Arc::new(MemDS::String(StringDS::from("1"))));
+                }
+                snapshot
+            })
+            .collect();
+        let rewritten = write_rewrite(&path, &snapshots);
+        wal.finish_rewrite(rewritten).unwrap();
+        wal.append(0, &to_args(&["INCR", "d"])).unwrap();
+        wal.append(1, &to_args(&["INCR", "e"])).unwrap();
+
+        let mut commands = Vec::new();
+        replay(&path, |args| {
+            commands.push(join(args));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                "SET a 1", "SELECT 2", "SET b 1", "SELECT 0", "INCR c", "INCR d", "SELECT 1",
+                "INCR e"
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stream_commands() {
+        let mut stream = StreamDS::default();
+        stream.add(StreamId::new(1, 0), vec![("a".into(), "1".into())]);
+        stream.add(StreamId::new(2, 0), vec![("b".into(), "2".into())]);
+        stream.remove(&[StreamId::new(2, 0)]);
+        stream.create_group("g", StreamId::MIN, None);
+        stream.read_group_new("g", "alice", None, false, 100);
+
+        let mut data = Data::new();
+        data.insert("s".into(), Arc::new(MemDS::Stream(stream)));
+        data.insert("e".into(), Arc::new(MemDS::Stream(StreamDS::default())));
+        let mut commands = Vec::new();
+        keyspace_commands(&data, &ExpireTimes::new(), |args| {
+            commands.push(join(args));
+            Ok(())
+        })
+        .unwrap();
+        commands.sort();
+        assert_eq!(
+            commands,
+            vec![
+                "XADD e MAXLEN = 0 0-1 x y",
+                "XADD s 1-0 a 1",
+                "XCLAIM s g alice 0 1-0 TIME 100 RETRYCOUNT 1 FORCE JUSTID",
+                "XGROUP CREATE s g 1-0",
+                "XGROUP CREATECONSUMER s g alice",
+                "XSETID e 0-0 ENTRIESADDED 0 MAXDELETEDID 0-0",
+                "XSETID s 2-0 ENTRIESADDED 2 MAXDELETEDID 2-0",
+            ]
+        );
+    }
+}
@@ -1,22 +1,38 @@
+use std::time::Duration;
+
+use deseresp::types::owned::{SimpleError, SimpleString};
 use memds::{
     client::Client,
     command::{
-        connection::PingCommand,
-        string::{GetCommand, IncrCommand},
+        admin::{BgsaveCommand, InfoCommand, LastsaveCommand},
+        connection::{PingCommand, PingReply},
+        keyspace::{ExpireCommand, ExpireCondition, PersistCommand, PexpireCommand, TtlCommand},
+        list::{LlenCommand, RpushCommand},
+        pubsub::PublishCommand,
+        string::{AppendCommand, GetCommand, IncrCommand},
     },
+    config::{AppendFsync, Config},
+    connection::FrameReader,
     Server,
 };
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
 
 #[tokio::test]
 async fn test_ping_command() {
     let port = 0;
-    let server = Server::new(port, "/dev/null".into());
+    let server = Server::new(port, "/dev/null".into()).unwrap();
 
     let (addr, server_handle) = server.service().await.unwrap();
 
     let mut client = Client::from_addr(addr).await.unwrap();
-    let result = client.execute(&PingCommand).await.unwrap();
-    assert_eq!(result.0, "PONG");
+    let result = client
+        .execute(&PingCommand { message: None })
+        .await
+        .unwrap();
+    assert_eq!(result, PingReply::Pong(SimpleString("PONG".into())));
 
     server_handle.await;
 }
@@ -24,12 +40,12 @@ async fn test_ping_command() {
 #[tokio::test]
 async fn test_get_command() {
     let port = 1234;
-    let server = Server::new(port, "/dev/null".into());
+    let server = Server::new(port, "/dev/null".into()).unwrap();
 
     let (addr, server_handle) = server.service().await.unwrap();
 
     let mut client = Client::from_addr(addr).await.unwrap();
-    let result = client.execute(&GetCommand { key: "a" }).await.unwrap();
+    let result = client.execute(&GetCommand { key: b"a" }).await.unwrap();
     assert_eq!(result, None);
 
     server_handle.await;
@@ -38,22 +54,462 @@ async fn test_get_command() {
 #[tokio::test]
 async fn test_incr_command() {
     let port = 1235;
-    let server = Server::new(port, "/dev/null".into());
+    let server = Server::new(port, "/dev/null".into()).unwrap();
 
     let (addr, server_handle) = server.service().await.unwrap();
 
     let mut client = Client::from_addr(addr).await.unwrap();
-    let result = client.execute(&IncrCommand { key: "a" }).await.unwrap();
+    let result = client.execute(&IncrCommand { key: b"a" }).await.unwrap();
     assert_eq!(result, 1);
 
-    let result = client.execute(&GetCommand { key: "a" }).await.unwrap();
-    assert_eq!(result, Some("1".to_string()));
+    let result = client.execute(&GetCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, Some("1".into()));
 
-    let result = client.execute(&IncrCommand { key: "a" }).await.unwrap();
+    let result = client.execute(&IncrCommand { key: b"a" }).await.unwrap();
     assert_eq!(result, 2);
 
-    let result = client.execute(&GetCommand { key: "a" }).await.unwrap();
-    assert_eq!(result, Some("2".to_string()));
+    let result = client.execute(&GetCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, Some("2".into()));
+
+    server_handle.await;
+}
+
+#[tokio::test]
+async fn test_expire_command() {
+    let server = Server::new(0, "/dev/null".into()).unwrap();
+
+    let (addr, server_handle) = server.service().await.unwrap();
+
+    let mut client = Client::from_addr(addr).await.unwrap();
+    let result = client.execute(&TtlCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, -2);
+
+    client.execute(&IncrCommand { key: b"a" }).await.unwrap();
+    let result = client.execute(&TtlCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, -1);
+
+    let expire = ExpireCommand {
+        key: b"a",
+        seconds: 100,
+        condition: ExpireCondition::NotVolatile,
+    };
+    let result = client.execute(&expire).await.unwrap();
+    assert_eq!(result, 1);
+    let result = client.execute(&expire).await.unwrap();
+    assert_eq!(result, 0);
+    let result = client.execute(&TtlCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, 100);
+
+    let result = client.execute(&PersistCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, 1);
+    let result = client.execute(&TtlCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, -1);
+
+    let pexpire = PexpireCommand {
+        key: b"a",
+        milliseconds: 10,
+        condition: ExpireCondition::Always,
+    };
+    client.execute(&pexpire).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let result = client.execute(&GetCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, None);
+
+    server_handle.await;
+}
+
+#[tokio::test]
+async fn test_aof_replay() {
+    let aof_path = std::env::temp_dir().join(format!("memds-test-{}.aof", std::process::id()));
+    let aof_path = aof_path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&aof_path);
+    let config = Config {
+        port: 0,
+        db_path: "/dev/null".into(),
+        appendonly: true,
+        aof_path: aof_path.clone(),
+        appendfsync: AppendFsync::Always,
+        ..Default::default()
+    };
+
+    let server = Server::from_config(config.clone()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    client.execute(&IncrCommand { key: b"a" }).await.unwrap();
+    client.execute(&IncrCommand { key: b"a" }).await.unwrap();
+    let expire = ExpireCommand {
+        key: b"a",
+        seconds: 100,
+        condition: ExpireCondition::Always,
+    };
+    client.execute(&expire).await.unwrap();
+    server_handle.await;
+
+    let server = Server::from_config(config).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    let result = client.execute(&GetCommand { key: b"a" }).await.unwrap();
+    assert_eq!(result, Some("2".into()));
+    let result = client.execute(&TtlCommand { key: b"a" }).await.unwrap();
+    assert!(result > 90 && result <= 100);
+    server_handle.await;
+
+    std::fs::remove_file(&aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_value() {
+    let aof_path = std::env::temp_dir().join(format!("memds-binary-{}.aof", std::process::id()));
+    let aof_path = aof_path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&aof_path);
+    let config = Config {
+        port: 0,
+        db_path: "/dev/null".into(),
+        appendonly: true,
+        aof_path: aof_path.clone(),
+        appendfsync: AppendFsync::Always,
+        ..Default::default()
+    };
+    let key = b"\xff\x00";
+    let value = b"\x00\xfe\r\n";
+
+    let server = Server::from_config(config.clone()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    let append = AppendCommand { key, value };
+    assert_eq!(client.execute(&append).await.unwrap(), 4);
+    let result = client.execute(&GetCommand { key }).await.unwrap();
+    assert_eq!(result.unwrap(), &value[..]);
+    server_handle.await;
+
+    let server = Server::from_config(config).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    let result = client.execute(&GetCommand { key }).await.unwrap();
+    assert_eq!(result.unwrap(), &value[..]);
+    server_handle.await;
+
+    std::fs::remove_file(&aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_persistence_commands() {
+    let server = Server::new(0, "/dev/null".into()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+
+    let lastsave = client.execute(&LastsaveCommand).await.unwrap();
+    assert!(lastsave > 0);
+    client.execute(&IncrCommand { key: b"a" }).await.unwrap();
+    let info = client
+        .execute(&InfoCommand {
+            section: Some("persistence"),
+        })
+        .await
+        .unwrap();
+    assert!(info.0.contains("rdb_changes_since_last_save:1\r\n"));
+    assert!(info.0.contains("aof_enabled:0\r\n"));
+
+    let result = client.execute(&BgsaveCommand).await.unwrap();
+    assert_eq!(result, "Background saving started");
+
+    server_handle.await;
+}
+
+/// Reads from the socket until `expected` is received
+async fn read_exact_reply(socket: &mut TcpStream, expected: &str) {
+    let mut buf = vec![0; expected.len()];
+    tokio::time::timeout(Duration::from_secs(5), socket.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn test_pubsub() {
+    let server = Server::new(0, "/dev/null".into()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+
+    let mut subscriber = TcpStream::connect(addr).await.unwrap();
+    subscriber
+        .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n")
+        .await
+        .unwrap();
+    read_exact_reply(
+        &mut subscriber,
+        "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n",
+    )
+    .await;
+
+    let mut publisher = Client::from_addr(addr).await.unwrap();
+    let publish = PublishCommand {
+        channel: b"news",
+        message: b"hello",
+    };
+    assert_eq!(publisher.execute(&publish).await.unwrap(), 1);
+    read_exact_reply(
+        &mut subscriber,
+        "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n",
+    )
+    .await;
+
+    drop(subscriber);
+    // the subscriptions are removed once the session of the subscriber ends
+    for _ in 0..50 {
+        if publisher.execute(&publish).await.unwrap() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(publisher.execute(&publish).await.unwrap(), 0);
+
+    server_handle.await;
+}
+
+#[tokio::test]
+async fn test_blocking_pop() {
+    let aof_path = std::env::temp_dir().join(format!("memds-blocking-{}.aof", std::process::id()));
+    let aof_path = aof_path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&aof_path);
+    let config = Config {
+        port: 0,
+        db_path: "/dev/null".into(),
+        appendonly: true,
+        aof_path: aof_path.clone(),
+        appendfsync: AppendFsync::Always,
+        ..Default::default()
+    };
+
+    let server = Server::from_config(config.clone()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let blpop = b"*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$1\r\n0\r\n";
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    first.write_all(blpop).await.unwrap();
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    // the clients are served in the order they blocked
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    second.write_all(blpop).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let rpush = RpushCommand {
+        key: b"q",
+        elements: vec![b"a", b"b", b"c"],
+    };
+    assert_eq!(client.execute(&rpush).await.unwrap(), 3);
+    read_exact_reply(&mut first, "*2\r\n+q\r\n+a\r\n").await;
+    read_exact_reply(&mut second, "*2\r\n+q\r\n+b\r\n").await;
+
+    // null reply once the timeout passes
+    first
+        .write_all(b"*3\r\n$5\r\nBRPOP\r\n$1\r\nx\r\n$4\r\n0.05\r\n")
+        .await
+        .unwrap();
+    read_exact_reply(&mut first, "*-1\r\n").await;
+
+    // the session of a client blocked forever ends on shutdown
+    second
+        .write_all(b"*3\r\n$5\r\nBRPOP\r\n$1\r\nx\r\n$1\r\n0\r\n")
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server_handle)
+        .await
+        .unwrap();
+
+    // the pops are replayed from the AOF
+    let server = Server::from_config(config).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    assert_eq!(client.execute(&LlenCommand { key: b"q" }).await.unwrap(), 1);
+    server_handle.await;
+
+    std::fs::remove_file(&aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_client_tracking() {
+    let server = Server::new(0, "/dev/null".into()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+
+    let mut reader = TcpStream::connect(addr).await.unwrap();
+    reader
+        .write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
+        .await
+        .unwrap();
+    read_exact_reply(
+        &mut reader,
+        "%3\r\n+server\r\n+memds\r\n+version\r\n+0.0.1\r\n+proto\r\n:3\r\n",
+    )
+    .await;
+    reader
+        .write_all(
+            b"*3\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\nON\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+        )
+        .await
+        .unwrap();
+    read_exact_reply(&mut reader, "+OK\r\n_\r\n").await;
+
+    // the reader is pushed the invalidation of the key it read once another client modifies it
+    let mut client = Client::from_addr(addr).await.unwrap();
+    let append = AppendCommand {
+        key: b"k",
+        value: b"v",
+    };
+    assert_eq!(client.execute(&append).await.unwrap(), 1);
+    read_exact_reply(&mut reader, ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n").await;
+
+    server_handle.await;
+}
+
+/// Encodes a command as an array of bulk strings
+fn encode_command(args: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+
+    buf
+}
+
+#[tokio::test]
+async fn test_scripting() {
+    let aof_path =
+        std::env::temp_dir().join(format!("memds-test-script-{}.aof", std::process::id()));
+    let aof_path = aof_path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&aof_path);
+    let config = Config {
+        port: 0,
+        db_path: "/dev/null".into(),
+        appendonly: true,
+        aof_path: aof_path.clone(),
+        appendfsync: AppendFsync::Always,
+        lua_time_limit: 50,
+        ..Default::default()
+    };
+
+    let server = Server::from_config(config.clone()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut script_client = TcpStream::connect(addr).await.unwrap();
+    let eval = encode_command(&[
+        "EVAL",
+        "redis.call('SET', KEYS[1], ARGV[1]) return redis.call('INCR', KEYS[1])",
+        "1",
+        "s",
+        "41",
+    ]);
+    script_client.write_all(&eval).await.unwrap();
+    read_exact_reply(&mut script_client, ":42\r\n").await;
+
+    // a script running past the time limit gets other clients replied BUSY until it is killed
+    let eval = encode_command(&["EVAL", "while true do end", "0"]);
+    script_client.write_all(&eval).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut killer = TcpStream::connect(addr).await.unwrap();
+    killer.write_all(&encode_command(&["PING"])).await.unwrap();
+    read_exact_reply(
+        &mut killer,
+        "-BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN \
+         NOSAVE.\r\n",
+    )
+    .await;
+    killer
+        .write_all(&encode_command(&["SCRIPT", "KILL"]))
+        .await
+        .unwrap();
+    read_exact_reply(&mut killer, "+OK\r\n").await;
+    read_exact_reply(
+        &mut script_client,
+        "-ERR Script killed by user with SCRIPT KILL...\r\n",
+    )
+    .await;
+    server_handle.await;
+
+    // the writes of the script are replayed from the AOF
+    let server = Server::from_config(config).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut client = Client::from_addr(addr).await.unwrap();
+    let result = client.execute(&GetCommand { key: b"s" }).await.unwrap();
+    assert_eq!(result, Some("42".into()));
+    server_handle.await;
+
+    std::fs::remove_file(&aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_script_errors() {
+    let server = Server::new(0, "/dev/null".into()).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut commands = encode_command(&["EVAL", "error('boom')", "0"]);
+    commands.extend(encode_command(&[
+        "EVAL",
+        "return {err='ERR a\\r\\nb'}",
+        "0",
+    ]));
+    commands.extend(encode_command(&["PING"]));
+    writer.write_all(&commands).await.unwrap();
+
+    // the errors take one line each, the reply after them is still parsed
+    let mut connection = FrameReader::new(reader);
+    let mut replies = Vec::new();
+    while replies.len() < 2 {
+        match connection.next_buffered_frame::<SimpleError>().unwrap() {
+            Some(error) => replies.push(error.0),
+            None => assert!(connection.read_to_buf().await.unwrap() > 0),
+        }
+    }
+    assert!(replies[0].starts_with("ERR Error running script (call to f_"));
+    assert!(replies[0].ends_with("boom"), "{}", replies[0]);
+    assert_eq!(replies[1], "ERR a  b");
+    let pong = loop {
+        match connection.next_buffered_frame::<String>().unwrap() {
+            Some(pong) => break pong,
+            None => assert!(connection.read_to_buf().await.unwrap() > 0),
+        }
+    };
+    assert_eq!(pong, "PONG");
+
+    server_handle.await;
+}
+
+#[tokio::test]
+async fn test_wait_for_script_without_blocking() {
+    let config = Config {
+        port: 0,
+        db_path: "/dev/null".into(),
+        lua_time_limit: 2000,
+        ..Default::default()
+    };
+    let server = Server::from_config(config).unwrap();
+    let (addr, server_handle) = server.service().await.unwrap();
+    let mut script_client = TcpStream::connect(addr).await.unwrap();
+    let eval = encode_command(&["EVAL", "while true do end", "0"]);
+    script_client.write_all(&eval).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // a client waiting for the script does not hold up the other sessions, even on a single
+    // thread: the script is killed well before its time limit
+    let mut waiting = TcpStream::connect(addr).await.unwrap();
+    waiting
+        .write_all(&encode_command(&["GET", "k"]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let started = std::time::Instant::now();
+    let mut killer = TcpStream::connect(addr).await.unwrap();
+    killer
+        .write_all(&encode_command(&["SCRIPT", "KILL"]))
+        .await
+        .unwrap();
+    read_exact_reply(&mut killer, "+OK\r\n").await;
+    assert!(started.elapsed() < Duration::from_millis(1000));
+    read_exact_reply(
+        &mut script_client,
+        "-ERR Script killed by user with SCRIPT KILL...\r\n",
+    )
+    .await;
+    read_exact_reply(&mut waiting, "$-1\r\n").await;
 
     server_handle.await;
 }